@@ -1,14 +1,265 @@
-use std::{fmt::Display, process::Command};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::LazyLock,
+    thread,
+    time::{Duration, Instant},
+};
 
-use serde::{Deserialize, Serialize};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
 use spdlog::debug;
+use tokio::sync::watch;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::priority::IoClass;
+use crate::ratelimit::{self, RateLimit};
+use crate::shell::{self, Shell};
+
+/// Maximum length (in bytes) of a single output line before it gets truncated.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Environment variable holding the source address the tools should bind to, if any.
+pub const BIND_ADDRESS_ENV: &str = "AGENT_BIND_ADDRESS";
+
+/// Appended to lines that were truncated.
+pub const LINE_TRUNCATED_MARKER: &str = "...[line truncated]";
+
+#[derive(Debug, Serialize, Clone)]
 /// Represents a command to execute with arguments and a variant label.
 pub struct Action {
     cmd: String,
     args: Vec<String>,
     variant: String,
+    /// Run the command with a pseudo-terminal as stdout (Unix only).
+    pty: bool,
+    /// Run the command line as a script of the configured shell, see [`Shell`].
+    shell: bool,
+    /// Script run as it is in shell mode instead of the command line, so it may use pipes,
+    /// redirections or loops. The command still names the tool for the metrics.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    script: Option<String>,
+    /// Exit codes meaning the command succeeded, e.g. `[0, 1]` for `grep`.
+    success_exit_codes: Vec<i32>,
+    /// The process is killed once it ran this long, given in seconds.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_timeout"
+    )]
+    timeout: Option<Duration>,
+    /// Variables added to the environment of the process.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Working directory of the process, the agent's one by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cwd: Option<PathBuf>,
+    /// Written to the standard input of the process, which reads nothing otherwise (never the
+    /// agent's own input).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdin: Option<String>,
+    /// Nice value of the process (Unix only), clamped to [`crate::priority::NICE_RANGE`] and to
+    /// at least the agent's one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nice: Option<i32>,
+    /// I/O scheduling class of the process (Linux only), never the realtime one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    io_class: Option<IoClass>,
+    #[serde(skip)]
+    max_line_length: usize,
+}
+
+fn default_success_exit_codes() -> Vec<i32> {
+    vec![0]
+}
+
+fn serialize_timeout<S>(timeout: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match timeout {
+        Some(timeout) => serializer.serialize_some(&timeout.as_secs_f64()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Validates the optional fields so malformed actions are rejected when the job is fetched
+/// rather than misbehaving once run. Actions only made of `cmd` and `args` are still accepted.
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct ActionHelper {
+            cmd: String,
+            #[serde(default)]
+            args: Vec<String>,
+            #[serde(default)]
+            variant: String,
+            #[serde(default)]
+            pty: bool,
+            #[serde(default)]
+            shell: bool,
+            #[serde(default)]
+            script: Option<String>,
+            #[serde(default = "default_success_exit_codes")]
+            success_exit_codes: Vec<i32>,
+            #[serde(default)]
+            timeout: Option<f64>,
+            #[serde(default)]
+            env: Option<BTreeMap<String, serde_json::Value>>,
+            #[serde(default)]
+            cwd: Option<String>,
+            #[serde(default)]
+            stdin: Option<String>,
+            #[serde(default)]
+            nice: Option<i64>,
+            #[serde(default)]
+            io_class: Option<IoClass>,
+        }
+
+        let helper = ActionHelper::deserialize(deserializer)?;
+
+        let timeout = match helper.timeout {
+            Some(seconds) if seconds > 0.0 => {
+                Some(Duration::try_from_secs_f64(seconds).map_err(|err| {
+                    D::Error::custom(format!("invalid timeout {}: {}", seconds, err))
+                })?)
+            }
+            Some(seconds) => {
+                return Err(D::Error::custom(format!(
+                    "invalid timeout {}: expected a positive number of seconds",
+                    seconds
+                )));
+            }
+            None => None,
+        };
+
+        let mut env = BTreeMap::new();
+        for (name, value) in helper.env.unwrap_or_default() {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(D::Error::custom(format!(
+                    "invalid environment variable name {:?}",
+                    name
+                )));
+            }
+            let serde_json::Value::String(value) = value else {
+                return Err(D::Error::custom(format!(
+                    "invalid value of environment variable {}: expected a string, got {}",
+                    name, value
+                )));
+            };
+            env.insert(name, value);
+        }
+
+        if let Some(io_class) = helper.io_class {
+            io_class.check().map_err(D::Error::custom)?;
+        }
+
+        if helper.script.is_some() && !helper.shell {
+            return Err(D::Error::custom("invalid script: only run in shell mode"));
+        }
+
+        let cwd = match helper.cwd {
+            Some(cwd) if cwd.is_empty() => {
+                return Err(D::Error::custom("invalid cwd: expected a non-empty path"));
+            }
+            cwd => cwd.map(PathBuf::from),
+        };
+
+        Ok(Action {
+            cmd: helper.cmd,
+            args: helper.args,
+            variant: helper.variant,
+            pty: helper.pty,
+            shell: helper.shell,
+            script: helper.script,
+            success_exit_codes: helper.success_exit_codes,
+            timeout,
+            env,
+            cwd,
+            stdin: helper.stdin,
+            // beyond i32, the value is clamped anyway
+            nice: helper
+                .nice
+                .map(|nice| nice.clamp(i32::MIN as i64, i32::MAX as i64) as i32),
+            io_class: helper.io_class,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        })
+    }
+}
+
+/// Attempts at spawning a process when it fails transiently, e.g. under fork pressure.
+const SPAWN_ATTEMPTS: u32 = 3;
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// How often a process is checked for exit while a deadline or a shutdown signal is set.
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The `{<name>}` placeholders of the arguments.
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{([a-z_]+)\}").expect("valid placeholder regex"));
+
+/// Agent-wide settings applied to every spawned action.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Unprivileged user the actions are run as (Unix only).
+    pub run_as_user: Option<String>,
+    /// Directory the jobs' own directories are created in, the system's temporary directory
+    /// when unset.
+    pub work_dir: Option<PathBuf>,
+    /// Processes still running at this instant are killed.
+    pub deadline: Option<Instant>,
+    /// Values of the `{<name>}` placeholders of the arguments, such as `{hostname}`.
+    pub variables: HashMap<String, String>,
+    /// Fail instead of leaving unknown placeholders untouched.
+    pub strict_variables: bool,
+    /// Shutdown signal: running processes are killed as soon as it turns true.
+    pub cancel: Option<watch::Receiver<bool>>,
+    /// Turns true when the lease of the job was lost, its process is then killed as the server
+    /// may have handed the job to another agent.
+    pub lease_lost: Option<watch::Receiver<bool>>,
+    /// Source address the tools should bind to, exported as [`BIND_ADDRESS_ENV`].
+    pub bind_address: Option<IpAddr>,
+    /// Packets-per-second budget passed to the scan tools through their rate option.
+    pub rate_limit: Option<RateLimit>,
+    /// Interpreter of the shell-mode actions, which are refused when unset.
+    pub shell: Option<Shell>,
+}
+
+impl RunOptions {
+    /// Whether the shutdown signal was received.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| *cancel.borrow())
+    }
+
+    /// Whether the lease of the job was lost.
+    pub fn is_lease_lost(&self) -> bool {
+        self.lease_lost.as_ref().is_some_and(|lost| *lost.borrow())
+    }
+}
+
+/// What a finished action produced.
+#[derive(Debug, Clone, Default)]
+pub struct ActionOutput {
+    pub stdout: String,
+    /// Warnings and errors printed by the process, whether it succeeded or not.
+    pub stderr: String,
+    /// Sizes of the streams as produced by the process, before any truncation.
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    /// None when the process was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+impl ActionOutput {
+    /// The process ran but printed nothing meaningful (only whitespace, if anything).
+    pub fn is_empty(&self) -> bool {
+        self.stdout.trim().is_empty()
+    }
 }
 
 impl Action {
@@ -17,15 +268,354 @@ impl Action {
             cmd,
             args,
             variant: "".to_string(),
+            pty: false,
+            shell: false,
+            script: None,
+            success_exit_codes: default_success_exit_codes(),
+            timeout: None,
+            env: BTreeMap::new(),
+            cwd: None,
+            stdin: None,
+            nice: None,
+            io_class: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+
+    /// Copy of the action whose tool is limited to the rate budget. Tools without a known rate
+    /// option are left unchanged, with a warning the first time.
+    pub fn with_rate_limit(&self, limit: &RateLimit) -> Action {
+        match limit.apply(&self.cmd, &self.args) {
+            Some(args) => Action {
+                args,
+                ..self.clone()
+            },
+            None => {
+                ratelimit::warn_unlimited(&self.cmd);
+                self.clone()
+            }
+        }
+    }
+
+    /// Returns an action running another command in the same environment (variables and
+    /// working directory), such as the hooks of a job.
+    pub fn with_command(&self, cmd: String, args: Vec<String>) -> Action {
+        Action {
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            ..Action::new(cmd, args)
         }
     }
 
     /// Executes the command with its arguments and returns the standard output as a String.
+    /// The output is read as a stream so a single huge line never has to fit in memory.
+    #[allow(dead_code)]
     pub fn run(&self) -> Result<String, std::io::Error> {
+        self.run_with_options(&RunOptions::default())
+    }
+
+    /// Same as [`Action::run`], applying the agent's run options to the spawned process.
+    #[allow(dead_code)]
+    pub fn run_with_options(&self, options: &RunOptions) -> Result<String, std::io::Error> {
+        self.execute(options).map(|output| output.stdout)
+    }
+
+    /// Executes the command and returns its output along with the size of its streams.
+    pub fn execute(&self, options: &RunOptions) -> Result<ActionOutput, std::io::Error> {
         debug!("Action.run(): {:?}", self.cmd);
-        let output = Command::new(&self.cmd).args(&self.args).output()?;
+        let mut command = match (self.shell, &options.shell) {
+            (true, Some(shell)) => shell.command(&self.script()),
+            (true, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "shell mode is not enabled",
+                ));
+            }
+            (false, _) => {
+                let mut command = Command::new(&self.cmd);
+                command.args(&self.args);
+                command
+            }
+        };
+        command.envs(&self.env).stderr(Stdio::piped());
+        if let Some(address) = options.bind_address {
+            command.env(BIND_ADDRESS_ENV, address.to_string());
+        }
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command.stdin(match self.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        });
+
+        let pty = if self.pty {
+            Some(Action::attach_pty(&mut command)?)
+        } else {
+            command.stdout(Stdio::piped());
+            // the terminal already puts the process in a session of its own
+            Action::set_process_group(&mut command);
+            None
+        };
+
+        // before dropping privileges, which may be needed to raise the priority
+        if self.nice.is_some() || self.io_class.is_some() {
+            Action::set_priority(&mut command, self.nice, self.io_class);
+        }
+        if let Some(user) = &options.run_as_user {
+            Action::run_as(&mut command, user)?;
+        }
 
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        let mut child = spawn_retrying(|| command.spawn())?;
+        // closes our copy of the terminal so reading it ends when the process exits
+        drop(command);
+        // the deadline of the action, or the cycle's one when it comes first
+        let deadline = match (options.deadline, self.timeout) {
+            (Some(deadline), Some(timeout)) => Some(deadline.min(Instant::now() + timeout)),
+            (deadline, timeout) => deadline.or_else(|| timeout.map(|t| Instant::now() + t)),
+        };
+
+        // written from its own thread, a process not reading its input must not block us. the
+        // pipe is closed once written so the process sees the end of its input
+        if let (Some(input), Some(mut stdin)) = (self.stdin.clone(), child.stdin.take()) {
+            thread::spawn(move || {
+                let _ = stdin.write_all(input.as_bytes());
+            });
+        }
+
+        // stderr is drained in its own thread so a chatty process never blocks on a full pipe
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let max_line_length = self.max_line_length;
+        let stderr_reader =
+            thread::spawn(move || read_capped_lines(BufReader::new(stderr), max_line_length));
+
+        let stdout: Box<dyn Read + Send> = match pty {
+            Some(pty) => pty,
+            None => Box::new(child.stdout.take().expect("stdout is piped")),
+        };
+        let stdout_reader =
+            thread::spawn(move || read_capped_lines(BufReader::new(stdout), max_line_length));
+
+        // None when the process was killed
+        let status =
+            if deadline.is_some() || options.cancel.is_some() || options.lease_lost.is_some() {
+                Action::wait_until(&mut child, options, deadline)?
+            } else {
+                Some(child.wait()?)
+            };
+
+        let output = stdout_reader
+            .join()
+            .map_err(|_| io::Error::other("stdout reader panicked"))?;
+        let (stderr, stderr_bytes) = stderr_reader
+            .join()
+            .map_err(|_| io::Error::other("stderr reader panicked"))??;
+        let Some(status) = status else {
+            let budget_exceeded = options
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            return Err(if options.is_cancelled() {
+                io::Error::new(io::ErrorKind::Interrupted, "cancelled by shutdown")
+            } else if options.is_lease_lost() {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "lease lost")
+            } else if budget_exceeded {
+                io::Error::new(io::ErrorKind::TimedOut, "cycle budget exceeded")
+            } else {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {:?}", self.timeout.unwrap_or_default()),
+                )
+            });
+        };
+        let (stdout, stdout_bytes) = output?;
+
+        Ok(ActionOutput {
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
+            stdout_bytes,
+            stderr_bytes,
+            exit_code: status.code(),
+        })
+    }
+
+    // wait for the process to exit, killing it if it is still running at the deadline, once the
+    // shutdown signal is received or once the lease of the job is lost. returns its exit status,
+    // or None if it was killed
+    fn wait_until(
+        child: &mut Child,
+        options: &RunOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Option<ExitStatus>, std::io::Error> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if options.is_cancelled()
+                || options.is_lease_lost()
+                || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                Action::kill(child)?;
+                child.wait()?;
+                return Ok(None);
+            }
+            thread::sleep(DEADLINE_POLL_INTERVAL);
+        }
+    }
+
+    // the process leads a group of its own, killing the group also kills the processes it
+    // spawned, which would otherwise keep running and hold its output open
+    #[cfg(unix)]
+    fn set_process_group(command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    #[cfg(not(unix))]
+    fn set_process_group(_command: &mut Command) {}
+
+    #[cfg(unix)]
+    fn kill(child: &mut Child) -> Result<(), std::io::Error> {
+        // the id of the process is the one of its group, see set_process_group
+        match unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } {
+            0 => Ok(()),
+            _ => child.kill(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn kill(child: &mut Child) -> Result<(), std::io::Error> {
+        child.kill()
+    }
+
+    /// Whether the exit code means the action succeeded. A process killed by a signal has no
+    /// exit code and never succeeds.
+    pub fn is_success_exit_code(&self, exit_code: Option<i32>) -> bool {
+        exit_code.is_some_and(|code| self.success_exit_codes.contains(&code))
+    }
+
+    #[cfg(unix)]
+    fn attach_pty(command: &mut Command) -> Result<Box<dyn Read + Send>, std::io::Error> {
+        Ok(Box::new(crate::pty::attach(command)?))
+    }
+
+    #[cfg(not(unix))]
+    fn attach_pty(_command: &mut Command) -> Result<Box<dyn Read + Send>, std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "running actions in a pseudo-terminal is only supported on Unix",
+        ))
+    }
+
+    #[cfg(unix)]
+    fn set_priority(command: &mut Command, nice: Option<i32>, io_class: Option<IoClass>) {
+        crate::priority::set_priority(command, nice, io_class);
+    }
+
+    #[cfg(not(unix))]
+    fn set_priority(_command: &mut Command, _nice: Option<i32>, _io_class: Option<IoClass>) {
+        spdlog::warn!("Scheduling priorities are only supported on Unix, ignoring them");
+    }
+
+    #[cfg(unix)]
+    fn run_as(command: &mut Command, user: &str) -> Result<(), std::io::Error> {
+        let user = crate::privilege::lookup_user(user)?;
+        crate::privilege::drop_privileges(command, &user);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn run_as(_command: &mut Command, _user: &str) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "running actions as another user is only supported on Unix",
+        ))
+    }
+
+    /// Returns a copy of the action whose `{input:<name>}` placeholders are replaced by the
+    /// local path of the matching staged input.
+    pub fn with_inputs(&self, inputs: &HashMap<String, PathBuf>) -> Action {
+        let mut action = self.clone();
+        for arg in action.args.iter_mut() {
+            for (name, path) in inputs {
+                let placeholder = format!("{{input:{}}}", name);
+                if arg.contains(&placeholder) {
+                    *arg = arg.replace(&placeholder, &path.to_string_lossy());
+                }
+            }
+        }
+        action
+    }
+
+    /// Returns a copy of the action whose `{<name>}` placeholders are replaced by the matching
+    /// variable. Unknown placeholders are left untouched, or rejected when `strict` is set.
+    pub fn with_variables(
+        &self,
+        variables: &HashMap<String, String>,
+        strict: bool,
+    ) -> Result<Action, io::Error> {
+        let mut action = self.clone();
+
+        for arg in action.args.iter_mut() {
+            if strict
+                && let Some(unknown) = PLACEHOLDER
+                    .captures_iter(arg)
+                    .find(|captures| !variables.contains_key(&captures[1]))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown placeholder {}", &unknown[0]),
+                ));
+            }
+
+            *arg = PLACEHOLDER
+                .replace_all(arg, |captures: &Captures| {
+                    match variables.get(&captures[1]) {
+                        Some(value) => value.clone(),
+                        None => captures[0].to_string(),
+                    }
+                })
+                .into_owned();
+        }
+
+        Ok(action)
+    }
+
+    #[allow(dead_code)]
+    pub fn set_pty(&mut self, pty: bool) {
+        self.pty = pty;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_shell(&mut self, shell: bool) {
+        self.shell = shell;
+    }
+
+    pub fn is_shell(&self) -> bool {
+        self.shell
+    }
+
+    #[allow(dead_code)]
+    pub fn set_script(&mut self, script: Option<String>) {
+        self.script = script;
+    }
+
+    /// The script run by the shell in shell mode: the action's own script, or else its command
+    /// line quoted so every word reaches the command unchanged.
+    fn script(&self) -> String {
+        match &self.script {
+            Some(script) => script.clone(),
+            None => self.command_line(),
+        }
+    }
+
+    fn command_line(&self) -> String {
+        let words = std::iter::once(&self.cmd).chain(&self.args);
+        shell::command_line(words.map(String::as_str))
+    }
+
+    #[allow(dead_code)]
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.max_line_length = max_line_length;
     }
 
     #[allow(dead_code)]
@@ -33,15 +623,102 @@ impl Action {
         &self.cmd
     }
 
+    // used by unit tests, to rewrite jobs like a transformer would
+    #[allow(dead_code)]
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
     #[allow(dead_code)]
     pub fn get_args(&self) -> &Vec<String> {
         &self.args
     }
+
+    pub fn get_variant(&self) -> &str {
+        &self.variant
+    }
+}
+
+/// Calls `spawn` again after a short delay when it fails with a transient error (`EAGAIN` when
+/// the system is out of processes, `EINTR`), at most [`SPAWN_ATTEMPTS`] times in total. Other
+/// errors, such as a missing command or a permission denied, are returned right away.
+fn spawn_retrying<T>(mut spawn: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match spawn() {
+            Err(err)
+                if attempt < SPAWN_ATTEMPTS
+                    && matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                    ) =>
+            {
+                debug!(
+                    "Spawn attempt {}/{} failed: {}",
+                    attempt, SPAWN_ATTEMPTS, err
+                );
+                attempt += 1;
+                thread::sleep(SPAWN_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
 }
 
+/// Reads the whole stream, keeping at most `max_line_length` bytes of each line. The remaining
+/// bytes of an overly long line are dropped and replaced by [`LINE_TRUNCATED_MARKER`].
+/// Returns the kept output along with the number of bytes actually read.
+fn read_capped_lines<R: BufRead>(
+    mut reader: R,
+    max_line_length: usize,
+) -> Result<(Vec<u8>, u64), std::io::Error> {
+    let mut output = Vec::new();
+    let mut total_bytes = 0;
+    let mut line_length = 0;
+    let mut truncated = false;
+
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+
+        for &byte in buffer {
+            if byte == b'\n' {
+                if truncated {
+                    output.extend_from_slice(LINE_TRUNCATED_MARKER.as_bytes());
+                }
+                output.push(byte);
+                line_length = 0;
+                truncated = false;
+            } else if line_length < max_line_length {
+                output.push(byte);
+                line_length += 1;
+            } else {
+                truncated = true;
+            }
+        }
+
+        let consumed = buffer.len();
+        total_bytes += consumed as u64;
+        reader.consume(consumed);
+    }
+
+    if truncated {
+        output.extend_from_slice(LINE_TRUNCATED_MARKER.as_bytes());
+    }
+
+    Ok((output, total_bytes))
+}
+
+/// The command line quoted for the platform, so it runs the same argv when pasted in a terminal.
+/// Shell-mode scripts are shown as they are run.
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.cmd, self.args.join(" "))
+        if self.shell {
+            return write!(f, "{}", self.script());
+        }
+        write!(f, "{}", self.command_line())
     }
 }
 
@@ -74,6 +751,30 @@ mod tests {
         assert_eq!(err.kind(), io::ErrorKind::NotFound);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_action_run_in_configured_shell() {
+        // Given a script relying on bash arrays
+        let Ok(shell) = crate::shell::parse_shell("bash") else {
+            return;
+        };
+        let mut action = Action::new("echo".to_string(), vec![]);
+        action.set_shell(true);
+        action.set_script(Some(
+            "targets=(10.0.0.1 10.0.0.2 10.0.0.3); echo ${#targets[@]}".to_string(),
+        ));
+        let options = RunOptions {
+            shell: Some(shell),
+            ..Default::default()
+        };
+
+        // When
+        let output = action.run_with_options(&options).unwrap();
+
+        // Then
+        assert_eq!(output, "3\n");
+    }
+
     #[test]
     fn test_action_display() {
         let action = Action::new(
@@ -83,4 +784,372 @@ mod tests {
         let display_str = format!("{}", action);
         assert_eq!(display_str, "echo hello world");
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_display_quotes_spaces_and_quotes() {
+        let action = Action::new(
+            "/opt/my tools/nmap".to_string(),
+            vec!["target with space".to_string(), r#"say "it's""#.to_string()],
+        );
+
+        assert_eq!(
+            action.to_string(),
+            r#"'/opt/my tools/nmap' 'target with space' 'say "it'\''s"'"#
+        );
+        assert_eq!(
+            Action::new("uptime".to_string(), vec![]).to_string(),
+            "uptime"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_run_tool_in_path_with_spaces() {
+        // Given a tool installed in a directory with a space in its name
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir()
+            .join(format!("agent-action-{}", uuid::Uuid::new_v4()))
+            .join("my tools");
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("nmap");
+        std::fs::write(&tool, "#!/bin/sh\nprintf '[%s]' \"$@\"\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cmd = tool.display().to_string();
+        let args = ["target with space", r#"say "it's""#];
+        let action = Action::new(cmd.clone(), args.map(String::from).to_vec());
+        // the words of the command line are quoted in shell mode too
+        let mut script = action.clone();
+        script.set_shell(true);
+
+        let options = RunOptions {
+            shell: Some(Shell::default()),
+            ..Default::default()
+        };
+
+        // When
+        let output = action.run().unwrap();
+        let script_output = script.run_with_options(&options).unwrap();
+
+        // Then
+        assert_eq!(output, r#"[target with space][say "it's"]"#);
+        assert_eq!(script_output, output);
+        assert_eq!(script.to_string(), action.to_string());
+        let _ = std::fs::remove_dir_all(dir.parent().unwrap());
+    }
+
+    #[test]
+    fn test_read_capped_lines_truncates_long_lines_only() {
+        let input = "short\nthis line is way too long\nok\n";
+        let (output, total_bytes) = read_capped_lines(input.as_bytes(), 8).unwrap();
+
+        assert_eq!(total_bytes, input.len() as u64);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("short\nthis lin{}\nok\n", LINE_TRUNCATED_MARKER)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_run_truncates_single_long_line() {
+        // a single 200KB line without any newline
+        let mut action = Action::new(
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "head -c 200000 /dev/zero | tr '\\0' a".to_string(),
+            ],
+        );
+        action.set_max_line_length(1024);
+
+        let output = action.run().unwrap();
+
+        assert_eq!(output.len(), 1024 + LINE_TRUNCATED_MARKER.len());
+        assert!(output.ends_with(LINE_TRUNCATED_MARKER));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_run_as_user() {
+        // dropping privileges requires root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let user = crate::privilege::lookup_user("nobody").unwrap();
+        let action = Action::new("id".to_string(), vec!["-u".to_string()]);
+        let options = RunOptions {
+            run_as_user: Some("nobody".to_string()),
+            ..Default::default()
+        };
+
+        let output = action.run_with_options(&options).unwrap();
+
+        assert_eq!(output.trim(), user.uid.to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_with_pty_is_a_tty() {
+        let script = "test -t 1 && echo tty || echo notty";
+        let mut action = Action::new("sh".to_string(), vec!["-c".to_string(), script.to_string()]);
+
+        assert_eq!(action.run().unwrap(), "notty\n");
+
+        action.set_pty(true);
+        assert_eq!(action.run().unwrap(), "tty\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_exports_bind_address() {
+        let script = format!("echo ${}", BIND_ADDRESS_ENV);
+        let action = Action::new("sh".to_string(), vec!["-c".to_string(), script]);
+        let options = RunOptions {
+            bind_address: Some("10.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(action.run_with_options(&options).unwrap(), "10.0.0.2\n");
+        assert_eq!(action.run().unwrap(), "\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_nice_value() {
+        // Given actions printing their own nice value, the last ones out of range
+        let parse = |nice: i64| -> Action {
+            serde_json::from_value(serde_json::json!({"cmd": "nice", "nice": nice})).unwrap()
+        };
+        let current = crate::priority::current_nice();
+
+        // When / Then the value is never below the agent's one
+        let expected = |nice: i32| format!("{}\n", nice.max(current));
+        assert_eq!(parse(17).run().unwrap(), expected(17));
+        assert_eq!(parse(100).run().unwrap(), expected(19));
+        assert_eq!(parse(-100).run().unwrap(), expected(-20));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_invalid_fields() {
+        let parse = |action: serde_json::Value| serde_json::from_value::<Action>(action);
+
+        let err = parse(serde_json::json!({"cmd": "nmap", "args": [], "timeout": -5})).unwrap_err();
+        assert!(err.to_string().contains("invalid timeout -5"));
+        assert!(parse(serde_json::json!({"cmd": "nmap", "timeout": 0})).is_err());
+
+        let err = parse(serde_json::json!({"cmd": "nmap", "env": {"RATE": 100}})).unwrap_err();
+        assert!(err.to_string().contains("environment variable RATE"));
+        assert!(parse(serde_json::json!({"cmd": "nmap", "env": {"A=B": "c"}})).is_err());
+        assert!(parse(serde_json::json!({"cmd": "nmap", "cwd": ""})).is_err());
+        assert!(parse(serde_json::json!({"cmd": "nmap", "script": "nmap -sn $NET"})).is_err());
+        let err = parse(serde_json::json!({"cmd": "nmap", "io_class": "realtime"})).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("realtime I/O class is not allowed")
+        );
+    }
+
+    #[test]
+    fn test_deserialize_extended_action() {
+        // Given
+        let raw = serde_json::json!({
+            "cmd": "sh",
+            "args": ["-c", "cat; echo \"$GREETING from $(pwd)\""],
+            "timeout": 2.5,
+            "env": {"GREETING": "hello"},
+            "cwd": "/",
+            "stdin": "input\n",
+            "io_class": "best-effort",
+        });
+
+        // When
+        let action: Action = serde_json::from_value(raw).unwrap();
+
+        // Then
+        assert_eq!(action.timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(action.variant, "");
+        assert_eq!(action.success_exit_codes, vec![0]);
+        assert_eq!(action.io_class, Some(IoClass::BestEffort));
+        #[cfg(unix)]
+        assert_eq!(action.run().unwrap(), "input\nhello from /\n");
+
+        // and it serializes back to the same fields
+        let serialized = serde_json::to_value(&action).unwrap();
+        assert_eq!(serialized["timeout"], 2.5);
+        assert_eq!(serialized["env"]["GREETING"], "hello");
+        assert_eq!(serialized["io_class"], "best-effort");
+        assert!(serialized.get("nice").is_none());
+        let action: Action = serde_json::from_value(serialized).unwrap();
+        assert_eq!(action.cwd, Some(PathBuf::from("/")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_without_stdin_reads_nothing() {
+        let action = Action::new("cat".to_string(), vec![]);
+
+        assert_eq!(action.run().unwrap(), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_timeout() {
+        let action: Action = serde_json::from_value(
+            serde_json::json!({"cmd": "sleep", "args": ["5"], "timeout": 0.1}),
+        )
+        .unwrap();
+        let started = Instant::now();
+
+        let err = action.run().unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_retries_transient_errors() {
+        // Given a spawn failing once with EAGAIN
+        let mut attempts = 0;
+        let spawn = || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                _ => Ok("spawned"),
+            }
+        };
+
+        // When
+        let result = spawn_retrying(spawn);
+
+        // Then
+        assert_eq!(result.unwrap(), "spawned");
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_spawn_does_not_retry_permanent_errors() {
+        let mut attempts = 0;
+        let result: io::Result<()> = spawn_retrying(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = spawn_retrying(|| {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(attempts, SPAWN_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_action_with_variables() {
+        let action = Action::new(
+            "echo".to_string(),
+            vec!["{hostname}-{unknown}".to_string(), "{input:a}".to_string()],
+        );
+        let variables = HashMap::from([("hostname".to_string(), "box".to_string())]);
+
+        let substituted = action.with_variables(&variables, false).unwrap();
+
+        assert_eq!(
+            substituted.get_args(),
+            &vec!["box-{unknown}".to_string(), "{input:a}".to_string()]
+        );
+        let err = action.with_variables(&variables, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_run_as_nonexistent_user_fails() {
+        let action = Action::new("id".to_string(), vec!["-u".to_string()]);
+        let options = RunOptions {
+            run_as_user: Some("non_existing_user".to_string()),
+            ..Default::default()
+        };
+
+        let err = action.run_with_options(&options).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_action_execute_counts_stream_bytes() {
+        let mut action = Action::new(
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "head -c 5000 /dev/zero | tr '\\0' a; printf 'xyz' >&2".to_string(),
+            ],
+        );
+        action.set_max_line_length(100);
+
+        let output = action.execute(&RunOptions::default()).unwrap();
+
+        // the counters reflect what the process produced, not the truncated output
+        assert_eq!(output.stdout_bytes, 5000);
+        assert_eq!(output.stderr_bytes, 3);
+        assert_eq!(output.stdout.len(), 100 + LINE_TRUNCATED_MARKER.len());
+    }
+
+    #[test]
+    fn test_action_execute_killed_on_shutdown() {
+        // Given a long running action and a shutdown signal sent while it runs
+        let action = Action::new("sleep".to_string(), vec!["10".to_string()]);
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let options = RunOptions {
+            cancel: Some(cancel_rx),
+            ..Default::default()
+        };
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            cancel_tx.send(true).unwrap();
+        });
+
+        // When
+        let started = Instant::now();
+        let err = action.execute(&options).unwrap_err();
+
+        // Then
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_action_execute_kills_process_group() {
+        // Given an action whose process spawns a long running one, which holds its output open
+        let action = Action::new(
+            "sh".to_string(),
+            vec!["-c".to_string(), "sleep 10 & wait".to_string()],
+        );
+        let options = RunOptions {
+            deadline: Some(Instant::now() + Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        // When
+        let started = Instant::now();
+        let err = action.execute(&options).unwrap_err();
+
+        // Then the spawned process is killed too, so reading the output ends right away
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_action_execute_empty_output() {
+        let action = Action::new("echo".to_string(), vec![]);
+
+        let output = action.execute(&RunOptions::default()).unwrap();
+
+        assert!(output.is_empty());
+        assert_eq!(output.stdout_bytes, 1);
+    }
 }
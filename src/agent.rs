@@ -1,22 +1,67 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use reqwest::StatusCode;
 use serde::Deserializer;
 use serde::Serializer;
+use serde::de::Error as _;
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Serialize};
 use spdlog::info;
-use spdlog::{debug, error};
+use spdlog::{debug, error, warn};
 
-use crate::api::client::ClientError;
+use crate::action::{Action, RunOptions};
+use crate::api::client::{ClientError, WireFormat};
+use crate::compress::{RESULTS_ENCODING_GZIP_BASE64, compress_result};
+use crate::disk::DiskBudget;
 use crate::job::Job;
-use crate::job::JobPatch;
-use crate::{api::ApiClient, tool::Tool};
+use crate::job::{
+    DEFAULT_RESULTS_FIELD, DeferReason, Deferral, JobClaim, JobCreation, JobDeferral,
+    JobLeaseRenewal, JobPatch, JobStatus, SkipReason,
+};
+use crate::maintenance::{self, MaintenanceWindow};
+use crate::metrics::{Metrics, Summary};
+use crate::parser::{OutputParser, ParserRegistry};
+use crate::resources::HostResources;
+use crate::schedule::LocalJob;
+use crate::scope::Scope;
+use crate::stream::json_with_streamed_field;
+use crate::transform::{JobTransformer, NoopTransformer};
+use crate::upload::{ArtifactRetention, RetryPolicy, UploadQueue};
+use crate::{
+    api::{ApiClient, ApiData, ApiTransport},
+    tool::{Tool, VersionProbe},
+};
+use tokio::sync::watch;
 
 use gethostname::gethostname;
 
+// version of the agent binary and the git commit it was built from (set by build.rs)
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const AGENT_BUILD: &str = env!("AGENT_GIT_SHA");
+
+// years between which the clock is trusted
+const PLAUSIBLE_YEARS: (i32, i32) = (2000, 2100);
+
+// bounds on a single fetch of the jobs list, whatever the server's pagination
+const MAX_JOB_PAGES: usize = 100;
+const MAX_FETCHED_JOBS: usize = 1000;
+
+// size of an input downloaded for a job, large wordlists included
+pub const DEFAULT_MAX_INPUT_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "UPPERCASE")]
 enum AgentPlatform {
@@ -35,6 +80,8 @@ pub struct AgentCapabilities {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentPresence {
     last_seen_at: Option<DateTime<Utc>>,
+    // the agent neither fetches nor starts jobs until it is resumed
+    paused: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -52,11 +99,26 @@ pub enum RunJobsError {
     Mutex,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentStatus {
+    status: String,
+    last_seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentSummary {
+    summary: Summary,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentRegister {
     platform: Option<AgentPlatform>,
     hostname: Option<String>,
     last_seen_at: Option<DateTime<Utc>>,
+    version: Option<String>,
+    build: Option<String>,
+    #[serde(flatten)]
+    resources: HostResources,
 }
 
 /// Main agents structure. It maps the agent's table on the BD + has some required fields
@@ -77,11 +139,150 @@ pub struct Agent {
     platform: Option<AgentPlatform>,
     last_seen_at: Option<DateTime<Utc>>,
     created_at: Option<DateTime<Utc>>,
+    // version and git commit of the agent binary, see AGENT_VERSION and AGENT_BUILD
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    build: Option<String>,
 
     available_tools: Option<Vec<Tool>>,
 
-    #[serde(skip)]
+    #[serde(skip, default = "ApiClient::detached")]
     client: ApiClient,
+
+    // JSON requests go through it instead of the client when set, see `transport`
+    #[serde(skip)]
+    transport: Option<Arc<dyn ApiTransport>>,
+
+    // client of the service the reports and artifacts are sent to, the API's one when unset
+    #[serde(skip)]
+    results_client: Option<ApiClient>,
+
+    // correct timestamps sent to the API with the server's clock offset
+    #[serde(skip)]
+    use_server_time: bool,
+
+    // hash of the last submitted capabilities, used to skip PATCHes when nothing changed
+    #[serde(skip)]
+    capabilities_hash: Option<u64>,
+    #[serde(skip)]
+    capabilities_submitted_at: Option<Instant>,
+    // rescan the tools and force a capabilities submission after this interval even if nothing
+    // changed
+    #[serde(skip)]
+    capabilities_refresh_interval: Option<Duration>,
+    // rescan the tools on the next cycle, e.g. once one was installed
+    #[serde(skip)]
+    capabilities_rescan: Arc<AtomicBool>,
+    // capabilities are registered out-of-band, never scan nor submit them
+    #[serde(skip)]
+    capabilities_disabled: bool,
+    // a failed capabilities submission at startup is an error instead of being retried next
+    // cycle, later failures are always retried
+    #[serde(skip)]
+    capabilities_required: bool,
+    // commands of the tools looked for when the API cannot list them
+    #[serde(skip)]
+    fallback_tools: Vec<String>,
+
+    // parsers used to attach structured output to job reports, looked up by action variant
+    #[serde(skip)]
+    parsers: ParserRegistry,
+
+    // settings applied to every spawned action
+    #[serde(skip)]
+    run_options: RunOptions,
+
+    // directory each job's log file is written to, if any
+    #[serde(skip)]
+    job_log_dir: Option<PathBuf>,
+
+    // maximum time spent running jobs in a single cycle
+    #[serde(skip)]
+    cycle_budget: Option<Duration>,
+
+    // the lease of the running jobs is renewed this often, not at all when unset
+    #[serde(skip)]
+    lease_renewal_interval: Option<Duration>,
+
+    // results larger than this many bytes are compressed before being submitted
+    #[serde(skip)]
+    compression_threshold: Option<usize>,
+    // results (once compressed) larger than this many bytes are streamed to the server
+    #[serde(skip)]
+    stream_results_threshold: Option<usize>,
+    // encoding of the reports, back to JSON once the server rejected MessagePack
+    #[serde(skip)]
+    wire_format: WireFormat,
+    // name of the results field of the reports, which depends on the API version
+    #[serde(skip, default = "default_results_field")]
+    results_field: String,
+
+    // uploads jobs artifacts in the background
+    #[serde(skip)]
+    uploads: UploadQueue,
+
+    // targets the jobs are allowed to scan, out-of-scope jobs are rejected
+    #[serde(skip)]
+    scope: Scope,
+
+    // sent on SIGTERM or SIGINT, running jobs are killed and no new job is started once received.
+    // a drain only stops the poll loop and lets the running jobs finish
+    #[serde(skip)]
+    cancel: Option<watch::Receiver<bool>>,
+
+    // retries of the tools version probe
+    #[serde(skip)]
+    version_probe: VersionProbe,
+
+    // periods during which no job is started
+    #[serde(skip)]
+    maintenance_windows: Vec<MaintenanceWindow>,
+
+    // recurring jobs defined locally, along with when they were last evaluated
+    #[serde(skip)]
+    local_jobs: Vec<LocalJob>,
+    #[serde(skip)]
+    local_jobs_checked_at: Option<DateTime<Utc>>,
+
+    // limits on the disk space used by the job logs
+    #[serde(skip)]
+    disk_budget: DiskBudget,
+
+    // no job is fetched nor started while paused, in-flight jobs are still reported
+    #[serde(skip)]
+    paused: Arc<AtomicBool>,
+
+    // executions and durations of the jobs by tool
+    #[serde(skip)]
+    metrics: Metrics,
+
+    // source of the current time, replaced by unit tests
+    #[serde(skip, default = "default_clock")]
+    clock: fn() -> DateTime<Utc>,
+    // the clock is out of the plausible range and was already warned about
+    #[serde(skip)]
+    implausible_clock: AtomicBool,
+
+    // just before the creation time of the newest job fetched, sent as `since` to only get the
+    // jobs created since then. jobs created at the same time as that one are fetched again
+    // rather than missed, and recognized by their id
+    #[serde(skip)]
+    jobs_cursor: Option<DateTime<Utc>>,
+    // jobs asked for (and queued) per fetch
+    #[serde(skip)]
+    max_jobs_per_fetch: Option<usize>,
+    // size of each input downloaded for the jobs
+    #[serde(skip, default = "default_max_input_bytes")]
+    max_input_bytes: u64,
+
+    // retries of the job reports, independent from the artifact uploads ones
+    #[serde(skip, default = "default_report_retry")]
+    report_retry: RetryPolicy,
+
+    // rewrites (or rejects) the fetched jobs before they are queued
+    #[serde(skip, default = "default_transformer")]
+    transformer: Arc<dyn JobTransformer>,
 }
 
 /// Serde JSON serialization and deserialization methods
@@ -97,395 +298,4449 @@ where
     seq.end()
 }
 
+fn default_clock() -> fn() -> DateTime<Utc> {
+    Utc::now
+}
+
+fn default_results_field() -> String {
+    DEFAULT_RESULTS_FIELD.to_string()
+}
+
+fn default_max_input_bytes() -> u64 {
+    DEFAULT_MAX_INPUT_BYTES
+}
+
+fn default_report_retry() -> RetryPolicy {
+    RetryPolicy::REPORTS
+}
+
+fn default_transformer() -> Arc<dyn JobTransformer> {
+    Arc::new(NoopTransformer)
+}
+
 type SharedJobs = Arc<Mutex<Vec<Arc<Job>>>>;
+
+// the jobs are a bare array, or an object wrapping it under `data` (as the API lists them) or
+// `jobs`. the array is picked before deserializing the jobs, so an invalid job is reported as
+// such rather than as an array matching none of the shapes
 fn deserialize_jobs<'de, D>(deserializer: D) -> Result<SharedJobs, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let jobs_vec = Vec::<Job>::deserialize(deserializer)?;
+    let jobs = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Object(mut wrapper) => wrapper
+            .remove("data")
+            .or_else(|| wrapper.remove("jobs"))
+            .ok_or_else(|| D::Error::custom("expected the jobs under `data` or `jobs`"))?,
+        jobs => jobs,
+    };
+    let jobs_vec = Vec::<Job>::deserialize(jobs).map_err(D::Error::custom)?;
     Ok(Arc::new(Mutex::new(
         jobs_vec.into_iter().map(Arc::new).collect(),
     )))
 }
 
-impl Agent {
-    pub async fn new(base_url: String, token: String) -> Result<Agent, ClientError> {
-        let mut client = ApiClient::new(base_url, token.clone())?;
+// results of a job streamed to the server. the raw output is read from the job log when there is
+// one, so it is not copied once more for each attempt at sending the report
+enum StreamedResults {
+    // the job's output, of this many bytes
+    Raw(Arc<Job>, u64),
+    // the output once encoded (e.g. compressed)
+    Encoded(Bytes),
+}
 
-        let mut agent = Agent::get_info(&mut client).await?;
-        agent.platform = Agent::get_platform();
-        agent.hostname = Some(Agent::get_hostname());
-        agent.client = client;
+impl StreamedResults {
+    fn len(&self) -> u64 {
+        match self {
+            StreamedResults::Raw(_, len) => *len,
+            StreamedResults::Encoded(results) => results.len() as u64,
+        }
+    }
 
-        Ok(agent)
+    fn reader(&self) -> Box<dyn Read + Send> {
+        match self {
+            StreamedResults::Raw(job, len) => {
+                if let Some((path, offset)) = job.get_log() {
+                    let file = File::open(&path).and_then(|mut file| {
+                        file.seek(SeekFrom::Start(offset))?;
+                        Ok(file)
+                    });
+                    match file {
+                        Ok(file) => return Box::new(file.take(*len)),
+                        // e.g. removed by the disk budget, the output is still in memory
+                        Err(err) => {
+                            warn!("Could not read the log of job {}: {}", job.get_id(), err)
+                        }
+                    }
+                }
+                Box::new(Cursor::new(job.get_result_as_string().unwrap_or_default()))
+            }
+            StreamedResults::Encoded(results) => Box::new(Cursor::new(results.clone())),
+        }
     }
+}
 
+impl Agent {
     #[allow(dead_code)]
-    pub fn available_tools(&self) -> &Option<Vec<Tool>> {
-        &self.available_tools
+    pub async fn new(base_url: String, token: String) -> Result<Agent, ClientError> {
+        let client = ApiClient::new(base_url, token.clone())?;
+
+        Agent::with_client(client).await
     }
 
-    // performs PATCH /self
-    pub async fn announce_presence(&mut self) -> Result<(), ClientError> {
-        info!("Announcing presence...");
-        let uri = "/self";
-        self.last_seen_at = Some(Utc::now());
+    // same as `new` with an already configured client
+    pub async fn with_client(client: ApiClient) -> Result<Agent, ClientError> {
+        let agent = Agent::get_info(&client, &client.endpoints().agent_self()).await?;
 
-        let agent = AgentPresence {
-            last_seen_at: self.last_seen_at,
-        };
+        Ok(agent.with_local_info(client))
+    }
 
-        self.client.patch(uri, None, &agent).await?;
-        info!("Finished");
+    // same as `with_client`, every JSON request (GET /self included) going through `transport`
+    // instead, e.g. the `FileTransport` of the air-gapped mode
+    pub async fn with_transport(
+        client: ApiClient,
+        transport: Arc<dyn ApiTransport>,
+    ) -> Result<Agent, ClientError> {
+        let agent = Agent::get_info(transport.as_ref(), &client.endpoints().agent_self()).await?;
+        let mut agent = agent.with_local_info(client);
+        agent.transport = Some(transport);
 
-        Ok(())
+        Ok(agent)
     }
 
-    // performs PATCH /self to update agent's hostname, platform and last_seen_at
-    pub async fn register(&mut self) -> Result<(), ClientError> {
-        info!("Registring agent...");
-        let uri = "/self";
-        self.last_seen_at = Some(Utc::now());
-
-        let agent = AgentRegister {
-            hostname: self.hostname.clone(),
-            platform: self.platform.clone(),
-            last_seen_at: self.last_seen_at,
-        };
+    // the agent's info known locally rather than by the server
+    fn with_local_info(mut self, client: ApiClient) -> Agent {
+        self.platform = Agent::get_platform();
+        self.hostname = Some(Agent::get_hostname());
+        self.version = Some(AGENT_VERSION.to_string());
+        self.build = Some(AGENT_BUILD.to_string());
+        self.client = client;
 
-        self.client.patch(uri, None, &agent).await?;
-        info!("Done");
+        self
+    }
 
-        Ok(())
+    pub fn set_use_server_time(&mut self, enabled: bool) {
+        self.use_server_time = enabled;
     }
 
-    // performs GET /self to fetch agent's info at the startup of this daemon
-    pub async fn get_info(client: &mut ApiClient) -> Result<Agent, ClientError> {
-        let uri = "/self";
-        let res = client.get(uri, None).await?;
-        let data = res.data.unwrap();
-        let agent: Agent = serde_json::from_value(data).map_err(ClientError::ParseError)?;
+    pub fn set_capabilities_disabled(&mut self, disabled: bool) {
+        self.capabilities_disabled = disabled;
+    }
 
-        Ok(agent)
+    pub fn set_capabilities_required(&mut self, required: bool) {
+        self.capabilities_required = required;
     }
 
-    // performs GET /jobs to fetch agent's jobs
-    pub async fn get_jobs(&mut self) -> Result<(), ClientError> {
-        info!("Fetching jobs...");
+    pub fn set_fallback_tools(&mut self, tools: Vec<String>) {
+        self.fallback_tools = tools;
+    }
 
-        let uri = "/jobs";
-        let res = self.client.get(uri, None).await?;
-        let jobs: Vec<Job> = serde_json::from_value(res.data.unwrap()).unwrap();
+    pub fn set_capabilities_refresh_interval(&mut self, interval: Option<Duration>) {
+        self.capabilities_refresh_interval = interval;
+    }
 
-        if !jobs.is_empty() {
-            let mut guard = self.jobs.lock().unwrap();
-            guard.extend(jobs.into_iter().map(Arc::new));
+    pub fn set_strict(&mut self, strict: bool) {
+        self.client.set_strict(strict);
+        if let Some(results_client) = &mut self.results_client {
+            results_client.set_strict(strict);
         }
+    }
 
-        info!("Finished");
+    // the results service gets a breaker of its own, with the same settings
+    pub fn set_circuit_breaker(&mut self, threshold: u32, cooldown: Duration) {
+        self.client.set_circuit_breaker(threshold, cooldown);
+        if let Some(results_client) = &mut self.results_client {
+            results_client.set_circuit_breaker(threshold, cooldown);
+        }
+    }
 
-        Ok(())
+    pub fn set_run_options(&mut self, run_options: RunOptions) {
+        self.run_options = run_options;
     }
 
-    // run jobs in background using tokio's futures and Arc + Mutexes to ensure the Agent structure
-    // is thread-safe
-    pub async fn run_jobs(&self) -> Result<(), RunJobsError> {
-        let jobs = {
-            let guard = self.jobs.lock().map_err(|_| RunJobsError::Mutex)?;
-            // really make sure we do not rerun jobs that are already  running in the background
-            guard
-                .iter()
-                .filter(|job| job.get_started_at().is_none() && job.get_completed_at().is_none())
-                .cloned()
-                .collect::<Vec<_>>() // only fresh jobs
-        };
+    pub fn set_job_log_dir(&mut self, dir: Option<PathBuf>) {
+        self.job_log_dir = dir;
+    }
 
-        // launch jobs in background
-        let futures = jobs.into_iter().map(|job| {
-            info!("Running job: {}", &job);
-            tokio::task::spawn(async move {
-                match job.run() {
-                    Ok(output) => {
-                        info!("Job {} finished, creating Report...", job.get_id());
-                        job.set_result(output.clone());
-                        job.set_completed_at();
-                        job.set_success(true);
+    pub fn set_cycle_budget(&mut self, budget: Option<Duration>) {
+        self.cycle_budget = budget;
+    }
 
-                        Ok(output)
-                    }
-                    Err(err) => {
-                        job.set_result(err.to_string());
-                        job.set_completed_at();
-                        job.set_success(false);
-                        Err(RunJobsError::JobFailed(format!(
-                            "Job {} failed, {}: {}",
-                            &job,
-                            job.get_action(),
-                            err
-                        )))
-                    }
-                }
-            })
-        });
+    pub fn set_lease_renewal_interval(&mut self, interval: Option<Duration>) {
+        self.lease_renewal_interval = interval;
+    }
 
-        // wait for all jobs and start to fetch their output to return them
-        let results = futures::future::join_all(futures).await;
-        let mut reports = Vec::new();
-        let mut errors = Vec::new();
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
 
-        for res in results {
-            match res {
-                Ok(Ok(report)) => {
-                    debug!("OK(OK(report)) => pushing report");
-                    reports.push(report);
-                }
-                Ok(Err(job_err)) => {
-                    debug!("OK(Err(job_err)) => pushing errors");
-                    errors.push(job_err);
-                }
-                Err(join_err) => {
-                    debug!("Err(join_err)) => join error");
-                    errors.push(RunJobsError::Join(join_err));
-                }
-            }
-        }
+    pub fn set_stream_results_threshold(&mut self, threshold: Option<usize>) {
+        self.stream_results_threshold = threshold;
+    }
 
-        if errors.is_empty() {
-            Ok(())
-        } else if errors.len() == 1 {
-            Err(errors.remove(0))
-        } else {
-            Err(RunJobsError::AtLeastOneFailed(errors))
-        }
+    pub fn set_max_jobs_per_fetch(&mut self, max_jobs: Option<usize>) {
+        self.max_jobs_per_fetch = max_jobs;
     }
 
-    // perform GET /tools to fetch available tools on the API so the agent can check its own
-    // available tools (capabilities)
-    async fn get_tools(&self) -> Result<Vec<Tool>, ClientError> {
-        debug!("Getting tools...");
-        let uri = "/tools";
-        let res = self.client.get(uri, None).await?;
+    pub fn set_max_input_bytes(&mut self, max_bytes: u64) {
+        self.max_input_bytes = max_bytes;
+    }
 
-        let data = res.data.ok_or(ClientError::MissingData)?;
+    pub fn set_wire_format(&mut self, wire_format: WireFormat) {
+        self.wire_format = wire_format;
+    }
 
-        // Make sure it's an array
-        let tools_array = match data {
-            serde_json::Value::Array(ref arr) => arr,
-            _ => return Ok(vec![]), // or Err if preferred
-        };
+    pub fn set_results_field(&mut self, field: String) {
+        self.results_field = field;
+    }
 
-        // Map each element's "attributes" to Tool
-        let tools: Result<Vec<Tool>, ClientError> = tools_array
-            .iter()
-            .map(|item| serde_json::from_value(item.clone()).map_err(ClientError::ParseError))
-            .collect();
+    pub fn set_upload_limits(&mut self, max_concurrent: usize, retry: RetryPolicy) {
+        self.uploads = UploadQueue::new(max_concurrent, retry);
+    }
 
-        tools
+    // the retained artifacts are bounded by the disk budget of the job logs, only the ones in
+    // `dir` are ever removed
+    pub fn set_artifact_retention(&mut self, retention: ArtifactRetention, dir: Option<PathBuf>) {
+        self.uploads
+            .set_retention(retention, self.disk_budget.max_bytes, dir);
     }
 
-    // for each tool returned by the GET /tools, check locally if the agent has access to them
-    pub async fn get_available_tools(&self) -> Result<Vec<Tool>, ClientError> {
-        let mut available_tools: Vec<Tool> = self
-            .get_tools()
-            .await?
-            .into_iter()
-            .filter(|tool| tool.is_available())
-            .collect();
+    // air-gapped mode: the artifacts are copied into `dir` instead of being uploaded
+    pub fn set_artifacts_copy_dir(&mut self, dir: Option<PathBuf>) {
+        self.uploads.set_copy_dir(dir);
+    }
 
-        for tool in available_tools.iter_mut() {
-            if tool.version().is_none() {
-                let _ = tool.get_version();
-            }
-        }
+    pub fn set_report_retry(&mut self, retry: RetryPolicy) {
+        self.report_retry = retry;
+    }
 
-        Ok(available_tools)
+    // extension point for deployments rewriting their jobs, used by unit tests
+    #[allow(dead_code)]
+    pub fn set_job_transformer(&mut self, transformer: Arc<dyn JobTransformer>) {
+        self.transformer = transformer;
     }
 
-    // perform PATCH /self to update its available_tools (capabilities)
-    pub async fn submit_capabilities(&mut self) -> Result<(), ClientError> {
-        info!("Submitting submit_capabilities...");
-        self.available_tools = Some(self.get_available_tools().await?);
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn set_transport(&mut self, transport: Arc<dyn ApiTransport>) {
+        self.transport = Some(transport);
+    }
 
-        let uri = "/self";
-        let capabilities = AgentCapabilities {
-            available_tools: self.available_tools.clone(),
-        };
+    // same as `transport`, for the tasks outliving the borrow of the agent
+    fn shared_transport(&self) -> Arc<dyn ApiTransport> {
+        self.transport
+            .clone()
+            .unwrap_or_else(|| Arc::new(self.client.clone()))
+    }
 
-        self.client.patch(uri, None, &capabilities).await?;
-        info!("Done");
+    // what JSON requests to the API go through: the client itself unless another transport
+    // was set. file transfers and streamed reports always use the client
+    fn transport(&self) -> &dyn ApiTransport {
+        self.transport.as_deref().unwrap_or(&self.client)
+    }
 
+    // the results service gets its own token, the API's one is never sent to it
+    pub fn set_results_url(
+        &mut self,
+        results_url: String,
+        token: Option<String>,
+    ) -> Result<(), ClientError> {
+        url::Url::parse(&results_url)?;
+        self.results_client = Some(self.client.with_base_url(&results_url, token));
         Ok(())
     }
 
-    // perform PATCH /jobs/<id> to update job's output after executing it
-    pub async fn submit_report(&mut self) -> Result<(), ClientError> {
-        let jobs: Vec<Arc<Job>> = self
-            .jobs
+    // client of the service ingesting the reports and artifacts
+    fn results_client(&self) -> ApiClient {
+        self.results_client
             .clone()
-            .lock()
-            .unwrap()
-            .iter()
-            .filter(|job| !job.was_submitted())
-            .cloned()
-            .collect();
+            .unwrap_or_else(|| self.client.clone())
+    }
 
-        for job in jobs {
-            info!("Submitting job report...");
+    pub fn set_scope(&mut self, scope: Scope) {
+        self.scope = scope;
+    }
 
-            let uri = format!("/jobs/{}", job.get_id());
-            job.set_submitted(true);
+    pub fn set_version_probe(&mut self, version_probe: VersionProbe) {
+        self.version_probe = version_probe;
+    }
 
-            let patch = JobPatch {
-                started_at: job.get_started_at(),
-                completed_at: job.get_completed_at(),
-                results: job.get_result_as_string(),
-                success: Some(job.is_success()),
-            };
+    pub fn set_disk_budget(&mut self, disk_budget: DiskBudget) {
+        self.disk_budget = disk_budget;
+    }
 
-            self.client.patch(&uri, None, &patch).await?;
-            info!("Finished!");
-        }
+    pub fn set_maintenance_windows(&mut self, windows: Vec<MaintenanceWindow>) {
+        self.maintenance_windows = windows;
+    }
 
-        Ok(())
+    pub fn set_local_jobs(&mut self, local_jobs: Vec<LocalJob>) {
+        self.local_jobs = local_jobs;
     }
 
-    fn get_hostname() -> String {
-        gethostname().to_string_lossy().into_owned()
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn set_clock(&mut self, clock: fn() -> DateTime<Utc>) {
+        self.clock = clock;
     }
 
-    fn get_platform() -> Option<AgentPlatform> {
-        match std::env::consts::OS {
-            "linux" => Some(AgentPlatform::Linux),
-            "macos" => Some(AgentPlatform::MacOS),
-            "windows" => Some(AgentPlatform::Windows),
-            _ => None, // Unknown OS
+    pub fn set_cancel_signal(&mut self, cancel: watch::Receiver<bool>) {
+        self.cancel = Some(cancel);
+    }
+
+    #[allow(dead_code)]
+    pub fn register_parser<P: OutputParser + 'static>(&mut self, variant: &str, parser: P) {
+        self.parsers.register(variant, parser);
+    }
+
+    // warn if the local clock diverges from the server's one by more than the given threshold
+    pub fn check_clock_skew(&self, threshold: TimeDelta) {
+        if let Some(offset) = self.client.server_time_offset()
+            && offset.abs() > threshold
+        {
+            warn!(
+                "Local clock diverges from the server's one by {}s",
+                offset.num_seconds()
+            );
+        }
+    }
+
+    // current time, corrected with the server's clock offset when enabled
+    fn now(&self) -> DateTime<Utc> {
+        let local = (self.clock)();
+        let now = match self.client.server_time_offset() {
+            Some(offset) if self.use_server_time => {
+                local.checked_add_signed(offset).unwrap_or(local)
+            }
+            _ => local,
+        };
+
+        self.plausible_time(now)
+    }
+
+    // a clock set decades away makes the server reject the timestamps (years past 9999 are not
+    // even valid RFC 3339), so they are clamped to a plausible range. warns once until the clock
+    // is plausible again
+    fn plausible_time(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let min = Utc
+            .with_ymd_and_hms(PLAUSIBLE_YEARS.0, 1, 1, 0, 0, 0)
+            .unwrap();
+        let max = Utc
+            .with_ymd_and_hms(PLAUSIBLE_YEARS.1, 1, 1, 0, 0, 0)
+            .unwrap();
+
+        let clamped = time.clamp(min, max);
+        if clamped == time {
+            self.implausible_clock.store(false, Ordering::Relaxed);
+        } else if !self.implausible_clock.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Implausible system time {:?}, sending {} instead",
+                time,
+                clamped.to_rfc3339()
+            );
+        }
+
+        clamped
+    }
+
+    // shared list of the jobs, to inspect them while the agent is running
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn jobs_handle(&self) -> Arc<Mutex<Vec<Arc<Job>>>> {
+        self.jobs.clone()
+    }
+
+    // shared paused state, to pause and resume the agent while it is running
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    // shared rescan request, to rescan the tools while the agent is running
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn rescan_handle(&self) -> Arc<AtomicBool> {
+        self.capabilities_rescan.clone()
+    }
+
+    // shared metrics, to expose them while the agent is running
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn metrics_handle(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub fn summary(&self) -> Summary {
+        self.metrics.summary(self.uploads.uploaded_bytes())
+    }
+
+    // perform PATCH /self with what the agent did during its lifetime
+    pub async fn submit_summary(&self, summary: Summary) -> Result<(), ClientError> {
+        let uri = self.client.endpoints().agent_self();
+
+        self.transport()
+            .patch(&uri, None, serde_json::to_value(AgentSummary { summary })?)
+            .await?;
+
+        Ok(())
+    }
+
+    // POST the metrics to the API every `interval`, in the background until the agent exits.
+    // failures are only logged, the next push sends the counters again
+    pub fn spawn_metrics_push(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let transport = self.shared_transport();
+        let uri = self.client.endpoints().metrics();
+        let metrics = self.metrics.clone();
+        let uploads = self.uploads.clone();
+
+        tokio::task::spawn(async move {
+            let mut ticks =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                let snapshot = metrics.snapshot(uploads.uploaded_bytes());
+                let result = match serde_json::to_value(&snapshot) {
+                    Ok(body) => transport.post(&uri, None, body).await.map(|_| ()),
+                    Err(err) => Err(err.into()),
+                };
+                match result {
+                    Ok(()) => debug!("Pushed the metrics"),
+                    Err(err) => warn!("Could not push the metrics: {}", err),
+                }
+            }
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn available_tools(&self) -> &Option<Vec<Tool>> {
+        &self.available_tools
+    }
+
+    // performs PATCH /self
+    pub async fn announce_presence(&mut self) -> Result<(), ClientError> {
+        info!("Announcing presence...");
+        let uri = self.client.endpoints().agent_self();
+        self.last_seen_at = Some(self.now());
+
+        let agent = AgentPresence {
+            last_seen_at: self.last_seen_at,
+            paused: self.is_paused(),
+        };
+
+        self.transport()
+            .patch(&uri, None, serde_json::to_value(&agent)?)
+            .await?;
+        info!("Finished");
+
+        Ok(())
+    }
+
+    // performs PATCH /self to tell the server this agent stopped for good
+    pub async fn deregister(&mut self) -> Result<(), ClientError> {
+        info!("Deregistering agent...");
+        let uri = self.client.endpoints().agent_self();
+
+        let agent = AgentStatus {
+            status: "offline".to_string(),
+            last_seen_at: Some(self.now()),
+        };
+
+        self.transport()
+            .patch(&uri, None, serde_json::to_value(&agent)?)
+            .await?;
+        info!("Done");
+
+        Ok(())
+    }
+
+    // performs PATCH /self to update agent's hostname, platform and last_seen_at
+    pub async fn register(&mut self) -> Result<(), ClientError> {
+        info!("Registring agent...");
+        self.ensure_id().await?;
+
+        let uri = self.client.endpoints().agent_self();
+        self.last_seen_at = Some(self.now());
+
+        let agent = AgentRegister {
+            hostname: self.hostname.clone(),
+            platform: self.platform.clone(),
+            last_seen_at: self.last_seen_at,
+            version: self.version.clone(),
+            build: self.build.clone(),
+            resources: HostResources::collect(),
+        };
+
+        self.transport()
+            .patch(&uri, None, serde_json::to_value(&agent)?)
+            .await?;
+        info!("Done");
+
+        Ok(())
+    }
+
+    // make sure the server assigned an id to this agent, re-fetching GET /self if the first
+    // contact did not return one
+    async fn ensure_id(&mut self) -> Result<(), ClientError> {
+        if self.id.is_some() {
+            return Ok(());
+        }
+
+        warn!("Agent has no id yet, fetching it again...");
+        let uri = self.client.endpoints().agent_self();
+        let agent = Agent::get_info(self.transport(), &uri).await?;
+        self.id = Some(agent.id.ok_or(ClientError::MissingAgentId)?);
+
+        Ok(())
+    }
+
+    // performs GET /self to fetch agent's info at the startup of this daemon
+    pub async fn get_info(transport: &dyn ApiTransport, uri: &str) -> Result<Agent, ClientError> {
+        let res = transport.get(uri, None).await?;
+        let data = res
+            .data
+            .filter(|data| !data.is_null())
+            .ok_or(ClientError::MissingData)?;
+        let agent: Agent = serde_json::from_value(data).map_err(ClientError::ParseError)?;
+
+        Ok(agent)
+    }
+
+    // queue the local jobs due since the last evaluation, they run and are reported like the
+    // jobs fetched from the server. each one is created on the server first so it can be
+    // reported, a job the server refused is not run
+    pub async fn schedule_local_jobs(&mut self) {
+        if self.local_jobs.is_empty() || self.is_paused() {
+            return;
+        }
+
+        let now = self.now();
+        let since = self.local_jobs_checked_at.replace(now);
+        let agent_id = self.id.unwrap_or_default();
+        let due: Vec<Job> = self
+            .local_jobs
+            .iter()
+            .filter(|local| local.schedule.is_due(since, now))
+            .map(|local| {
+                let action = Action::new(local.cmd.clone(), local.args.clone());
+                Job::local(local.name(), action, agent_id, now)
+            })
+            .collect();
+
+        let uri = self.client.endpoints().jobs();
+        for job in due {
+            let creation = JobCreation {
+                id: job.get_id(),
+                name: job.get_name(),
+                agent_id: job.get_agent_id(),
+                action: job.get_action(),
+                created_at: job.get_created_at(),
+            };
+            let result = match serde_json::to_value(&creation) {
+                Ok(body) => self.transport().post(&uri, None, body).await.map(|_| ()),
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                warn!("Could not create local job {}: {}", job.get_name(), err);
+                continue;
+            }
+
+            info!("Local job {} ({}) is due", job.get_id(), job.get_name());
+            job.set_fetched_at(now);
+            self.jobs.lock().unwrap().push(Arc::new(job));
+        }
+    }
+
+    // performs GET /jobs to fetch agent's jobs
+    pub async fn get_jobs(&mut self) -> Result<(), ClientError> {
+        if self.is_paused() {
+            debug!("Paused, not fetching jobs");
+            return Ok(());
+        }
+        info!("Fetching jobs...");
+
+        let uri = self
+            .client
+            .endpoints()
+            .jobs_list(self.jobs_cursor.as_ref(), self.max_jobs_per_fetch);
+        let jobs = self.get_job_pages(uri).await?;
+        let newest = jobs.iter().map(Job::get_created_at).max();
+        let known: HashSet<uuid::Uuid> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| *job.get_id())
+            .collect();
+
+        let mut claimed = Vec::with_capacity(jobs.len());
+        let mut claim_error = None;
+        for mut job in jobs {
+            // fetched again at the cursor
+            if known.contains(job.get_id()) {
+                continue;
+            }
+
+            // a server bug must not make this agent run the jobs of another one
+            if let Some(id) = self.id
+                && *job.get_agent_id() != id
+            {
+                warn!(
+                    "Job {} is assigned to agent {}, not to this one ({}), dropping it",
+                    job.get_id(),
+                    job.get_agent_id(),
+                    id
+                );
+                continue;
+            }
+
+            if job.is_completed_server_side() {
+                debug!(
+                    "Job {} is already {:?} on the server, skipping it",
+                    job.get_id(),
+                    job.get_status()
+                );
+                continue;
+            }
+
+            if job.is_claimed_by(self.id) {
+                debug!(
+                    "Job {} is already claimed by this agent, skipping it",
+                    job.get_id()
+                );
+                continue;
+            }
+
+            // rejected jobs are still claimed, to be reported as skipped
+            let rejection = self.transformer.transform(&mut job).err();
+            match self.claim_job(&job).await {
+                Ok(true) => {
+                    job.set_fetched_at(Utc::now());
+                    if let Some(reason) = rejection {
+                        warn!("Rejecting job {}: {}", job.get_id(), reason);
+                        job.skip(SkipReason::Rejected, reason);
+                    }
+                    claimed.push(Arc::new(job));
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    claim_error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        if !claimed.is_empty() {
+            let mut guard = self.jobs.lock().unwrap();
+            guard.extend(claimed);
+        }
+
+        // the jobs not claimed yet are fetched again by the next poll, the cursor only moves
+        // once all of them were handled
+        if let Some(err) = claim_error {
+            return Err(err);
+        }
+        if let Some(newest) = newest {
+            let cursor = newest - TimeDelta::microseconds(1);
+            self.jobs_cursor = self.jobs_cursor.max(Some(cursor));
+        }
+
+        info!("Finished");
+
+        Ok(())
+    }
+
+    // follow the pages of the jobs list starting at `uri`. the list may shift between two
+    // requests, so a job appearing on two pages is only kept once, and a server handing out
+    // pages forever (or more jobs than asked for) cannot keep the agent fetching
+    async fn get_job_pages(&self, uri: String) -> Result<Vec<Job>, ClientError> {
+        let max_jobs = self
+            .max_jobs_per_fetch
+            .map_or(MAX_FETCHED_JOBS, |limit| limit.min(MAX_FETCHED_JOBS));
+        let mut jobs: Vec<Job> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(uri);
+        let mut pages = 0;
+
+        while let Some(uri) = next.take() {
+            let res = self.transport().get(&uri, None).await?;
+            let page: Vec<Job> = serde_json::from_value(res.data.ok_or(ClientError::MissingData)?)?;
+            jobs.extend(page.into_iter().filter(|job| seen.insert(*job.get_id())));
+            pages += 1;
+
+            if res.next.is_some() && (pages >= MAX_JOB_PAGES || jobs.len() >= max_jobs) {
+                warn!(
+                    "Stopping after {} pages and {} jobs, the next ones are left for later",
+                    pages,
+                    jobs.len()
+                );
+                break;
+            }
+            next = res.next;
+        }
+        if jobs.len() > max_jobs {
+            warn!(
+                "Received {} jobs, only the {} oldest are queued",
+                jobs.len(),
+                max_jobs
+            );
+            // the oldest ones are kept so the since cursor does not skip the others
+            jobs.sort_by_key(Job::get_created_at);
+            jobs.truncate(max_jobs);
+        }
+
+        Ok(jobs)
+    }
+
+    // mark the job as running for this agent before executing it. returns false if another
+    // agent already claimed it, in which case the job must be dropped
+    async fn claim_job(&self, job: &Job) -> Result<bool, ClientError> {
+        let uri = self.client.endpoints().job(job.get_id());
+        let claim = JobClaim {
+            status: JobStatus::Running,
+            agent_id: self.id,
+            claimed_at: self.now(),
+        };
+
+        match self
+            .transport()
+            .patch(&uri, None, serde_json::to_value(&claim)?)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(ClientError::ApiError(err)) if err.code() == StatusCode::CONFLICT => {
+                warn!(
+                    "Job {} was already claimed by another agent, dropping it",
+                    job.get_id()
+                );
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // run jobs in background using tokio's futures and Arc + Mutexes to ensure the Agent structure
+    // is thread-safe. jobs depending on another one are deferred until their dependency completed
+    // and are run in a later batch, or skipped if their condition does not match
+    pub async fn run_jobs(&self) -> Result<(), RunJobsError> {
+        let mut errors = Vec::new();
+        let mut run_options = self.run_options.clone();
+        run_options.deadline = self.cycle_budget.map(|budget| Instant::now() + budget);
+        run_options.variables = self.variables();
+        run_options.cancel = self.cancel.clone();
+
+        // jobs already fetched wait for the agent to be resumed
+        if self.is_paused() {
+            debug!("Paused, not starting jobs");
+            return Ok(());
+        }
+
+        if let Some(closes_at) = maintenance::closes_at(&self.maintenance_windows, self.now()) {
+            let deferred = self.defer_jobs(Deferral {
+                reason: DeferReason::MaintenanceWindow,
+                until: Some(closes_at),
+            })?;
+            if deferred > 0 {
+                info!(
+                    "Maintenance window until {}, deferring {} job(s)",
+                    closes_at, deferred
+                );
+            }
+            return Ok(());
+        }
+
+        if !self.check_disk_budget() {
+            let deferred = self.defer_jobs(Deferral {
+                reason: DeferReason::LowDiskSpace,
+                until: None,
+            })?;
+            warn!("Disk space critically low, deferring {} job(s)", deferred);
+            return Ok(());
+        }
+
+        let skipped = self.skip_jobs_with_missing_dependency().await?;
+        self.metrics.record_skipped(skipped);
+
+        loop {
+            if run_options.is_cancelled() {
+                warn!("Shutdown requested, deferring the remaining jobs");
+                break;
+            }
+
+            // jobs not started yet are left for the next cycle
+            if run_options
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                warn!("Cycle budget exceeded, deferring the remaining jobs");
+                break;
+            }
+
+            // skipping a job may unblock the jobs depending on it, so keep scheduling until
+            // nothing happens anymore
+            let (jobs, skipped) = self.schedule_jobs()?;
+            if jobs.is_empty() && skipped == 0 {
+                break;
+            }
+
+            let scheduled = jobs.len();
+            let jobs: Vec<Arc<Job>> = jobs
+                .into_iter()
+                .filter(|job| {
+                    self.check_shell(job, &run_options)
+                        && self.check_scope(job, &run_options)
+                        && Agent::check_tool(job)
+                })
+                .collect();
+            self.metrics
+                .record_skipped(skipped + scheduled - jobs.len());
+            errors.extend(self.run_batch(jobs, &run_options).await);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors.remove(0))
+        } else {
+            Err(RunJobsError::AtLeastOneFailed(errors))
+        }
+    }
+
+    // remove the oldest job logs and uploaded artifacts beyond the disk budget of their
+    // directory. returns false when the disk holding one of the agent's directories, the jobs'
+    // included, is too full to start jobs
+    fn check_disk_budget(&self) -> bool {
+        if !self.disk_budget.is_enabled() {
+            return true;
+        }
+
+        let artifacts_dir = self.uploads.get_artifacts_dir();
+        if let Some(dir) = self.job_log_dir.as_deref() {
+            Agent::report_enforced_budget("job log", self.disk_budget.enforce(dir));
+        }
+        if let Some(dir) = artifacts_dir {
+            let enforced = self
+                .disk_budget
+                .enforce_keeping(dir, |path| self.uploads.is_pending(path));
+            Agent::report_enforced_budget("artifact", enforced);
+        }
+
+        let work_dir = self
+            .run_options
+            .work_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir);
+        let dirs = [self.job_log_dir.as_ref(), artifacts_dir, Some(&work_dir)];
+        !self
+            .disk_budget
+            .is_space_low(dirs.into_iter().flatten().map(PathBuf::as_path))
+    }
+
+    fn report_enforced_budget(kind: &str, enforced: std::io::Result<Vec<PathBuf>>) {
+        match enforced {
+            Ok(removed) if !removed.is_empty() => {
+                info!(
+                    "Removed {} old {}(s) over the disk budget",
+                    removed.len(),
+                    kind
+                )
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Could not enforce the disk budget: {}", err),
+        }
+    }
+
+    // postpone the jobs not started yet, returns how many were deferred
+    fn defer_jobs(&self, deferral: Deferral) -> Result<usize, RunJobsError> {
+        let jobs = self.jobs.lock().map_err(|_| RunJobsError::Mutex)?.clone();
+        let mut deferred = 0;
+        for job in jobs
+            .iter()
+            .filter(|job| !job.is_started() && !job.is_completed())
+        {
+            job.defer(deferral);
+            deferred += 1;
+        }
+
+        Ok(deferred)
+    }
+
+    // values of the placeholders jobs can use in their args so one job template adapts to
+    // each agent
+    fn variables(&self) -> HashMap<String, String> {
+        let mut variables = HashMap::new();
+        variables.insert(
+            "hostname".to_string(),
+            self.hostname.clone().unwrap_or_else(Agent::get_hostname),
+        );
+        variables.insert("name".to_string(), self.name.clone());
+        if let Some(id) = self.id {
+            variables.insert("agent_id".to_string(), id.to_string());
+        }
+        if let Some(serde_json::Value::String(platform)) = self
+            .platform
+            .as_ref()
+            .and_then(|p| serde_json::to_value(p).ok())
+        {
+            variables.insert("platform".to_string(), platform);
+        }
+        if let Some(address) = self.run_options.bind_address {
+            variables.insert("bind_address".to_string(), address.to_string());
+        }
+        if let Some(dir) = self.uploads.get_artifacts_dir() {
+            variables.insert(
+                "artifacts_dir".to_string(),
+                dir.to_string_lossy().into_owned(),
+            );
+        }
+        variables
+    }
+
+    // reject the job when it targets hosts outside of the allowlist, it is then reported without
+    // being run. targets are checked once the variables are substituted, as they will be run
+    fn check_scope(&self, job: &Job, run_options: &RunOptions) -> bool {
+        // the hooks may target hosts too
+        let mut out_of_scope = Vec::new();
+        for action in std::iter::once(job.get_action().clone()).chain(job.hook_actions()) {
+            let action = action
+                .with_variables(&run_options.variables, false)
+                .unwrap_or(action);
+            out_of_scope.extend(self.scope.out_of_scope(action.get_cmd(), action.get_args()));
+        }
+        if out_of_scope.is_empty() {
+            return true;
+        }
+
+        warn!(
+            "Rejecting job {}: out-of-scope targets {}",
+            job.get_id(),
+            out_of_scope.join(", ")
+        );
+        job.skip(
+            SkipReason::OutOfScope,
+            format!("out-of-scope targets: {}", out_of_scope.join(", ")),
+        );
+        false
+    }
+
+    // reject the shell-mode jobs unless the operator enabled them. the targets of a script
+    // (in a substitution, after a pipe...) cannot be told apart, so they are rejected when a
+    // scope is set too
+    fn check_shell(&self, job: &Job, run_options: &RunOptions) -> bool {
+        let reason = match (job.get_action().is_shell(), &run_options.shell) {
+            (false, _) => return true,
+            (true, None) => "shell mode is not enabled",
+            (true, Some(_)) if self.scope.is_enabled() => "shell mode is not allowed with a scope",
+            (true, Some(_)) => return true,
+        };
+
+        warn!("Rejecting job {}: {}", job.get_id(), reason);
+        job.skip(SkipReason::Rejected, reason.to_string());
+        false
+    }
+
+    // reject the job when its command is not installed, it is then reported as unavailable
+    // rather than failed so the server can hand it to another agent
+    fn check_tool(job: &Job) -> bool {
+        // the command line of a shell-mode action may start with a builtin or a variable
+        let unavailable = std::iter::once(job.get_action().clone())
+            .chain(job.hook_actions())
+            .filter(|action| !action.is_shell())
+            .map(|action| action.get_cmd().to_string())
+            .find(|cmd| !Tool::from_cmd(cmd.clone()).is_available());
+        let Some(cmd) = unavailable else {
+            return true;
+        };
+
+        warn!("Rejecting job {}: {} is not available", job.get_id(), cmd);
+        job.skip(
+            SkipReason::ToolUnavailable,
+            format!("{} is not available", cmd),
+        );
+        false
+    }
+
+    // skip the jobs whose dependency is neither among the jobs of the agent nor on the server,
+    // they would wait for it forever. a dependency the server knows (e.g. assigned to another
+    // agent) keeps them waiting, as does a failed lookup
+    async fn skip_jobs_with_missing_dependency(&self) -> Result<usize, RunJobsError> {
+        let jobs = self.jobs.lock().map_err(|_| RunJobsError::Mutex)?.clone();
+        let known: HashSet<&uuid::Uuid> = jobs.iter().map(|job| job.get_id()).collect();
+        let mut skipped = 0;
+
+        for job in jobs
+            .iter()
+            .filter(|job| !job.is_started() && !job.is_completed())
+        {
+            let Some(dependency_id) = job.get_depends_on().filter(|id| !known.contains(id)) else {
+                continue;
+            };
+
+            let uri = self.client.endpoints().job(dependency_id);
+            match self.transport().get(&uri, None).await {
+                Err(ClientError::ApiError(err)) if err.code() == StatusCode::NOT_FOUND => {
+                    info!(
+                        "Skipping job {}: dependency {} not found",
+                        job, dependency_id
+                    );
+                    job.skip(
+                        SkipReason::DependencyNotFound,
+                        format!("dependency {} not found", dependency_id),
+                    );
+                    skipped += 1;
+                }
+                Ok(_) => debug!("Job {} waits for {}", job.get_id(), dependency_id),
+                Err(err) => debug!(
+                    "Could not look up dependency {} of job {}: {}",
+                    dependency_id,
+                    job.get_id(),
+                    err
+                ),
+            }
+        }
+
+        Ok(skipped)
+    }
+
+    // select the fresh jobs that can be run right now and skip the ones whose dependency
+    // completed without matching their condition. returns the jobs to run and how many were
+    // skipped
+    fn schedule_jobs(&self) -> Result<(Vec<Arc<Job>>, usize), RunJobsError> {
+        // the list is cloned so the outer lock is not held while reading the state of the jobs,
+        // which the running jobs update meanwhile
+        let jobs = self.jobs.lock().map_err(|_| RunJobsError::Mutex)?.clone();
+        let by_id: HashMap<&uuid::Uuid, &Arc<Job>> =
+            jobs.iter().map(|job| (job.get_id(), job)).collect();
+        let mut ready = Vec::new();
+        let mut skipped = 0;
+
+        // really make sure we do not rerun jobs that are already  running in the background
+        let fresh_jobs = jobs
+            .iter()
+            .filter(|job| !job.is_started() && !job.is_completed());
+
+        for job in fresh_jobs {
+            let Some(dependency_id) = job.get_depends_on() else {
+                ready.push(job.clone());
+                continue;
+            };
+
+            // dependencies not fetched or not completed yet keep the job waiting
+            let dependency = by_id
+                .get(dependency_id)
+                .filter(|other| other.is_completed());
+
+            if let Some(dependency) = dependency {
+                if job.get_condition().is_met_by(dependency) {
+                    ready.push(job.clone());
+                } else {
+                    info!(
+                        "Skipping job {}: condition on {} not met",
+                        job, dependency_id
+                    );
+                    job.skip(
+                        SkipReason::DependencyNotMet,
+                        format!("condition on job {} not met", dependency_id),
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+
+        Ok((ready, skipped))
+    }
+
+    // launch a batch of jobs in background and wait for all of them
+    async fn run_batch(&self, jobs: Vec<Arc<Job>>, run_options: &RunOptions) -> Vec<RunJobsError> {
+        let parsers = Arc::new(self.parsers.clone());
+        let run_options = Arc::new(run_options.clone());
+        let futures = jobs.into_iter().map(|job| {
+            info!("Running job: {}", &job);
+            let parsers = parsers.clone();
+            let run_options = run_options.clone();
+            let client = self.client.clone();
+            let results_client = self.results_client();
+            let job_log_dir = self.job_log_dir.clone();
+            // the inputs are kept within the disk budget too, while their job runs
+            let max_input_bytes = self
+                .max_input_bytes
+                .min(self.disk_budget.max_bytes.unwrap_or(u64::MAX));
+            let uploads = self.uploads.clone();
+            let metrics = self.metrics.clone();
+            let (renewal, run_options) = match self.lease_renewal_interval {
+                Some(interval) => {
+                    let (lost_tx, lost_rx) = watch::channel(false);
+                    let mut job_options = (*run_options).clone();
+                    job_options.lease_lost = Some(lost_rx);
+                    let renewal = tokio::task::spawn(Agent::renew_lease(
+                        self.shared_transport(),
+                        self.client.endpoints().job(job.get_id()),
+                        JobLeaseRenewal {
+                            agent_id: self.id,
+                            lease_renewed_at: self.now(),
+                        },
+                        self.clock,
+                        interval,
+                        lost_tx,
+                    ));
+                    (Some(renewal), Arc::new(job_options))
+                }
+                None => (None, run_options),
+            };
+            tokio::task::spawn(async move {
+                let staged = Agent::stage_inputs(&client, &job, &run_options, max_input_bytes);
+                let result = match staged.await {
+                    // the action blocks until its process exits, keep it off the runtime's
+                    // workers so the shutdown signal is still handled meanwhile
+                    Ok(()) => {
+                        let (job, run_options) = (job.clone(), run_options.clone());
+                        tokio::task::spawn_blocking(move || job.run_with_options(&run_options))
+                            .await
+                            .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+                    }
+                    Err(err) => Err(std::io::Error::other(format!(
+                        "could not stage inputs: {}",
+                        err
+                    ))),
+                };
+                // the report (or the next claim of a cancelled job) releases the lease
+                if let Some(renewal) = renewal {
+                    renewal.abort();
+                }
+                job.cleanup_work_dir();
+
+                let outcome = match result {
+                    Ok(output) => {
+                        info!("Job {} finished, creating Report...", job.get_id());
+                        match parsers.parse(job.get_action().get_variant(), &output) {
+                            Some(Ok(structured)) => job.set_structured_result(structured),
+                            Some(Err(err)) => {
+                                warn!("Could not parse output of job {}: {}", job.get_id(), err)
+                            }
+                            None => {}
+                        }
+                        job.set_result(output.clone());
+                        job.set_completed_at();
+                        job.set_success(job.determine_success(&output));
+                        Agent::enqueue_artifacts(&uploads, &results_client, &job);
+
+                        Ok(output)
+                    }
+                    // killed because the agent is shutting down, not a failure of the job
+                    Err(err)
+                        if err.kind() == std::io::ErrorKind::Interrupted
+                            && run_options.is_cancelled() =>
+                    {
+                        info!("Job {} cancelled", job.get_id());
+                        job.skip(SkipReason::Cancelled, err.to_string());
+                        Ok(err.to_string())
+                    }
+                    Err(err) => {
+                        job.set_result(err.to_string());
+                        job.set_completed_at();
+                        job.set_success(false);
+                        Err(RunJobsError::JobFailed(format!(
+                            "Job {} failed, {}: {}",
+                            &job,
+                            job.get_action(),
+                            err
+                        )))
+                    }
+                };
+
+                // jobs cancelled by the shutdown did not really run
+                if job.is_skipped() {
+                    metrics.record_skipped(1);
+                } else if let (Some(started_at), Some(completed_at)) =
+                    (job.get_started_at(), job.get_completed_at())
+                {
+                    metrics.record(
+                        job.get_action().get_cmd(),
+                        (completed_at - started_at).to_std().unwrap_or_default(),
+                        job.is_success(),
+                    );
+                }
+
+                if let Some(dir) = job_log_dir.as_deref()
+                    && let Err(err) = Agent::write_job_log(dir, &job)
+                {
+                    warn!("Could not write log of job {}: {}", job.get_id(), err);
+                }
+
+                outcome
+            })
+        });
+
+        // wait for all jobs and start to fetch their output to return them
+        let results = futures::future::join_all(futures).await;
+        let mut reports = Vec::new();
+        let mut errors = Vec::new();
+
+        for res in results {
+            match res {
+                Ok(Ok(report)) => {
+                    debug!("OK(OK(report)) => pushing report");
+                    reports.push(report);
+                }
+                Ok(Err(job_err)) => {
+                    debug!("OK(Err(job_err)) => pushing errors");
+                    errors.push(job_err);
+                }
+                Err(join_err) => {
+                    debug!("Err(join_err)) => join error");
+                    errors.push(RunJobsError::Join(join_err));
+                }
+            }
+        }
+
+        errors
+    }
+
+    // PATCH the job every `interval` while it runs, until this task is aborted. the job is
+    // aborted in turn when the server rejects a renewal, as it may have handed the job to another
+    // agent meanwhile. network errors are retried at the next renewal
+    async fn renew_lease(
+        transport: Arc<dyn ApiTransport>,
+        uri: String,
+        mut renewal: JobLeaseRenewal,
+        clock: fn() -> DateTime<Utc>,
+        interval: Duration,
+        lost: watch::Sender<bool>,
+    ) {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            renewal.lease_renewed_at = clock();
+            let body = match serde_json::to_value(&renewal) {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("Could not serialize lease renewal: {}", err);
+                    return;
+                }
+            };
+
+            match transport.patch(&uri, None, body).await {
+                Ok(_) => debug!("Renewed lease of {}", uri),
+                Err(err) if err.is_transient() => {
+                    warn!("Could not renew lease of {}, retrying: {}", uri, err)
+                }
+                Err(err) => {
+                    error!("Lost lease of {}, aborting the job: {}", uri, err);
+                    let _ = lost.send(true);
+                    return;
+                }
+            }
+        }
+    }
+
+    // hand the files produced by the job over to the upload queue, so slow uploads never delay
+    // the next jobs
+    // only the files of the artifacts directory are sent: the paths come from the server, and
+    // the agent may read files the user running the actions could not
+    fn enqueue_artifacts(uploads: &UploadQueue, client: &ApiClient, job: &Job) {
+        for artifact in job.get_artifacts() {
+            match uploads.resolve(Path::new(artifact)) {
+                Some(path) if path.is_file() => {
+                    uploads.enqueue(client, *job.get_id(), path, job.is_success())
+                }
+                Some(_) => warn!(
+                    "Artifact {} of job {} does not exist",
+                    artifact,
+                    job.get_id()
+                ),
+                None => warn!(
+                    "Artifact {} of job {} is not in the artifacts directory, it is not sent",
+                    artifact,
+                    job.get_id()
+                ),
+            }
+        }
+    }
+
+    // write `<job_id>.log` in `dir` with the command line, timestamps and output of the job, for
+    // post-mortem debugging. only the owner can read it as the output may be sensitive
+    fn write_job_log(dir: &Path, job: &Job) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.log", job.get_id()));
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path)?;
+
+        let timestamp =
+            |at: Option<DateTime<Utc>>| at.map(|at| at.to_rfc3339()).unwrap_or_default();
+        writeln!(file, "job: {} ({})", job.get_id(), job)?;
+        writeln!(file, "command: {}", job.get_action())?;
+        writeln!(file, "started_at: {}", timestamp(job.get_started_at()))?;
+        writeln!(file, "completed_at: {}", timestamp(job.get_completed_at()))?;
+        writeln!(file, "success: {}", job.is_success())?;
+        writeln!(file, "output:")?;
+        let offset = file.stream_position()?;
+        writeln!(file, "{}", job.get_result_as_string().unwrap_or_default())?;
+        // the output is streamed from there when the report is sent
+        job.set_log(path.clone(), offset);
+
+        Ok(path)
+    }
+
+    // download the job's inputs to its own directory so the action can read them. each input
+    // is limited to `max_bytes`
+    async fn stage_inputs(
+        client: &ApiClient,
+        job: &Job,
+        run_options: &RunOptions,
+        max_bytes: u64,
+    ) -> Result<(), ClientError> {
+        if job.get_inputs().is_empty() {
+            return Ok(());
+        }
+
+        let dir = job.create_work_dir(run_options)?;
+
+        for input in job.get_inputs() {
+            // the name is used as file name, it must not escape the job's directory
+            if Path::new(&input.name).file_name() != Some(OsStr::new(&input.name)) {
+                return Err(ClientError::UnexpectedData(format!(
+                    "invalid input name {:?}",
+                    input.name
+                )));
+            }
+
+            let path = dir.join(&input.name);
+            // registered before downloading so a partial file is cleaned up too
+            job.set_staged_input(input.name.clone(), path.clone());
+            let size = client.download_file(&input.url, &path, max_bytes).await?;
+            debug!(
+                "Staged input {} of job {} ({} bytes)",
+                input.name,
+                job.get_id(),
+                size
+            );
+        }
+
+        Ok(())
+    }
+
+    // perform GET /tools to fetch available tools on the API so the agent can check its own
+    // available tools (capabilities)
+    async fn get_tools(&self) -> Result<Vec<Tool>, ClientError> {
+        debug!("Getting tools...");
+        let uri = self.client.endpoints().tools();
+        let res = self.transport().get(&uri, None).await?;
+
+        let data = res.data.ok_or(ClientError::MissingData)?;
+
+        // Make sure it's an array
+        let tools_array = match data {
+            serde_json::Value::Array(ref arr) => arr,
+            _ if self.client.is_strict() => {
+                error!("Expected an array of tools, got {}", data);
+                return Err(ClientError::UnexpectedData(format!(
+                    "expected an array of tools, got {}",
+                    data
+                )));
+            }
+            _ => return Ok(vec![]),
+        };
+
+        // items may either be the tools themselves (attributes already flattened by the client)
+        // or raw JSON:API resource objects, so accept both
+        tools_array
+            .iter()
+            .map(|item| {
+                let (attributes, shape) = Agent::tool_attributes(item);
+                debug!("Received tool as {}: {}", shape, item);
+                serde_json::from_value(attributes.clone()).map_err(ClientError::ParseError)
+            })
+            .collect()
+    }
+
+    // attributes of a tool item along with a description of the shape it was received in
+    fn tool_attributes(item: &serde_json::Value) -> (&serde_json::Value, &'static str) {
+        match item.get("attributes") {
+            Some(attributes) if attributes.is_object() => (attributes, "resource object"),
+            _ => (item, "flattened attributes"),
+        }
+    }
+
+    // for each tool returned by the GET /tools, check locally if the agent has access to them
+    pub async fn get_available_tools(&self) -> Result<Vec<Tool>, ClientError> {
+        let tools = match self.get_tools().await {
+            Ok(tools) => tools,
+            // the capabilities are still submitted, from the local list, when /tools is down
+            Err(err) if !self.fallback_tools.is_empty() => {
+                warn!(
+                    "Could not get the tools from the API ({}), using the {} fallback tool(s)",
+                    err,
+                    self.fallback_tools.len()
+                );
+                self.fallback_tools
+                    .iter()
+                    .cloned()
+                    .map(Tool::from_cmd)
+                    .collect()
+            }
+            Err(err) => return Err(err),
+        };
+
+        // the tools are run, and their probes retried after a delay, off the runtime's threads
+        let probe = self.version_probe;
+        let available_tools = tokio::task::spawn_blocking(move || {
+            let mut available_tools: Vec<Tool> = tools
+                .into_iter()
+                .filter(|tool| tool.is_available())
+                .collect();
+
+            for tool in available_tools.iter_mut() {
+                tool.detect_packaging();
+                if tool.version().is_none()
+                    && let Err(err) = tool.probe_version(&probe)
+                {
+                    debug!("No version for {}: {}", tool.cmd(), err);
+                }
+            }
+            available_tools
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+
+        Ok(available_tools)
+    }
+
+    // perform PATCH /self to update its available_tools (capabilities). the PATCH is skipped when
+    // the discovered tools did not change since the last submission, unless the refresh interval
+    // has elapsed
+    pub async fn submit_capabilities(&mut self) -> Result<(), ClientError> {
+        if self.capabilities_disabled {
+            debug!("Capabilities submission disabled");
+            return Ok(());
+        }
+
+        info!("Scanning the available tools...");
+        let available_tools = self.get_available_tools().await?;
+        let hash = Agent::hash_capabilities(&available_tools)?;
+
+        let refresh_due = match (
+            self.capabilities_submitted_at,
+            self.capabilities_refresh_interval,
+        ) {
+            (Some(submitted_at), Some(interval)) => submitted_at.elapsed() >= interval,
+            _ => false,
+        };
+
+        if self.capabilities_hash == Some(hash) && !refresh_due {
+            debug!("Capabilities unchanged, skipping submission");
+            return Ok(());
+        }
+
+        self.available_tools = Some(available_tools);
+
+        let uri = self.client.endpoints().agent_self();
+        let capabilities = AgentCapabilities {
+            available_tools: self.available_tools.clone(),
+        };
+
+        self.transport()
+            .patch(&uri, None, serde_json::to_value(&capabilities)?)
+            .await?;
+        self.capabilities_hash = Some(hash);
+        self.capabilities_submitted_at = Some(Instant::now());
+        info!("Done");
+
+        Ok(())
+    }
+
+    // submit the capabilities at startup: failures are only returned when capabilities are
+    // required, otherwise they are retried next cycle like in `sync_capabilities`
+    pub async fn sync_startup_capabilities(&mut self) -> Result<(), ClientError> {
+        self.sync_capabilities_with(self.capabilities_required)
+            .await
+    }
+
+    // submit the capabilities during a poll cycle: failures are logged and the submission is
+    // retried next cycle (the hash is only stored once submitted), a cycle never fails on them.
+    // the tools are only scanned until submitted once, then when the refresh interval elapsed or
+    // a rescan was requested
+    pub async fn sync_capabilities(&mut self) -> Result<(), ClientError> {
+        self.sync_capabilities_with(false).await
+    }
+
+    async fn sync_capabilities_with(&mut self, required: bool) -> Result<(), ClientError> {
+        let requested = self.capabilities_rescan.swap(false, Ordering::Relaxed);
+        let due = match (
+            self.capabilities_submitted_at,
+            self.capabilities_refresh_interval,
+        ) {
+            (None, _) => true,
+            (Some(submitted_at), Some(interval)) => submitted_at.elapsed() >= interval,
+            (Some(_), None) => false,
+        };
+        if !due && !requested {
+            return Ok(());
+        }
+
+        let result = self.submit_capabilities().await;
+        if result.is_err() && requested {
+            self.capabilities_rescan.store(true, Ordering::Relaxed);
+        }
+        match result {
+            Err(err) if !required => {
+                warn!(
+                    "Could not submit capabilities, retrying next cycle: {}",
+                    err
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn hash_capabilities(tools: &[Tool]) -> Result<u64, ClientError> {
+        let serialized = serde_json::to_string(tools).map_err(ClientError::ParseError)?;
+        let mut hasher = DefaultHasher::new();
+        serialized.hash(&mut hasher);
+
+        Ok(hasher.finish())
+    }
+
+    // perform PATCH /jobs/<id> to update job's output after executing it
+    pub async fn submit_report(&mut self) -> Result<(), ClientError> {
+        let jobs: Vec<Arc<Job>> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .clone()
+            .into_iter()
+            // jobs waiting on a dependency are reported once they completed, and jobs whose
+            // artifacts are still uploading once the uploads are done. jobs whose lease was lost
+            // are left to the agent the server handed them to
+            .filter(|job| {
+                !job.was_submitted()
+                    && !job.is_lease_lost()
+                    && job.is_completed()
+                    && self.uploads.is_settled(job.get_id())
+            })
+            .collect();
+
+        for job in jobs {
+            info!("Submitting job report...");
+
+            let uri = self.client.endpoints().job(job.get_id());
+            job.set_submitted(true);
+            job.set_reported_at(self.now());
+
+            let (mut results, results_encoding) = self.encode_result(&job);
+            let content_type = job.content_type(results_encoding.as_deref());
+            let (output_file, output_file_encoding) = self.encode_output_file(&job);
+            let success_unknown_reason = job.success_unknown_reason();
+            // huge results are streamed instead of being serialized along with the report
+            let streamed = results
+                .take_if(|results| {
+                    self.stream_results_threshold
+                        .is_some_and(|threshold| results.len() > threshold)
+                })
+                .map(|results| match results_encoding {
+                    None => StreamedResults::Raw(job.clone(), results.len() as u64),
+                    Some(_) => StreamedResults::Encoded(Bytes::from(results)),
+                });
+            let patch = JobPatch {
+                status: job.is_skipped().then_some(JobStatus::Skipped),
+                started_at: job.get_started_at(),
+                completed_at: job.get_completed_at(),
+                results,
+                results_encoding,
+                artifacts: Some(self.uploads.confirmed(job.get_id())).filter(|a| !a.is_empty()),
+                structured_results: job.get_structured_result(),
+                skipped: job.is_skipped().then_some(true),
+                skip_reason: job.get_skip_reason(),
+                success: success_unknown_reason.is_none().then(|| job.is_success()),
+                success_unknown_reason,
+                reported_at: job.get_reported_at(),
+                empty_output: job.has_empty_output().then_some(true),
+                budget_exceeded: job.is_budget_exceeded().then_some(true),
+                content_type: Some(content_type.to_string()),
+                exit_code: job.get_exit_code(),
+                timings: Some(job.timings(Utc::now())),
+                stdout_bytes: job.get_stdout_bytes(),
+                stderr_bytes: job.get_stderr_bytes(),
+                stderr: job.get_stderr(),
+                hook_errors: Some(job.get_hook_errors()).filter(|errors| !errors.is_empty()),
+                output_file,
+                output_file_encoding,
+            };
+
+            if let Some(results) = &streamed {
+                debug!(
+                    "Streaming result of job {} ({} bytes)",
+                    job.get_id(),
+                    results.len()
+                );
+            }
+            // network and server errors are retried, the server rejecting the report is not
+            let mut retry = 0;
+            let res = loop {
+                match self.send_report(&uri, &patch, streamed.as_ref()).await {
+                    Err(err) if err.is_transient() && retry < self.report_retry.retries => {
+                        retry += 1;
+                        warn!(
+                            "Report of job {} failed ({}), retrying ({}/{})",
+                            job.get_id(),
+                            err,
+                            retry,
+                            self.report_retry.retries
+                        );
+                        tokio::time::sleep(self.report_retry.delay(retry)).await;
+                    }
+                    res => break res?,
+                }
+            };
+            self.uploads.forget(job.get_id());
+            if let Some(received_at) = Agent::get_received_at(&res) {
+                job.set_received_at(received_at);
+                if let Some(reported_at) = job.get_reported_at() {
+                    debug!(
+                        "Report of job {} received by the server after {}ms",
+                        job.get_id(),
+                        (received_at - reported_at).num_milliseconds()
+                    );
+                }
+            }
+            info!("Finished!");
+        }
+
+        self.submit_deferrals().await
+    }
+
+    async fn send_report(
+        &mut self,
+        uri: &str,
+        patch: &JobPatch,
+        streamed: Option<&StreamedResults>,
+    ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        let client = self.results_client();
+        let transport = self.transport.as_deref().unwrap_or(&client);
+        match streamed {
+            Some(results) => {
+                let body = json_with_streamed_field(patch, &self.results_field, results.reader())?;
+                client.patch_stream(uri, None, body).await
+            }
+            None if self.wire_format == WireFormat::Msgpack => {
+                let body = self.report_body(patch)?;
+                match client.patch_msgpack(uri, None, &body).await {
+                    Err(ClientError::ApiError(err))
+                        if err.code() == StatusCode::UNSUPPORTED_MEDIA_TYPE =>
+                    {
+                        warn!("The server does not accept MessagePack reports, using JSON");
+                        self.wire_format = WireFormat::Json;
+                        transport.patch(uri, None, body).await
+                    }
+                    res => res,
+                }
+            }
+            None => transport.patch(uri, None, self.report_body(patch)?).await,
+        }
+    }
+
+    // the report with its results under the field name the API expects
+    fn report_body(&self, patch: &JobPatch) -> Result<serde_json::Value, ClientError> {
+        let mut body = serde_json::to_value(patch)?;
+        if self.results_field != DEFAULT_RESULTS_FIELD
+            && let Some(fields) = body.as_object_mut()
+            && let Some(results) = fields.remove(DEFAULT_RESULTS_FIELD)
+        {
+            fields.insert(self.results_field.clone(), results);
+        }
+
+        Ok(body)
+    }
+
+    // perform PATCH /jobs/<id> for the deferred jobs, so the server keeps them pending until
+    // they can be started
+    async fn submit_deferrals(&self) -> Result<(), ClientError> {
+        let jobs: Vec<Arc<Job>> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .clone()
+            .into_iter()
+            .filter(|job| {
+                job.get_deferral().is_some()
+                    && !job.was_deferral_submitted()
+                    && !job.is_started()
+                    && !job.is_completed()
+            })
+            .collect();
+
+        let results = self.results_client();
+        for job in jobs {
+            let Some(deferral) = job.get_deferral() else {
+                continue;
+            };
+            let uri = self.client.endpoints().job(job.get_id());
+            let deferral = JobDeferral {
+                status: JobStatus::Pending,
+                deferred: true,
+                defer_reason: deferral.reason,
+                deferred_until: deferral.until,
+            };
+            self.transport
+                .as_deref()
+                .unwrap_or(&results)
+                .patch(&uri, None, serde_json::to_value(&deferral)?)
+                .await?;
+            job.set_deferral_submitted(true);
+        }
+
+        Ok(())
+    }
+
+    // compress the job's result when it is large and compressible enough, returning the
+    // result to submit along with its encoding
+    fn encode_result(&self, job: &Job) -> (Option<String>, Option<String>) {
+        self.encode(job, "result", job.get_result_as_string())
+    }
+
+    // the contents of the output file are encoded like the result
+    fn encode_output_file(&self, job: &Job) -> (Option<String>, Option<String>) {
+        self.encode(job, "output file", job.get_output_file_contents())
+    }
+
+    fn encode(
+        &self,
+        job: &Job,
+        what: &str,
+        result: Option<String>,
+    ) -> (Option<String>, Option<String>) {
+        let (Some(threshold), Some(raw)) = (self.compression_threshold, result.as_deref()) else {
+            return (result, None);
+        };
+
+        match compress_result(raw, threshold) {
+            Ok(Some(compressed)) => {
+                debug!(
+                    "Compressed {} of job {} from {} to {} bytes",
+                    what,
+                    job.get_id(),
+                    raw.len(),
+                    compressed.len()
+                );
+                (
+                    Some(compressed),
+                    Some(RESULTS_ENCODING_GZIP_BASE64.to_string()),
+                )
+            }
+            Ok(None) => (result, None),
+            Err(err) => {
+                warn!(
+                    "Could not compress {} of job {}: {}",
+                    what,
+                    job.get_id(),
+                    err
+                );
+                (result, None)
+            }
+        }
+    }
+
+    // server's acknowledged receipt time of a report, if the PATCH response contains one
+    fn get_received_at(res: &ApiData<serde_json::Value>) -> Option<DateTime<Utc>> {
+        let received_at = res.data.as_ref()?.get("received_at")?;
+        serde_json::from_value(received_at.clone()).ok()
+    }
+
+    fn get_hostname() -> String {
+        gethostname().to_string_lossy().into_owned()
+    }
+
+    fn get_platform() -> Option<AgentPlatform> {
+        match std::env::consts::OS {
+            "linux" => Some(AgentPlatform::Linux),
+            "macos" => Some(AgentPlatform::MacOS),
+            "windows" => Some(AgentPlatform::Windows),
+            _ => None, // Unknown OS
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::api::Endpoints;
+    use crate::api::client::MSGPACK_CONTENT_TYPE;
+    use crate::api::fake::{FakeRequest, FakeTransport};
+    use crate::api::mock::MockServer;
+    use crate::maintenance::parse_maintenance_window;
+    use crate::schedule::parse_local_job;
+    use crate::scope::parse_scope_entry;
+    use crate::shell::Shell;
+    use chrono::{Datelike, Utc};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    // id of the agents built by make_agent, which the jobs fetched by tests must be assigned to
+    pub(crate) const TEST_AGENT_ID: Uuid = Uuid::from_u128(0x550e8400_e29b_41d4_a716_446655440000);
+
+    fn make_agent() -> Agent {
+        Agent {
+            id: Some(TEST_AGENT_ID),
+            token: "token".to_string(),
+            jobs: Arc::new(Mutex::new(vec![])),
+            name: "myname".to_string(),
+            hostname: None,
+            description: Some("Test agent".to_string()),
+            platform: None,
+            last_seen_at: None,
+            created_at: Some(Utc::now()),
+            version: Some(AGENT_VERSION.to_string()),
+            build: Some(AGENT_BUILD.to_string()),
+            available_tools: Some(vec![]),
+            client: ApiClient::new("http://fake.url.com".to_string(), "fake_token".to_string())
+                .unwrap(),
+            use_server_time: false,
+            capabilities_hash: None,
+            capabilities_submitted_at: None,
+            capabilities_refresh_interval: None,
+            capabilities_disabled: false,
+            capabilities_required: false,
+            fallback_tools: vec![],
+            parsers: ParserRegistry::new(),
+            run_options: RunOptions::default(),
+            job_log_dir: None,
+            cycle_budget: None,
+            lease_renewal_interval: None,
+            compression_threshold: None,
+            wire_format: WireFormat::Json,
+            results_field: DEFAULT_RESULTS_FIELD.to_string(),
+            stream_results_threshold: None,
+            uploads: UploadQueue::default(),
+            report_retry: RetryPolicy::REPORTS,
+            transformer: Arc::new(NoopTransformer),
+            transport: None,
+            results_client: None,
+            scope: Scope::default(),
+            cancel: None,
+            version_probe: VersionProbe::default(),
+            maintenance_windows: vec![],
+            local_jobs: vec![],
+            local_jobs_checked_at: None,
+            disk_budget: DiskBudget::default(),
+            paused: Arc::new(AtomicBool::new(false)),
+            capabilities_rescan: Arc::new(AtomicBool::new(false)),
+            metrics: Metrics::default(),
+            clock: Utc::now,
+            implausible_clock: AtomicBool::new(false),
+            jobs_cursor: None,
+            max_jobs_per_fetch: None,
+            max_input_bytes: DEFAULT_MAX_INPUT_BYTES,
+        }
+    }
+
+    fn make_agent_with_server(server: &MockServer) -> Agent {
+        Agent {
+            client: ApiClient::new(server.url(), "fake_token".to_string()).unwrap(),
+            ..make_agent()
+        }
+    }
+
+    fn make_jobs() -> Vec<Arc<Job>> {
+        vec![
+            Arc::new(Job::new(
+                "echo_hello".to_string(),
+                "echo".to_string(),
+                ["Hello, world!".to_string()].to_vec(),
+            )),
+            Arc::new(Job::new(
+                "sleep_1".to_string(),
+                "sleep".to_string(),
+                ["1".to_string()].to_vec(),
+            )),
+        ]
+    }
+
+    // a job as the API lists it, assigned to the test agent. tests add the members they need
+    // (status, hooks, inputs...) to the returned object
+    pub(crate) fn make_job_item(cmd: &str, args: &[&str]) -> serde_json::Value {
+        json!({
+            "id": Uuid::new_v4(),
+            "name": cmd,
+            "created_at": Utc::now(),
+            "agent_id": TEST_AGENT_ID,
+            "action": {"cmd": cmd, "args": args, "variant": ""},
+        })
+    }
+
+    fn parse_job_item(item: serde_json::Value) -> Arc<Job> {
+        Arc::new(serde_json::from_value(item).unwrap())
+    }
+
+    fn make_jobs_that_crash() -> Vec<Arc<Job>> {
+        vec![
+            Arc::new(Job::new(
+                "echo_hello".to_string(),
+                "echo1234".to_string(),
+                ["Hello, world!".to_string()].to_vec(),
+            )),
+            Arc::new(Job::new(
+                "sleep_1".to_string(),
+                "sleep1234".to_string(),
+                ["1".to_string()].to_vec(),
+            )),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_get_hostname() {
+        let hostname = Agent::get_hostname();
+        assert!(!hostname.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_platform() {
+        let platform = Agent::get_platform();
+
+        assert!(platform.is_some());
+        assert!(matches!(
+            platform,
+            Some(AgentPlatform::Linux) | Some(AgentPlatform::MacOS) | Some(AgentPlatform::Windows)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_submit_jobs() {
+        // Given
+        let agent = make_agent();
+        let jobs = make_jobs();
+
+        // Prevent deadlock by the agent.run_jobs() function
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = jobs;
+        }
+
+        // When
+        let _result = agent.run_jobs().await;
+
+        // Then
+        let any_incompleted_job = agent
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|job| !job.is_completed());
+
+        assert!(!any_incompleted_job);
+    }
+
+    #[tokio::test]
+    async fn test_submit_jobs_whose_tool_is_unavailable() {
+        // Given jobs whose commands are not installed
+        let server = MockServer::start().await;
+        let mut agent = make_agent_with_server(&server);
+        let jobs = make_jobs_that_crash();
+        for job in &jobs {
+            server.mock("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        }
+
+        // Prevent deadlock by the agent.run_jobs() function
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = jobs.clone();
+        }
+
+        // When
+        let result = agent.run_jobs().await;
+        agent.submit_report().await.unwrap();
+
+        // Then they are not failures, but reported as unavailable without being run
+        assert!(result.is_ok());
+        for job in jobs {
+            assert_eq!(job.get_skip_reason(), Some(SkipReason::ToolUnavailable));
+            assert!(job.was_submitted());
+            assert!(job.get_started_at().is_none());
+
+            let patch = &server.requests_to("PATCH", &format!("/jobs/{}", job.get_id()))[0];
+            assert_eq!(patch.json()["status"], json!("skipped"));
+            assert_eq!(patch.json()["skip_reason"], json!("tool_unavailable"));
+            assert!(patch.json().get("success").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_success_states() {
+        // Given a job that succeeded, one that failed and one cancelled by the shutdown
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let mut jobs = make_jobs();
+        jobs.push(Arc::new(Job::new(
+            "sleep_long".to_string(),
+            "sleep".to_string(),
+            vec!["60".to_string()],
+        )));
+        jobs[0].set_success(true);
+        jobs[0].set_completed_at();
+        jobs[1].set_completed_at();
+        jobs[2].skip(SkipReason::Cancelled, "cancelled by shutdown".to_string());
+        agent.jobs.lock().unwrap().extend(jobs.iter().cloned());
+        for job in &jobs {
+            transport.respond("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        }
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let bodies: Vec<_> = transport
+            .requests()
+            .iter()
+            .map(|r| r.body.clone().unwrap())
+            .collect();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0]["success"], json!(true));
+        assert!(bodies[0].get("success_unknown_reason").is_none());
+        assert_eq!(bodies[1]["success"], json!(false));
+        assert!(bodies[1].get("success_unknown_reason").is_none());
+        assert!(bodies[2].get("success").is_none());
+        assert_eq!(bodies[2]["success_unknown_reason"], json!("cancelled"));
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_uses_configured_results_field() {
+        // Given an API expecting the results as `output`
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_results_field("output".to_string());
+        let job = make_jobs().remove(0);
+        job.set_result("Hello, world!".to_string());
+        job.set_success(true);
+        job.set_completed_at();
+        agent.jobs.lock().unwrap().push(job.clone());
+        transport.respond("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let body = transport.requests()[0].body.clone().unwrap();
+        assert_eq!(body["output"], json!("Hello, world!"));
+        assert!(body.get("results").is_none());
+        assert_eq!(body["success"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn test_register_applies_server_time_offset() {
+        // Given a server whose clock is one hour ahead
+        let server = MockServer::start().await;
+        let server_time = (Utc::now() + TimeDelta::hours(1)).to_rfc2822();
+        server.mock_with_headers(
+            "PATCH",
+            "/self",
+            200,
+            vec![("Date", &server_time)],
+            json!({"data": {"attributes": {}}}),
+        );
+        let mut agent = make_agent_with_server(&server);
+        agent.set_use_server_time(true);
+
+        // When the first request records the offset and the second one uses it
+        agent.announce_presence().await.unwrap();
+        agent.register().await.unwrap();
+
+        // Then
+        let requests = server.requests_to("PATCH", "/self");
+        let last_seen_at: DateTime<Utc> =
+            serde_json::from_value(requests[1].json()["last_seen_at"].clone()).unwrap();
+        let skew = last_seen_at - Utc::now();
+        assert!((skew - TimeDelta::hours(1)).abs() < TimeDelta::seconds(5));
+    }
+
+    #[tokio::test]
+    async fn test_announce_presence_with_implausible_clock() {
+        // Given a system clock set far in the future
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("PATCH", "/self", 200, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_clock(|| DateTime::<Utc>::MAX_UTC);
+
+        // When
+        agent.announce_presence().await.unwrap();
+        agent.register().await.unwrap();
+
+        // Then the agent warned and sent valid, clamped timestamps
+        assert!(agent.implausible_clock.load(Ordering::Relaxed));
+        for request in transport.requests() {
+            let last_seen_at = request.body.unwrap()["last_seen_at"].clone();
+            let last_seen_at = DateTime::parse_from_rfc3339(last_seen_at.as_str().unwrap());
+            assert_eq!(last_seen_at.unwrap().year(), PLAUSIBLE_YEARS.1);
+        }
+
+        // And once the clock is fixed, it is trusted again
+        agent.set_clock(Utc::now);
+        assert!((agent.now() - Utc::now()).abs() < TimeDelta::seconds(5));
+        assert!(!agent.implausible_clock.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_now_ignores_server_time_when_disabled() {
+        let server = MockServer::start().await;
+        let server_time = (Utc::now() + TimeDelta::hours(1)).to_rfc2822();
+        server.mock_with_headers(
+            "PATCH",
+            "/self",
+            200,
+            vec![("Date", &server_time)],
+            json!({"data": {"attributes": {}}}),
+        );
+        let mut agent = make_agent_with_server(&server);
+
+        agent.announce_presence().await.unwrap();
+
+        assert!(agent.client.server_time_offset().is_some());
+        assert!((agent.now() - Utc::now()).abs() < TimeDelta::seconds(5));
+    }
+
+    #[tokio::test]
+    async fn test_submit_capabilities_only_when_tools_change() {
+        // Given /tools answers twice with the same tools, then with an additional one
+        let server = MockServer::start().await;
+        let echo = json!({"cmd": "echo", "version": null, "version_arg": null});
+        let sh = json!({"cmd": "sh", "version": null, "version_arg": null});
+        let missing = json!({"cmd": "non_existing_cmd", "version": null, "version_arg": null});
+        server.mock("GET", "/tools", 200, json!({"data": [echo, missing]}));
+        server.mock("GET", "/tools", 200, json!({"data": [echo, missing]}));
+        server.mock("GET", "/tools", 200, json!({"data": [echo, sh, missing]}));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+
+        // When / Then
+        agent.submit_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 1);
+
+        agent.submit_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 1);
+
+        agent.submit_capabilities().await.unwrap();
+        let patches = server.requests_to("PATCH", "/self");
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[1].json()["available_tools"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_capabilities_forced_after_refresh_interval() {
+        let server = MockServer::start().await;
+        let echo = json!({"cmd": "echo", "version": null, "version_arg": null});
+        server.mock("GET", "/tools", 200, json!({"data": [echo]}));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_capabilities_refresh_interval(Some(Duration::ZERO));
+
+        agent.submit_capabilities().await.unwrap();
+        agent.submit_capabilities().await.unwrap();
+
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_capabilities_failure_is_retried_by_default() {
+        // Given a server failing the first capabilities submission
+        let server = MockServer::start().await;
+        let echo = json!({"cmd": "echo", "version": null, "version_arg": null});
+        server.mock("GET", "/tools", 200, json!({"data": [echo]}));
+        server.mock(
+            "PATCH",
+            "/self",
+            500,
+            json!({"errors": [{"detail": "down"}]}),
+        );
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+
+        // When / Then the failure is not an error, and the next cycle submits them again
+        agent.sync_capabilities().await.unwrap();
+        assert!(agent.capabilities_hash.is_none());
+
+        agent.sync_capabilities().await.unwrap();
+        assert!(agent.capabilities_hash.is_some());
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_capabilities_rescans_only_when_due() {
+        // Given capabilities submitted once, without refresh interval
+        let server = MockServer::start().await;
+        let echo = json!({"cmd": "echo", "version": null, "version_arg": null});
+        let sh = json!({"cmd": "sh", "version": null, "version_arg": null});
+        server.mock("GET", "/tools", 200, json!({"data": [echo]}));
+        server.mock("GET", "/tools", 200, json!({"data": [echo, sh]}));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.sync_capabilities().await.unwrap();
+
+        // When / Then the next cycles do not scan the tools again
+        agent.sync_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("GET", "/tools").len(), 1);
+
+        // unless a rescan is requested
+        agent.rescan_handle().store(true, Ordering::Relaxed);
+        agent.sync_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("GET", "/tools").len(), 2);
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 2);
+        agent.sync_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("GET", "/tools").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_submit_capabilities_with_fallback_tools() {
+        // Given /tools failing, and a local list of tools
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/tools",
+            500,
+            json!({"errors": [{"detail": "down"}]}),
+        );
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_capabilities_required(true);
+        agent.set_fallback_tools(vec!["sh".to_string(), "non_existing_cmd".to_string()]);
+
+        // When
+        agent.sync_capabilities().await.unwrap();
+
+        // Then the available tools of the local list are submitted
+        let patches = server.requests_to("PATCH", "/self");
+        assert_eq!(patches.len(), 1);
+        let tools = patches[0].json()["available_tools"].clone();
+        assert_eq!(tools.as_array().unwrap().len(), 1);
+        assert_eq!(tools[0]["cmd"], json!("sh"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_capabilities_failure_when_required() {
+        let server = MockServer::start().await;
+        let echo = json!({"cmd": "echo", "version": null, "version_arg": null});
+        server.mock("GET", "/tools", 200, json!({"data": [echo]}));
+        server.mock(
+            "PATCH",
+            "/self",
+            500,
+            json!({"errors": [{"detail": "down"}]}),
+        );
+        let mut agent = make_agent_with_server(&server);
+        agent.set_capabilities_required(true);
+
+        let err = agent.sync_startup_capabilities().await.unwrap_err();
+
+        assert!(matches!(err, ClientError::ApiError(_)));
+        assert!(agent.capabilities_hash.is_none());
+        // the later cycles retry them instead of failing
+        agent.sync_capabilities().await.unwrap();
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 2);
+        assert!(agent.capabilities_hash.is_none());
+    }
+
+    fn make_self_response(id: Option<Uuid>) -> serde_json::Value {
+        json!({"data": {"attributes": {
+            "id": id,
+            "token": "token",
+            "jobs": [],
+            "name": "myname",
+        }}})
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_substitutes_agent_variables() {
+        let mut agent = Agent {
+            hostname: Some("scanner-box".to_string()),
+            ..make_agent()
+        };
+        agent.set_run_options(RunOptions {
+            bind_address: Some("10.0.0.2".parse().unwrap()),
+            ..Default::default()
+        });
+        let job = Arc::new(Job::new(
+            "echo_hostname".to_string(),
+            "echo".to_string(),
+            vec!["running on {hostname} from {bind_address} ({unknown})".to_string()],
+        ));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        agent.run_jobs().await.unwrap();
+
+        assert_eq!(
+            job.get_result_as_string().unwrap(),
+            "running on scanner-box from 10.0.0.2 ({unknown})\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_rejects_shell_jobs_unless_enabled() {
+        let make_shell_job = || {
+            let mut item = make_job_item("echo", &[]);
+            item["action"]["script"] = json!("echo $((1 + 1))");
+            item["action"]["shell"] = json!(true);
+            parse_job_item(item)
+        };
+        // Given a shell-mode job on an agent without --shell, one with it and one with a scope
+        let disabled = make_agent();
+        let mut enabled = make_agent();
+        enabled.set_run_options(RunOptions {
+            shell: Some(Shell::default()),
+            ..Default::default()
+        });
+        let mut scoped = make_agent();
+        scoped.set_run_options(RunOptions {
+            shell: Some(Shell::default()),
+            ..Default::default()
+        });
+        scoped.set_scope(Scope::new(
+            vec![parse_scope_entry("10.0.0.0/24").unwrap()],
+            vec![],
+        ));
+        let jobs = [make_shell_job(), make_shell_job(), make_shell_job()];
+
+        // When
+        for (agent, job) in [&disabled, &enabled, &scoped].into_iter().zip(&jobs) {
+            agent.jobs.lock().unwrap().push(job.clone());
+            agent.run_jobs().await.unwrap();
+        }
+
+        // Then only the agent with --shell and no scope ran it
+        assert_eq!(jobs[0].get_skip_reason(), Some(SkipReason::Rejected));
+        assert_eq!(
+            jobs[0].get_result_as_string().unwrap(),
+            "shell mode is not enabled"
+        );
+        assert_eq!(jobs[1].get_result_as_string().unwrap(), "2\n");
+        assert_eq!(jobs[2].get_skip_reason(), Some(SkipReason::Rejected));
+        assert_eq!(
+            jobs[2].get_result_as_string().unwrap(),
+            "shell mode is not allowed with a scope"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_rejects_out_of_scope_targets() {
+        // Given an allowlist covering a single network
+        let server = MockServer::start().await;
+        let mut agent = make_agent_with_server(&server);
+        agent.set_scope(Scope::new(
+            vec![parse_scope_entry("10.0.0.0/24").unwrap()],
+            vec![],
+        ));
+        let in_scope = Arc::new(Job::new(
+            "in_scope".to_string(),
+            "echo".to_string(),
+            vec!["10.0.0.5".to_string()],
+        ));
+        let out_of_scope = Arc::new(Job::new(
+            "out_of_scope".to_string(),
+            "echo".to_string(),
+            vec!["192.168.1.5".to_string()],
+        ));
+        // the hooks of a job are checked like its action
+        let mut hooked = make_job_item("echo", &["10.0.0.6"]);
+        hooked["post_hook"] = json!({"cmd": "echo", "args": ["192.168.1.6"]});
+        let hooked = parse_job_item(hooked);
+        for job in [&in_scope, &out_of_scope, &hooked] {
+            server.mock("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        }
+        agent
+            .jobs
+            .lock()
+            .unwrap()
+            .extend([in_scope.clone(), out_of_scope.clone(), hooked.clone()]);
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then only the in-scope job ran, the other one is reported as rejected
+        assert_eq!(in_scope.get_result_as_string().unwrap(), "10.0.0.5\n");
+        assert!(in_scope.get_started_at().is_some());
+        assert!(out_of_scope.get_started_at().is_none());
+        assert_eq!(out_of_scope.get_skip_reason(), Some(SkipReason::OutOfScope));
+        assert!(hooked.get_started_at().is_none());
+        assert_eq!(hooked.get_skip_reason(), Some(SkipReason::OutOfScope));
+
+        let patch = &server.requests_to("PATCH", &format!("/jobs/{}", out_of_scope.get_id()))[0];
+        assert_eq!(patch.json()["skip_reason"], json!("out_of_scope"));
+        assert!(patch.json().get("success").is_none());
+        assert_eq!(
+            patch.json()["results"],
+            json!("out-of-scope targets: 192.168.1.5")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_through_fake_transport() {
+        // Given a completed job, a failed one and a job still running
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let jobs = make_jobs();
+        jobs[0].set_result("hello".to_string());
+        jobs[0].set_success(true);
+        jobs[0].set_completed_at();
+        jobs[1].set_result("boom".to_string());
+        jobs[1].set_completed_at();
+        let running = Arc::new(Job::new("running".to_string(), "sleep".to_string(), vec![]));
+        agent.jobs.lock().unwrap().extend(jobs.iter().cloned());
+        agent.jobs.lock().unwrap().push(running.clone());
+        for job in &jobs {
+            transport.respond("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        }
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then only the completed jobs were reported, once each
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        for (request, job) in requests.iter().zip(&jobs) {
+            assert!(matches!(
+                request,
+                FakeRequest { method, uri, body: Some(_) }
+                    if method == "PATCH" && *uri == format!("/jobs/{}", job.get_id())
+            ));
+            assert!(job.was_submitted());
+        }
+        let bodies: Vec<_> = requests.iter().map(|r| r.body.clone().unwrap()).collect();
+        assert_eq!(bodies[0]["results"], json!("hello"));
+        assert_eq!(bodies[0]["success"], json!(true));
+        assert_eq!(bodies[1]["results"], json!("boom"));
+        assert_eq!(bodies[1]["success"], json!(false));
+        assert!(!running.was_submitted());
+
+        // And nothing is submitted twice
+        agent.submit_report().await.unwrap();
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_defers_jobs_during_maintenance_window() {
+        // Given a window lasting all day today
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let today = Utc::now().weekday();
+        agent.set_maintenance_windows(vec![
+            parse_maintenance_window(&format!("{} 00:00-00:00", today)).unwrap(),
+        ]);
+        let job = Arc::new(Job::new(
+            "echo".to_string(),
+            "echo".to_string(),
+            vec!["hi".to_string()],
+        ));
+        agent.jobs.lock().unwrap().push(job.clone());
+        let uri = format!("/jobs/{}", job.get_id());
+        transport.respond("PATCH", &uri, 200, json!({}));
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then the job is not started and reported as pending-deferred
+        assert!(job.get_started_at().is_none());
+        assert_eq!(job.state(), "deferred");
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        let body = requests[0].body.clone().unwrap();
+        assert_eq!(body["status"], json!("pending"));
+        assert_eq!(body["deferred"], json!(true));
+        assert!(body.get("success").is_none());
+        assert_eq!(body["defer_reason"], json!("maintenance_window"));
+        assert!(body["deferred_until"].is_string());
+
+        // And the deferral is reported only once
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_run_jobs_while_polling() {
+        // Given many jobs, and a poller adding jobs and reading their state meanwhile as the
+        // fetches and the control socket do
+        let agent = make_agent();
+        let make_job = |i: usize| {
+            Arc::new(Job::new(
+                format!("job-{}", i),
+                "echo".to_string(),
+                vec![i.to_string()],
+            ))
+        };
+        agent.jobs.lock().unwrap().extend((0..64).map(make_job));
+        let handle = agent.jobs_handle();
+        let poller = tokio::spawn(async move {
+            for i in 64..128 {
+                handle.lock().unwrap().push(make_job(i));
+                let states: Vec<&str> = handle
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|job| job.state())
+                    .collect();
+                assert_eq!(states.len(), i + 1);
+                tokio::task::yield_now().await;
+            }
+        });
+
+        // When the jobs run, then the ones added during the run
+        let run = async {
+            agent.run_jobs().await.unwrap();
+            poller.await.unwrap();
+            agent.run_jobs().await.unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(30), run)
+            .await
+            .expect("run_jobs should not deadlock with a concurrent poller");
+
+        // Then
+        let jobs = agent.jobs.lock().unwrap().clone();
+        assert_eq!(jobs.len(), 128);
+        assert!(
+            jobs.iter()
+                .all(|job| job.is_completed() && job.is_success())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schedule_local_jobs() {
+        // Given a job due every minute and one due on new year's day only
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("POST", "/jobs", 201, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_clock(|| "2025-06-16T10:00:30Z".parse().unwrap());
+        agent.set_local_jobs(vec![
+            parse_local_job("* * * * * echo every minute").unwrap(),
+            parse_local_job("0 0 1 1 * echo happy new year").unwrap(),
+        ]);
+
+        // When
+        agent.schedule_local_jobs().await;
+        let job = agent.jobs.lock().unwrap()[0].clone();
+        transport.respond("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then only the due job was created on the server, ran, and was reported
+        let jobs = agent.jobs.lock().unwrap().clone();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].get_name(), "echo every minute");
+        assert_eq!(jobs[0].get_agent_id(), &TEST_AGENT_ID);
+        assert_eq!(jobs[0].get_result_as_string().unwrap(), "every minute\n");
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "POST");
+        let creation = requests[0].body.clone().unwrap();
+        assert_eq!(creation["id"], json!(job.get_id()));
+        assert_eq!(creation["agent_id"], json!(TEST_AGENT_ID));
+        assert_eq!(creation["action"]["cmd"], json!("echo"));
+        assert_eq!(requests[1].uri, format!("/jobs/{}", job.get_id()));
+        assert_eq!(requests[1].body.clone().unwrap()["success"], json!(true));
+
+        // And it is not queued again within the same minute
+        agent.schedule_local_jobs().await;
+        assert_eq!(agent.jobs.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_local_jobs_refused_by_the_server() {
+        // Given a server refusing to create jobs
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("POST", "/jobs", 403, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_local_jobs(vec![parse_local_job("* * * * * echo hi").unwrap()]);
+
+        // When
+        agent.schedule_local_jobs().await;
+
+        // Then the job is not run, it could not be reported
+        assert!(agent.jobs.lock().unwrap().is_empty());
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_outside_maintenance_window() {
+        // Given a window lasting all day tomorrow
+        let mut agent = make_agent();
+        let tomorrow = Utc::now().weekday().succ();
+        agent.set_maintenance_windows(vec![
+            parse_maintenance_window(&format!("{} 00:00-00:00", tomorrow)).unwrap(),
+        ]);
+        let job = Arc::new(Job::new(
+            "echo".to_string(),
+            "echo".to_string(),
+            vec!["hi".to_string()],
+        ));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then
+        assert!(job.is_success());
+        assert!(job.get_deferral().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_fails_on_fake_transport_errors() {
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let job = make_jobs().remove(0);
+        job.set_completed_at();
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        let err = agent.submit_report().await.unwrap_err();
+
+        assert!(matches!(err, ClientError::ApiError(err) if err.code() == StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_includes_stderr_of_successful_jobs() {
+        // Given a successful job warning on stderr and another one printing nothing there
+        let server = MockServer::start().await;
+        let mut agent = make_agent_with_server(&server);
+        let warning = Arc::new(Job::new(
+            "warning".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "echo ok; echo 'host down, skipped' >&2".to_string(),
+            ],
+        ));
+        let quiet = Arc::new(Job::new(
+            "quiet".to_string(),
+            "echo".to_string(),
+            vec!["ok".to_string()],
+        ));
+        for job in [&warning, &quiet] {
+            server.mock("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+        }
+        agent
+            .jobs
+            .lock()
+            .unwrap()
+            .extend([warning.clone(), quiet.clone()]);
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let patch = server.requests_to("PATCH", &format!("/jobs/{}", warning.get_id()))[0].json();
+        assert_eq!(patch["success"], json!(true));
+        assert_eq!(patch["stderr"], json!("host down, skipped\n"));
+        let patch = server.requests_to("PATCH", &format!("/jobs/{}", quiet.get_id()))[0].json();
+        assert!(patch.get("stderr").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_cancels_jobs_on_shutdown() {
+        // Given a batch of long running jobs, and a job waiting for one of them
+        let mut agent = make_agent();
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        agent.set_cancel_signal(cancel_rx);
+        let jobs = vec![
+            Arc::new(Job::new(
+                "sleep_a".to_string(),
+                "sleep".to_string(),
+                vec!["10".to_string()],
+            )),
+            Arc::new(Job::new(
+                "sleep_b".to_string(),
+                "sleep".to_string(),
+                vec!["10".to_string()],
+            )),
+        ];
+        let dependent = make_dependent_job("echo", Some(*jobs[0].get_id()), json!("always"));
+        agent.jobs.lock().unwrap().extend(jobs.iter().cloned());
+        agent.jobs.lock().unwrap().push(dependent.clone());
+
+        // When the shutdown signal is received while they run
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel_tx.send(true).unwrap();
+        });
+        let started = Instant::now();
+        let result = agent.run_jobs().await;
+
+        // Then the running jobs are killed and the dependent one is never started
+        assert!(result.is_ok());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        for job in jobs {
+            assert_eq!(job.get_skip_reason(), Some(SkipReason::Cancelled));
+            assert!(!job.is_success());
+            assert!(job.get_completed_at().is_some());
+            assert_eq!(job.get_result_as_string().unwrap(), "cancelled by shutdown");
+        }
+        assert!(dependent.get_started_at().is_none());
+        assert!(dependent.get_completed_at().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_cancels_jobs_exceeding_cycle_budget() {
+        // Given jobs lasting much longer than the cycle budget
+        let mut agent = make_agent();
+        agent.set_cycle_budget(Some(Duration::from_millis(300)));
+        let jobs = vec![
+            Arc::new(Job::new(
+                "sleep_a".to_string(),
+                "sleep".to_string(),
+                vec!["5".to_string()],
+            )),
+            Arc::new(Job::new(
+                "sleep_b".to_string(),
+                "sleep".to_string(),
+                vec!["5".to_string()],
+            )),
+        ];
+        agent.jobs.lock().unwrap().extend(jobs.iter().cloned());
+
+        // When
+        let started = Instant::now();
+        let result = agent.run_jobs().await;
+
+        // Then
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        for job in jobs {
+            assert!(job.is_budget_exceeded());
+            assert!(!job.is_success());
+            assert!(job.get_completed_at().is_some());
+            assert_eq!(job.get_result_as_string().unwrap(), "cycle budget exceeded");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_renews_leases_until_completion() {
+        // Given a job lasting several renewal intervals
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_lease_renewal_interval(Some(Duration::from_millis(200)));
+        let job = Arc::new(Job::new(
+            "sleep_1".to_string(),
+            "sleep".to_string(),
+            vec!["1".to_string()],
+        ));
+        let uri = format!("/jobs/{}", job.get_id());
+        transport.respond("PATCH", &uri, 200, json!({}));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+        let renewals = transport.requests().len();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // Then the lease was renewed while it ran, and is not anymore
+        assert!(job.is_success());
+        assert!(renewals >= 1, "{} renewals", renewals);
+        assert_eq!(transport.requests().len(), renewals);
+        let renewal = transport.requests()[0].clone();
+        assert_eq!(renewal.uri, uri);
+        let body = renewal.body.unwrap();
+        assert_eq!(body["agent_id"], json!(TEST_AGENT_ID));
+        assert!(body["lease_renewed_at"].is_string());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_renew_lease_every_interval_until_aborted() {
+        // Given a lease renewed every 200ms
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("PATCH", "/jobs/1", 200, json!({}));
+        let (lost_tx, lost_rx) = watch::channel(false);
+        let renewal = JobLeaseRenewal {
+            agent_id: Some(TEST_AGENT_ID),
+            lease_renewed_at: Utc::now(),
+        };
+
+        // When the job runs for 700ms
+        let task = tokio::task::spawn(Agent::renew_lease(
+            transport.clone(),
+            "/jobs/1".to_string(),
+            renewal,
+            Utc::now,
+            Duration::from_millis(200),
+            lost_tx,
+        ));
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        task.abort();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Then the lease was renewed three times, and not after the job completed
+        assert_eq!(transport.requests().len(), 3);
+        assert!(!*lost_rx.borrow());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_aborts_jobs_whose_lease_is_lost() {
+        // Given a job whose lease renewal is rejected by the server
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_lease_renewal_interval(Some(Duration::from_millis(200)));
+        let job = Arc::new(Job::new(
+            "sleep_10".to_string(),
+            "sleep".to_string(),
+            vec!["10".to_string()],
+        ));
+        let uri = format!("/jobs/{}", job.get_id());
+        transport.respond("PATCH", &uri, 409, json!({}));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        let started = Instant::now();
+        let result = agent.run_jobs().await;
+
+        // Then it is killed after the first renewal
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(transport.requests().len(), 1);
+        assert!(!job.is_success());
+        assert_eq!(job.get_result_as_string().unwrap(), "lease lost");
+        // and not reported, the server may have handed it to another agent
+        agent.submit_report().await.unwrap();
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_references_uploaded_artifacts() {
+        // Given a job writing a file declared as artifact in the artifacts directory
+        let server = MockServer::start().await;
+        let artifacts_dir = make_artifacts_dir();
+        let mut item = make_job_item("touch", &["{artifacts_dir}/scan.xml"]);
+        item["artifacts"] = json!(["scan.xml"]);
+        let job = parse_job_item(item);
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock(
+            "POST",
+            &format!("{}/artifacts", uri),
+            201,
+            json!({"data": {"attributes": {"id": "artifact-1"}}}),
+        );
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_artifact_retention(ArtifactRetention::All, Some(artifacts_dir.clone()));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.uploads.wait_idle().await;
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        assert_eq!(body["artifacts"], json!(["artifact-1"]));
+        std::fs::remove_dir_all(artifacts_dir).unwrap();
+    }
+
+    fn make_artifacts_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-artifacts-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_artifacts_outside_of_the_artifacts_directory_are_not_sent() {
+        // Given a job declaring a file of the agent, directly and through a link
+        let server = MockServer::start().await;
+        let artifacts_dir = make_artifacts_dir();
+        let secret = std::env::temp_dir().join(format!("agent-secret-{}", Uuid::new_v4()));
+        std::fs::write(&secret, "token").unwrap();
+        let link = artifacts_dir.join("scan.xml");
+        let mut item = make_job_item(
+            "ln",
+            &["-s", secret.to_str().unwrap(), "{artifacts_dir}/scan.xml"],
+        );
+        item["artifacts"] = json!([secret, "scan.xml", "../secret"]);
+        let job = parse_job_item(item);
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_artifact_retention(ArtifactRetention::All, Some(artifacts_dir.clone()));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.uploads.wait_idle().await;
+        agent.submit_report().await.unwrap();
+
+        // Then none of them is sent
+        assert!(link.is_symlink());
+        assert!(
+            server
+                .requests_to("POST", &format!("{}/artifacts", uri))
+                .is_empty()
+        );
+        std::fs::remove_dir_all(artifacts_dir).unwrap();
+        std::fs::remove_file(secret).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reports_and_artifacts_use_their_own_retry_policy() {
+        // Given a failing API, artifacts retried twice and reports once
+        let server = MockServer::start().await;
+        let artifacts_dir = make_artifacts_dir();
+        let mut item = make_job_item("touch", &["{artifacts_dir}/scan.xml"]);
+        item["artifacts"] = json!(["scan.xml"]);
+        let job = parse_job_item(item);
+        let uri = format!("/jobs/{}", job.get_id());
+        let unavailable = json!({"errors": [{"detail": "unavailable"}]});
+        server.mock(
+            "POST",
+            &format!("{}/artifacts", uri),
+            503,
+            unavailable.clone(),
+        );
+        server.mock("PATCH", &uri, 503, unavailable);
+        let mut agent = make_agent_with_server(&server);
+        let backoff = Duration::from_millis(10);
+        agent.set_upload_limits(
+            1,
+            RetryPolicy {
+                retries: 2,
+                backoff,
+            },
+        );
+        agent.set_report_retry(RetryPolicy {
+            retries: 1,
+            backoff,
+        });
+        agent.set_artifact_retention(ArtifactRetention::All, Some(artifacts_dir.clone()));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.uploads.wait_idle().await;
+        let result = agent.submit_report().await;
+
+        // Then
+        assert!(result.is_err());
+        assert_eq!(
+            server
+                .requests_to("POST", &format!("{}/artifacts", uri))
+                .len(),
+            3
+        );
+        assert_eq!(server.requests_to("PATCH", &uri).len(), 2);
+        std::fs::remove_dir_all(artifacts_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reports_are_not_retried() {
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_report_retry(RetryPolicy {
+            retries: 3,
+            backoff: Duration::ZERO,
+        });
+        let job = make_jobs().remove(0);
+        job.set_completed_at();
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        assert!(agent.submit_report().await.is_err());
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_honors_success_exit_codes() {
+        // Given two grep jobs without any match, one accepting exit code 1
+        let agent = make_agent();
+        let make_grep = |codes: Option<Vec<i32>>| {
+            let mut item = make_job_item("grep", &["needle", "/dev/null"]);
+            if let Some(codes) = codes {
+                item["action"]["success_exit_codes"] = json!(codes);
+            }
+            parse_job_item(item)
+        };
+        let strict = make_grep(None);
+        let lenient = make_grep(Some(vec![0, 1]));
+        agent
+            .jobs
+            .lock()
+            .unwrap()
+            .extend([strict.clone(), lenient.clone()]);
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then
+        assert_eq!(strict.get_exit_code(), Some(1));
+        assert!(!strict.is_success());
+        assert_eq!(lenient.get_exit_code(), Some(1));
+        assert!(lenient.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_writes_job_log() {
+        // Given an agent keeping job logs
+        let dir = std::env::temp_dir().join(format!("agent-logs-{}", Uuid::new_v4()));
+        let mut agent = make_agent();
+        agent.set_job_log_dir(Some(dir.clone()));
+        let job = Arc::new(Job::new(
+            "echo_hello".to_string(),
+            "echo".to_string(),
+            vec!["Hello, world!".to_string()],
+        ));
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then
+        let path = dir.join(format!("{}.log", job.get_id()));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let quoted = crate::shell::quote("Hello, world!");
+        assert!(contents.contains(&format!("command: echo {}", quoted)));
+        assert!(contents.contains("started_at: 20"));
+        assert!(contents.contains("success: true"));
+        assert!(contents.contains("output:\nHello, world!\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_push() {
+        // Given an agent which ran some jobs
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("POST", "/metrics", 200, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        for name in ["echo_a", "echo_b"] {
+            agent.jobs.lock().unwrap().push(Arc::new(Job::new(
+                name.to_string(),
+                "echo".to_string(),
+                vec!["hello".to_string()],
+            )));
+        }
+        agent.run_jobs().await.unwrap();
+
+        // When
+        let push = agent.spawn_metrics_push(Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(180)).await;
+        push.abort();
+
+        // Then
+        let pushes = transport.requests();
+        assert!(pushes.len() >= 2);
+        assert!(pushes.iter().all(|push| push.method == "POST"));
+        let body = pushes[0].body.clone().unwrap();
+        assert_eq!(body["tools"]["echo"]["executions"], 2);
+        assert_eq!(body["tools"]["echo"]["failures"], 0);
+        assert_eq!(body["jobs_run"], 2);
+        assert_eq!(body["succeeded"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_push_survives_failures() {
+        // Given an API rejecting the metrics
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+
+        // When
+        let push = agent.spawn_metrics_push(Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(180)).await;
+
+        // Then it keeps pushing
+        assert!(!push.is_finished());
+        assert!(transport.requests().len() >= 2);
+        push.abort();
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_records_metrics_by_tool() {
+        // Given two echo jobs and a failing sh one
+        let agent = make_agent();
+        let jobs = vec![
+            Arc::new(Job::new("a".to_string(), "echo".to_string(), vec![])),
+            Arc::new(Job::new("b".to_string(), "echo".to_string(), vec![])),
+            Arc::new(Job::new(
+                "c".to_string(),
+                "sh".to_string(),
+                vec!["-c".to_string(), "exit 3".to_string()],
+            )),
+        ];
+        agent.jobs.lock().unwrap().extend(jobs);
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then
+        let metrics = agent.metrics_handle();
+        assert_eq!(metrics.counts("echo"), (2, 0));
+        assert_eq!(metrics.counts("sh"), (1, 1));
+        let rendered = metrics.render();
+        assert!(rendered.contains("tool_executions_total{tool=\"echo\"} 2\n"));
+        assert!(rendered.contains("tool_failures_total{tool=\"sh\"} 1\n"));
+        assert!(rendered.contains("tool_duration_seconds_count{tool=\"sh\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_removes_old_job_logs_over_the_disk_budget() {
+        // Given a log directory already filled up to its budget
+        let dir = std::env::temp_dir().join(format!("agent-logs-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old_log = dir.join("old.log");
+        std::fs::write(&old_log, vec![b'x'; 1024]).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&old_log)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        let mut agent = make_agent();
+        agent.set_job_log_dir(Some(dir.clone()));
+        agent.set_disk_budget(DiskBudget {
+            max_bytes: Some(1024),
+            min_free_bytes: None,
+        });
+        let jobs: Vec<_> = (0..2)
+            .map(|_| Arc::new(Job::new("echo".to_string(), "echo".to_string(), vec![])))
+            .collect();
+
+        // When a job writes its log and the next cycle checks the budget
+        agent.jobs.lock().unwrap().push(jobs[0].clone());
+        agent.run_jobs().await.unwrap();
+        agent.jobs.lock().unwrap().push(jobs[1].clone());
+        agent.run_jobs().await.unwrap();
+
+        // Then the oldest log was removed to make room
+        assert!(!old_log.exists());
+        assert!(dir.join(format!("{}.log", jobs[0].get_id())).exists());
+        assert!(jobs[1].is_success());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_disk_budget_removes_old_artifacts() {
+        // Given an artifacts directory over its budget
+        let dir = make_artifacts_dir();
+        let old = dir.join("old.xml");
+        std::fs::write(&old, vec![b'x'; 1024]).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        let new = dir.join("new.xml");
+        std::fs::write(&new, vec![b'x'; 1024]).unwrap();
+        let mut agent = make_agent();
+        agent.set_disk_budget(DiskBudget {
+            max_bytes: Some(1024),
+            min_free_bytes: None,
+        });
+        agent.set_artifact_retention(ArtifactRetention::All, Some(dir.clone()));
+
+        // When
+        assert!(agent.check_disk_budget());
+
+        // Then
+        assert!(!old.exists());
+        assert!(new.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_jobs_defers_jobs_when_disk_space_is_low() {
+        // Given more free space required than any disk has
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_disk_budget(DiskBudget {
+            max_bytes: None,
+            min_free_bytes: Some(u64::MAX),
+        });
+        let job = Arc::new(Job::new("echo".to_string(), "echo".to_string(), vec![]));
+        agent.jobs.lock().unwrap().push(job.clone());
+        transport.respond("PATCH", &format!("/jobs/{}", job.get_id()), 200, json!({}));
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then
+        assert!(job.get_started_at().is_none());
+        let body = transport.requests()[0].body.clone().unwrap();
+        assert_eq!(body["status"], json!("pending"));
+        assert_eq!(body["defer_reason"], json!("low_disk_space"));
+        assert!(body.get("deferred_until").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_stages_inputs() {
+        // Given a job reading a target list provided by the server
+        let server = MockServer::start().await;
+        let mut item = make_job_item("cat", &["{input:targets}"]);
+        item["inputs"] = json!([{"name": "targets", "url": "/files/targets.txt"}]);
+        let job = parse_job_item(item);
+        server.mock("GET", "/files/targets.txt", 200, json!(["10.0.0.1"]));
+        let work_dir = std::env::temp_dir().join(format!("agent-work-{}", Uuid::new_v4()));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_run_options(RunOptions {
+            work_dir: Some(work_dir.clone()),
+            ..Default::default()
+        });
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then the action read the downloaded file, which is gone afterwards
+        assert_eq!(job.get_result_as_string().unwrap(), r#"["10.0.0.1"]"#);
+        let downloads = server.requests_to("GET", "/files/targets.txt");
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(
+            downloads[0].header("authorization"),
+            Some("Bearer fake_token")
+        );
+        assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+        assert_eq!(job.get_work_dir(), None);
+        std::fs::remove_dir(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_fails_when_inputs_cannot_be_staged() {
+        let server = MockServer::start().await;
+        let mut item = make_job_item("cat", &["{input:targets}"]);
+        item["inputs"] = json!([{"name": "targets", "url": "/files/missing.txt"}]);
+        let job = parse_job_item(item);
+        let work_dir = std::env::temp_dir().join(format!("agent-work-{}", Uuid::new_v4()));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_run_options(RunOptions {
+            work_dir: Some(work_dir.clone()),
+            ..Default::default()
+        });
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        assert!(agent.run_jobs().await.is_err());
+        assert!(!job.is_success());
+        assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+        std::fs::remove_dir(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_skips_jobs_completed_server_side() {
+        // Given the server still lists jobs completed in a previous run
+        let server = MockServer::start().await;
+        let make_job = |status: Option<&str>| {
+            let mut item = make_job_item("echo", &[]);
+            item["status"] = json!(status);
+            item
+        };
+        let jobs = vec![
+            make_job(Some("pending")),
+            make_job(Some("completed")),
+            make_job(Some("failed")),
+            make_job(None),
+            make_job(Some("skipped")),
+        ];
+        server.mock("GET", "/jobs", 200, json!({ "data": jobs }));
+        for job in &jobs {
+            let uri = format!("/jobs/{}", job["id"].as_str().unwrap());
+            server.mock("PATCH", &uri, 200, json!({"data": {}}));
+        }
+        let mut agent = make_agent_with_server(&server);
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then only the pending and skipped ones are claimed and queued
+        let guard = agent.jobs.lock().unwrap();
+        let queued: Vec<String> = guard.iter().map(|job| job.get_id().to_string()).collect();
+        assert_eq!(
+            queued,
+            vec![
+                jobs[0]["id"].as_str().unwrap().to_string(),
+                jobs[3]["id"].as_str().unwrap().to_string(),
+                jobs[4]["id"].as_str().unwrap().to_string()
+            ]
+        );
+        assert_eq!(guard[0].get_status(), &JobStatus::Pending);
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_does_not_claim_jobs_twice() {
+        // Given the server lists a job this agent claimed in an earlier cycle
+        let server = MockServer::start().await;
+        let mut job = make_job_item("echo", &[]);
+        job["status"] = json!("running");
+        server.mock("GET", "/jobs", 200, json!({ "data": [job] }));
+        let mut agent = make_agent_with_server(&server);
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then it is neither claimed again nor queued
+        assert!(agent.jobs.lock().unwrap().is_empty());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_paused_agent_does_not_fetch_nor_start_jobs() {
+        // Given a paused agent with a job fetched before it was paused
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("GET", "/jobs", 200, json!([]));
+        transport.respond("PATCH", "/self", 200, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let job = Arc::new(Job::new("echo".to_string(), "echo".to_string(), vec![]));
+        agent.jobs.lock().unwrap().push(job.clone());
+        agent.pause_handle().store(true, Ordering::Relaxed);
+
+        // When
+        agent.announce_presence().await.unwrap();
+        agent.get_jobs().await.unwrap();
+        agent.run_jobs().await.unwrap();
+
+        // Then it heartbeats as paused, without fetching nor running anything
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].uri, "/self");
+        assert_eq!(requests[0].body.as_ref().unwrap()["paused"], json!(true));
+        assert!(job.get_started_at().is_none());
+
+        // And once resumed it fetches and runs jobs again
+        agent.pause_handle().store(false, Ordering::Relaxed);
+        agent.announce_presence().await.unwrap();
+        agent.get_jobs().await.unwrap();
+        agent.run_jobs().await.unwrap();
+        let requests = transport.requests();
+        assert_eq!(requests[1].body.as_ref().unwrap()["paused"], json!(false));
+        assert_eq!(
+            (requests[2].method.as_str(), requests[2].uri.as_str()),
+            ("GET", "/jobs")
+        );
+        assert!(job.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_dedupes_overlapping_pages() {
+        // Given two pages sharing a job, as if the list shifted between the requests
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let jobs: Vec<_> = (0..3).map(|_| make_job_item("echo", &[])).collect();
+        transport.respond_page("/jobs", json!([jobs[0], jobs[1]]), Some("/jobs?page=2"));
+        transport.respond_page("/jobs?page=2", json!([jobs[1], jobs[2]]), None);
+        for job in &jobs {
+            let uri = format!("/jobs/{}", job["id"].as_str().unwrap());
+            transport.respond("PATCH", &uri, 200, json!({}));
+        }
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then each job is queued and claimed once
+        let queued: Vec<String> = agent
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| job.get_id().to_string())
+            .collect();
+        let expected: Vec<String> = jobs
+            .iter()
+            .map(|job| job["id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(queued, expected);
+        let claims = transport
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PATCH")
+            .count();
+        assert_eq!(claims, 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_stops_following_endless_pages() {
+        // Given a server whose next page is always the same one
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let job = make_job_item("echo", &[]);
+        transport.respond_page("/jobs", json!([job]), Some("/jobs?page=2"));
+        transport.respond_page("/jobs?page=2", json!([job]), Some("/jobs?page=2"));
+        let uri = format!("/jobs/{}", job["id"].as_str().unwrap());
+        transport.respond("PATCH", &uri, 200, json!({}));
+
+        // When
+        let result = agent.get_jobs().await;
+
+        // Then
+        assert!(result.is_ok());
+        let fetches = transport
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "GET")
+            .count();
+        assert_eq!(fetches, MAX_JOB_PAGES);
+    }
+
+    #[test]
+    fn test_deserialize_jobs_bare_or_wrapped() {
+        // Given the serialized form of an agent with jobs
+        let agent = make_agent();
+        let jobs = make_jobs();
+        let expected: Vec<Uuid> = jobs.iter().map(|job| *job.get_id()).collect();
+        agent.jobs.lock().unwrap().extend(jobs);
+        let serialized = serde_json::to_value(&agent).unwrap();
+        let jobs = serialized["jobs"].clone();
+        assert!(jobs.is_array());
+
+        for wrapped in [jobs.clone(), json!({"data": jobs}), json!({"jobs": jobs})] {
+            // When
+            let mut value = serialized.clone();
+            value["jobs"] = wrapped.clone();
+            let deserialized: Agent = serde_json::from_value(value).unwrap();
+
+            // Then
+            let ids: Vec<Uuid> = deserialized
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|job| *job.get_id())
+                .collect();
+            assert_eq!(ids, expected, "{}", wrapped);
+        }
+
+        let mut invalid = serialized.clone();
+        invalid["jobs"] = json!({"items": []});
+        assert!(serde_json::from_value::<Agent>(invalid).is_err());
+
+        // an invalid job is reported as such
+        let mut invalid = serialized.clone();
+        invalid["jobs"] = json!({"data": [{"name": "no id"}]});
+        let err = serde_json::from_value::<Agent>(invalid).unwrap_err();
+        assert!(err.to_string().contains("missing field `id`"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_get_info_without_data() {
+        // Given servers answering /self successfully but without any agent info
+        let empty = MockServer::start().await;
+        empty.mock_raw("GET", "/self", 200, "");
+        let null = MockServer::start().await;
+        null.mock("GET", "/self", 200, json!({"data": null}));
+
+        for server in [empty, null] {
+            // When
+            let result = Agent::new(server.url(), "token".to_string()).await;
+
+            // Then
+            assert!(matches!(result, Err(ClientError::MissingData)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_info_reports_invalid_jobs() {
+        // Given a server listing a job without an action in the agent info
+        let server = MockServer::start().await;
+        let mut info = make_self_response(Some(Uuid::new_v4()));
+        info["data"]["attributes"]["jobs"] = json!({"data": [{"id": Uuid::new_v4()}]});
+        server.mock("GET", "/self", 200, info);
+
+        // When
+        let result = Agent::new(server.url(), "token".to_string()).await;
+
+        // Then the error points at the job rather than at the shape of the list
+        match result {
+            Err(ClientError::ParseError(err)) => {
+                assert!(err.to_string().contains("missing field"), "{}", err)
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_drops_jobs_of_other_agents() {
+        // Given a batch mixing jobs assigned to this agent and to another one
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let mine = make_job_item("echo", &[]);
+        let mut theirs = make_job_item("echo", &[]);
+        theirs["agent_id"] = json!(Uuid::new_v4());
+        transport.respond("GET", "/jobs", 200, json!([theirs, mine]));
+        for job in [&mine, &theirs] {
+            let uri = format!("/jobs/{}", job["id"].as_str().unwrap());
+            transport.respond("PATCH", &uri, 200, json!({}));
+        }
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then only the job of this agent is claimed and queued
+        let queued: Vec<String> = agent
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| job.get_id().to_string())
+            .collect();
+        assert_eq!(queued, vec![mine["id"].clone()]);
+        let claims: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PATCH")
+            .map(|request| request.uri)
+            .collect();
+        assert_eq!(
+            claims,
+            vec![format!("/jobs/{}", mine["id"].as_str().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_caps_jobs_per_fetch() {
+        // Given a server ignoring the limit and sending three jobs, the newest first
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        agent.set_max_jobs_per_fetch(Some(2));
+        let mut jobs: Vec<_> = (0..3).map(|_| make_job_item("echo", &[])).collect();
+        jobs.reverse();
+        transport.respond("GET", "/jobs?limit=2", 200, json!(jobs));
+        for job in &jobs {
+            let uri = format!("/jobs/{}", job["id"].as_str().unwrap());
+            transport.respond("PATCH", &uri, 200, json!({}));
+        }
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then the limit was asked for, and only the two oldest jobs are queued
+        assert_eq!(transport.requests()[0].uri, "/jobs?limit=2");
+        let queued: Vec<String> = agent
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| job.get_id().to_string())
+            .collect();
+        assert_eq!(queued, vec![jobs[2]["id"].clone(), jobs[1]["id"].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_sends_since_cursor() {
+        // Given a first batch of two jobs, the newest one being already completed
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let created_at = Utc::now() - TimeDelta::minutes(10);
+        let make_job = |created_at: DateTime<Utc>, status: &str| {
+            let mut item = make_job_item("echo", &[]);
+            item["created_at"] = json!(created_at);
+            item["status"] = json!(status);
+            item
+        };
+        let newest = created_at + TimeDelta::minutes(5);
+        let first_batch = json!([
+            make_job(created_at, "pending"),
+            make_job(newest, "completed")
+        ]);
+        transport.respond("GET", "/jobs", 200, first_batch.clone());
+        let uri = format!("/jobs/{}", first_batch[0]["id"].as_str().unwrap());
+        transport.respond("PATCH", &uri, 200, json!({}));
+        let endpoints = Endpoints::default();
+        let since = endpoints.jobs_list(Some(&(newest - TimeDelta::microseconds(1))), None);
+        transport.respond("GET", &since, 200, json!([first_batch[1]]));
+
+        // When
+        agent.get_jobs().await.unwrap();
+        agent.get_jobs().await.unwrap();
+        agent.get_jobs().await.unwrap();
+
+        // Then the first poll has no cursor and the next ones start at the newest job
+        let fetches: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.uri)
+            .collect();
+        assert_eq!(fetches, vec!["/jobs".to_string(), since.clone(), since]);
+        assert_eq!(agent.jobs.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_keeps_cursor_when_a_claim_fails() {
+        // Given two jobs created at the same time, the claim of the second one failing once
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let created_at = Utc::now();
+        let jobs: Vec<serde_json::Value> = (0..2)
+            .map(|_| {
+                let mut item = make_job_item("echo", &[]);
+                item["created_at"] = json!(created_at);
+                item
+            })
+            .collect();
+        transport.respond("GET", "/jobs", 200, json!(jobs));
+        let uris: Vec<String> = jobs
+            .iter()
+            .map(|job| format!("/jobs/{}", job["id"].as_str().unwrap()))
+            .collect();
+        transport.respond("PATCH", &uris[0], 200, json!({}));
+        transport.respond("PATCH", &uris[1], 500, json!({}));
+
+        // When the first fetch fails to claim the second job, and the next one succeeds
+        assert!(agent.get_jobs().await.is_err());
+        transport.respond("PATCH", &uris[1], 200, json!({}));
+        agent.get_jobs().await.unwrap();
+
+        // Then both jobs are fetched again from the same cursor, each one claimed once
+        let requests = transport.requests();
+        let fetches: Vec<&str> = requests
+            .iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.uri.as_str())
+            .collect();
+        assert_eq!(fetches, ["/jobs", "/jobs"]);
+        let claims = |uri: &String| requests.iter().filter(|r| &r.uri == uri).count();
+        assert_eq!((claims(&uris[0]), claims(&uris[1])), (1, 2));
+        assert_eq!(agent.jobs.lock().unwrap().len(), 2);
+        assert_eq!(
+            agent.jobs_cursor,
+            Some(created_at - TimeDelta::microseconds(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_claims_fetched_jobs() {
+        // Given two fetched jobs, the second one being already claimed by another agent
+        let server = MockServer::start().await;
+        let jobs = make_jobs();
+        let items: Vec<_> = jobs
+            .iter()
+            .map(|job| {
+                let mut item = serde_json::to_value(job.as_ref()).unwrap();
+                item["agent_id"] = json!(TEST_AGENT_ID);
+                item
+            })
+            .collect();
+        server.mock("GET", "/jobs", 200, json!({ "data": items }));
+        let claimed_path = format!("/jobs/{}", jobs[0].get_id());
+        let conflict_path = format!("/jobs/{}", jobs[1].get_id());
+        server.mock("PATCH", &claimed_path, 200, json!({"data": {}}));
+        server.mock(
+            "PATCH",
+            &conflict_path,
+            409,
+            json!({"errors": [{"detail": "already claimed"}]}),
+        );
+        let mut agent = make_agent_with_server(&server);
+
+        // When
+        agent.get_jobs().await.unwrap();
+
+        // Then
+        let claims = server.requests_to("PATCH", &claimed_path);
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[0].json()["status"], "running");
+        assert_eq!(claims[0].json()["agent_id"], json!(agent.id));
+        assert_eq!(server.requests_to("PATCH", &conflict_path).len(), 1);
+
+        let guard = agent.jobs.lock().unwrap();
+        assert_eq!(guard.len(), 1);
+        assert_eq!(guard[0].get_id(), jobs[0].get_id());
+    }
+
+    // appends `--appended` to every action, and rejects the jobs named "forbidden"
+    #[derive(Debug)]
+    struct AppendArgTransformer;
+
+    impl JobTransformer for AppendArgTransformer {
+        fn transform(&self, job: &mut Job) -> Result<(), String> {
+            if job.get_name() == "forbidden" {
+                return Err("forbidden job".to_string());
+            }
+            let mut action = job.get_action().clone();
+            let mut args = action.get_args().clone();
+            args.push("--appended".to_string());
+            action.set_args(args);
+            job.set_action(action);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_jobs_transforms_jobs() {
+        // Given an echo job and a job the transformer rejects
+        let server = MockServer::start().await;
+        let make_job = |name: &str| {
+            let mut item = make_job_item("echo", &["hello"]);
+            item["name"] = json!(name);
+            let uri = format!("/jobs/{}", item["id"].as_str().unwrap());
+            server.mock("PATCH", &uri, 200, json!({"data": {}}));
+            item
+        };
+        let items = json!([make_job("echo"), make_job("forbidden")]);
+        server.mock("GET", "/jobs", 200, json!({ "data": items }));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_job_transformer(Arc::new(AppendArgTransformer));
+
+        // When
+        agent.get_jobs().await.unwrap();
+        agent.run_jobs().await.unwrap();
+
+        // Then the echo job ran with the appended arg, the other one was skipped
+        let jobs = agent.jobs.lock().unwrap().clone();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(
+            jobs[0].get_result_as_string().unwrap(),
+            "hello --appended\n"
+        );
+        assert_eq!(jobs[1].get_skip_reason(), Some(SkipReason::Rejected));
+        assert_eq!(jobs[1].get_result_as_string().unwrap(), "forbidden job");
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_to_results_url() {
+        // Given a job fetched from the API, and a separate results collector
+        let server = MockServer::start().await;
+        let results = MockServer::start().await;
+        let job = make_job_item("echo", &["hello"]);
+        let id = job["id"].as_str().unwrap().to_string();
+        server.mock("GET", "/jobs", 200, json!({ "data": [job] }));
+        server.mock("PATCH", &format!("/jobs/{}", id), 200, json!({"data": {}}));
+        results.mock("PATCH", &format!("/jobs/{}", id), 200, json!({"data": {}}));
+        let mut agent = make_agent_with_server(&server);
+        agent
+            .set_results_url(results.url(), Some("results-token".to_string()))
+            .unwrap();
+
+        // When
+        agent.get_jobs().await.unwrap();
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then the job was fetched and claimed on the API, and reported to the collector
+        let uri = format!("/jobs/{}", id);
+        assert_eq!(server.requests_to("GET", "/jobs").len(), 1);
+        let claims = server.requests_to("PATCH", &uri);
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[0].json()["status"], json!("running"));
+        let reports = results.requests_to("PATCH", &uri);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].json()["success"], json!(true));
+        assert!(results.requests_to("GET", "/jobs").is_empty());
+        // with its own token, the API's one is not sent to the collector
+        assert_eq!(
+            reports[0].header("authorization"),
+            Some("Bearer results-token")
+        );
+        assert_ne!(
+            claims[0].header("authorization"),
+            reports[0].header("authorization")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_with_prefix() {
+        // Given an API mounted under a prefix
+        let server = MockServer::start().await;
+        let id = Uuid::new_v4();
+        server.mock(
+            "GET",
+            "/api/v2/agent/self",
+            200,
+            make_self_response(Some(id)),
+        );
+        server.mock("PATCH", "/api/v2/agent/self", 200, json!({"data": {}}));
+        server.mock("GET", "/api/v2/agent/jobs", 200, json!({"data": []}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let mut endpoints = Endpoints::default();
+        endpoints.set_prefix("/api/v2/agent");
+        client.set_endpoints(endpoints);
+
+        // When
+        let mut agent = Agent::with_client(client).await.unwrap();
+        agent.register().await.unwrap();
+        agent.get_jobs().await.unwrap();
+
+        // Then
+        let paths: Vec<String> = server.requests().into_iter().map(|req| req.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/api/v2/agent/self",
+                "/api/v2/agent/self",
+                "/api/v2/agent/jobs"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_sends_agent_version() {
+        let server = MockServer::start().await;
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+
+        agent.register().await.unwrap();
+
+        let body = server.requests_to("PATCH", "/self")[0].json();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["build"], AGENT_BUILD);
+        assert!(!AGENT_BUILD.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_sends_host_resources() {
+        // Given
+        let transport = Arc::new(FakeTransport::new());
+        transport.respond("PATCH", "/self", 200, json!({}));
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+
+        // When
+        agent.register().await.unwrap();
+
+        // Then
+        let body = transport.requests()[0].body.clone().unwrap();
+        assert!(body["cpu_cores"].as_u64().unwrap() >= 1);
+        #[cfg(target_os = "linux")]
+        {
+            let total = body["memory_total"].as_u64().unwrap();
+            let available = body["memory_available"].as_u64().unwrap();
+            assert!(total > 0);
+            assert!(available <= total);
         }
+        assert!(body.get("disk_free").is_some());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use chrono::Utc;
-    use std::sync::{Arc, Mutex};
-    use uuid::Uuid;
+    #[tokio::test]
+    async fn test_register_refetches_missing_id() {
+        // Given an agent whose first /self did not contain any id
+        let server = MockServer::start().await;
+        let id = Uuid::new_v4();
+        server.mock("GET", "/self", 200, make_self_response(Some(id)));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = Agent {
+            id: None,
+            ..make_agent_with_server(&server)
+        };
 
-    fn make_agent() -> Agent {
-        Agent {
-            id: Some(Uuid::new_v4()),
-            token: "token".to_string(),
-            jobs: Arc::new(Mutex::new(vec![])),
-            name: "myname".to_string(),
-            hostname: None,
-            description: Some("Test agent".to_string()),
-            platform: None,
-            last_seen_at: None,
-            created_at: Some(Utc::now()),
-            available_tools: Some(vec![]),
-            client: ApiClient::new("http://fake.url.com".to_string(), "fake_token".to_string())
-                .unwrap(),
+        // When
+        agent.register().await.unwrap();
+
+        // Then
+        assert_eq!(agent.id, Some(id));
+        assert_eq!(server.requests_to("GET", "/self").len(), 1);
+        assert_eq!(server.requests_to("PATCH", "/self").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_register_fails_when_server_never_assigns_id() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 200, make_self_response(None));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = Agent {
+            id: None,
+            ..make_agent_with_server(&server)
+        };
+
+        let result = agent.register().await;
+
+        assert!(matches!(result, Err(ClientError::MissingAgentId)));
+        assert!(server.requests_to("PATCH", "/self").is_empty());
+    }
+
+    struct UppercaseParser {
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl OutputParser for UppercaseParser {
+        fn parse(&self, raw: &str) -> Result<serde_json::Value, crate::parser::ParseError> {
+            self.calls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(json!({ "output": raw.trim().to_uppercase() }))
         }
     }
 
-    fn make_jobs() -> Vec<Arc<Job>> {
-        vec![
-            Arc::new(Job::new(
-                "echo_hello".to_string(),
-                "echo".to_string(),
-                ["Hello, world!".to_string()].to_vec(),
-            )),
-            Arc::new(Job::new(
-                "sleep_1".to_string(),
-                "sleep".to_string(),
-                ["1".to_string()].to_vec(),
-            )),
-        ]
+    fn make_job_with_variant(variant: &str) -> Arc<Job> {
+        let mut item = make_job_item("echo", &["hello"]);
+        item["action"]["variant"] = json!(variant);
+        parse_job_item(item)
     }
 
-    fn make_jobs_that_crash() -> Vec<Arc<Job>> {
-        vec![
-            Arc::new(Job::new(
-                "echo_hello".to_string(),
-                "echo1234".to_string(),
-                ["Hello, world!".to_string()].to_vec(),
-            )),
-            Arc::new(Job::new(
-                "sleep_1".to_string(),
-                "sleep1234".to_string(),
-                ["1".to_string()].to_vec(),
-            )),
-        ]
+    #[tokio::test]
+    async fn test_run_jobs_uses_parser_of_matching_variant() {
+        // Given
+        let mut agent = make_agent();
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        agent.register_parser(
+            "upper",
+            UppercaseParser {
+                calls: calls.clone(),
+            },
+        );
+        let parsed_job = make_job_with_variant("upper");
+        let raw_job = make_job_with_variant("other");
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![parsed_job.clone(), raw_job.clone()];
+        }
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(
+            parsed_job.get_structured_result(),
+            Some(json!({ "output": "HELLO" }))
+        );
+        assert!(raw_job.get_structured_result().is_none());
+        assert!(raw_job.get_result_as_string().unwrap().contains("hello"));
+    }
+
+    fn make_dependent_job(
+        cmd: &str,
+        depends_on: Option<Uuid>,
+        condition: serde_json::Value,
+    ) -> Arc<Job> {
+        let mut item = make_job_item(cmd, &["80/tcp open"]);
+        item["depends_on"] = json!(depends_on);
+        item["condition"] = condition;
+        parse_job_item(item)
     }
 
     #[tokio::test]
-    async fn test_get_hostname() {
-        let hostname = Agent::get_hostname();
-        assert!(!hostname.is_empty());
+    async fn test_run_jobs_skips_dependency_chain_on_failure() {
+        // Given a chain port_scan -> deep_scan -> report where port_scan fails
+        let agent = make_agent();
+        let port_scan = make_dependent_job("false", None, json!(null));
+        let deep_scan = make_dependent_job("echo", Some(*port_scan.get_id()), json!("success"));
+        let report = make_dependent_job("echo", Some(*deep_scan.get_id()), json!(null));
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![report.clone(), deep_scan.clone(), port_scan.clone()];
+        }
+
+        // When
+        let result = agent.run_jobs().await;
+
+        // Then
+        assert!(result.is_ok());
+        assert!(!port_scan.is_success());
+        assert!(!port_scan.is_skipped());
+        for job in [&deep_scan, &report] {
+            assert_eq!(job.get_skip_reason(), Some(SkipReason::DependencyNotMet));
+            assert!(!job.is_success());
+            assert!(job.get_started_at().is_none());
+            assert!(job.get_completed_at().is_some());
+        }
     }
 
     #[tokio::test]
-    async fn test_get_platform() {
-        let platform = Agent::get_platform();
+    async fn test_run_jobs_runs_dependent_job_when_condition_matches() {
+        let agent = make_agent();
+        let port_scan = make_dependent_job("echo", None, json!(null));
+        let deep_scan = make_dependent_job(
+            "echo",
+            Some(*port_scan.get_id()),
+            json!({"output_contains": "open"}),
+        );
+        let skipped_scan = make_dependent_job(
+            "echo",
+            Some(*port_scan.get_id()),
+            json!({"output_contains": "filtered"}),
+        );
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![deep_scan.clone(), skipped_scan.clone(), port_scan.clone()];
+        }
 
-        assert!(platform.is_some());
-        assert!(matches!(
-            platform,
-            Some(AgentPlatform::Linux) | Some(AgentPlatform::MacOS) | Some(AgentPlatform::Windows)
-        ));
+        agent.run_jobs().await.unwrap();
+
+        assert!(deep_scan.is_success());
+        assert!(deep_scan.get_started_at().unwrap() >= port_scan.get_completed_at().unwrap());
+        assert!(skipped_scan.is_skipped());
     }
 
     #[tokio::test]
-    async fn test_submit_jobs() {
+    async fn test_run_jobs_defers_job_with_unknown_dependency() {
+        // Given a job depending on a job the server has but this agent did not fetch
+        let transport = Arc::new(FakeTransport::new());
+        let dependency_id = Uuid::new_v4();
+        transport.respond(
+            "GET",
+            &format!("/jobs/{}", dependency_id),
+            200,
+            json!({"id": dependency_id, "status": "running"}),
+        );
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let job = make_dependent_job("echo", Some(dependency_id), json!(null));
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![job.clone()];
+        }
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then it keeps waiting
+        assert!(job.get_started_at().is_none());
+        assert!(!job.is_skipped());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs_skips_job_with_missing_dependency() {
+        // Given a job depending on a job the server does not have
+        let transport = Arc::new(FakeTransport::new());
+        let mut agent = make_agent();
+        agent.set_transport(transport.clone());
+        let dependency_id = Uuid::new_v4();
+        let job = make_dependent_job("echo", Some(dependency_id), json!(null));
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![job.clone()];
+        }
+
+        // When
+        agent.run_jobs().await.unwrap();
+
+        // Then it is skipped rather than waiting forever
+        assert!(job.get_started_at().is_none());
+        assert_eq!(job.get_skip_reason(), Some(SkipReason::DependencyNotFound));
+        assert_eq!(
+            transport.requests()[0].uri,
+            format!("/jobs/{}", dependency_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_waits_for_deferred_jobs() {
+        // Given a job whose dependency is not fetched yet, thus not run yet
+        let server = MockServer::start().await;
+        let dependency_id = Uuid::new_v4();
+        let agent_job = make_dependent_job("echo", Some(dependency_id), json!(null));
+        let uri = format!("/jobs/{}", agent_job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        server.mock(
+            "GET",
+            &format!("/jobs/{}", dependency_id),
+            200,
+            json!({"data": {"id": dependency_id}}),
+        );
+        let mut agent = make_agent_with_server(&server);
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![agent_job.clone()];
+        }
+
+        // When
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        // Then
+        assert!(server.requests_to("PATCH", &uri).is_empty());
+        assert!(!agent_job.was_submitted());
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_includes_reported_at_and_stores_receipt() {
         // Given
-        let agent = make_agent();
-        let jobs = make_jobs();
+        let server = MockServer::start().await;
+        let job = Arc::new(Job::new(
+            "echo_hello".to_string(),
+            "echo".to_string(),
+            vec!["hello".to_string()],
+        ));
+        let uri = format!("/jobs/{}", job.get_id());
+        let received_at = Utc::now();
+        server.mock(
+            "PATCH",
+            &uri,
+            200,
+            json!({"data": {"attributes": {"received_at": received_at}}}),
+        );
+        let mut agent = make_agent_with_server(&server);
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![job.clone()];
+        }
+        agent.run_jobs().await.unwrap();
 
-        // Prevent deadlock by the agent.run_jobs() function
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        let reported_at: DateTime<Utc> =
+            serde_json::from_value(body["reported_at"].clone()).unwrap();
+        assert_eq!(Some(reported_at), job.get_reported_at());
+        assert_eq!(job.get_received_at(), Some(received_at));
+    }
+
+    #[tokio::test]
+    async fn test_get_tools_with_flattened_attributes() {
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/tools",
+            200,
+            json!({"data": [{"cmd": "echo", "version": "1.0", "version_arg": null}]}),
+        );
+        let agent = make_agent_with_server(&server);
+
+        let tools = agent.get_tools().await.unwrap();
+
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].cmd(), "echo");
+        assert_eq!(tools[0].version(), &Some("1.0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_get_tools_with_resource_objects() {
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/tools",
+            200,
+            json!({"data": [{
+                "id": Uuid::new_v4(),
+                "type": "tools",
+                "attributes": {"cmd": "echo", "version": "1.0", "version_arg": null},
+            }]}),
+        );
+        let agent = make_agent_with_server(&server);
+
+        let tools = agent.get_tools().await.unwrap();
+
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].cmd(), "echo");
+        assert_eq!(tools[0].version(), &Some("1.0".to_string()));
+    }
+
+    #[test]
+    fn test_tool_attributes_of_both_shapes() {
+        let flat = json!({"cmd": "echo"});
+        let nested = json!({"id": "1", "attributes": {"cmd": "echo"}});
+
+        assert_eq!(Agent::tool_attributes(&flat).0, &flat);
+        assert_eq!(Agent::tool_attributes(&nested).0, &json!({"cmd": "echo"}));
+    }
+
+    #[tokio::test]
+    async fn test_get_tools_with_object_data_in_strict_mode() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/tools", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+
+        assert!(agent.get_tools().await.unwrap().is_empty());
+
+        agent.set_strict(true);
+        let result = agent.get_tools().await;
+
+        assert!(matches!(result, Err(ClientError::UnexpectedData(_))));
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_compresses_large_results() {
+        // Given a job with a large and repetitive output, and output file
+        let server = MockServer::start().await;
+        let output = "<host addr=\"10.0.0.1\"><port>443</port></host>\n".repeat(500);
+        let job = Arc::new(Job::new("scan".to_string(), "echo".to_string(), vec![]));
+        job.set_result(output.clone());
+        job.set_output_file_contents(output.clone());
+        job.set_completed_at();
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_compression_threshold(Some(1024));
+        agent.jobs.lock().unwrap().push(job);
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        assert_eq!(body["results_encoding"], RESULTS_ENCODING_GZIP_BASE64);
+        assert_eq!(body["content_type"], "application/gzip");
+        let results = body["results"].as_str().unwrap();
+        assert!(results.len() < output.len());
+        assert_eq!(crate::compress::decompress_result(results).unwrap(), output);
+        assert_eq!(body["output_file_encoding"], RESULTS_ENCODING_GZIP_BASE64);
+        let output_file = body["output_file"].as_str().unwrap();
+        assert_eq!(
+            crate::compress::decompress_result(output_file).unwrap(),
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_as_msgpack() {
+        // Given an agent submitting MessagePack reports
+        let server = MockServer::start().await;
+        let job = Arc::new(Job::new("scan".to_string(), "echo".to_string(), vec![]));
+        job.set_result("80/tcp open\n".to_string());
+        job.set_completed_at();
+        job.set_success(true);
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_wire_format(WireFormat::Msgpack);
+        agent.jobs.lock().unwrap().push(job.clone());
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then the report decodes to the same fields as its JSON counterpart
+        let request = &server.requests_to("PATCH", &uri)[0];
+        assert_eq!(request.header("content-type"), Some(MSGPACK_CONTENT_TYPE));
+        let body: serde_json::Value = rmp_serde::from_slice(&request.raw_body).unwrap();
+        assert_eq!(body["results"], "80/tcp open\n");
+        assert_eq!(body["success"], true);
+        assert_eq!(
+            body["completed_at"],
+            serde_json::to_value(job.get_completed_at()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_falls_back_to_json() {
+        // Given a server rejecting MessagePack bodies
+        let server = MockServer::start().await;
+        let job = Arc::new(Job::new("scan".to_string(), "echo".to_string(), vec![]));
+        job.set_completed_at();
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock(
+            "PATCH",
+            &uri,
+            415,
+            json!({"errors": [{"detail": "unsupported"}]}),
+        );
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_wire_format(WireFormat::Msgpack);
+        agent.jobs.lock().unwrap().push(job);
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then the report is sent again as JSON, as are the next ones
+        let requests = server.requests_to("PATCH", &uri);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].header("content-type"), Some("application/json"));
+        assert!(requests[1].json()["completed_at"].is_string());
+        assert_eq!(agent.wire_format, WireFormat::Json);
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_streams_huge_results() {
+        // Given a job with a large output full of characters to escape
+        let server = MockServer::start().await;
+        let output = "line with \"quotes\", \\ and unicode é ✓\n".repeat(20_000);
+        let job = Arc::new(Job::new("scan".to_string(), "echo".to_string(), vec![]));
+        job.set_result(output.clone());
+        job.set_completed_at();
+        job.set_success(true);
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_stream_results_threshold(Some(1024));
+        agent.jobs.lock().unwrap().push(job);
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then
+        let request = &server.requests_to("PATCH", &uri)[0];
+        assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+        let body = request.json();
+        assert_eq!(body["results"], output);
+        assert_eq!(body["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_streams_results_from_the_job_log() {
+        // Given jobs with large outputs, the log of the first one holding it (in upper case to
+        // tell it apart) and the log of the second one being gone
+        let server = MockServer::start().await;
+        let output = "scan line\n".repeat(200);
+        let log = std::env::temp_dir().join(format!("agent-log-{}", Uuid::new_v4()));
+        std::fs::write(&log, format!("output:\n{}\n", output.to_uppercase())).unwrap();
+        let mut agent = make_agent_with_server(&server);
+        agent.set_stream_results_threshold(Some(1024));
+        let mut uris = vec![];
+        for log in [log.clone(), log.with_extension("gone")] {
+            let job = Arc::new(Job::new("scan".to_string(), "echo".to_string(), vec![]));
+            job.set_result(output.clone());
+            job.set_completed_at();
+            job.set_log(log, "output:\n".len() as u64);
+            let uri = format!("/jobs/{}", job.get_id());
+            server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+            agent.jobs.lock().unwrap().push(job);
+            uris.push(uri);
+        }
+
+        // When
+        agent.submit_report().await.unwrap();
+
+        // Then the output is read from the log, or from memory without it
+        let results = |uri: &str| server.requests_to("PATCH", uri)[0].json()["results"].clone();
+        assert_eq!(results(&uris[0]), output.to_uppercase());
+        assert_eq!(results(&uris[1]), output);
+        std::fs::remove_file(log).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_sends_redacted_result() {
+        // Given a job whose output contains a secret
+        let server = MockServer::start().await;
+        let mut item = make_job_item("echo", &["found admin:s3cr3t"]);
+        item["redactions"] = json!(["admin:\\S+"]);
+        let job = parse_job_item(item);
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
         {
             let mut guard = agent.jobs.lock().unwrap();
-            *guard = jobs;
+            *guard = vec![job.clone()];
         }
 
         // When
-        let _result = agent.run_jobs().await;
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
 
         // Then
-        let any_incompleted_job = agent
-            .jobs
-            .lock()
-            .unwrap()
-            .iter()
-            .any(|job| !job.is_completed());
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        assert_eq!(body["results"].as_str().unwrap().trim(), "found ***");
+    }
 
-        assert!(!any_incompleted_job);
+    #[tokio::test]
+    async fn test_submit_report_includes_stream_byte_counts() {
+        let server = MockServer::start().await;
+        let job = Arc::new(Job::new(
+            "counts".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "printf 'abcde'; printf 'xyz' >&2".to_string(),
+            ],
+        ));
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![job.clone()];
+        }
+
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        assert_eq!(body["stdout_bytes"], json!(5));
+        assert_eq!(body["stderr_bytes"], json!(3));
     }
 
     #[tokio::test]
-    async fn test_submit_jobs_that_crash() {
-        // Given
-        let agent = make_agent();
-        let jobs = make_jobs_that_crash();
+    async fn test_submit_capabilities_disabled_does_not_probe_tools() {
+        // Given a tool whose version probe leaves a file behind
+        let server = MockServer::start().await;
+        let marker = std::env::temp_dir().join(format!("agent-probe-{}", Uuid::new_v4()));
+        let tool = json!({"cmd": "touch", "version": null, "version_arg": marker});
+        server.mock("GET", "/tools", 200, json!({"data": [tool]}));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        agent.set_capabilities_disabled(true);
 
-        // Prevent deadlock by the agent.run_jobs() function
+        // When
+        agent.submit_capabilities().await.unwrap();
+
+        // Then
+        assert!(!marker.exists());
+        assert!(server.requests().is_empty());
+
+        // and the probe does run once capabilities are enabled again
+        agent.set_capabilities_disabled(false);
+        agent.submit_capabilities().await.unwrap();
+        assert!(marker.exists());
+        let _ = std::fs::remove_file(marker);
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_declares_content_type() {
+        // Given one job handled by a parser and one printing JSON
+        let server = MockServer::start().await;
+        let mut agent = make_agent_with_server(&server);
+        agent.register_parser(
+            "upper",
+            UppercaseParser {
+                calls: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            },
+        );
+        let parsed_job = make_job_with_variant("upper");
+        let json_job = parse_job_item(make_job_item("echo", &["{\"hosts\": []}"]));
+        for job in [&parsed_job, &json_job] {
+            let uri = format!("/jobs/{}", job.get_id());
+            server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        }
         {
             let mut guard = agent.jobs.lock().unwrap();
-            *guard = jobs;
+            *guard = vec![parsed_job.clone(), json_job.clone()];
         }
 
         // When
-        let result = agent.run_jobs().await;
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
 
         // Then
-        assert!(result.is_err());
-        if let Err(RunJobsError::AtLeastOneFailed(job_errors)) = result {
-            assert_eq!(job_errors.len(), 2);
-            assert!(
-                job_errors
-                    .iter()
-                    .any(|e| matches!(e, RunJobsError::JobFailed(_)))
-            );
-        } else {
-            panic!("Expected AtLeastOneFailed error variant");
+        let content_type = |job: &Arc<Job>| {
+            let uri = format!("/jobs/{}", job.get_id());
+            server.requests_to("PATCH", &uri)[0].json()["content_type"].clone()
+        };
+        assert_eq!(content_type(&parsed_job), json!("text/plain"));
+        assert_eq!(content_type(&json_job), json!("application/json"));
+    }
+
+    #[tokio::test]
+    async fn test_submit_report_flags_empty_output() {
+        let server = MockServer::start().await;
+        let job = Arc::new(Job::new("silent".to_string(), "true".to_string(), vec![]));
+        let uri = format!("/jobs/{}", job.get_id());
+        server.mock("PATCH", &uri, 200, json!({"data": {"attributes": {}}}));
+        let mut agent = make_agent_with_server(&server);
+        {
+            let mut guard = agent.jobs.lock().unwrap();
+            *guard = vec![job.clone()];
         }
+
+        agent.run_jobs().await.unwrap();
+        agent.submit_report().await.unwrap();
+
+        let body = server.requests_to("PATCH", &uri)[0].json();
+        assert_eq!(body["success"], json!(true));
+        assert_eq!(body["results"], json!(""));
+        assert_eq!(body["empty_output"], json!(true));
     }
 }
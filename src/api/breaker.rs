@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+pub const MAX_COOLDOWN: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    // cool-down elapsed, the next request tells whether the API recovered
+    HalfOpen,
+}
+
+// Circuit breaker protecting the API from being hammered while it keeps failing. After
+// `threshold` consecutive failures it opens for a cool-down window, which doubles each time the
+// recovery test fails (up to MAX_COOLDOWN)
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    threshold: u32,
+    cooldown: Duration,
+    // number of times the breaker re-opened right after a failed recovery test
+    reopened: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            threshold: threshold.max(1),
+            cooldown,
+            reopened: 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    // returns the remaining cool-down if requests must be short-circuited
+    pub fn check(&mut self) -> Result<(), Duration> {
+        if let BreakerState::Open { until } = self.state {
+            let now = Instant::now();
+            if now < until {
+                return Err(until - now);
+            }
+            self.state = BreakerState::HalfOpen;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.consecutive_failures = 0;
+        self.reopened = 0;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+
+        let cooldown = match self.state {
+            BreakerState::HalfOpen => {
+                self.reopened += 1;
+                self.cooldown
+                    .saturating_mul(2u32.saturating_pow(self.reopened))
+                    .min(MAX_COOLDOWN.max(self.cooldown))
+            }
+            _ if self.consecutive_failures >= self.threshold => self.cooldown,
+            _ => return,
+        };
+
+        self.state = BreakerState::Open {
+            until: Instant::now() + cooldown,
+        };
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_COOLDOWN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), BreakerState::Open { .. }));
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let mut breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        breaker.record_failure();
+
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_cooldown_doubles_when_recovery_fails() {
+        let mut breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(15));
+        breaker.check().unwrap();
+
+        breaker.record_failure();
+
+        let remaining = breaker.check().unwrap_err();
+        assert!(remaining > Duration::from_millis(10));
+        assert!(remaining <= Duration::from_millis(20));
+    }
+}
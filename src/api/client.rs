@@ -1,22 +1,83 @@
 use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::api::{ApiData, ApiError};
-use reqwest::{Error, RequestBuilder, Response, header::HeaderMap};
+use crate::api::breaker::CircuitBreaker;
+use crate::api::{ApiData, ApiError, Endpoints, wirelog};
+use crate::stream;
+use chrono::{DateTime, TimeDelta, Utc};
+use reqwest::{
+    Body, Error, RequestBuilder, Response,
+    header::{
+        CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, DATE, HeaderMap, HeaderName, HeaderValue,
+    },
+};
 use serde::Serialize;
 use serde_json::Error as SerdeError;
 use spdlog::prelude::*;
 use thiserror::Error;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use url::Url;
 
-#[derive(Debug)]
+// cloning is cheap: the reqwest client (and its connection pool) as well as the clock offset
+// and circuit breaker are shared between clones
+#[derive(Debug, Clone)]
 pub struct ApiClient {
     base_url: String,
     // TODO: remove warning
     #[allow(dead_code)]
     token: String,
     client: reqwest::Client,
+    // settings the reqwest client is built with
+    default_headers: HeaderMap,
+    resolve_overrides: Vec<(String, IpAddr)>,
+    // difference between the server's clock (from the `Date` header of the first response) and
+    // ours
+    server_time_offset: Arc<Mutex<Option<TimeDelta>>>,
+    // fail on responses whose data does not have the expected shape instead of silently
+    // falling back to empty results
+    strict: bool,
+    // short-circuits requests while the API keeps failing
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    // paths of the endpoints used by the agent
+    endpoints: Endpoints,
+    connection: ConnectionSettings,
+    // log every request and response at debug level, credentials redacted
+    log_http: bool,
 }
 
+// how connections to the API are established and kept around. over TLS, HTTP/2 is negotiated
+// (ALPN) and the client falls back to HTTP/1.1 with servers not supporting it
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionSettings {
+    // speak HTTP/2 right away on cleartext connections (h2c). there is no fallback then: the
+    // server must support it
+    pub http2_prior_knowledge: bool,
+    // idle connections kept in the pool are closed after this long
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    // interval of the TCP keep-alive probes
+    pub tcp_keepalive: Option<Duration>,
+    // source address of the connections, on hosts with several interfaces
+    pub local_address: Option<IpAddr>,
+}
+
+// encoding of the job reports. MessagePack is more compact than JSON, for servers supporting it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// bytes of an interrupted upload received by the server, answered to a HEAD request
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+
 #[derive(Error, Debug)]
 pub enum ClientError {
     #[error("bad base url")]
@@ -33,6 +94,32 @@ pub enum ClientError {
 
     #[error("missing data in response")]
     MissingData,
+
+    #[error("the server did not assign any id to this agent")]
+    MissingAgentId,
+
+    #[error("unexpected data in response: {0}")]
+    UnexpectedData(String),
+
+    #[error("circuit breaker open, retrying in {0:?}")]
+    CircuitOpen(Duration),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("msgpack error: {0}")]
+    MsgpackError(#[from] rmp_serde::encode::Error),
+}
+
+impl ClientError {
+    // network errors and server errors, which may go away by themselves
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::ReqwestError(_) => true,
+            ClientError::ApiError(err) => err.code().is_server_error(),
+            _ => false,
+        }
+    }
 }
 
 // Custom api client wrapped around rust's reqwest crate
@@ -50,21 +137,130 @@ impl ApiClient {
             base_url,
             token,
             client: reqwest::Client::new(),
+            default_headers: HeaderMap::new(),
+            resolve_overrides: vec![],
+            server_time_offset: Arc::new(Mutex::new(None)),
+            strict: false,
+            breaker: Arc::new(Mutex::new(CircuitBreaker::default())),
+            endpoints: Endpoints::default(),
+            connection: ConnectionSettings::default(),
+            log_http: false,
         })
     }
 
+    // client of another service (e.g. a separate results collector) sharing the settings and
+    // connection pool of this one. the API's token is not sent to it, only its own if any, and
+    // it has its own circuit breaker and clock offset as it fails and drifts on its own
+    pub fn with_base_url(&self, base_url: &str, token: Option<String>) -> Self {
+        ApiClient {
+            base_url: base_url.to_string(),
+            token: token.unwrap_or_default(),
+            server_time_offset: Arc::new(Mutex::new(None)),
+            breaker: Arc::new(Mutex::new(CircuitBreaker::default())),
+            ..self.clone()
+        }
+    }
+
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    // headers sent with every request (e.g. required by a gateway in front of the API)
+    pub fn set_default_headers(&mut self, headers: HeaderMap) -> Result<(), ClientError> {
+        self.default_headers = headers;
+        self.rebuild()
+    }
+
+    // pin hostnames to specific addresses instead of asking the system resolver (like curl's
+    // --resolve)
+    pub fn set_resolve_overrides(
+        &mut self,
+        overrides: Vec<(String, IpAddr)>,
+    ) -> Result<(), ClientError> {
+        self.resolve_overrides = overrides;
+        self.rebuild()
+    }
+
+    pub fn set_connection_settings(
+        &mut self,
+        connection: ConnectionSettings,
+    ) -> Result<(), ClientError> {
+        self.connection = connection;
+        self.rebuild()
+    }
+
+    fn rebuild(&mut self) -> Result<(), ClientError> {
+        let mut builder = reqwest::Client::builder().default_headers(self.default_headers.clone());
+        // the port is ignored by reqwest, the one of the url is used
+        for (host, ip) in &self.resolve_overrides {
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+        }
+        if self.connection.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(timeout) = self.connection.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max_idle) = self.connection.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(interval) = self.connection.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+        if let Some(address) = self.connection.local_address {
+            builder = builder.local_address(address);
+        }
+
+        self.client = builder.build()?;
+        Ok(())
+    }
+
+    pub fn set_circuit_breaker(&mut self, threshold: u32, cooldown: Duration) {
+        self.breaker = Arc::new(Mutex::new(CircuitBreaker::new(threshold, cooldown)));
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_log_http(&mut self, enabled: bool) {
+        self.log_http = enabled;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    // offset to add to the local clock to get the server's time, if the server sent a `Date`
+    // header
+    pub fn server_time_offset(&self) -> Option<TimeDelta> {
+        *self.server_time_offset.lock().unwrap()
+    }
+
+    // the token is left out rather than sent empty to a service without one
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.token.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.token)
+        }
+    }
+
     pub async fn get(
         &self,
         uri: &str,
         headers: Option<HeaderMap>,
     ) -> Result<ApiData<serde_json::Value>, ClientError> {
         let url = format!("{}{}", self.base_url, uri);
-        let request = self.client.get(url).bearer_auth(&self.token);
+        let request = self.authorize(self.client.get(url));
 
         self.send(request, headers).await
     }
 
-    #[allow(dead_code)]
     pub async fn post<T: Serialize>(
         &self,
         uri: &str,
@@ -72,7 +268,7 @@ impl ApiClient {
         body: &T,
     ) -> Result<ApiData<serde_json::Value>, ClientError> {
         let url = format!("{}{}", self.base_url, uri);
-        let request = self.client.post(url).json(body).bearer_auth(&self.token);
+        let request = self.authorize(self.client.post(url).json(body));
 
         self.send(request, headers).await
     }
@@ -84,24 +280,235 @@ impl ApiClient {
         body: &T,
     ) -> Result<ApiData<serde_json::Value>, ClientError> {
         let url = format!("{}{}", self.base_url, uri);
-        let request = self.client.patch(url).json(body).bearer_auth(&self.token);
+        let request = self.authorize(self.client.patch(url).json(body));
 
         self.send(request, headers).await
     }
 
+    pub async fn delete(
+        &self,
+        uri: &str,
+        headers: Option<HeaderMap>,
+    ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        let url = format!("{}{}", self.base_url, uri);
+        let request = self.authorize(self.client.delete(url));
+
+        self.send(request, headers).await
+    }
+
+    // download the file at `url` (absolute, or relative to the base url) to `dest`, which must
+    // not exist yet, and return its size. the file is streamed to disk, never held in memory at
+    // once, and a file larger than `max_bytes` is rejected. the token is only sent to the API
+    // itself
+    pub async fn download_file(
+        &self,
+        url: &str,
+        dest: &Path,
+        max_bytes: u64,
+    ) -> Result<u64, ClientError> {
+        let request = if url.starts_with('/') {
+            self.authorize(self.client.get(format!("{}{}", self.base_url, url)))
+        } else {
+            let url = Url::parse(url)?;
+            let same_host =
+                Url::parse(&self.base_url).is_ok_and(|base| base.origin() == url.origin());
+            let request = self.client.get(url);
+            if same_host {
+                self.authorize(request)
+            } else {
+                request
+            }
+        };
+
+        let mut response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::ApiError(ApiError::new(
+                status,
+                format!("could not download {}", url),
+            )));
+        }
+
+        let too_large =
+            || ClientError::UnexpectedData(format!("{} is larger than {} bytes", url, max_bytes));
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(too_large());
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest)
+            .await?;
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await? {
+            size += chunk.len() as u64;
+            if size > max_bytes {
+                return Err(too_large());
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(size)
+    }
+
+    // PATCH a JSON body produced while being sent (chunked transfer encoding), see
+    // `stream::json_with_streamed_field`
+    pub async fn patch_stream(
+        &self,
+        uri: &str,
+        headers: Option<HeaderMap>,
+        body: Body,
+    ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        let url = format!("{}{}", self.base_url, uri);
+        let request = self
+            .authorize(self.client.patch(url))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+
+        self.send(request, headers).await
+    }
+
+    // PATCH a MessagePack body. fields are encoded by name so the server decodes the same
+    // structure as the JSON one
+    pub async fn patch_msgpack<T: Serialize>(
+        &self,
+        uri: &str,
+        headers: Option<HeaderMap>,
+        body: &T,
+    ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        let url = format!("{}{}", self.base_url, uri);
+        let request = self
+            .authorize(self.client.patch(url))
+            .header(CONTENT_TYPE, MSGPACK_CONTENT_TYPE)
+            .body(rmp_serde::to_vec_named(body)?);
+
+        self.send(request, headers).await
+    }
+
+    // POST the content of the file at `path` to `uri` and return the reference the server
+    // assigned to it (its id, or the file name if the server did not return any). the file is
+    // streamed, never read in memory at once. when resuming an interrupted upload, the server
+    // is first asked how much of it it received, see `received_offset`
+    pub async fn upload_file(
+        &self,
+        uri: &str,
+        path: &Path,
+        resume: bool,
+    ) -> Result<String, ClientError> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let url = format!("{}{}", self.base_url, uri);
+        let mut request = self
+            .authorize(self.client.post(&url))
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header("X-Filename", &file_name);
+
+        // only the part the server did not receive yet is sent when resuming an upload
+        let offset = match resume {
+            true => self.received_offset(uri, &file_name).await,
+            false => None,
+        };
+        let offset = match offset.filter(|offset| *offset > 0 && *offset < size) {
+            Some(offset) => {
+                debug!("Resuming upload of {} at byte {}", file_name, offset);
+                file.seek(SeekFrom::Start(offset)).await?;
+                request = request.header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, size - 1, size),
+                );
+                offset
+            }
+            None => 0,
+        };
+        let request = request
+            .header(CONTENT_LENGTH, size - offset)
+            .body(stream::file_body(file));
+
+        let res = self.send(request, None).await?;
+        let reference = res
+            .data
+            .as_ref()
+            .and_then(|data| data.get("id"))
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+
+        Ok(reference.unwrap_or(file_name))
+    }
+
+    // bytes of the file the server already received from an interrupted upload, for the servers
+    // supporting resumable uploads: answering a HEAD request on the upload uri with an
+    // `Upload-Offset` header. None when the upload has to start over
+    async fn received_offset(&self, uri: &str, file_name: &str) -> Option<u64> {
+        let url = format!("{}{}", self.base_url, uri);
+        let request = self
+            .authorize(self.client.head(url))
+            .header("X-Filename", file_name);
+        let (_, headers) = self.send_with_headers(request, None).await.ok()?;
+
+        headers
+            .get(UPLOAD_OFFSET)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
     // to be called by each get, post, patch methods that simply build a RequestBuilder
     // this one, submits it
     async fn send(
         &self,
-        mut request: RequestBuilder,
+        request: RequestBuilder,
         headers: Option<HeaderMap>,
     ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        self.send_with_headers(request, headers)
+            .await
+            .map(|(data, _)| data)
+    }
+
+    // send, also returning the headers of the response
+    async fn send_with_headers(
+        &self,
+        mut request: RequestBuilder,
+        headers: Option<HeaderMap>,
+    ) -> Result<(ApiData<serde_json::Value>, HeaderMap), ClientError> {
         if let Some(headers) = headers {
             request = request.headers(headers);
         }
 
-        let res = request.send().await?;
-        self.handle_response(res).await
+        self.breaker
+            .lock()
+            .unwrap()
+            .check()
+            .map_err(ClientError::CircuitOpen)?;
+
+        let (client, request) = request.build_split();
+        let request = request?;
+        if self.log_http {
+            debug!("{}", wirelog::format_request(&request));
+        }
+        let result = match client.execute(request).await {
+            Ok(res) => {
+                let headers = res.headers().clone();
+                self.handle_response(res).await.map(|data| (data, headers))
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        // only network errors and server errors mean the API is failing
+        let mut breaker = self.breaker.lock().unwrap();
+        match &result {
+            Err(err) if err.is_transient() => breaker.record_failure(),
+            _ => breaker.record_success(),
+        }
+
+        result
     }
 
     // post send method to be called. it parses OK or ERROR api responses properly
@@ -110,30 +517,44 @@ impl ApiClient {
         &self,
         response: Response,
     ) -> Result<ApiData<serde_json::Value>, ClientError> {
+        self.record_server_time(response.headers());
+
         let status = response.status();
+        let url = response.url().to_string();
         let message = response.text().await?;
-        let body: HashMap<String, serde_json::Value> =
-            serde_json::from_str(&message).map_err(ClientError::ParseError)?;
-
+        if self.log_http {
+            debug!(
+                "{}",
+                wirelog::format_response(status, &url, &message, &self.token)
+            );
+        }
         if status.is_client_error() || status.is_server_error() {
-            let mut error_messages = Vec::new();
-            if let Some(errors) = body.get("errors").and_then(|v| v.as_array()) {
-                for err in errors {
-                    let detail = err
-                        .get("detail")
-                        .and_then(|d| d.as_str())
-                        .unwrap_or_default();
-                    error_messages.push(detail.to_string());
-                }
-            }
-            let combined_message = error_messages.join("; ");
-            return Err(ClientError::ApiError(ApiError::new(
-                status,
-                combined_message,
-            )));
+            // errors that do not follow the JSON:API format, or bodies that are not even JSON
+            // (e.g. the HTML page of a proxy's 502), are kept out rather than hiding the status
+            let body: HashMap<String, serde_json::Value> =
+                serde_json::from_str(&message).unwrap_or_default();
+            let errors = body
+                .get("errors")
+                .and_then(|v| v.as_array())
+                .map(|errors| {
+                    errors
+                        .iter()
+                        .filter_map(|err| serde_json::from_value(err.clone()).ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+            return Err(ClientError::ApiError(ApiError::with_errors(status, errors)));
         }
 
+        // an empty body (e.g. 204 No Content) carries no data
+        let body: HashMap<String, serde_json::Value> = if message.trim().is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(&message).map_err(ClientError::ParseError)?
+        };
+
         let mut api_response: ApiData<serde_json::Value> = ApiData::new();
+        api_response.code = Some(status);
 
         if status.is_success()
             && let Some(data) = body.get("data")
@@ -154,23 +575,618 @@ impl ApiClient {
                     .get("attributes")
                     .cloned()
                     .unwrap_or(serde_json::Value::Object(Default::default())),
+                serde_json::Value::Null => data.clone(),
+                _ if self.strict => {
+                    error!("Unexpected scalar data in response: {}", data);
+                    return Err(ClientError::UnexpectedData(format!(
+                        "expected an object or an array, got {}",
+                        data
+                    )));
+                }
                 _ => data.clone(),
             };
             api_response.data = Some(value);
         }
 
+        // absolute links to the API are made relative to the base url like the other uris
+        api_response.next = body
+            .get("links")
+            .and_then(|links| links.get("next"))
+            .and_then(|next| next.as_str())
+            .map(|next| {
+                next.strip_prefix(&self.base_url)
+                    .unwrap_or(next)
+                    .to_string()
+            });
+
         Ok(api_response)
     }
+
+    // only the first response carrying a `Date` header is used to compute the clock offset
+    fn record_server_time(&self, headers: &HeaderMap) {
+        let mut offset = self.server_time_offset.lock().unwrap();
+        if offset.is_some() {
+            return;
+        }
+
+        let server_time = headers
+            .get(DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+
+        if let Some(server_time) = server_time {
+            *offset = Some(server_time.with_timezone(&Utc) - Utc::now());
+        }
+    }
 }
 
-// TODO: remove this or improve
-impl Default for ApiClient {
-    fn default() -> Self {
-        // Provide dummy values just to satisfy the trait
+// parses a "Name: Value" header given on the command line
+pub fn parse_header(raw: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = raw
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header {:?}, expected \"Name: Value\"", raw))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|err| format!("invalid header name {:?}: {}", name, err))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|err| format!("invalid header value for {}: {}", name, err))?;
+
+    Ok((name, value))
+}
+
+// parses a "host:ip" DNS override given on the command line
+pub fn parse_resolve(raw: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = raw
+        .split_once(':')
+        .ok_or_else(|| format!("invalid override {:?}, expected \"host:ip\"", raw))?;
+    if host.is_empty() {
+        return Err(format!("missing host in override {:?}", raw));
+    }
+    let ip = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|err| format!("invalid address in override {:?}: {}", raw, err))?;
+
+    Ok((host.to_string(), ip))
+}
+
+pub fn parse_wire_format(raw: &str) -> Result<WireFormat, String> {
+    match raw {
+        "json" => Ok(WireFormat::Json),
+        "msgpack" => Ok(WireFormat::Msgpack),
+        _ => Err(format!(
+            "invalid wire format {:?}, expected \"json\" or \"msgpack\"",
+            raw
+        )),
+    }
+}
+
+impl ApiClient {
+    // client bound to no API at all, every request through it fails. only meant as a placeholder
+    // while deserializing structures holding a client, which is then replaced by a real one
+    pub(crate) fn detached() -> Self {
         ApiClient {
             base_url: String::new(),
             token: String::new(),
             client: reqwest::Client::new(),
+            default_headers: HeaderMap::new(),
+            resolve_overrides: vec![],
+            server_time_offset: Arc::new(Mutex::new(None)),
+            strict: false,
+            breaker: Arc::new(Mutex::new(CircuitBreaker::default())),
+            endpoints: Endpoints::default(),
+            connection: ConnectionSettings::default(),
+            log_http: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::error::{ErrorObject, ErrorSource};
+    use crate::api::mock::MockServer;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    // server only speaking HTTP/2, answering every request with `body`. returns its url and
+    // the number of connections it accepted
+    async fn start_h2_server(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let Ok(mut connection) = h2::server::handshake(socket).await else {
+                        return;
+                    };
+                    while let Some(Ok((_request, mut respond))) = connection.accept().await {
+                        let response = http::Response::builder()
+                            .status(200)
+                            .header("content-type", "application/json")
+                            .body(())
+                            .unwrap();
+                        let mut stream = respond.send_response(response, false).unwrap();
+                        stream
+                            .send_data(bytes::Bytes::from_static(body.as_bytes()), true)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        (url, connections)
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge_reuses_connection() {
+        // Given a server only speaking HTTP/2
+        let (url, connections) = start_h2_server(r#"{"data": {"attributes": {"ok": true}}}"#).await;
+        let mut client = ApiClient::new(url, "token".to_string()).unwrap();
+        client
+            .set_connection_settings(ConnectionSettings {
+                http2_prior_knowledge: true,
+                pool_idle_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
+            })
+            .unwrap();
+
+        // When
+        for _ in 0..3 {
+            let res = client.get("/self", None).await.unwrap();
+
+            // Then
+            assert_eq!(res.data, Some(json!({"ok": true})));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_http1_server_without_prior_knowledge() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client
+            .set_connection_settings(ConnectionSettings {
+                pool_max_idle_per_host: Some(1),
+                tcp_keepalive: Some(Duration::from_secs(60)),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(client.get("/self", None).await.is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_connections_bind_local_address() {
+        // Given a client bound to another loopback address than the server's
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let local_address: IpAddr = "127.0.0.2".parse().unwrap();
+        client
+            .set_connection_settings(ConnectionSettings {
+                local_address: Some(local_address),
+                ..Default::default()
+            })
+            .unwrap();
+
+        // When
+        client.get("/self", None).await.unwrap();
+
+        // Then
+        assert_eq!(server.requests()[0].peer.ip(), local_address);
+    }
+
+    #[tokio::test]
+    async fn test_next_page_link() {
+        // Given a paginated list, its next link being absolute
+        let server = MockServer::start().await;
+        let next = format!("{}/jobs?page=2", server.url());
+        server.mock(
+            "GET",
+            "/jobs",
+            200,
+            json!({"data": [], "links": {"self": "/jobs", "next": next}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        // When
+        let res = client.get("/jobs", None).await.unwrap();
+
+        // Then
+        assert_eq!(res.next.as_deref(), Some("/jobs?page=2"));
+    }
+
+    #[tokio::test]
+    async fn test_server_time_offset_from_date_header() {
+        // Given a server whose clock is one hour ahead
+        let server = MockServer::start().await;
+        let server_time = (Utc::now() + TimeDelta::hours(1)).to_rfc2822();
+        server.mock_with_headers(
+            "GET",
+            "/self",
+            200,
+            vec![("Date", &server_time)],
+            json!({"data": {"attributes": {}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        // When
+        client.get("/self", None).await.unwrap();
+
+        // Then
+        let offset = client.server_time_offset().unwrap();
+        assert!((offset - TimeDelta::hours(1)).abs() < TimeDelta::seconds(5));
+    }
+
+    #[tokio::test]
+    async fn test_server_time_offset_without_date_header() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 200, json!({"data": {"attributes": {}}}));
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        client.get("/self", None).await.unwrap();
+
+        assert!(client.server_time_offset().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scalar_data_in_lenient_mode() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/tools", 200, json!({"data": "unexpected"}));
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        let res = client.get("/tools", None).await.unwrap();
+
+        assert_eq!(res.data, Some(json!("unexpected")));
+    }
+
+    #[tokio::test]
+    async fn test_scalar_data_in_strict_mode() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/tools", 200, json!({"data": "unexpected"}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_strict(true);
+
+        let res = client.get("/tools", None).await;
+
+        assert!(matches!(res, Err(ClientError::UnexpectedData(_))));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_short_circuits_during_cooldown() {
+        // Given a failing API
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/self",
+            500,
+            json!({"errors": [{"detail": "internal error"}]}),
+        );
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_circuit_breaker(3, Duration::from_secs(60));
+
+        // When
+        for _ in 0..3 {
+            let res = client.get("/self", None).await;
+            assert!(matches!(res, Err(ClientError::ApiError(_))));
         }
+        let res = client.get("/self", None).await;
+
+        // Then
+        assert!(matches!(res, Err(ClientError::CircuitOpen(_))));
+        assert_eq!(server.requests_to("GET", "/self").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_recovers_after_cooldown() {
+        let server = MockServer::start().await;
+        let error = json!({"errors": [{"detail": "internal error"}]});
+        server.mock("GET", "/self", 500, error.clone());
+        server.mock("GET", "/self", 500, error);
+        server.mock("GET", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_circuit_breaker(2, Duration::from_millis(50));
+
+        let _ = client.get("/self", None).await;
+        let _ = client.get("/self", None).await;
+        assert!(matches!(
+            client.get("/self", None).await,
+            Err(ClientError::CircuitOpen(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        assert!(client.get("/self", None).await.is_ok());
+        assert_eq!(server.requests_to("GET", "/self").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_error_response_keeps_every_error() {
+        // Given
+        let server = MockServer::start().await;
+        server.mock(
+            "PATCH",
+            "/jobs/1",
+            422,
+            json!({"errors": [
+                {
+                    "status": "422",
+                    "code": "invalid",
+                    "title": "Invalid attribute",
+                    "detail": "results must be a string",
+                    "source": {"pointer": "/data/attributes/results"},
+                },
+                {"title": "Missing attribute", "source": {"parameter": "status"}},
+            ]}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        // When
+        let res = client.patch("/jobs/1", None, &json!({})).await;
+
+        // Then
+        let Err(ClientError::ApiError(err)) = res else {
+            panic!("expected an API error, got {:?}", res);
+        };
+        assert_eq!(err.code(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            err.errors(),
+            [
+                ErrorObject {
+                    status: Some("422".to_string()),
+                    code: Some("invalid".to_string()),
+                    title: Some("Invalid attribute".to_string()),
+                    detail: Some("results must be a string".to_string()),
+                    source: Some(ErrorSource {
+                        pointer: Some("/data/attributes/results".to_string()),
+                        ..Default::default()
+                    }),
+                },
+                ErrorObject {
+                    title: Some("Missing attribute".to_string()),
+                    source: Some(ErrorSource {
+                        parameter: Some("status".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Error HTTP 422: results must be a string; Missing attribute"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_resumes_from_received_offset() {
+        // Given a server reporting the first 4 bytes as received
+        let server = MockServer::start().await;
+        server.mock_with_headers(
+            "HEAD",
+            "/upload",
+            200,
+            vec![("Upload-Offset", "4")],
+            json!({}),
+        );
+        server.mock(
+            "POST",
+            "/upload",
+            201,
+            json!({"data": {"attributes": {"id": "a1"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let path = std::env::temp_dir().join(format!("agent-upload-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "0123456789").unwrap();
+
+        // When
+        let first = client.upload_file("/upload", &path, false).await.unwrap();
+        let resumed = client.upload_file("/upload", &path, true).await.unwrap();
+
+        // Then the server is only asked for the received bytes when resuming
+        assert_eq!((first.as_str(), resumed.as_str()), ("a1", "a1"));
+        assert_eq!(server.requests_to("HEAD", "/upload").len(), 1);
+        let posts = server.requests_to("POST", "/upload");
+        assert_eq!(posts[0].body, "0123456789");
+        assert_eq!(posts[0].header("content-range"), None);
+        assert_eq!(posts[1].body, "456789");
+        assert_eq!(posts[1].header("content-range"), Some("bytes 4-9/10"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_file_is_capped() {
+        // Given a 12 bytes file
+        let server = MockServer::start().await;
+        server.mock("GET", "/files/wordlist.txt", 200, json!("0123456789"));
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let dir = std::env::temp_dir().join(format!("agent-download-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+
+        // When / Then it is downloaded within the cap, and rejected beyond it
+        let size = client
+            .download_file("/files/wordlist.txt", &dir.join("within"), 12)
+            .await
+            .unwrap();
+        assert_eq!(size, 12);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("within")).unwrap(),
+            "\"0123456789\""
+        );
+        let result = client
+            .download_file("/files/wordlist.txt", &dir.join("beyond"), 11)
+            .await;
+        assert!(matches!(result, Err(ClientError::UnexpectedData(_))));
+
+        // and an existing file is never overwritten
+        let result = client
+            .download_file("/files/wordlist.txt", &dir.join("within"), 12)
+            .await;
+        assert!(matches!(result, Err(ClientError::IoError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_non_json_server_errors_are_transient() {
+        // Given a proxy answering with an HTML page
+        let server = MockServer::start().await;
+        server.mock_raw(
+            "GET",
+            "/self",
+            503,
+            "<html><body>503 Service Unavailable</body></html>",
+        );
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_circuit_breaker(1, Duration::from_secs(60));
+
+        // When
+        let res = client.get("/self", None).await;
+
+        // Then the status is kept, and counted as a failure of the API
+        match res {
+            Err(ClientError::ApiError(err)) => {
+                assert_eq!(err.code(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+                assert!(ClientError::ApiError(err).is_transient());
+            }
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        let res = client.get("/self", None).await;
+        assert!(matches!(res, Err(ClientError::CircuitOpen(_))));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_ignores_client_errors() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 404, json!({"errors": [{"detail": "nope"}]}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_circuit_breaker(1, Duration::from_secs(60));
+
+        let _ = client.get("/self", None).await;
+        let res = client.get("/self", None).await;
+
+        assert!(matches!(res, Err(ClientError::ApiError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_cloned_clients_share_configuration() {
+        let server = MockServer::start().await;
+        let server_time = (Utc::now() + TimeDelta::hours(1)).to_rfc2822();
+        server.mock_with_headers(
+            "GET",
+            "/self",
+            200,
+            vec![("Date", &server_time)],
+            json!({"data": {"attributes": {}}}),
+        );
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        client.set_strict(true);
+        client.set_circuit_breaker(1, Duration::from_secs(60));
+
+        let clone = client.clone();
+        client.get("/self", None).await.unwrap();
+
+        assert_eq!(clone.base_url, client.base_url);
+        assert_eq!(clone.token, client.token);
+        assert!(clone.is_strict());
+        assert!(Arc::ptr_eq(&clone.breaker, &client.breaker));
+        assert!(clone.server_time_offset().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_detached_client_fails_requests() {
+        let client = ApiClient::detached();
+
+        assert!(client.get("/self", None).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let (name, value) = parse_header("X-Org-Id:  42 ").unwrap();
+
+        assert_eq!(name.as_str(), "x-org-id");
+        assert_eq!(value.to_str().unwrap(), "42");
+    }
+
+    #[test]
+    fn test_parse_malformed_headers() {
+        assert!(parse_header("X-Org-Id").is_err());
+        assert!(parse_header("X Org: 42").is_err());
+        assert!(parse_header("X-Org-Id: bad\nvalue").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_default_headers_are_sent() {
+        let server = MockServer::start().await;
+        server.mock("GET", "/self", 200, json!({"data": {"attributes": {}}}));
+        server.mock("PATCH", "/self", 200, json!({"data": {"attributes": {}}}));
+        let mut client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let mut headers = HeaderMap::new();
+        for raw in ["X-Org-Id: 42", "CF-Access-Client-Id: my-client"] {
+            let (name, value) = parse_header(raw).unwrap();
+            headers.insert(name, value);
+        }
+        client.set_default_headers(headers).unwrap();
+
+        client.get("/self", None).await.unwrap();
+        client.patch("/self", None, &json!({})).await.unwrap();
+
+        for request in server.requests() {
+            assert_eq!(request.header("X-Org-Id"), Some("42"));
+            assert_eq!(request.header("CF-Access-Client-Id"), Some("my-client"));
+            assert_eq!(request.header("Authorization"), Some("Bearer token"));
+        }
+    }
+
+    #[test]
+    fn test_parse_resolve() {
+        assert_eq!(
+            parse_resolve("api.pentulz.test:10.0.0.1").unwrap(),
+            ("api.pentulz.test".to_string(), "10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            parse_resolve("api.pentulz.test:[::1]").unwrap().1,
+            "::1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_resolve("api.pentulz.test").is_err());
+        assert!(parse_resolve(":10.0.0.1").is_err());
+        assert!(parse_resolve("api.pentulz.test:not-an-ip").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_override_routes_to_address() {
+        // Given a hostname that does not exist, pinned to the mock server's address
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/self",
+            200,
+            json!({"data": {"attributes": {"name": "me"}}}),
+        );
+        let port = Url::parse(&server.url()).unwrap().port().unwrap();
+        let base_url = format!("http://api.pentulz.invalid:{}", port);
+        let mut client = ApiClient::new(base_url, "token".to_string()).unwrap();
+
+        // When
+        client
+            .set_resolve_overrides(vec![
+                parse_resolve("api.pentulz.invalid:127.0.0.1").unwrap(),
+            ])
+            .unwrap();
+        let res = client.get("/self", None).await.unwrap();
+
+        // Then
+        assert_eq!(res.data, Some(json!({"name": "me"})));
+        assert_eq!(
+            server.requests()[0].header("Host"),
+            Some(format!("api.pentulz.invalid:{}", port).as_str())
+        );
     }
 }
@@ -0,0 +1,148 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use uuid::Uuid;
+
+// Paths of the API endpoints used by the agent, relative to the base url. the prefix is
+// prepended to all of them so the same agent works with APIs mounted under another path
+// (e.g. `/api/v2/agent/self`)
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    prefix: String,
+    self_path: String,
+    jobs_path: String,
+    tools_path: String,
+    metrics_path: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            prefix: String::new(),
+            self_path: "/self".to_string(),
+            jobs_path: "/jobs".to_string(),
+            tools_path: "/tools".to_string(),
+            metrics_path: "/metrics".to_string(),
+        }
+    }
+}
+
+// paths always start with a single slash and never end with one
+fn normalize(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+impl Endpoints {
+    pub fn set_prefix(&mut self, prefix: &str) {
+        self.prefix = normalize(prefix);
+    }
+
+    pub fn set_self_path(&mut self, path: &str) {
+        self.self_path = normalize(path);
+    }
+
+    pub fn set_jobs_path(&mut self, path: &str) {
+        self.jobs_path = normalize(path);
+    }
+
+    pub fn set_tools_path(&mut self, path: &str) {
+        self.tools_path = normalize(path);
+    }
+
+    pub fn set_metrics_path(&mut self, path: &str) {
+        self.metrics_path = normalize(path);
+    }
+
+    pub fn agent_self(&self) -> String {
+        format!("{}{}", self.prefix, self.self_path)
+    }
+
+    pub fn jobs(&self) -> String {
+        format!("{}{}", self.prefix, self.jobs_path)
+    }
+
+    // jobs created after `since`, so the ones already seen are not downloaded again, and at
+    // most `limit` of them
+    pub fn jobs_list(&self, since: Option<&DateTime<Utc>>, limit: Option<usize>) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(since) = since {
+            query.append_pair("since", &since.to_rfc3339_opts(SecondsFormat::Micros, true));
+        }
+        if let Some(limit) = limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+
+        match query.finish() {
+            query if query.is_empty() => self.jobs(),
+            query => format!("{}?{}", self.jobs(), query),
+        }
+    }
+
+    pub fn job(&self, id: &Uuid) -> String {
+        format!("{}/{}", self.jobs(), id)
+    }
+
+    pub fn job_artifacts(&self, id: &Uuid) -> String {
+        format!("{}/artifacts", self.job(id))
+    }
+
+    pub fn tools(&self) -> String {
+        format!("{}{}", self.prefix, self.tools_path)
+    }
+
+    pub fn metrics(&self) -> String {
+        format!("{}{}", self.prefix, self.metrics_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_endpoints() {
+        let endpoints = Endpoints::default();
+        let id = Uuid::new_v4();
+
+        assert_eq!(endpoints.agent_self(), "/self");
+        assert_eq!(endpoints.jobs(), "/jobs");
+        assert_eq!(endpoints.job(&id), format!("/jobs/{}", id));
+        assert_eq!(endpoints.tools(), "/tools");
+        assert_eq!(endpoints.metrics(), "/metrics");
+    }
+
+    #[test]
+    fn test_endpoints_with_prefix_and_overrides() {
+        let mut endpoints = Endpoints::default();
+        endpoints.set_prefix("api/v2/");
+        endpoints.set_jobs_path("/agent/tasks/");
+        let id = Uuid::new_v4();
+
+        assert_eq!(endpoints.agent_self(), "/api/v2/self");
+        assert_eq!(endpoints.jobs(), "/api/v2/agent/tasks");
+        assert_eq!(
+            endpoints.job_artifacts(&id),
+            format!("/api/v2/agent/tasks/{}/artifacts", id)
+        );
+        assert_eq!(endpoints.tools(), "/api/v2/tools");
+    }
+
+    #[test]
+    fn test_jobs_list() {
+        let endpoints = Endpoints::default();
+        let since = DateTime::parse_from_rfc3339("2025-08-28T12:41:34.061276+02:00").unwrap();
+
+        assert_eq!(
+            endpoints.jobs_list(Some(&since.to_utc()), None),
+            "/jobs?since=2025-08-28T10%3A41%3A34.061276Z"
+        );
+        assert_eq!(
+            endpoints.jobs_list(Some(&since.to_utc()), Some(50)),
+            "/jobs?since=2025-08-28T10%3A41%3A34.061276Z&limit=50"
+        );
+        assert_eq!(endpoints.jobs_list(None, None), "/jobs");
+    }
+}
@@ -11,11 +11,43 @@ pub struct ApiError {
     )]
     code: StatusCode,
     title: String,
+    // the `errors` array of the response, as sent by the server
+    #[serde(default)]
+    errors: Vec<ErrorObject>,
+}
+
+// one of the errors of a JSON:API error response, all of its members are optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorObject {
+    pub status: Option<String>,
+    pub code: Option<String>,
+    pub title: Option<String>,
+    pub detail: Option<String>,
+    pub source: Option<ErrorSource>,
+}
+
+// what caused the error: a member of the request document, a query parameter or a header
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorSource {
+    pub pointer: Option<String>,
+    pub parameter: Option<String>,
+    pub header: Option<String>,
+}
+
+impl ErrorObject {
+    // the detail of the error, or its title for the servers only sending one
+    fn message(&self) -> Option<&str> {
+        self.detail.as_deref().or(self.title.as_deref())
+    }
 }
 
 impl std::fmt::Debug for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Code: {:?}, Title: {:?}", &self.code, &self.title)
+        write!(
+            f,
+            "Code: {:?}, Title: {:?}, Errors: {:?}",
+            &self.code, &self.title, &self.errors
+        )
     }
 }
 
@@ -29,7 +61,36 @@ impl std::error::Error for ApiError {}
 
 impl ApiError {
     pub fn new(code: StatusCode, title: String) -> Self {
-        ApiError { code, title }
+        ApiError {
+            code,
+            title,
+            errors: vec![],
+        }
+    }
+
+    // error made of the `errors` array of a response, its title joining their messages
+    pub fn with_errors(code: StatusCode, errors: Vec<ErrorObject>) -> Self {
+        let title = errors
+            .iter()
+            .filter_map(ErrorObject::message)
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        ApiError {
+            code,
+            title,
+            errors,
+        }
+    }
+
+    pub fn code(&self) -> StatusCode {
+        self.code
+    }
+
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn errors(&self) -> &[ErrorObject] {
+        &self.errors
     }
 }
 
@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use reqwest::{StatusCode, header::HeaderMap};
+
+use crate::api::transport::{ApiResult, ApiTransport};
+use crate::api::{ApiData, ApiError};
+
+// In-memory ApiTransport used by unit tests: requests are answered right away with canned
+// responses and recorded, without going through HTTP at all. unknown routes answer 404
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeRequest {
+    pub method: String,
+    pub uri: String,
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+struct FakeResponse {
+    status: u16,
+    data: serde_json::Value,
+    next: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct FakeTransport {
+    // status, data (already unwrapped from the JSON:API envelope) and next page by method and uri
+    responses: Mutex<HashMap<(String, String), FakeResponse>>,
+    requests: Arc<Mutex<Vec<FakeRequest>>>,
+}
+
+impl FakeTransport {
+    pub fn new() -> FakeTransport {
+        FakeTransport::default()
+    }
+
+    pub fn respond(&self, method: &str, uri: &str, status: u16, data: serde_json::Value) {
+        self.insert(
+            method,
+            uri,
+            FakeResponse {
+                status,
+                data,
+                next: None,
+            },
+        );
+    }
+
+    // page of a paginated list, linking to the `next` one
+    pub fn respond_page(&self, uri: &str, data: serde_json::Value, next: Option<&str>) {
+        self.insert(
+            "GET",
+            uri,
+            FakeResponse {
+                status: 200,
+                data,
+                next: next.map(str::to_string),
+            },
+        );
+    }
+
+    fn insert(&self, method: &str, uri: &str, response: FakeResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert((method.to_string(), uri.to_string()), response);
+    }
+
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn answer(&self, method: &str, uri: &str, body: Option<serde_json::Value>) -> ApiResult {
+        self.requests.lock().unwrap().push(FakeRequest {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            body,
+        });
+
+        let response = self
+            .responses
+            .lock()
+            .unwrap()
+            .get(&(method.to_string(), uri.to_string()))
+            .cloned();
+        let response = response.unwrap_or(FakeResponse {
+            status: 404,
+            data: serde_json::Value::Null,
+            next: None,
+        });
+        let status = StatusCode::from_u16(response.status).expect("valid status code");
+        if !status.is_success() {
+            return Err(ApiError::new(status, format!("{} {}", method, uri)).into());
+        }
+
+        let mut res = ApiData::new();
+        res.code = Some(status);
+        res.data = Some(response.data);
+        res.next = response.next;
+        Ok(res)
+    }
+}
+
+impl ApiTransport for FakeTransport {
+    fn get<'a>(&'a self, uri: &'a str, _headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer("GET", uri, None) })
+    }
+
+    fn post<'a>(
+        &'a self,
+        uri: &'a str,
+        _headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer("POST", uri, Some(body)) })
+    }
+
+    fn patch<'a>(
+        &'a self,
+        uri: &'a str,
+        _headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer("PATCH", uri, Some(body)) })
+    }
+
+    fn delete<'a>(&'a self, uri: &'a str, _headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer("DELETE", uri, None) })
+    }
+}
@@ -0,0 +1,361 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use serde_json::{Value, json};
+use spdlog::{info, warn};
+use uuid::Uuid;
+
+use crate::api::client::ClientError;
+use crate::api::transport::ApiResult;
+use crate::api::{ApiData, ApiError, ApiTransport, Endpoints};
+
+// air-gapped operation: the jobs are read from files dropped in a directory out-of-band and the
+// reports are written to another one, instead of going through the API. a job file holds a job,
+// or an array of them, in the API's format, `agent_id` defaulting to this agent's. it is
+// renamed with a `.taken` suffix once read so its jobs only run once, and put back on startup
+// when some of its jobs did not finish (e.g. the agent crashed), those already reported being
+// left out. the claim and the report of a job are merged into `<job_id>.json` in the reports
+// directory, the registration and the capabilities of the agent into `agent.json`. the jobs
+// scheduled locally are created there too. there is no list of tools, the capabilities come
+// from `--fallback-tool`
+#[derive(Debug)]
+pub struct FileTransport {
+    jobs_dir: PathBuf,
+    reports_dir: PathBuf,
+    endpoints: Endpoints,
+    // there is no server to assign one, a new id is used on each run
+    agent_id: Uuid,
+}
+
+// name of the agent, which has no server-side record
+const OFFLINE_AGENT_NAME: &str = "offline";
+
+impl FileTransport {
+    pub fn new(
+        jobs_dir: PathBuf,
+        reports_dir: PathBuf,
+        endpoints: Endpoints,
+    ) -> Result<Self, ClientError> {
+        if !jobs_dir.is_dir() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("jobs directory {} not found", jobs_dir.display()),
+            )
+            .into());
+        }
+        fs::create_dir_all(&reports_dir)?;
+
+        let transport = FileTransport {
+            jobs_dir,
+            reports_dir,
+            endpoints,
+            agent_id: Uuid::new_v4(),
+        };
+        transport.requeue_unfinished()?;
+        Ok(transport)
+    }
+
+    fn answer_get(&self, uri: &str) -> ApiResult {
+        match path_of(uri) {
+            path if path == self.endpoints.agent_self() => ok(json!({
+                "id": self.agent_id,
+                "token": "",
+                "jobs": [],
+                "name": OFFLINE_AGENT_NAME,
+            })),
+            path if path == self.endpoints.jobs() => ok(self.take_jobs()?),
+            // not found, so the agent falls back on the tools given with `--fallback-tool`
+            _ => not_found("GET", uri),
+        }
+    }
+
+    fn answer_patch(&self, uri: &str, body: &Value) -> ApiResult {
+        let path = path_of(uri);
+        if path == self.endpoints.agent_self() {
+            return ok(self.merge_report(&self.reports_dir.join("agent.json"), body)?);
+        }
+
+        // the id is checked so the report cannot be written outside of the reports directory
+        let job_id = path
+            .strip_prefix(&format!("{}/", self.endpoints.jobs()))
+            .and_then(|id| Uuid::parse_str(id).ok());
+        match job_id {
+            Some(id) => {
+                let path = self.reports_dir.join(format!("{}.json", id));
+                ok(self.merge_report(&path, body)?)
+            }
+            None => not_found("PATCH", uri),
+        }
+    }
+
+    // the local jobs are created in the reports directory, their reports are merged into them
+    fn answer_post(&self, uri: &str, body: &Value) -> ApiResult {
+        let job_id = body
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok());
+        match job_id {
+            Some(id) if path_of(uri) == self.endpoints.jobs() => {
+                let path = self.reports_dir.join(format!("{}.json", id));
+                ok(self.merge_report(&path, body)?)
+            }
+            _ => not_found("POST", uri),
+        }
+    }
+
+    // files of the jobs directory with this extension, in the order of their names
+    fn job_files(&self, extension: &str) -> Result<Vec<PathBuf>, ClientError> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.jobs_dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    // jobs of a job file, none when it is invalid
+    fn read_jobs(path: &Path) -> Result<Vec<Value>, ClientError> {
+        let content = fs::read_to_string(path)?;
+        match serde_json::from_str::<Value>(&content) {
+            Ok(Value::Array(values)) => Ok(values),
+            Ok(value) => Ok(vec![value]),
+            Err(err) => {
+                warn!("Ignoring job file {}: {}", path.display(), err);
+                Ok(vec![])
+            }
+        }
+    }
+
+    // whether the report of the job says it completed, failed or was skipped
+    fn is_finished(&self, job: &Value) -> bool {
+        let Some(id) = job
+            .get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok())
+        else {
+            return false;
+        };
+        let report: Option<Value> = fs::read(self.reports_dir.join(format!("{}.json", id)))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok());
+        report
+            .and_then(|report| report.get("status").cloned())
+            .is_some_and(|status| {
+                status == "completed" || status == "failed" || status == "skipped"
+            })
+    }
+
+    // put back the taken files some jobs of which did not finish, they are run again
+    fn requeue_unfinished(&self) -> Result<(), ClientError> {
+        for path in self.job_files("taken")? {
+            let unfinished = FileTransport::read_jobs(&path)?
+                .iter()
+                .any(|job| !self.is_finished(job));
+            if unfinished {
+                warn!(
+                    "Requeuing {}, some of its jobs did not finish",
+                    path.display()
+                );
+                fs::rename(&path, path.with_extension(""))?;
+            }
+        }
+        Ok(())
+    }
+
+    // jobs of the files in the jobs directory, in the order of their names. the files are only
+    // renamed, so the jobs are requeued when the agent stops before reporting them
+    fn take_jobs(&self) -> Result<Value, ClientError> {
+        let mut jobs = Vec::new();
+        for path in self.job_files("json")? {
+            let values = FileTransport::read_jobs(&path)?;
+            // renamed even when invalid, so it is not read again on every cycle either
+            fs::rename(&path, path.with_extension("json.taken"))?;
+            if values.is_empty() {
+                continue;
+            }
+            info!("Read jobs from {}", path.display());
+            // the jobs of a requeued file which were already reported are not run again
+            jobs.extend(values.into_iter().filter(|job| !self.is_finished(job)));
+        }
+
+        for job in jobs.iter_mut().filter_map(Value::as_object_mut) {
+            job.entry("agent_id").or_insert(json!(self.agent_id));
+        }
+        Ok(Value::Array(jobs))
+    }
+
+    // merge a patch into the report at `path`, which then holds the last value of every field
+    fn merge_report(&self, path: &Path, patch: &Value) -> Result<Value, ClientError> {
+        let mut report = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => json!({}),
+            Err(err) => return Err(err.into()),
+        };
+        if let (Some(report), Some(patch)) = (report.as_object_mut(), patch.as_object()) {
+            report.extend(patch.clone());
+        }
+
+        // written aside then renamed, so the report is never read half written
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec_pretty(&report)?)?;
+        fs::rename(&partial, path)?;
+
+        Ok(report)
+    }
+}
+
+fn path_of(uri: &str) -> &str {
+    uri.split_once('?').map_or(uri, |(path, _)| path)
+}
+
+fn ok(data: Value) -> ApiResult {
+    let mut res = ApiData::new();
+    res.code = Some(StatusCode::OK);
+    res.data = Some(data);
+    Ok(res)
+}
+
+fn not_found(method: &str, uri: &str) -> ApiResult {
+    Err(ApiError::new(StatusCode::NOT_FOUND, format!("{} {}", method, uri)).into())
+}
+
+impl ApiTransport for FileTransport {
+    fn get<'a>(&'a self, uri: &'a str, _headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer_get(uri) })
+    }
+
+    fn post<'a>(
+        &'a self,
+        uri: &'a str,
+        _headers: Option<HeaderMap>,
+        body: Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer_post(uri, &body) })
+    }
+
+    fn patch<'a>(
+        &'a self,
+        uri: &'a str,
+        _headers: Option<HeaderMap>,
+        body: Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { self.answer_patch(uri, &body) })
+    }
+
+    fn delete<'a>(&'a self, uri: &'a str, _headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { not_found("DELETE", uri) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_dirs() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("agent-offline-{}", Uuid::new_v4()));
+        let jobs_dir = dir.join("jobs");
+        fs::create_dir_all(&jobs_dir).unwrap();
+        (jobs_dir, dir.join("reports"))
+    }
+
+    #[tokio::test]
+    async fn test_file_transport() {
+        // Given a job file dropped in the jobs directory
+        let (jobs_dir, reports_dir) = make_dirs();
+        let job_id = Uuid::new_v4();
+        fs::write(
+            jobs_dir.join("scan.json"),
+            json!({"id": job_id, "name": "scan"}).to_string(),
+        )
+        .unwrap();
+        let transport =
+            FileTransport::new(jobs_dir.clone(), reports_dir.clone(), Endpoints::default())
+                .unwrap();
+
+        // When
+        let first = transport.get("/jobs?limit=10", None).await.unwrap();
+        let second = transport.get("/jobs", None).await.unwrap();
+        let uri = format!("/jobs/{}", job_id);
+        transport
+            .patch(&uri, None, json!({"status": "running"}))
+            .await
+            .unwrap();
+        transport
+            .patch(&uri, None, json!({"status": "completed", "success": true}))
+            .await
+            .unwrap();
+
+        // Then the job is handed out once, with the agent's id
+        let jobs = first.data.unwrap();
+        assert_eq!(jobs[0]["id"], json!(job_id));
+        assert_eq!(jobs[0]["agent_id"], json!(transport.agent_id));
+        assert_eq!(second.data.unwrap(), json!([]));
+        assert!(jobs_dir.join("scan.json.taken").exists());
+        // and the patches are merged into its report
+        let report: Value = serde_json::from_slice(
+            &fs::read(reports_dir.join(format!("{}.json", job_id))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report, json!({"status": "completed", "success": true}));
+        // the agent falls back on its own list of tools
+        assert!(transport.get("/tools", None).await.is_err());
+        assert!(
+            transport
+                .patch("/jobs/..%2Fx", None, json!({}))
+                .await
+                .is_err()
+        );
+        assert!(transport.get("/unknown", None).await.is_err());
+
+        fs::remove_dir_all(jobs_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_jobs_are_requeued_on_startup() {
+        // Given a job file holding a finished job and a job which was running when the agent
+        // stopped
+        let (jobs_dir, reports_dir) = make_dirs();
+        let (finished_id, running_id) = (Uuid::new_v4(), Uuid::new_v4());
+        fs::write(
+            jobs_dir.join("scan.json"),
+            json!([{"id": finished_id}, {"id": running_id}]).to_string(),
+        )
+        .unwrap();
+        let transport =
+            FileTransport::new(jobs_dir.clone(), reports_dir.clone(), Endpoints::default())
+                .unwrap();
+        transport.get("/jobs", None).await.unwrap();
+        for (id, status) in [(finished_id, "completed"), (running_id, "running")] {
+            let uri = format!("/jobs/{}", id);
+            transport
+                .patch(&uri, None, json!({"status": status}))
+                .await
+                .unwrap();
+        }
+
+        // When the agent starts again
+        let transport =
+            FileTransport::new(jobs_dir.clone(), reports_dir.clone(), Endpoints::default())
+                .unwrap();
+        let jobs = transport.get("/jobs", None).await.unwrap().data.unwrap();
+
+        // Then only the unfinished job is run again
+        assert_eq!(jobs.as_array().unwrap().len(), 1);
+        assert_eq!(jobs[0]["id"], json!(running_id));
+        // and the file is not requeued once all of its jobs finished
+        let uri = format!("/jobs/{}", running_id);
+        transport
+            .patch(&uri, None, json!({"status": "failed"}))
+            .await
+            .unwrap();
+        FileTransport::new(jobs_dir.clone(), reports_dir, Endpoints::default()).unwrap();
+        assert!(!jobs_dir.join("scan.json").exists());
+        assert!(jobs_dir.join("scan.json.taken").exists());
+
+        fs::remove_dir_all(jobs_dir.parent().unwrap()).unwrap();
+    }
+}
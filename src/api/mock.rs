@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// Minimal HTTP server used by unit tests to stand in for the Pentulz API.
+// Responses are registered per (method, path) and every received request is recorded
+// so tests can assert on what the agent actually sent.
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // time waited before answering
+    pub delay: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // body as received, for binary payloads
+    pub raw_body: Vec<u8>,
+    pub received_at: Instant,
+    // address the request came from
+    pub peer: SocketAddr,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+type Routes = Arc<Mutex<HashMap<(String, String), VecDeque<MockResponse>>>>;
+
+pub struct MockServer {
+    url: String,
+    routes: Routes,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let routes: Routes = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let (task_routes, task_requests) = (routes.clone(), requests.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let (routes, requests) = (task_routes.clone(), task_requests.clone());
+                tokio::spawn(async move {
+                    let _ = handle_connection(stream, peer, routes, requests).await;
+                });
+            }
+        });
+
+        MockServer {
+            url,
+            routes,
+            requests,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    // queue a JSON response for the given route. when several responses are queued they are
+    // served in order, the last one being repeated forever
+    pub fn mock(&self, method: &str, path: &str, status: u16, body: serde_json::Value) {
+        self.mock_with_headers(method, path, status, vec![], body);
+    }
+
+    pub fn mock_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        status: u16,
+        headers: Vec<(&str, &str)>,
+        body: serde_json::Value,
+    ) {
+        self.push(
+            method,
+            path,
+            MockResponse {
+                status,
+                headers: headers
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                body: body.to_string(),
+                delay: None,
+            },
+        );
+    }
+
+    // respond with a body that may not be JSON, or be empty
+    pub fn mock_raw(&self, method: &str, path: &str, status: u16, body: &str) {
+        self.push(
+            method,
+            path,
+            MockResponse {
+                status,
+                headers: vec![],
+                body: body.to_string(),
+                delay: None,
+            },
+        );
+    }
+
+    pub fn mock_with_delay(
+        &self,
+        method: &str,
+        path: &str,
+        status: u16,
+        delay: Duration,
+        body: serde_json::Value,
+    ) {
+        self.push(
+            method,
+            path,
+            MockResponse {
+                status,
+                headers: vec![],
+                body: body.to_string(),
+                delay: Some(delay),
+            },
+        );
+    }
+
+    fn push(&self, method: &str, path: &str, response: MockResponse) {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), path.to_string()))
+            .or_default()
+            .push_back(response);
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|req| req.method == method && req.path == path)
+            .collect()
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    routes: Routes,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+    let path = match target.split_once('?') {
+        Some((path, _)) => path.to_string(),
+        None => target,
+    };
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let chunked = headers.iter().any(|(k, v)| {
+        k.eq_ignore_ascii_case("transfer-encoding") && v.eq_ignore_ascii_case("chunked")
+    });
+
+    let raw_body = if chunked {
+        // the body ends with an empty chunk
+        while !buffer[header_end..].ends_with(b"0\r\n\r\n") {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        decode_chunked(&buffer[header_end..])
+    } else {
+        while buffer.len() < header_end + content_length {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        buffer[header_end..].to_vec()
+    };
+    let body = String::from_utf8_lossy(&raw_body).to_string();
+
+    let response = {
+        let mut routes = routes.lock().unwrap();
+        match routes.get_mut(&(method.clone(), path.clone())) {
+            Some(queue) if queue.len() > 1 => queue.pop_front(),
+            Some(queue) => queue.front().cloned(),
+            None => None,
+        }
+    }
+    .unwrap_or(MockResponse {
+        status: 404,
+        headers: vec![],
+        body: r#"{"errors":[{"detail":"not found"}]}"#.to_string(),
+        delay: None,
+    });
+
+    requests.lock().unwrap().push(RecordedRequest {
+        method,
+        path,
+        headers,
+        body,
+        raw_body,
+        received_at: Instant::now(),
+        peer,
+    });
+
+    if let Some(delay) = response.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut raw = format!(
+        "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (key, value) in &response.headers {
+        raw.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    raw.push_str("\r\n");
+    raw.push_str(&response.body);
+
+    stream.write_all(raw.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// body sent with `Transfer-Encoding: chunked`: each chunk is prefixed by its hexadecimal size
+fn decode_chunked(mut raw: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    while let Some(line_end) = raw.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&raw[..line_end])
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .unwrap_or(0);
+        if size == 0 {
+            break;
+        }
+        let start = line_end + 2;
+        body.extend_from_slice(&raw[start..start + size]);
+        raw = &raw[start + size + 2..];
+    }
+
+    body
+}
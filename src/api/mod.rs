@@ -1,7 +1,18 @@
+pub mod breaker;
 pub mod client;
+pub mod endpoints;
 pub mod error;
+#[cfg(test)]
+pub mod fake;
+pub mod file;
+#[cfg(test)]
+pub mod mock;
+pub mod transport;
 pub mod types;
+pub mod wirelog;
 
 pub use client::ApiClient;
+pub use endpoints::Endpoints;
 pub use error::ApiError;
+pub use transport::ApiTransport;
 pub use types::*;
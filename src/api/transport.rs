@@ -0,0 +1,61 @@
+use futures::future::BoxFuture;
+use reqwest::header::HeaderMap;
+
+use crate::api::ApiClient;
+use crate::api::ApiData;
+use crate::api::client::ClientError;
+
+pub type ApiResult = Result<ApiData<serde_json::Value>, ClientError>;
+
+// JSON requests to the API. implemented by the ApiClient, and by fakes returning canned
+// responses so the agent logic can be unit tested without any HTTP server. bodies are JSON
+// values as generic methods would make the trait unusable as a trait object
+pub trait ApiTransport: Send + Sync + std::fmt::Debug {
+    fn get<'a>(&'a self, uri: &'a str, headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult>;
+
+    fn post<'a>(
+        &'a self,
+        uri: &'a str,
+        headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult>;
+
+    fn patch<'a>(
+        &'a self,
+        uri: &'a str,
+        headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult>;
+
+    // not used by the agent yet
+    #[allow(dead_code)]
+    fn delete<'a>(&'a self, uri: &'a str, headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult>;
+}
+
+impl ApiTransport for ApiClient {
+    fn get<'a>(&'a self, uri: &'a str, headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(ApiClient::get(self, uri, headers))
+    }
+
+    fn post<'a>(
+        &'a self,
+        uri: &'a str,
+        headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { ApiClient::post(self, uri, headers, &body).await })
+    }
+
+    fn patch<'a>(
+        &'a self,
+        uri: &'a str,
+        headers: Option<HeaderMap>,
+        body: serde_json::Value,
+    ) -> BoxFuture<'a, ApiResult> {
+        Box::pin(async move { ApiClient::patch(self, uri, headers, &body).await })
+    }
+
+    fn delete<'a>(&'a self, uri: &'a str, headers: Option<HeaderMap>) -> BoxFuture<'a, ApiResult> {
+        Box::pin(ApiClient::delete(self, uri, headers))
+    }
+}
@@ -10,6 +10,9 @@ pub struct ApiData<T> {
     )]
     pub code: Option<StatusCode>,
     pub data: Option<T>,
+    // uri of the next page of a paginated list (JSON:API `links.next`), relative to the base url
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
 }
 
 impl<T> ApiData<T> {
@@ -17,6 +20,7 @@ impl<T> ApiData<T> {
         ApiData {
             data: None,
             code: None,
+            next: None,
         }
     }
 }
@@ -0,0 +1,102 @@
+use regex::Regex;
+use reqwest::{Request, StatusCode, header::HeaderName};
+
+// lines of the verbose HTTP log (`--log-http`) used to debug the API integration. credentials
+// never make it to the logs: sensitive headers are masked, as well as the token, password and
+// secret members of the bodies
+
+const REDACTED: &str = "***";
+
+// headers carrying credentials, including the custom ones of API gateways
+fn is_sensitive(name: &HeaderName) -> bool {
+    let name = name.as_str();
+    matches!(
+        name,
+        "authorization" | "proxy-authorization" | "cookie" | "set-cookie"
+    ) || ["token", "key", "secret"]
+        .iter()
+        .any(|part| name.contains(part))
+}
+
+// the JSON members holding credentials are masked, as well as any occurrence of the agent's token
+pub fn redact_body(body: &str, token: &str) -> String {
+    let sensitive_member =
+        Regex::new(r#"("[A-Za-z_]*(?:token|password|secret)"\s*:\s*)"(?:[^"\\]|\\.)*""#)
+            .expect("valid sensitive member regex");
+    let body = sensitive_member.replace_all(body, format!("${{1}}\"{}\"", REDACTED));
+    if token.is_empty() {
+        body.into_owned()
+    } else {
+        body.replace(token, REDACTED)
+    }
+}
+
+pub fn format_request(request: &Request) -> String {
+    let headers: Vec<String> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive(name) {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect();
+
+    format!(
+        "HTTP request {} {} [{}]",
+        request.method(),
+        request.url(),
+        headers.join(", ")
+    )
+}
+
+pub fn format_response(status: StatusCode, url: &str, body: &str, token: &str) -> String {
+    format!(
+        "HTTP response {} {}: {}",
+        status.as_u16(),
+        url,
+        redact_body(body, token)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_request_masks_credentials() {
+        // Given
+        let request = reqwest::Client::new()
+            .get("http://api.example.com/self")
+            .bearer_auth("s3cr3t-token")
+            .header("X-Gateway-Key", "gateway-secret")
+            .header("Accept", "application/json")
+            .build()
+            .unwrap();
+
+        // When
+        let line = format_request(&request);
+
+        // Then
+        assert_eq!(
+            line,
+            "HTTP request GET http://api.example.com/self [authorization: ***, x-gateway-key: ***, accept: application/json]"
+        );
+    }
+
+    #[test]
+    fn test_format_response_masks_tokens() {
+        let body = r#"{"data": {"attributes": {"token": "s3cr3t-token", "name": "agent", "api_secret": "a\"b"}}}"#;
+
+        let line = format_response(StatusCode::OK, "/self", body, "s3cr3t-token");
+
+        assert_eq!(
+            line,
+            r#"HTTP response 200 /self: {"data": {"attributes": {"token": "***", "name": "agent", "api_secret": "***"}}}"#
+        );
+        assert_eq!(redact_body("echo s3cr3t-token", "s3cr3t-token"), "echo ***");
+    }
+}
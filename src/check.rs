@@ -0,0 +1,169 @@
+use std::fmt::{self, Display};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::api::ApiClient;
+use crate::api::client::ClientError;
+use crate::tool::Tool;
+
+// Result of the `--check` mode: can the agent reach and authenticate with the API, and which
+// of the API's tools are available locally. nothing is registered nor run
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub status: Option<StatusCode>,
+    pub agent_id: Option<Uuid>,
+    pub agent_name: Option<String>,
+    pub tools: Vec<(String, bool)>,
+    pub error: Option<String>,
+}
+
+// part of GET /self identifying the agent
+#[derive(Deserialize)]
+struct Identity {
+    id: Option<Uuid>,
+    name: String,
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.agent_id.is_some()
+    }
+}
+
+pub async fn run_check(client: &ApiClient) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    if let Err(err) = check_identity(client, &mut report).await {
+        report.error = Some(err.to_string());
+        return report;
+    }
+
+    if let Err(err) = check_tools(client, &mut report).await {
+        report.error = Some(format!("could not fetch tools: {}", err));
+    }
+
+    report
+}
+
+// performs GET /self
+async fn check_identity(client: &ApiClient, report: &mut CheckReport) -> Result<(), ClientError> {
+    let uri = client.endpoints().agent_self();
+    let res = client.get(&uri, None).await.inspect_err(|err| {
+        if let ClientError::ApiError(err) = err {
+            report.status = Some(err.code());
+        }
+    })?;
+    report.status = res.code;
+
+    let identity: Identity = serde_json::from_value(res.data.ok_or(ClientError::MissingData)?)?;
+    report.agent_id = Some(identity.id.ok_or(ClientError::MissingAgentId)?);
+    report.agent_name = Some(identity.name);
+
+    Ok(())
+}
+
+// performs GET /tools and checks each of them locally
+async fn check_tools(client: &ApiClient, report: &mut CheckReport) -> Result<(), ClientError> {
+    let uri = client.endpoints().tools();
+    let res = client.get(&uri, None).await?;
+    let tools: Vec<Tool> = serde_json::from_value(res.data.ok_or(ClientError::MissingData)?)?;
+
+    report.tools = tools
+        .iter()
+        .map(|tool| (tool.cmd().to_string(), tool.is_available()))
+        .collect();
+
+    Ok(())
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => writeln!(f, "API: HTTP {}", status)?,
+            None => writeln!(f, "API: unreachable")?,
+        }
+        if let (Some(id), Some(name)) = (&self.agent_id, &self.agent_name) {
+            writeln!(f, "Agent: {} ({})", name, id)?;
+        }
+        for (cmd, available) in &self.tools {
+            let state = if *available { "available" } else { "missing" };
+            writeln!(f, "Tool {}: {}", cmd, state)?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "Error: {}", error)?;
+        }
+        write!(
+            f,
+            "Check {}",
+            if self.passed() { "PASSED" } else { "FAILED" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockServer;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_check_success() {
+        let server = MockServer::start().await;
+        let id = Uuid::new_v4();
+        server.mock(
+            "GET",
+            "/self",
+            200,
+            json!({"data": {"attributes": {"id": id, "name": "scanner-1"}}}),
+        );
+        server.mock(
+            "GET",
+            "/tools",
+            200,
+            json!({"data": [
+                {"cmd": "echo", "version": null, "version_arg": null},
+                {"cmd": "non_existing_cmd", "version": null, "version_arg": null},
+            ]}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+
+        let report = run_check(&client).await;
+
+        assert!(report.passed());
+        assert_eq!(report.status, Some(StatusCode::OK));
+        assert_eq!(report.agent_id, Some(id));
+        assert_eq!(report.agent_name.as_deref(), Some("scanner-1"));
+        assert_eq!(
+            report.tools,
+            vec![
+                ("echo".to_string(), true),
+                ("non_existing_cmd".to_string(), false)
+            ]
+        );
+        assert!(report.to_string().ends_with("Check PASSED"));
+        // nothing is registered
+        assert!(server.requests().iter().all(|req| req.method == "GET"));
+    }
+
+    #[tokio::test]
+    async fn test_check_unauthorized() {
+        let server = MockServer::start().await;
+        server.mock(
+            "GET",
+            "/self",
+            401,
+            json!({"errors": [{"detail": "invalid token"}]}),
+        );
+        let client = ApiClient::new(server.url(), "bad_token".to_string()).unwrap();
+
+        let report = run_check(&client).await;
+
+        assert!(!report.passed());
+        assert_eq!(report.status, Some(StatusCode::UNAUTHORIZED));
+        assert!(report.error.is_some());
+        assert!(report.to_string().ends_with("Check FAILED"));
+        assert!(server.requests_to("GET", "/tools").is_empty());
+    }
+}
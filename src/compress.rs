@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use flate2::{Compression, write::GzEncoder};
+
+// marker sent along with compressed results so the server knows how to decode them
+pub const RESULTS_ENCODING_GZIP_BASE64: &str = "gzip+base64";
+
+// compressed results are only sent when they are at most this fraction of the raw size, the
+// base64 overhead making small gains pointless
+const MAX_COMPRESSED_RATIO: f64 = 0.75;
+
+// gzip then base64 encode `result` when it is larger than `threshold` bytes and compresses
+// well enough. returns None when the raw result should be sent instead
+pub fn compress_result(result: &str, threshold: usize) -> Result<Option<String>, io::Error> {
+    if result.len() <= threshold {
+        return Ok(None);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(result.as_bytes())?;
+    let encoded = STANDARD.encode(encoder.finish()?);
+
+    if (encoded.len() as f64) > (result.len() as f64) * MAX_COMPRESSED_RATIO {
+        return Ok(None);
+    }
+
+    Ok(Some(encoded))
+}
+
+// used by unit tests
+#[allow(dead_code)]
+pub fn decompress_result(encoded: &str) -> Result<String, io::Error> {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let compressed = STANDARD
+        .decode(encoded)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut result = String::new();
+    GzDecoder::new(compressed.as_slice()).read_to_string(&mut result)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_result_round_trip() {
+        let result = "<host><port>80</port></host>\n".repeat(1000);
+
+        let encoded = compress_result(&result, 1024).unwrap().unwrap();
+
+        assert!(encoded.len() < result.len() / 4);
+        assert_eq!(decompress_result(&encoded).unwrap(), result);
+    }
+
+    #[test]
+    fn test_compress_result_below_threshold() {
+        assert!(compress_result("short", 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compress_result_incompressible() {
+        // base64 output is already dense, gzip + base64 grows it
+        let mut state: u32 = 42;
+        let bytes: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+        let result = STANDARD.encode(bytes);
+
+        assert!(compress_result(&result, 16).unwrap().is_none());
+    }
+}
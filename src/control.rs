@@ -0,0 +1,188 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use spdlog::{info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{Notify, watch},
+};
+
+use crate::job::Job;
+use crate::metrics::Metrics;
+
+// What the control socket acts upon: the jobs of the agent, the notifier waking the poll loop
+// up, the shutdown channel used to drain the agent, its paused state, its request to rescan the
+// tools and its metrics.
+#[derive(Clone)]
+pub struct Control {
+    pub jobs: Arc<Mutex<Vec<Arc<Job>>>>,
+    pub poll_now: Arc<Notify>,
+    pub shutdown: Arc<watch::Sender<bool>>,
+    pub paused: Arc<AtomicBool>,
+    pub rescan: Arc<AtomicBool>,
+    pub metrics: Metrics,
+}
+
+// Listens on a Unix domain socket for one-line commands, mostly useful when debugging:
+//   poll-now  run a poll cycle right away instead of waiting for the next one
+//   status    list the jobs known to the agent along with their state
+//   drain     stop polling once the current cycle is done, then exit
+//   pause     stop fetching and starting jobs, the agent keeps heartbeating and reporting
+//   resume    fetch and start jobs again, right away
+//   rescan    scan the available tools and submit the capabilities again, right away
+//   metrics   executions, failures and durations of the jobs by tool (Prometheus format)
+pub fn listen(path: &Path, control: Control) -> Result<(), std::io::Error> {
+    // a previous run may have left its socket behind
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Listening for control commands on {:?}", path);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let control = control.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_connection(stream, &control).await {
+                    warn!("Control connection failed: {}", err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_connection(stream: UnixStream, control: &Control) -> Result<(), std::io::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = run_command(line.trim(), control);
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+fn run_command(command: &str, control: &Control) -> String {
+    match command {
+        "poll-now" => {
+            info!("Poll requested through the control socket");
+            control.poll_now.notify_one();
+            "ok\n".to_string()
+        }
+        "status" => status(&control.jobs.lock().unwrap()),
+        "metrics" => control.metrics.render(),
+        "drain" => {
+            info!("Drain requested through the control socket");
+            let _ = control.shutdown.send(true);
+            "ok\n".to_string()
+        }
+        "pause" => {
+            info!("Pause requested through the control socket");
+            control.paused.store(true, Ordering::Relaxed);
+            "ok\n".to_string()
+        }
+        "resume" => {
+            info!("Resume requested through the control socket");
+            control.paused.store(false, Ordering::Relaxed);
+            control.poll_now.notify_one();
+            "ok\n".to_string()
+        }
+        "rescan" => {
+            info!("Tools rescan requested through the control socket");
+            control.rescan.store(true, Ordering::Relaxed);
+            control.poll_now.notify_one();
+            "ok\n".to_string()
+        }
+        _ => format!("unknown command {:?}\n", command),
+    }
+}
+
+fn status(jobs: &[Arc<Job>]) -> String {
+    let mut status = format!("jobs: {}\n", jobs.len());
+    for job in jobs {
+        status.push_str(&format!(
+            "{} {} {}\n",
+            job.get_id(),
+            job.get_name(),
+            job.state()
+        ));
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_control_socket_commands() {
+        // Given an agent with one pending job
+        let path = std::env::temp_dir().join(format!("agent-control-{}.sock", Uuid::new_v4()));
+        let job = Arc::new(Job::new(
+            "echo_hello".to_string(),
+            "echo".to_string(),
+            vec![],
+        ));
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let control = Control {
+            jobs: Arc::new(Mutex::new(vec![job.clone()])),
+            poll_now: Arc::new(Notify::new()),
+            shutdown: Arc::new(shutdown_tx),
+            paused: Arc::new(AtomicBool::new(false)),
+            rescan: Arc::new(AtomicBool::new(false)),
+            metrics: Metrics::default(),
+        };
+        listen(&path, control.clone()).unwrap();
+
+        // When
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"status\n").await.unwrap();
+        let count = lines.next_line().await.unwrap().unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        writer.write_all(b"poll-now\ndrain\n").await.unwrap();
+        let poll_now = lines.next_line().await.unwrap().unwrap();
+        let drain = lines.next_line().await.unwrap().unwrap();
+
+        // Then
+        assert_eq!(count, "jobs: 1");
+        assert_eq!(line, format!("{} echo_hello pending", job.get_id()));
+        assert_eq!((poll_now.as_str(), drain.as_str()), ("ok", "ok"));
+        control.poll_now.notified().await;
+        assert!(*shutdown_rx.borrow());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pause_and_resume_commands() {
+        let (shutdown_tx, _) = watch::channel(false);
+        let control = Control {
+            jobs: Arc::new(Mutex::new(vec![])),
+            poll_now: Arc::new(Notify::new()),
+            shutdown: Arc::new(shutdown_tx),
+            paused: Arc::new(AtomicBool::new(false)),
+            rescan: Arc::new(AtomicBool::new(false)),
+            metrics: Metrics::default(),
+        };
+
+        assert_eq!(run_command("pause", &control), "ok\n");
+        assert!(control.paused.load(Ordering::Relaxed));
+        assert_eq!(run_command("resume", &control), "ok\n");
+        assert!(!control.paused.load(Ordering::Relaxed));
+        assert_eq!(run_command("rescan", &control), "ok\n");
+        assert!(control.rescan.load(Ordering::Relaxed));
+    }
+}
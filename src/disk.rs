@@ -0,0 +1,183 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::resources;
+
+// limits on the disk space used by the files the agent keeps around (the job logs and the
+// artifacts), so a busy agent does not fill the disk: the least recently modified files of each
+// directory are removed once their total size exceeds the budget, and no job is started while a
+// filesystem the agent writes to is almost full
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskBudget {
+    // total size of the files kept in each directory, in bytes
+    pub max_bytes: Option<u64>,
+    // free space of the filesystem below which no job is started, in bytes
+    pub min_free_bytes: Option<u64>,
+}
+
+impl DiskBudget {
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.min_free_bytes.is_some()
+    }
+
+    // remove the oldest files of `dir` until they fit in the budget, returns the removed files
+    pub fn enforce(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.enforce_keeping(dir, |_| false)
+    }
+
+    // same as `enforce`, the files for which `keep` holds are counted but never removed
+    pub fn enforce_keeping(
+        &self,
+        dir: &Path,
+        keep: impl Fn(&Path) -> bool,
+    ) -> io::Result<Vec<PathBuf>> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(vec![]);
+        };
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut files = list_files(dir)?;
+        let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+        // oldest first
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut removed = Vec::new();
+        for (path, size, _) in files {
+            if total <= max_bytes {
+                break;
+            }
+            if keep(&path) {
+                continue;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+            removed.push(path);
+        }
+
+        Ok(removed)
+    }
+
+    // whether one of the filesystems holding `dirs` has less free space than allowed. unknown
+    // free space is not considered low
+    pub fn is_space_low<'a>(&self, dirs: impl IntoIterator<Item = &'a Path>) -> bool {
+        match self.min_free_bytes {
+            Some(min_free_bytes) => dirs
+                .into_iter()
+                .any(|dir| resources::disk_free(dir).is_some_and(|free| free < min_free_bytes)),
+            None => false,
+        }
+    }
+}
+
+// regular files under `dir` with their size and modification time
+fn list_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            files.extend(list_files(&entry.path())?);
+        } else if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((entry.path(), metadata.len(), modified));
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn write_file(dir: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, vec![b'x'; size]).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_enforce_removes_oldest_files() {
+        // Given three 100 bytes logs and a 250 bytes budget
+        let dir = std::env::temp_dir().join(format!("agent-disk-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let oldest = write_file(&dir, "a.log", 100, Duration::from_secs(300));
+        let older = write_file(&dir, "b.log", 100, Duration::from_secs(200));
+        let newest = write_file(&dir, "c.log", 100, Duration::from_secs(100));
+        let budget = DiskBudget {
+            max_bytes: Some(250),
+            min_free_bytes: None,
+        };
+
+        // When
+        let removed = budget.enforce(&dir).unwrap();
+
+        // Then
+        assert_eq!(removed, vec![oldest.clone()]);
+        assert!(!oldest.exists());
+        assert!(older.exists());
+        assert!(newest.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_enforce_within_budget_keeps_files() {
+        let dir = std::env::temp_dir().join(format!("agent-disk-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = write_file(&dir, "a.log", 100, Duration::from_secs(10));
+        let budget = DiskBudget {
+            max_bytes: Some(100),
+            min_free_bytes: None,
+        };
+
+        assert!(budget.enforce(&dir).unwrap().is_empty());
+        assert!(log.exists());
+        assert!(budget.enforce(&dir.join("missing")).unwrap().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_enforce_keeping_skips_kept_files() {
+        // Given an artifact still being uploaded, the oldest of the directory
+        let dir = std::env::temp_dir().join(format!("agent-disk-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let pending = write_file(&dir, "a.xml", 100, Duration::from_secs(300));
+        let uploaded = write_file(&dir, "b.xml", 100, Duration::from_secs(200));
+        let budget = DiskBudget {
+            max_bytes: Some(150),
+            min_free_bytes: None,
+        };
+
+        // When
+        let removed = budget
+            .enforce_keeping(&dir, |path| path == pending)
+            .unwrap();
+
+        // Then the next oldest one makes room instead
+        assert_eq!(removed, vec![uploaded.clone()]);
+        assert!(pending.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_space_low() {
+        let dir = std::env::temp_dir();
+        let low = DiskBudget {
+            max_bytes: None,
+            min_free_bytes: Some(u64::MAX),
+        };
+
+        assert!(low.is_space_low([dir.as_path()]));
+        assert!(!low.is_space_low([]));
+        assert!(!DiskBudget::default().is_space_low([dir.as_path()]));
+    }
+}
@@ -0,0 +1,140 @@
+use std::env;
+use std::ffi::OsString;
+
+// expansion of `${VAR}` references in the configuration values, so a single service definition
+// works across environments. `${VAR:-default}` falls back to `default` when VAR is unset or
+// empty, other undefined variables are errors rather than silently expanding to nothing. `$${`
+// stands for a literal `${`
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum InterpolationError {
+    #[error("undefined environment variable {0} (use ${{{0}:-default}} to provide a default)")]
+    Undefined(String),
+
+    #[error("unterminated variable reference in {0:?}")]
+    Unterminated(String),
+}
+
+// expands the variables of `value` from the process environment
+pub fn interpolate(value: &str) -> Result<String, InterpolationError> {
+    interpolate_with(value, |name| env::var(name).ok())
+}
+
+// expands the variables of a command line argument, which is left as is when it is not valid
+// UTF-8 (clap then reports it if the flag expects a string)
+pub fn interpolate_arg(arg: OsString) -> Result<OsString, InterpolationError> {
+    match arg.to_str() {
+        Some(value) => interpolate(value).map(OsString::from),
+        None => Ok(arg),
+    }
+}
+
+fn interpolate_with<F>(value: &str, lookup: F) -> Result<String, InterpolationError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if let Some(literal) = rest[..start].strip_suffix('$') {
+            expanded.push_str(literal);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| InterpolationError::Unterminated(value.to_string()))?;
+
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        let resolved = match (lookup(name).filter(|v| !v.is_empty()), default) {
+            (Some(resolved), _) => resolved,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => return Err(InterpolationError::Undefined(name.to_string())),
+        };
+        expanded.push_str(&resolved);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "PENTULZ_API" => Some("https://api.pentulz.example".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate_defined_variable() {
+        let value = interpolate_with("${PENTULZ_API}/v1", lookup);
+
+        assert_eq!(value.unwrap(), "https://api.pentulz.example/v1");
+    }
+
+    #[test]
+    fn test_interpolate_undefined_variable_with_default() {
+        assert_eq!(
+            interpolate_with("${MISSING:-http://localhost:8080}", lookup).unwrap(),
+            "http://localhost:8080"
+        );
+        assert_eq!(
+            interpolate_with("${EMPTY:-fallback}", lookup).unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            interpolate_with("${PENTULZ_API:-unused}", lookup).unwrap(),
+            "https://api.pentulz.example"
+        );
+    }
+
+    #[test]
+    fn test_interpolate_undefined_variable_without_default() {
+        let value = interpolate_with("Bearer ${MISSING}", lookup);
+
+        assert_eq!(
+            value,
+            Err(InterpolationError::Undefined("MISSING".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_interpolate_escaped_reference() {
+        assert_eq!(
+            interpolate_with("pa$${ss}word ${EMPTY:-x}", lookup).unwrap(),
+            "pa${ss}word x"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_interpolate_arg_keeps_invalid_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let arg = OsString::from_vec(vec![b'$', b'{', 0xff, b'}']);
+
+        assert_eq!(interpolate_arg(arg.clone()).unwrap(), arg);
+    }
+
+    #[test]
+    fn test_interpolate_leaves_plain_values_untouched() {
+        assert_eq!(interpolate_with("a $b {c}", lookup).unwrap(), "a $b {c}");
+        assert!(matches!(
+            interpolate_with("${PENTULZ_API", lookup),
+            Err(InterpolationError::Unterminated(_))
+        ));
+    }
+}
@@ -1,17 +1,122 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use spdlog::info;
+use serde_json::Value;
+use spdlog::{debug, info, warn};
 use std::{
+    collections::HashMap,
     fmt::{self, Display},
+    path::{Component, Path, PathBuf},
     sync::{
         Arc, Mutex,
         atomic::{AtomicBool, Ordering},
     },
+    time::Instant,
 };
 use uuid::Uuid;
 
 use chrono::{DateTime, Utc};
+use regex::Regex;
 
-use crate::action::Action;
+use crate::action::{Action, RunOptions};
+
+// content types declared in reports so the dashboard knows how to render the results
+pub const CONTENT_TYPE_TEXT: &str = "text/plain";
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_GZIP: &str = "application/gzip";
+
+// replacement of the output parts matching a redaction rule
+const REDACTED: &str = "***";
+
+// condition a job declaring a dependency expects from it before being run
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobCondition {
+    #[default]
+    Success,
+    Failure,
+    Always,
+    OutputContains(String),
+}
+
+impl JobCondition {
+    pub fn is_met_by(&self, dependency: &Job) -> bool {
+        match self {
+            JobCondition::Success => dependency.is_success(),
+            JobCondition::Failure => !dependency.is_success(),
+            JobCondition::Always => true,
+            JobCondition::OutputContains(pattern) => dependency
+                .get_result_as_string()
+                .is_some_and(|result| result.contains(pattern)),
+        }
+    }
+}
+
+// state of a job as tracked by the server
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    #[default]
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    // completed without running, see SkipReason
+    Skipped,
+    #[serde(other)]
+    Unknown,
+}
+
+// why a job completed without running (to its end), so the server can tell "could not run"
+// from "ran and failed"
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    // the job it depends on completed without matching its condition
+    DependencyNotMet,
+    // the job it depends on does not exist
+    DependencyNotFound,
+    // it targets hosts outside of the allowlist
+    OutOfScope,
+    // its command is not installed, another agent may run it
+    ToolUnavailable,
+    // killed because the agent was shutting down
+    Cancelled,
+    // refused by the agent's job transformer
+    Rejected,
+}
+
+// why a reported job neither succeeded nor failed, `success` being left out of the report
+// rather than defaulting to false
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuccessUnknownReason {
+    // it completed without running, see SkipReason
+    NotRun,
+    // killed by the shutdown before completing
+    Cancelled,
+    // killed when the cycle budget ran out
+    BudgetExceeded,
+}
+
+// file provided by the server that is downloaded before the job runs. its local path replaces
+// the `{input:<name>}` placeholders of the action's arguments
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JobInput {
+    pub name: String,
+    pub url: String,
+}
+
+// command run before or after the job's action (e.g. to snapshot state or notify a SIEM), with
+// the same options, placeholders, environment and working directory as the action. a failing
+// hook fails the job when fatal, otherwise it is only reported. hooks are subject to the scope
+// and tool checks of the action
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JobHook {
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub fatal: bool,
+}
 
 // structure to map Job's table on DB
 #[derive(Clone)]
@@ -24,15 +129,75 @@ pub struct Job {
     completed_at: Arc<Mutex<Option<DateTime<Utc>>>>,
     action: Action,
     agent_id: Uuid,
+    // job that must be completed (and match the condition) before this one is run
+    depends_on: Option<Uuid>,
+    condition: JobCondition,
+    // regexes whose matches are replaced in the output before it is stored or submitted
+    redactions: Vec<String>,
+    inputs: Vec<JobInput>,
+    // status of the job when it was fetched from the server
+    status: JobStatus,
+    // files written by the action that are uploaded once it completed
+    artifacts: Vec<String>,
+    // file the tool writes its results to (e.g. `-oX {output_file}`), read back once it exited.
+    // a relative path, resolved in the job's directory
+    output_file: Option<String>,
+    // regexes telling from the output whether the job succeeded, for tools always exiting 0
+    success_pattern: Option<String>,
+    failure_pattern: Option<String>,
+    pre_hook: Option<JobHook>,
+    post_hook: Option<JobHook>,
+    // failures of the hooks during the last run
+    hook_errors: Arc<Mutex<Vec<String>>>,
+    // a fatal post hook failed, the job failed but keeps the output of its action
+    post_hook_failed: Arc<AtomicBool>,
+    // local paths of the downloaded inputs, by name
+    staged_inputs: Arc<Mutex<HashMap<String, PathBuf>>>,
+    // directory of the job's own files, once created
+    work_dir: Arc<Mutex<Option<PathBuf>>>,
     result: Arc<Mutex<Option<String>>>,
+    // contents of the output file, redacted like the result
+    output_file_contents: Arc<Mutex<Option<String>>>,
+    // log of the job once written, and the offset of the output in it
+    log: Arc<Mutex<Option<(PathBuf, u64)>>>,
+    // what the action printed on stderr, redacted like the result
+    stderr: Arc<Mutex<Option<String>>>,
+    // result parsed by the parser registered for the action's variant, if any
+    structured_result: Arc<Mutex<Option<Value>>>,
     submitted: Arc<AtomicBool>,
+    skip_reason: Arc<Mutex<Option<SkipReason>>>,
+    // the action ran successfully but printed nothing
+    empty_output: Arc<AtomicBool>,
+    // the action was killed because the cycle's time budget elapsed
+    budget_exceeded: Arc<AtomicBool>,
+    // the action was killed because the server rejected the renewal of its lease. the server may
+    // have handed the job to another agent, so it is not reported
+    lease_lost: Arc<AtomicBool>,
     success: Arc<Mutex<Option<bool>>>,
+    // when the agent fetched the job, on the local clock like started_at and completed_at
+    fetched_at: Arc<Mutex<Option<DateTime<Utc>>>>,
+    // why the job is not started for now, and whether the server was told about it
+    deferral: Arc<Mutex<Option<Deferral>>>,
+    deferral_submitted: Arc<AtomicBool>,
+    // when the report was sent and when the server acknowledged receiving it
+    reported_at: Arc<Mutex<Option<DateTime<Utc>>>>,
+    received_at: Arc<Mutex<Option<DateTime<Utc>>>>,
+    // sizes of the output streams before any truncation
+    stdout_bytes: Arc<Mutex<Option<u64>>>,
+    stderr_bytes: Arc<Mutex<Option<u64>>>,
+    exit_code: Arc<Mutex<Option<i32>>>,
 }
 
+// name of the results field of the reports unless configured otherwise (`--results-field`)
+pub const DEFAULT_RESULTS_FIELD: &str = "results";
+
 // simpler structures to map API endpoints payload (easier for JOSN serialization/deserialization
 // and smaller payloads)
 #[derive(Debug, Serialize)]
 pub struct JobPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<JobStatus>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub started_at: Option<DateTime<Utc>>,
 
@@ -42,8 +207,131 @@ pub struct JobPatch {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub results: Option<String>,
 
+    // how `results` is encoded, absent when sent as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results_encoding: Option<String>,
+
+    // references of the uploaded artifacts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifacts: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub structured_results: Option<Value>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub success: Option<bool>,
+
+    // set instead of `success` when it cannot be determined
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_unknown_reason: Option<SuccessUnknownReason>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<SkipReason>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_at: Option<DateTime<Utc>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_output: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_exceeded: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<JobTimings>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout_bytes: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr_bytes: Option<u64>,
+
+    // contents of the file the tool wrote its results to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_file: Option<String>,
+
+    // how `output_file` is encoded, absent when sent as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_file_encoding: Option<String>,
+
+    // sent even when the job succeeded, as it often holds warnings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+
+    // failures of the hooks that did not fail the job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_errors: Option<Vec<String>>,
+}
+
+// where the time of a job went, in milliseconds
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct JobTimings {
+    pub queued_ms: Option<i64>,
+    pub executing_ms: Option<i64>,
+    pub reporting_ms: Option<i64>,
+}
+
+// sent right after fetching a job so the server does not hand it to another agent
+#[derive(Debug, Serialize)]
+pub struct JobClaim {
+    pub status: JobStatus,
+    pub agent_id: Option<Uuid>,
+    pub claimed_at: DateTime<Utc>,
+}
+
+// sent to create a job the agent scheduled on its own (`--local-job`), so it can be claimed and
+// reported like the jobs of the server
+#[derive(Debug, Serialize)]
+pub struct JobCreation<'a> {
+    pub id: &'a Uuid,
+    pub name: &'a str,
+    pub agent_id: &'a Uuid,
+    pub action: &'a Action,
+    pub created_at: DateTime<Utc>,
+}
+
+// sent periodically while a job runs so the server does not hand it to another agent when the
+// scan lasts long without any other news from the agent
+#[derive(Debug, Serialize)]
+pub struct JobLeaseRenewal {
+    pub agent_id: Option<Uuid>,
+    pub lease_renewed_at: DateTime<Utc>,
+}
+
+// why a claimed job is not started yet
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeferReason {
+    MaintenanceWindow,
+    // the disk is almost full
+    LowDiskSpace,
+}
+
+// postponement of a job, until a known time or not
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deferral {
+    pub reason: DeferReason,
+    pub until: Option<DateTime<Utc>>,
+}
+
+// sent for a claimed job that is not started for now, it stays pending rather than being
+// reported as failed
+#[derive(Debug, Serialize)]
+pub struct JobDeferral {
+    pub status: JobStatus,
+    pub deferred: bool,
+    pub defer_reason: DeferReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deferred_until: Option<DateTime<Utc>>,
 }
 
 impl Job {
@@ -59,12 +347,63 @@ impl Job {
             completed_at: Arc::new(Mutex::new(None)),
             action: Action::new(cmd, args),
             agent_id: Uuid::new_v4(),
+            depends_on: None,
+            condition: JobCondition::default(),
+            redactions: vec![],
+            inputs: vec![],
+            status: JobStatus::default(),
+            artifacts: vec![],
+            output_file: None,
+            success_pattern: None,
+            failure_pattern: None,
+            pre_hook: None,
+            post_hook: None,
+            hook_errors: Arc::new(Mutex::new(vec![])),
+            post_hook_failed: Arc::new(AtomicBool::new(false)),
+            staged_inputs: Arc::new(Mutex::new(HashMap::new())),
+            work_dir: Arc::new(Mutex::new(None)),
             result: Arc::new(Mutex::new(None)),
+            output_file_contents: Arc::new(Mutex::new(None)),
+            log: Arc::new(Mutex::new(None)),
+            stderr: Arc::new(Mutex::new(None)),
+            structured_result: Arc::new(Mutex::new(None)),
             submitted: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            skip_reason: Arc::new(Mutex::new(None)),
+            empty_output: Arc::new(AtomicBool::new(false)),
+            budget_exceeded: Arc::new(AtomicBool::new(false)),
+            lease_lost: Arc::new(AtomicBool::new(false)),
             success: Arc::new(Mutex::new(Some(false))),
+            fetched_at: Arc::new(Mutex::new(None)),
+            deferral: Arc::new(Mutex::new(None)),
+            deferral_submitted: Arc::new(AtomicBool::new(false)),
+            reported_at: Arc::new(Mutex::new(None)),
+            received_at: Arc::new(Mutex::new(None)),
+            stdout_bytes: Arc::new(Mutex::new(None)),
+            exit_code: Arc::new(Mutex::new(None)),
+            stderr_bytes: Arc::new(Mutex::new(None)),
         }
     }
 
+    // job created by the agent itself, not by the server
+    pub fn local(name: String, action: Action, agent_id: Uuid, created_at: DateTime<Utc>) -> Self {
+        Job::new_internal(
+            Uuid::new_v4(),
+            name,
+            None,
+            created_at,
+            None,
+            None,
+            action,
+            agent_id,
+            None,
+            JobCondition::default(),
+            vec![],
+            None,
+            Some(false),
+            false,
+        )
+    }
+
     // this function is only intended to be used by unit tests. this is why it is private
     #[allow(clippy::too_many_arguments)]
     fn new_internal(
@@ -76,8 +415,12 @@ impl Job {
         completed_at: Option<DateTime<Utc>>,
         action: Action,
         agent_id: Uuid,
+        depends_on: Option<Uuid>,
+        condition: JobCondition,
+        redactions: Vec<String>,
         result: Option<String>,
         success: Option<bool>,
+        submitted: bool,
     ) -> Self {
         Job {
             id,
@@ -88,9 +431,40 @@ impl Job {
             completed_at: Arc::new(Mutex::new(completed_at)),
             action,
             agent_id,
+            depends_on,
+            condition,
+            redactions,
+            inputs: vec![],
+            status: JobStatus::default(),
+            artifacts: vec![],
+            output_file: None,
+            success_pattern: None,
+            failure_pattern: None,
+            pre_hook: None,
+            post_hook: None,
+            hook_errors: Arc::new(Mutex::new(vec![])),
+            post_hook_failed: Arc::new(AtomicBool::new(false)),
+            staged_inputs: Arc::new(Mutex::new(HashMap::new())),
+            work_dir: Arc::new(Mutex::new(None)),
             result: Arc::new(Mutex::new(result)),
-            submitted: Arc::new(AtomicBool::new(false)),
+            output_file_contents: Arc::new(Mutex::new(None)),
+            log: Arc::new(Mutex::new(None)),
+            stderr: Arc::new(Mutex::new(None)),
+            structured_result: Arc::new(Mutex::new(None)),
+            submitted: Arc::new(AtomicBool::new(submitted)),
+            skip_reason: Arc::new(Mutex::new(None)),
+            empty_output: Arc::new(AtomicBool::new(false)),
+            budget_exceeded: Arc::new(AtomicBool::new(false)),
+            lease_lost: Arc::new(AtomicBool::new(false)),
             success: Arc::new(Mutex::new(success)),
+            fetched_at: Arc::new(Mutex::new(None)),
+            deferral: Arc::new(Mutex::new(None)),
+            deferral_submitted: Arc::new(AtomicBool::new(false)),
+            reported_at: Arc::new(Mutex::new(None)),
+            received_at: Arc::new(Mutex::new(None)),
+            stdout_bytes: Arc::new(Mutex::new(None)),
+            exit_code: Arc::new(Mutex::new(None)),
+            stderr_bytes: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -102,7 +476,59 @@ impl Job {
         self.submitted.store(val, Ordering::Relaxed)
     }
 
+    pub fn get_depends_on(&self) -> Option<&Uuid> {
+        self.depends_on.as_ref()
+    }
+
+    pub fn get_condition(&self) -> &JobCondition {
+        &self.condition
+    }
+
+    // mark the job as completed without running it (or without letting it finish), `message`
+    // being reported as its result
+    pub fn skip(&self, reason: SkipReason, message: String) {
+        *self.skip_reason.lock().unwrap() = Some(reason);
+        self.set_result(message);
+        self.set_success(false);
+        self.set_completed_at();
+    }
+
+    pub fn is_skipped(&self) -> bool {
+        self.get_skip_reason().is_some()
+    }
+
+    pub fn get_skip_reason(&self) -> Option<SkipReason> {
+        *self.skip_reason.lock().unwrap()
+    }
+
+    // None when the job is known to have succeeded or failed
+    pub fn success_unknown_reason(&self) -> Option<SuccessUnknownReason> {
+        match self.get_skip_reason() {
+            Some(SkipReason::Cancelled) => Some(SuccessUnknownReason::Cancelled),
+            Some(_) => Some(SuccessUnknownReason::NotRun),
+            None if self.is_budget_exceeded() => Some(SuccessUnknownReason::BudgetExceeded),
+            None => None,
+        }
+    }
+
+    pub fn is_budget_exceeded(&self) -> bool {
+        self.budget_exceeded.load(Ordering::Relaxed)
+    }
+
+    pub fn is_lease_lost(&self) -> bool {
+        self.lease_lost.load(Ordering::Relaxed)
+    }
+
+    pub fn has_empty_output(&self) -> bool {
+        self.empty_output.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
     pub fn run(&self) -> Result<String, std::io::Error> {
+        self.run_with_options(&RunOptions::default())
+    }
+
+    pub fn run_with_options(&self, options: &RunOptions) -> Result<String, std::io::Error> {
         // use mutex in a scope it right after the end of the scope, it is dropped by default
         // (closed if you will). this is a common practice in the Rust community (also propsed by
         // the linter "clippy")
@@ -110,14 +536,287 @@ impl Job {
             let mut guard = self.started_at.lock().unwrap();
             *guard = Some(Utc::now());
         }
-        info!("Running task: {}", &self.action);
-        self.action.run()
+        self.hook_errors.lock().unwrap().clear();
+        self.post_hook_failed.store(false, Ordering::Relaxed);
+        // the tool writes its results in the job's directory, where `{output_file}` points to
+        let output_file = match &self.output_file {
+            Some(name) => Some((self.create_work_dir(options)?, name)),
+            None => None,
+        };
+        let options = &match &output_file {
+            Some((dir, name)) => {
+                let mut variables = options.variables.clone();
+                variables.insert(
+                    "output_file".to_string(),
+                    dir.join(name).to_string_lossy().into_owned(),
+                );
+                RunOptions {
+                    variables,
+                    ..options.clone()
+                }
+            }
+            None => options.clone(),
+        };
+        let action = self
+            .action
+            .with_inputs(&self.staged_inputs.lock().unwrap())
+            .with_variables(&options.variables, options.strict_variables)?;
+        let action = match &options.rate_limit {
+            Some(limit) => action.with_rate_limit(limit),
+            None => action,
+        };
+        if let Some(hook) = &self.pre_hook {
+            self.run_hook("pre", hook, options)?;
+        }
+        // a file left by a previous run must not be taken for the output of this one
+        *self.output_file_contents.lock().unwrap() = None;
+        if let Some((dir, name)) = &output_file {
+            match std::fs::remove_file(dir.join(name)) {
+                Ok(()) => debug!("Removed stale output file {}", name),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(std::io::Error::new(
+                        err.kind(),
+                        format!("could not remove stale output file {}: {}", name, err),
+                    ));
+                }
+            }
+        }
+        info!("Running task: {}", &action);
+        let output = action.execute(options).inspect_err(|err| {
+            // the action may also have timed out on its own
+            if err.kind() == std::io::ErrorKind::TimedOut
+                && options
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.budget_exceeded.store(true, Ordering::Relaxed);
+            }
+            if err.kind() == std::io::ErrorKind::ConnectionAborted && options.is_lease_lost() {
+                self.lease_lost.store(true, Ordering::Relaxed);
+            }
+        })?;
+        {
+            *self.stdout_bytes.lock().unwrap() = Some(output.stdout_bytes);
+            *self.exit_code.lock().unwrap() = output.exit_code;
+            *self.stderr_bytes.lock().unwrap() = Some(output.stderr_bytes);
+        }
+        self.empty_output
+            .store(output.is_empty(), Ordering::Relaxed);
+        *self.stderr.lock().unwrap() = Some(self.redact(output.stderr)?);
+        if let Some((dir, name)) = &output_file {
+            let contents = read_owned_file(dir, name).map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("could not read output file {}: {}", name, err),
+                )
+            })?;
+            *self.output_file_contents.lock().unwrap() =
+                Some(self.redact(String::from_utf8_lossy(&contents).into_owned())?);
+        }
+        let stdout = self.redact(output.stdout)?;
+
+        // the action already ran, its output is kept even when a fatal post hook fails
+        if let Some(hook) = &self.post_hook
+            && let Err(err) = self.run_hook("post", hook, options)
+        {
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+            warn!("{}", err);
+            self.hook_errors.lock().unwrap().push(err.to_string());
+            self.post_hook_failed.store(true, Ordering::Relaxed);
+        }
+
+        Ok(stdout)
+    }
+
+    // the hooks run like the job's action, with their own command
+    pub fn hook_actions(&self) -> impl Iterator<Item = Action> + '_ {
+        [&self.pre_hook, &self.post_hook]
+            .into_iter()
+            .flatten()
+            .map(|hook| self.hook_action(hook))
+    }
+
+    fn hook_action(&self, hook: &JobHook) -> Action {
+        self.action
+            .with_command(hook.cmd.clone(), hook.args.clone())
+    }
+
+    // a hook failing (or killed by a shutdown) is an error when it is fatal, otherwise its
+    // failure is recorded for the report and the job goes on
+    fn run_hook(
+        &self,
+        stage: &str,
+        hook: &JobHook,
+        options: &RunOptions,
+    ) -> Result<(), std::io::Error> {
+        let action = self
+            .hook_action(hook)
+            .with_inputs(&self.staged_inputs.lock().unwrap())
+            .with_variables(&options.variables, options.strict_variables)?;
+        info!("Running {} hook: {}", stage, &action);
+
+        let failure = match action.execute(options) {
+            Ok(output) if action.is_success_exit_code(output.exit_code) => return Ok(()),
+            Ok(output) => match output.exit_code {
+                Some(code) => format!("{} hook {:?} exited with code {}", stage, hook.cmd, code),
+                None => format!("{} hook {:?} was killed", stage, hook.cmd),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => return Err(err),
+            Err(err) => format!("{} hook {:?} failed: {}", stage, hook.cmd, err),
+        };
+
+        if hook.fatal {
+            return Err(std::io::Error::other(failure));
+        }
+        warn!("{}", failure);
+        self.hook_errors.lock().unwrap().push(failure);
+        Ok(())
+    }
+
+    pub fn get_hook_errors(&self) -> Vec<String> {
+        self.hook_errors.lock().unwrap().clone()
+    }
+
+    // replace every match of the job's redaction rules so the original output never leaves the
+    // host. an invalid rule fails the job rather than leaking the output
+    fn redact(&self, output: String) -> Result<String, std::io::Error> {
+        let mut redacted = output;
+
+        for pattern in &self.redactions {
+            let regex = Regex::new(pattern).map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid redaction rule {:?}: {}", pattern, err),
+                )
+            })?;
+            redacted = regex.replace_all(&redacted, REDACTED).into_owned();
+        }
+
+        Ok(redacted)
+    }
+
+    pub fn get_status(&self) -> &JobStatus {
+        &self.status
+    }
+
+    // the server already has the outcome of this job (from a previous run of the agent for
+    // instance), it must not be run again. a skipped job is not done: the agent skipping it may
+    // have lacked its tool or scope, another one (or a later run) may still run it
+    pub fn is_completed_server_side(&self) -> bool {
+        matches!(self.status, JobStatus::Completed | JobStatus::Failed)
+    }
+
+    // the server lists the job as running for this agent: it was claimed in an earlier cycle (or
+    // run of the agent), claiming it again would run it twice
+    pub fn is_claimed_by(&self, agent_id: Option<Uuid>) -> bool {
+        self.status == JobStatus::Running && agent_id == Some(self.agent_id)
+    }
+
+    pub fn set_log(&self, path: PathBuf, offset: u64) {
+        *self.log.lock().unwrap() = Some((path, offset));
+    }
+
+    pub fn get_log(&self) -> Option<(PathBuf, u64)> {
+        self.log.lock().unwrap().clone()
+    }
+
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn set_output_file_contents(&self, contents: String) {
+        *self.output_file_contents.lock().unwrap() = Some(contents);
+    }
+
+    pub fn get_output_file_contents(&self) -> Option<String> {
+        self.output_file_contents.lock().unwrap().clone()
+    }
+
+    pub fn get_artifacts(&self) -> &Vec<String> {
+        &self.artifacts
+    }
+
+    pub fn get_inputs(&self) -> &Vec<JobInput> {
+        &self.inputs
+    }
+
+    // directory of the job's own files (its staged inputs and output file), created on first use
+    // in the agent's work directory. the directory must not exist yet, so one created in advance
+    // by another local user (or a link to another directory) is never written to. only the agent
+    // can enter it, and the group of the user running the actions when there is one, which may
+    // write its output file there
+    pub fn create_work_dir(&self, options: &RunOptions) -> std::io::Result<PathBuf> {
+        let mut guard = self.work_dir.lock().unwrap();
+        if let Some(dir) = guard.as_ref() {
+            return Ok(dir.clone());
+        }
+
+        let base = options.work_dir.clone().unwrap_or_else(std::env::temp_dir);
+        std::fs::create_dir_all(&base)?;
+        let dir = base.join(format!("agent-job-{}-{}", self.id, Uuid::new_v4().simple()));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+        // removed along with the job's files from now on
+        *guard = Some(dir.clone());
+
+        #[cfg(unix)]
+        if let Some(user) = &options.run_as_user {
+            use std::os::unix::fs::PermissionsExt;
+
+            let user = crate::privilege::lookup_user(user)?;
+            std::os::unix::fs::chown(&dir, None, Some(user.gid))?;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o770))?;
+        }
+
+        Ok(dir)
+    }
+
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn get_work_dir(&self) -> Option<PathBuf> {
+        self.work_dir.lock().unwrap().clone()
+    }
+
+    pub fn set_staged_input(&self, name: String, path: PathBuf) {
+        self.staged_inputs.lock().unwrap().insert(name, path);
+    }
+
+    // remove the job's directory, and the inputs downloaded to it, once the job ran
+    pub fn cleanup_work_dir(&self) {
+        self.staged_inputs.lock().unwrap().clear();
+        let Some(dir) = self.work_dir.lock().unwrap().take() else {
+            return;
+        };
+
+        if let Err(err) = std::fs::remove_dir_all(&dir) {
+            warn!("Could not remove the directory of job {}: {}", self.id, err);
+        }
+    }
+
+    // for the job transformers, used by unit tests
+    #[allow(dead_code)]
+    pub fn set_action(&mut self, action: Action) {
+        self.action = action;
     }
 
     pub fn get_action(&self) -> &Action {
         &self.action
     }
 
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn set_hooks(&mut self, pre_hook: Option<JobHook>, post_hook: Option<JobHook>) {
+        self.pre_hook = pre_hook;
+        self.post_hook = post_hook;
+    }
+
+    pub fn get_agent_id(&self) -> &Uuid {
+        &self.agent_id
+    }
+
     pub fn get_id(&self) -> &Uuid {
         &self.id
     }
@@ -127,6 +826,15 @@ impl Job {
         *guard = Some(val);
     }
 
+    pub fn set_structured_result(&self, val: Value) {
+        let mut guard = self.structured_result.lock().unwrap();
+        *guard = Some(val);
+    }
+
+    pub fn get_structured_result(&self) -> Option<Value> {
+        self.structured_result.lock().unwrap().clone()
+    }
+
     pub fn set_completed_at(&self) {
         let mut completed_guard = self.completed_at.lock().unwrap();
         *completed_guard = Some(Utc::now());
@@ -141,10 +849,184 @@ impl Job {
         *self.completed_at.lock().unwrap()
     }
 
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // short description of where the job is in its lifecycle
+    pub fn state(&self) -> &'static str {
+        if self.is_skipped() {
+            "skipped"
+        } else if self.is_completed() {
+            match (self.was_submitted(), self.is_success()) {
+                (true, _) => "reported",
+                (false, true) => "succeeded",
+                (false, false) => "failed",
+            }
+        } else if self.is_running() {
+            "running"
+        } else if self.get_deferral().is_some() {
+            "deferred"
+        } else {
+            "pending"
+        }
+    }
+
     pub fn get_started_at(&self) -> Option<DateTime<Utc>> {
         *self.started_at.lock().unwrap()
     }
 
+    pub fn get_created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn set_fetched_at(&self, val: DateTime<Utc>) {
+        let mut guard = self.fetched_at.lock().unwrap();
+        *guard = Some(val);
+    }
+
+    pub fn get_fetched_at(&self) -> Option<DateTime<Utc>> {
+        *self.fetched_at.lock().unwrap()
+    }
+
+    // postpone the job, the server is told again when it is deferred for another reason or to
+    // another time
+    pub fn defer(&self, deferral: Deferral) {
+        let mut guard = self.deferral.lock().unwrap();
+        if *guard != Some(deferral) {
+            *guard = Some(deferral);
+            self.deferral_submitted.store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_deferral(&self) -> Option<Deferral> {
+        *self.deferral.lock().unwrap()
+    }
+
+    pub fn was_deferral_submitted(&self) -> bool {
+        self.deferral_submitted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_deferral_submitted(&self, val: bool) {
+        self.deferral_submitted.store(val, Ordering::Relaxed)
+    }
+
+    // time spent queued (fetched to started), executing (started to completed) and reporting
+    // (completed to `reported_at`, on the local clock)
+    pub fn timings(&self, reported_at: DateTime<Utc>) -> JobTimings {
+        let millis = |from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>| {
+            Some((to? - from?).num_milliseconds().max(0))
+        };
+        let (fetched, started, completed) = (
+            self.get_fetched_at(),
+            self.get_started_at(),
+            self.get_completed_at(),
+        );
+
+        JobTimings {
+            queued_ms: millis(fetched, started),
+            executing_ms: millis(started, completed),
+            reporting_ms: millis(completed, Some(reported_at)),
+        }
+    }
+
+    pub fn set_reported_at(&self, val: DateTime<Utc>) {
+        let mut guard = self.reported_at.lock().unwrap();
+        *guard = Some(val);
+    }
+
+    pub fn get_reported_at(&self) -> Option<DateTime<Utc>> {
+        *self.reported_at.lock().unwrap()
+    }
+
+    pub fn set_received_at(&self, val: DateTime<Utc>) {
+        let mut guard = self.received_at.lock().unwrap();
+        *guard = Some(val);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_received_at(&self) -> Option<DateTime<Utc>> {
+        *self.received_at.lock().unwrap()
+    }
+
+    // type of the results as submitted: compressed results are gzip whatever the output, the
+    // raw output is JSON when the tool printed some and plain text otherwise. parsed results
+    // are reported apart, in the structured results
+    pub fn content_type(&self, results_encoding: Option<&str>) -> &'static str {
+        if results_encoding.is_some() {
+            return CONTENT_TYPE_GZIP;
+        }
+
+        let result = self.result.lock().unwrap();
+        let is_json = result.as_deref().is_some_and(|result| {
+            result.trim_start().starts_with(['{', '['])
+                && serde_json::from_str::<serde::de::IgnoredAny>(result).is_ok()
+        });
+        if is_json {
+            CONTENT_TYPE_JSON
+        } else {
+            CONTENT_TYPE_TEXT
+        }
+    }
+
+    pub fn get_exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock().unwrap()
+    }
+
+    // the action ran and exited with one of the codes it declares as successful
+    pub fn exited_successfully(&self) -> bool {
+        self.action.is_success_exit_code(self.get_exit_code())
+    }
+
+    // outcome of the job given its output: when exactly one of the success and failure patterns
+    // matches it decides, otherwise the exit code does
+    pub fn determine_success(&self, output: &str) -> bool {
+        if self.post_hook_failed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let matches = |pattern: &Option<String>| {
+            let Some(pattern) = pattern else {
+                return false;
+            };
+            match Regex::new(pattern) {
+                Ok(regex) => regex.is_match(output),
+                Err(err) => {
+                    warn!(
+                        "Ignoring invalid pattern {:?} of job {}: {}",
+                        pattern, self.id, err
+                    );
+                    false
+                }
+            }
+        };
+
+        match (
+            matches(&self.success_pattern),
+            matches(&self.failure_pattern),
+        ) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => self.exited_successfully(),
+        }
+    }
+
+    pub fn get_stdout_bytes(&self) -> Option<u64> {
+        *self.stdout_bytes.lock().unwrap()
+    }
+
+    pub fn get_stderr_bytes(&self) -> Option<u64> {
+        *self.stderr_bytes.lock().unwrap()
+    }
+
+    // None when the action printed nothing on stderr
+    pub fn get_stderr(&self) -> Option<String> {
+        self.stderr
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|stderr| !stderr.is_empty())
+    }
+
     pub fn get_result_as_string(&self) -> Option<String> {
         self.result.lock().unwrap().as_ref().map(|r| r.to_string())
     }
@@ -153,10 +1035,19 @@ impl Job {
         self.success.lock().unwrap().unwrap_or(false)
     }
 
-    // used by unit tests
-    #[allow(dead_code)]
+    // the job finished, whether it succeeded, failed or was skipped. a job failing before
+    // producing any output is completed too
     pub fn is_completed(&self) -> bool {
-        self.completed_at.lock().unwrap().is_some() && self.result.lock().unwrap().is_some()
+        self.completed_at.lock().unwrap().is_some()
+    }
+
+    // the job was started, it may have completed since. skipped jobs are never started
+    pub fn is_started(&self) -> bool {
+        self.started_at.lock().unwrap().is_some()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.is_started() && !self.is_completed()
     }
 }
 
@@ -171,45 +1062,120 @@ impl fmt::Debug for Job {
             .field("completed_at", &self.completed_at)
             .field("action", &self.action)
             .field("agent_id", &self.agent_id)
+            .field("depends_on", &self.depends_on)
+            .field("condition", &self.condition)
+            .field("redactions", &self.redactions)
             .field("results", &self.result)
+            .field("output_file", &self.output_file)
+            .field("success_pattern", &self.success_pattern)
+            .field("failure_pattern", &self.failure_pattern)
+            .field("output_file_contents", &self.output_file_contents)
+            .field("stderr", &self.stderr)
+            .field("structured_results", &self.structured_result)
             .field("success", &self.success)
+            .field("skip_reason", &self.skip_reason)
+            .field("empty_output", &self.empty_output)
+            .field("budget_exceeded", &self.budget_exceeded)
+            .field("lease_lost", &self.lease_lost)
+            .field("fetched_at", &self.fetched_at)
+            .field("deferral", &self.deferral)
+            .field("deferral_submitted", &self.deferral_submitted)
+            .field("reported_at", &self.reported_at)
+            .field("received_at", &self.received_at)
+            .field("stdout_bytes", &self.stdout_bytes)
+            .field("exit_code", &self.exit_code)
+            .field("stderr_bytes", &self.stderr_bytes)
+            .field("pre_hook", &self.pre_hook)
+            .field("post_hook", &self.post_hook)
+            .field("hook_errors", &self.hook_errors)
+            .field("post_hook_failed", &self.post_hook_failed)
             .finish()
     }
 }
 
 // Custom JSON serialization / deserialization functions
-impl Serialize for Job {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        use serde::ser::SerializeStruct;
 
-        let mut s = serializer.serialize_struct("Job", 8)?;
-        s.serialize_field("id", &self.id)?;
-        s.serialize_field("name", &self.name)?;
-        s.serialize_field("description", &self.description)?;
-        s.serialize_field("created_at", &self.created_at.to_rfc3339())?;
-        let started_at_guard = self.started_at.lock().unwrap();
-        s.serialize_field(
-            "started_at",
-            &started_at_guard.as_ref().map(|t| t.to_rfc3339()),
-        )?;
+// the job as serialized, borrowing its state from behind the locks. deriving it keeps the
+// serialized fields and their count in sync
+#[derive(Serialize)]
+struct JobSnapshot<'a> {
+    id: Uuid,
+    name: &'a str,
+    description: &'a Option<String>,
+    #[serde(serialize_with = "serialize_rfc3339")]
+    created_at: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_optional_rfc3339")]
+    started_at: Option<DateTime<Utc>>,
+    #[serde(serialize_with = "serialize_optional_rfc3339")]
+    completed_at: Option<DateTime<Utc>>,
+    action: &'a Action,
+    agent_id: Uuid,
+    depends_on: Option<Uuid>,
+    condition: &'a JobCondition,
+    inputs: &'a [JobInput],
+    status: &'a JobStatus,
+    artifacts: &'a [String],
+    output_file: &'a Option<String>,
+    success_pattern: &'a Option<String>,
+    failure_pattern: &'a Option<String>,
+    pre_hook: &'a Option<JobHook>,
+    post_hook: &'a Option<JobHook>,
+    results: &'a Option<String>,
+    success: Option<bool>,
+    submitted: bool,
+}
 
-        let completed_at_guard = self.completed_at.lock().unwrap();
-        s.serialize_field(
-            "completed_at",
-            &completed_at_guard.as_ref().map(|t| t.to_rfc3339()),
-        )?;
+// timestamps are sent as `to_rfc3339` formats them (with a `+00:00` offset)
+fn serialize_rfc3339<S>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&time.to_rfc3339())
+}
 
-        s.serialize_field("action", &self.action)?;
-        s.serialize_field("agent_id", &self.agent_id)?;
-        let output_guard = self.result.lock().unwrap();
-        s.serialize_field("results", &*output_guard)?;
+fn serialize_optional_rfc3339<S>(
+    time: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match time {
+        Some(time) => serialize_rfc3339(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
 
-        let success_guard = self.success.lock().unwrap();
-        s.serialize_field("success", &*success_guard)?;
-        s.end()
+impl Serialize for Job {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let result = self.result.lock().unwrap();
+        JobSnapshot {
+            id: self.id,
+            name: &self.name,
+            description: &self.description,
+            created_at: self.created_at,
+            started_at: *self.started_at.lock().unwrap(),
+            completed_at: *self.completed_at.lock().unwrap(),
+            action: &self.action,
+            agent_id: self.agent_id,
+            depends_on: self.depends_on,
+            condition: &self.condition,
+            inputs: &self.inputs,
+            status: &self.status,
+            artifacts: &self.artifacts,
+            output_file: &self.output_file,
+            success_pattern: &self.success_pattern,
+            failure_pattern: &self.failure_pattern,
+            pre_hook: &self.pre_hook,
+            post_hook: &self.post_hook,
+            results: &result,
+            success: *self.success.lock().unwrap(),
+            submitted: self.was_submitted(),
+        }
+        .serialize(serializer)
     }
 }
 
@@ -228,26 +1194,171 @@ impl<'de> Deserialize<'de> for Job {
             started_at: Option<DateTime<Utc>>,
             completed_at: Option<DateTime<Utc>>,
             action: Action,
+            #[serde(default)]
+            depends_on: Option<Uuid>,
+            #[serde(default, deserialize_with = "deserialize_condition")]
+            condition: JobCondition,
+            #[serde(default, deserialize_with = "deserialize_redactions")]
+            redactions: Vec<String>,
+            #[serde(default)]
+            inputs: Vec<JobInput>,
+            #[serde(default, deserialize_with = "deserialize_status")]
+            status: JobStatus,
+            #[serde(default)]
+            artifacts: Vec<String>,
+            #[serde(default, deserialize_with = "deserialize_output_file")]
+            output_file: Option<String>,
+            #[serde(default)]
+            success_pattern: Option<String>,
+            #[serde(default)]
+            failure_pattern: Option<String>,
+            #[serde(default)]
+            pre_hook: Option<JobHook>,
+            #[serde(default)]
+            post_hook: Option<JobHook>,
+            // serialized by the agent as `results`
+            #[serde(alias = "results")]
             result: Option<String>,
+            #[serde(default, deserialize_with = "deserialize_success")]
             success: Option<bool>,
+            // only present in jobs serialized by the agent itself
+            #[serde(default)]
+            submitted: bool,
         }
 
         let helper = JobHelper::deserialize(deserializer)?;
-        Ok(Job::new_internal(
-            helper.id,
-            helper.name,
-            helper.description,
-            helper.created_at,
-            helper.started_at,
-            helper.completed_at,
-            helper.action,
-            helper.agent_id,
-            helper.result,
-            helper.success,
-        ))
+        Ok(Job {
+            inputs: helper.inputs,
+            status: helper.status,
+            artifacts: helper.artifacts,
+            output_file: helper.output_file,
+            success_pattern: helper.success_pattern,
+            failure_pattern: helper.failure_pattern,
+            pre_hook: helper.pre_hook,
+            post_hook: helper.post_hook,
+            ..Job::new_internal(
+                helper.id,
+                helper.name,
+                helper.description,
+                helper.created_at,
+                helper.started_at,
+                helper.completed_at,
+                helper.action,
+                helper.agent_id,
+                helper.depends_on,
+                helper.condition,
+                helper.redactions,
+                helper.result,
+                helper.success,
+                helper.submitted,
+            )
+        })
+    }
+}
+
+// a null status means the default one
+fn deserialize_status<'de, D>(deserializer: D) -> Result<JobStatus, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<JobStatus>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// a null condition means the default one
+fn deserialize_condition<'de, D>(deserializer: D) -> Result<JobCondition, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<JobCondition>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// representations of `success` sent by the API
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LenientBool {
+    Bool(bool),
+    Number(i64),
+    Text(String),
+}
+
+// the API does not always send `success` as a boolean: the strings "true"/"false" and the
+// numbers 1/0 are accepted too, anything else is an error
+fn deserialize_success<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let success = match Option::<LenientBool>::deserialize(deserializer) {
+        Ok(success) => success,
+        Err(_) => return Err(serde::de::Error::custom("invalid success value")),
+    };
+
+    match success {
+        None => Ok(None),
+        Some(LenientBool::Bool(success)) => Ok(Some(success)),
+        Some(LenientBool::Number(1)) => Ok(Some(true)),
+        Some(LenientBool::Number(0)) => Ok(Some(false)),
+        Some(LenientBool::Text(text)) if text.eq_ignore_ascii_case("true") => Ok(Some(true)),
+        Some(LenientBool::Text(text)) if text.eq_ignore_ascii_case("false") => Ok(Some(false)),
+        Some(LenientBool::Number(number)) => Err(serde::de::Error::custom(format!(
+            "invalid success value {}, expected 0 or 1",
+            number
+        ))),
+        Some(LenientBool::Text(text)) => Err(serde::de::Error::custom(format!(
+            "invalid success value {:?}, expected \"true\" or \"false\"",
+            text
+        ))),
     }
 }
 
+fn deserialize_redactions<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Vec<String>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// the output file is written by the tool, possibly as another user, and read by the agent: it
+// must stay in the job's directory
+fn deserialize_output_file<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let output_file = Option::<String>::deserialize(deserializer)?;
+    if let Some(name) = &output_file {
+        let mut components = std::path::Path::new(name).components().peekable();
+        if components.peek().is_none()
+            || !components.all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(serde::de::Error::custom(format!(
+                "invalid output_file {:?}: expected a relative path in the job's directory",
+                name
+            )));
+        }
+    }
+    Ok(output_file)
+}
+
+// read a file of the job's directory, which the tool may have replaced by a link to a file it
+// could not read itself
+fn read_owned_file(dir: &Path, name: &str) -> std::io::Result<Vec<u8>> {
+    let path = dir.join(name).canonicalize()?;
+    if !path.starts_with(dir.canonicalize()?) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "outside of the job's directory",
+        ));
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    // the file may still be swapped for a link after it was resolved
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+    let mut contents = Vec::new();
+    std::io::Read::read_to_end(&mut options.open(path)?, &mut contents)?;
+    Ok(contents)
+}
+
 impl Display for Job {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} {}", &self.id, &self.action)
@@ -257,6 +1368,7 @@ impl Display for Job {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ratelimit::RateLimit;
     use chrono::Utc;
     use std::str::FromStr;
     use uuid::Uuid;
@@ -295,6 +1407,46 @@ mod tests {
         assert!(job.was_submitted());
     }
 
+    #[test]
+    fn test_started_job_is_running() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        assert!(!job.is_started());
+        assert!(!job.is_running());
+
+        *job.started_at.lock().unwrap() = Some(Utc::now());
+
+        assert!(job.is_started());
+        assert!(job.is_running());
+        assert!(!job.is_completed());
+    }
+
+    #[test]
+    fn test_completed_job_is_no_longer_running() {
+        // Given a successful job and one failing before producing any output
+        let succeeded = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        let failed = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        for job in [&succeeded, &failed] {
+            *job.started_at.lock().unwrap() = Some(Utc::now());
+        }
+
+        // When
+        succeeded.set_result("ok".to_string());
+        succeeded.set_success(true);
+        succeeded.set_completed_at();
+        failed.set_success(false);
+        failed.set_completed_at();
+
+        // Then
+        for job in [&succeeded, &failed] {
+            assert!(job.is_completed());
+            assert!(job.is_started());
+            assert!(!job.is_running());
+        }
+        assert!(succeeded.is_success());
+        assert!(!failed.is_success());
+        assert!(failed.get_result_as_string().is_none());
+    }
+
     #[test]
     fn test_set_result_and_completion() {
         let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
@@ -321,6 +1473,120 @@ mod tests {
         assert!(output.contains("hello"));
     }
 
+    #[test]
+    fn test_run_with_rate_limit() {
+        // Given echo standing for a scanner with a rate option
+        let job = Job::new(
+            "test".to_string(),
+            "echo".to_string(),
+            vec!["scan".to_string()],
+        );
+        let mut limit = RateLimit::new(100);
+        limit.set_flag("echo".to_string(), "--max-rate".to_string());
+        let options = RunOptions {
+            rate_limit: Some(limit),
+            ..Default::default()
+        };
+
+        // When
+        let output = job.run_with_options(&options).unwrap();
+
+        // Then
+        assert_eq!(output, "scan --max-rate 100\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_with_hooks() {
+        // Given hooks and an action appending to the same file
+        let log = std::env::temp_dir().join(format!("agent-hooks-{}", Uuid::new_v4()));
+        let append = |line: &str| {
+            let script = format!("echo {} >> {}", line, log.display());
+            ("sh".to_string(), vec!["-c".to_string(), script])
+        };
+        let (cmd, args) = append("action");
+        let mut job = Job::new("test".to_string(), cmd, args);
+        let (cmd, args) = append("pre");
+        let pre_hook = JobHook {
+            cmd,
+            args,
+            fatal: true,
+        };
+        let (cmd, args) = append("post");
+        let post_hook = JobHook {
+            cmd,
+            args,
+            fatal: true,
+        };
+        job.set_hooks(Some(pre_hook), Some(post_hook));
+
+        // When
+        job.run().unwrap();
+
+        // Then
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "pre\naction\npost\n"
+        );
+        assert!(job.get_hook_errors().is_empty());
+        let _ = std::fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_run_with_failing_hooks() {
+        // Given a failing pre hook that is not fatal
+        let mut job = Job::new(
+            "test".to_string(),
+            "echo".to_string(),
+            vec!["ok".to_string()],
+        );
+        let mut pre_hook = JobHook {
+            cmd: "nonexistent_command".to_string(),
+            args: vec![],
+            fatal: false,
+        };
+        job.set_hooks(Some(pre_hook.clone()), None);
+
+        // When / Then the job runs and the failure is reported
+        assert_eq!(job.run().unwrap(), "ok\n");
+        assert_eq!(job.get_hook_errors().len(), 1);
+        assert!(job.get_hook_errors()[0].starts_with("pre hook \"nonexistent_command\""));
+
+        // and a fatal one fails the job
+        pre_hook.fatal = true;
+        job.set_hooks(Some(pre_hook), None);
+        assert!(job.run().is_err());
+        // the failures of a previous run are not reported again
+        assert_eq!(job.get_hook_errors().len(), 0);
+    }
+
+    #[test]
+    fn test_run_with_failing_fatal_post_hook() {
+        // Given a fatal post hook failing after the action ran
+        let mut job = Job::new(
+            "test".to_string(),
+            "echo".to_string(),
+            vec!["found".to_string()],
+        );
+        job.set_hooks(
+            None,
+            Some(JobHook {
+                cmd: "nonexistent_command".to_string(),
+                args: vec![],
+                fatal: true,
+            }),
+        );
+
+        // When
+        let output = job.run().unwrap();
+
+        // Then the output of the action is kept, but the job failed
+        assert_eq!(output, "found\n");
+        assert!(!job.determine_success(&output));
+        assert_eq!(job.get_hook_errors().len(), 1);
+        assert!(job.get_hook_errors()[0].starts_with("post hook \"nonexistent_command\""));
+    }
+
     #[tokio::test]
     async fn test_run_action_failure() {
         let job = Job::new(
@@ -398,4 +1664,547 @@ mod tests {
         assert_eq!(deserialized.get_action().get_cmd(), "echo");
         assert_eq!(deserialized.get_action().get_args(), &vec!["hi"]);
     }
+
+    #[test]
+    fn test_deserialization_of_dependency() {
+        let raw_json = r#"
+    {
+        "id": "550e8400-e29b-41d4-a716-446655440001",
+        "name": "deep_scan",
+        "created_at": "2025-08-28T12:41:34.061276Z",
+        "agent_id": "550e8400-e29b-41d4-a716-446655440002",
+        "depends_on": "550e8400-e29b-41d4-a716-446655440003",
+        "condition": {"output_contains": "open"},
+        "action": {"cmd": "echo", "args": [], "variant": ""}
+    }
+    "#;
+
+        let deserialized: Job = serde_json::from_str(raw_json).unwrap();
+
+        assert_eq!(
+            deserialized.get_depends_on(),
+            Some(&Uuid::from_str("550e8400-e29b-41d4-a716-446655440003").unwrap())
+        );
+        assert_eq!(
+            deserialized.get_condition(),
+            &JobCondition::OutputContains("open".to_string())
+        );
+    }
+
+    #[test]
+    fn test_condition_is_met_by() {
+        let dependency = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        dependency.set_result("80/tcp open".to_string());
+        dependency.set_success(true);
+
+        assert!(JobCondition::Success.is_met_by(&dependency));
+        assert!(!JobCondition::Failure.is_met_by(&dependency));
+        assert!(JobCondition::Always.is_met_by(&dependency));
+        assert!(JobCondition::OutputContains("open".to_string()).is_met_by(&dependency));
+        assert!(!JobCondition::OutputContains("closed".to_string()).is_met_by(&dependency));
+    }
+
+    #[test]
+    fn test_skip() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+
+        job.skip(
+            SkipReason::DependencyNotMet,
+            "dependency failed".to_string(),
+        );
+
+        assert!(job.is_skipped());
+        assert_eq!(job.get_skip_reason(), Some(SkipReason::DependencyNotMet));
+        assert!(job.is_completed());
+        assert!(!job.is_success());
+        assert!(job.get_started_at().is_none());
+    }
+
+    #[test]
+    fn test_skip_reason_serialization() {
+        let reasons = [
+            (SkipReason::DependencyNotMet, "dependency_not_met"),
+            (SkipReason::DependencyNotFound, "dependency_not_found"),
+            (SkipReason::OutOfScope, "out_of_scope"),
+            (SkipReason::ToolUnavailable, "tool_unavailable"),
+            (SkipReason::Cancelled, "cancelled"),
+            (SkipReason::Rejected, "rejected"),
+        ];
+
+        for (reason, expected) in reasons {
+            assert_eq!(serde_json::to_value(reason).unwrap(), expected);
+        }
+        assert_eq!(serde_json::to_value(JobStatus::Skipped).unwrap(), "skipped");
+    }
+
+    fn make_job_with_redactions(args: Vec<&str>, redactions: Vec<&str>) -> Job {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "name": "test",
+            "created_at": Utc::now(),
+            "agent_id": Uuid::new_v4(),
+            "action": {"cmd": "echo", "args": args, "variant": ""},
+            "redactions": redactions,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_redacts_output() {
+        let job = make_job_with_redactions(
+            vec!["user=admin password=hunter2 host=db.internal"],
+            vec![r"password=\S+", r"[\w.-]+\.internal"],
+        );
+
+        let output = job.run().unwrap();
+
+        assert_eq!(output.trim(), "user=admin *** host=***");
+    }
+
+    fn make_job_with_success(success: serde_json::Value) -> Result<Job, serde_json::Error> {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "name": "test",
+            "created_at": Utc::now(),
+            "agent_id": Uuid::new_v4(),
+            "action": {"cmd": "echo", "args": [], "variant": ""},
+            "success": success,
+        }))
+    }
+
+    #[test]
+    fn test_deserialize_success_as_bool() {
+        assert!(
+            make_job_with_success(serde_json::json!(true))
+                .unwrap()
+                .is_success()
+        );
+        assert!(
+            !make_job_with_success(serde_json::json!(false))
+                .unwrap()
+                .is_success()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_success_as_string() {
+        assert!(
+            make_job_with_success(serde_json::json!("true"))
+                .unwrap()
+                .is_success()
+        );
+        assert!(
+            make_job_with_success(serde_json::json!("TRUE"))
+                .unwrap()
+                .is_success()
+        );
+        assert!(
+            !make_job_with_success(serde_json::json!("false"))
+                .unwrap()
+                .is_success()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_success_as_number() {
+        assert!(
+            make_job_with_success(serde_json::json!(1))
+                .unwrap()
+                .is_success()
+        );
+        assert!(
+            !make_job_with_success(serde_json::json!(0))
+                .unwrap()
+                .is_success()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_missing_success() {
+        let job = make_job_with_success(serde_json::Value::Null).unwrap();
+
+        assert_eq!(*job.success.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn test_deserialize_success_rejects_garbage() {
+        for garbage in [
+            serde_json::json!("yes"),
+            serde_json::json!(2),
+            serde_json::json!(0.5),
+            serde_json::json!([true]),
+        ] {
+            let err = make_job_with_success(garbage.clone()).unwrap_err();
+
+            assert!(
+                err.to_string().contains("invalid success value"),
+                "{}: {}",
+                garbage,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_with_invalid_redaction_fails() {
+        let job = make_job_with_redactions(vec!["password=hunter2"], vec!["("]);
+
+        let err = job.run().unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!err.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_content_type_of_raw_output() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        job.set_result("raw output".to_string());
+
+        assert_eq!(job.content_type(None), CONTENT_TYPE_TEXT);
+    }
+
+    #[test]
+    fn test_content_type_of_parsed_output() {
+        // the structured results are reported apart, the results stay the raw output
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        job.set_result("raw output".to_string());
+        job.set_structured_result(serde_json::json!({"hosts": []}));
+
+        assert_eq!(job.content_type(None), CONTENT_TYPE_TEXT);
+    }
+
+    #[test]
+    fn test_content_type_of_json_output() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        job.set_result("{\"hosts\": []}\n".to_string());
+
+        assert_eq!(job.content_type(None), CONTENT_TYPE_JSON);
+
+        job.set_result("[not json".to_string());
+
+        assert_eq!(job.content_type(None), CONTENT_TYPE_TEXT);
+    }
+
+    #[test]
+    fn test_content_type_of_compressed_output() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        job.set_result("{\"hosts\": []}".to_string());
+
+        assert_eq!(job.content_type(Some("gzip+base64")), CONTENT_TYPE_GZIP);
+    }
+
+    #[test]
+    fn test_timings() {
+        // Given a job fetched a bit before it ran
+        let job = Job::new(
+            "sleep".to_string(),
+            "sleep".to_string(),
+            vec!["0.1".to_string()],
+        );
+        job.set_fetched_at(Utc::now() - chrono::TimeDelta::milliseconds(50));
+
+        // When
+        job.run().unwrap();
+        job.set_completed_at();
+        let timings = job.timings(Utc::now() + chrono::TimeDelta::milliseconds(20));
+
+        // Then
+        assert!(timings.queued_ms.unwrap() >= 50);
+        assert!(timings.executing_ms.unwrap() >= 100);
+        assert!(timings.reporting_ms.unwrap() >= 20);
+    }
+
+    #[test]
+    fn test_timings_of_unfinished_job() {
+        let job = Job::new("test".to_string(), "true".to_string(), vec![]);
+
+        let timings = job.timings(Utc::now() - chrono::TimeDelta::hours(1));
+
+        assert_eq!(timings.queued_ms, None);
+        assert_eq!(timings.executing_ms, None);
+        assert_eq!(timings.reporting_ms, None);
+    }
+
+    #[test]
+    fn test_run_with_empty_output() {
+        let job = Job::new("test".to_string(), "true".to_string(), vec![]);
+
+        let output = job.run().unwrap();
+
+        assert!(output.is_empty());
+        assert!(job.has_empty_output());
+    }
+
+    #[test]
+    fn test_run_keeps_stderr_of_successful_command() {
+        // Given a command warning on stderr but exiting 0
+        let job = Job::new(
+            "test".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "echo done; echo 'option -x is deprecated' >&2".to_string(),
+            ],
+        );
+
+        // When
+        let output = job.run().unwrap();
+        job.set_success(job.exited_successfully());
+
+        // Then
+        assert_eq!(output, "done\n");
+        assert!(job.is_success());
+        assert_eq!(job.get_stderr().unwrap(), "option -x is deprecated\n");
+    }
+
+    fn make_job_with_output_file(script: &str, output_file: &str) -> Job {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "name": "test",
+            "created_at": Utc::now(),
+            "agent_id": Uuid::new_v4(),
+            "action": {"cmd": "sh", "args": ["-c", script], "variant": ""},
+            "output_file": output_file,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_captures_output_file() {
+        // Given a command writing its results to the file of the job's directory
+        let job = make_job_with_output_file(
+            "echo scanning; echo '<host up/>' > {output_file}",
+            "scan.xml",
+        );
+
+        // When
+        let output = job.run().unwrap();
+
+        // Then both stdout and the file are captured
+        assert_eq!(output, "scanning\n");
+        assert_eq!(job.get_output_file_contents().unwrap(), "<host up/>\n");
+        let dir = job.get_work_dir().unwrap();
+        job.cleanup_work_dir();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_run_fails_when_output_file_is_missing() {
+        // a file left by a previous run is not taken for the output
+        let job = make_job_with_output_file("echo forgot to write it", "scan.xml");
+        let dir = job.create_work_dir(&RunOptions::default()).unwrap();
+        std::fs::write(dir.join("scan.xml"), "<stale/>").unwrap();
+
+        let err = job.run().unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("could not read output file"));
+        assert_eq!(job.get_output_file_contents(), None);
+        job.cleanup_work_dir();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_does_not_follow_output_file_out_of_the_job_directory() {
+        // Given a tool replacing its output file by a link to a file of the agent
+        let secret = std::env::temp_dir().join(format!("pentulz-secret-{}", Uuid::new_v4()));
+        std::fs::write(&secret, "token").unwrap();
+        let job = make_job_with_output_file(
+            &format!("ln -s {} {{output_file}}", secret.display()),
+            "scan.xml",
+        );
+
+        // When
+        let err = job.run().unwrap_err();
+
+        // Then the linked file is not read
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(job.get_output_file_contents(), None);
+        job.cleanup_work_dir();
+        assert_eq!(std::fs::read_to_string(&secret).unwrap(), "token");
+        std::fs::remove_file(secret).unwrap();
+    }
+
+    #[test]
+    fn test_deserialize_rejects_output_file_out_of_the_job_directory() {
+        for output_file in ["/etc/shadow", "../scan.xml", "out/../../scan.xml", ""] {
+            let result = serde_json::from_value::<Job>(serde_json::json!({
+                "id": Uuid::new_v4(),
+                "name": "test",
+                "created_at": Utc::now(),
+                "agent_id": Uuid::new_v4(),
+                "action": {"cmd": "nmap", "args": [], "variant": ""},
+                "output_file": output_file,
+            }));
+
+            let err = result.unwrap_err().to_string();
+            assert!(
+                err.contains("invalid output_file"),
+                "{}: {}",
+                output_file,
+                err
+            );
+        }
+    }
+
+    fn make_job_with_patterns(
+        script: &str,
+        success_pattern: Option<&str>,
+        failure_pattern: Option<&str>,
+    ) -> Job {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "name": "test",
+            "created_at": Utc::now(),
+            "agent_id": Uuid::new_v4(),
+            "action": {"cmd": "sh", "args": ["-c", script], "variant": ""},
+            "success_pattern": success_pattern,
+            "failure_pattern": failure_pattern,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_success_pattern_overrides_exit_code() {
+        // Given a tool reporting success in its output but exiting 1
+        let job = make_job_with_patterns(
+            "echo '3 hosts up'; exit 1",
+            Some(r"[1-9]\d* hosts? up"),
+            None,
+        );
+
+        // When
+        let output = job.run().unwrap();
+
+        // Then
+        assert!(!job.exited_successfully());
+        assert!(job.determine_success(&output));
+    }
+
+    #[test]
+    fn test_failure_pattern_overrides_exit_code() {
+        // Given a tool always exiting 0
+        let job = make_job_with_patterns(
+            "echo '0 hosts up'",
+            Some(r"[1-9]\d* hosts? up"),
+            Some(r"\b0 hosts up"),
+        );
+
+        // When
+        let output = job.run().unwrap();
+
+        // Then
+        assert!(job.exited_successfully());
+        assert!(!job.determine_success(&output));
+    }
+
+    #[test]
+    fn test_patterns_fall_back_to_exit_code() {
+        // both patterns match
+        let both = make_job_with_patterns("echo 'up and down'; exit 1", Some("up"), Some("down"));
+        let output = both.run().unwrap();
+        assert!(!both.determine_success(&output));
+
+        // none matches
+        let none = make_job_with_patterns("echo 'nothing'", Some("up"), Some("down"));
+        let output = none.run().unwrap();
+        assert!(none.determine_success(&output));
+
+        // an invalid pattern never matches
+        let invalid = make_job_with_patterns("echo 'up'; exit 2", Some("("), None);
+        let output = invalid.run().unwrap();
+        assert!(!invalid.determine_success(&output));
+    }
+
+    #[test]
+    fn test_run_without_stderr() {
+        let job = Job::new("test".to_string(), "true".to_string(), vec![]);
+
+        job.run().unwrap();
+
+        assert_eq!(job.get_stderr(), None);
+    }
+
+    #[test]
+    fn test_run_with_output() {
+        let job = Job::new(
+            "test".to_string(),
+            "echo".to_string(),
+            vec!["hello".to_string()],
+        );
+
+        job.run().unwrap();
+
+        assert!(!job.has_empty_output());
+    }
+
+    #[test]
+    fn test_submitted_flag_round_trip() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+        job.set_submitted(true);
+
+        let serialized = serde_json::to_string(&job).unwrap();
+        let deserialized: Job = serde_json::from_str(&serialized).unwrap();
+
+        assert!(deserialized.was_submitted());
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        // Given a job with every serialized field set
+        let raw = serde_json::json!({
+            "id": "550e8400-e29b-41d4-a716-446655440001",
+            "name": "scan",
+            "description": "full scan",
+            "created_at": "2025-08-28T12:41:34+00:00",
+            "started_at": "2025-08-28T12:42:00+00:00",
+            "completed_at": "2025-08-28T12:43:00+00:00",
+            "agent_id": "550e8400-e29b-41d4-a716-446655440002",
+            "action": {"cmd": "echo", "args": ["hi"], "variant": ""},
+            "depends_on": "550e8400-e29b-41d4-a716-446655440003",
+            "inputs": [{"name": "targets", "url": "https://example.com/targets.txt"}],
+            "status": "running",
+            "artifacts": ["/tmp/scan.xml"],
+            "output_file": "scan.json",
+            "success_pattern": "done",
+            "failure_pattern": "error",
+            "pre_hook": {"cmd": "true"},
+            "post_hook": {"cmd": "false"},
+            "result": "hi",
+            "success": true,
+        });
+        let job: Job = serde_json::from_value(raw).unwrap();
+        job.set_submitted(true);
+
+        // When
+        let serialized = serde_json::to_value(&job).unwrap();
+        let deserialized: Job = serde_json::from_value(serialized.clone()).unwrap();
+
+        // Then every field is serialized and survives the round trip
+        let fields = serialized.as_object().unwrap();
+        assert_eq!(fields.len(), 21);
+        assert_eq!(fields["created_at"], "2025-08-28T12:41:34+00:00");
+        assert_eq!(fields["started_at"], "2025-08-28T12:42:00+00:00");
+        assert_eq!(fields["completed_at"], "2025-08-28T12:43:00+00:00");
+        assert_eq!(fields["status"], "running");
+        assert_eq!(fields["results"], "hi");
+        assert_eq!(fields["success"], true);
+        assert_eq!(fields["submitted"], true);
+        assert_eq!(serde_json::to_value(&deserialized).unwrap(), serialized);
+
+        // And the declared field count matches, as formats encoding structs as arrays rely on it
+        // (MessagePack array16 header: a marker and the length)
+        let packed = rmp_serde::to_vec(&job).unwrap();
+        assert_eq!(packed[..3], [0xdc, 0, fields.len() as u8]);
+    }
+
+    #[test]
+    fn test_submitted_flag_defaults_to_false() {
+        let job = Job::new("test".to_string(), "echo".to_string(), vec![]);
+
+        let serialized = serde_json::to_string(&job).unwrap();
+        let deserialized: Job = serde_json::from_str(&serialized).unwrap();
+
+        assert!(!deserialized.was_submitted());
+    }
 }
@@ -0,0 +1,193 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, TimeDelta, Utc, Weekday};
+
+// time ranges during which the agent keeps heartbeating but does not start new jobs, written
+// "[days] HH:MM-HH:MM [offset]" (e.g. "mon-fri 09:00-17:00 +01:00"). a range ending before it
+// starts spans midnight, and one ending when it starts lasts the whole day. days default to every
+// day and the offset to UTC
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    // days the window opens on, every day when empty
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+}
+
+impl MaintenanceWindow {
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    // when the window covering `now` closes, None when `now` is outside of it
+    pub fn closes_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(&self.offset);
+        let (today, time) = (local.date_naive(), local.time());
+
+        let end_day = if self.start < self.end {
+            (self.start <= time && time < self.end && self.opens_on(local.weekday()))
+                .then_some(today)
+        } else if time >= self.start {
+            // opened today, closes tomorrow
+            self.opens_on(local.weekday())
+                .then(|| today + TimeDelta::days(1))
+        } else if time < self.end {
+            // opened yesterday
+            self.opens_on(local.weekday().pred()).then_some(today)
+        } else {
+            None
+        }?;
+
+        end_day
+            .and_time(self.end)
+            .and_local_timezone(self.offset)
+            .single()
+            .map(|end| end.with_timezone(&Utc))
+    }
+}
+
+// when the first of the windows covering `now` closes, None when none of them does
+pub fn closes_at(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    windows
+        .iter()
+        .filter_map(|window| window.closes_at(now))
+        .min()
+}
+
+// "mon", "mon-fri" or "sat,sun", a range may wrap around the end of the week ("fri-mon")
+fn parse_days(raw: &str) -> Result<Vec<Weekday>, String> {
+    let parse_day = |day: &str| {
+        day.trim()
+            .parse::<Weekday>()
+            .map_err(|_| format!("invalid day {:?}", day))
+    };
+
+    let mut days = Vec::new();
+    for part in raw.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (parse_day(first)?, parse_day(last)?);
+                days.push(day);
+                while day != last {
+                    day = day.succ();
+                    days.push(day);
+                }
+            }
+            None => days.push(parse_day(part)?),
+        }
+    }
+
+    Ok(days)
+}
+
+fn parse_offset(raw: &str) -> Result<FixedOffset, String> {
+    if raw.eq_ignore_ascii_case("utc") || raw == "Z" {
+        return Ok(FixedOffset::east_opt(0).expect("zero offset"));
+    }
+    raw.parse::<FixedOffset>()
+        .map_err(|err| format!("invalid offset {:?}: {}", raw, err))
+}
+
+pub fn parse_maintenance_window(raw: &str) -> Result<MaintenanceWindow, String> {
+    let invalid = || {
+        format!(
+            "invalid maintenance window {:?}, expected \"[days] HH:MM-HH:MM [offset]\"",
+            raw
+        )
+    };
+
+    let parts: Vec<&str> = raw.split_whitespace().collect();
+    // the time range is the first part looking like "HH:MM-HH:MM", a negative offset coming
+    // after it
+    let range_index = parts
+        .iter()
+        .position(|part| part.contains(':') && part.contains('-'))
+        .ok_or_else(invalid)?;
+    let (days, offset) = match (&parts[..range_index], &parts[range_index + 1..]) {
+        ([], []) => (vec![], None),
+        ([days], []) => (parse_days(days)?, None),
+        ([], [offset]) => (vec![], Some(*offset)),
+        ([days], [offset]) => (parse_days(days)?, Some(*offset)),
+        _ => return Err(invalid()),
+    };
+
+    let (start, end) = parts[range_index].split_once('-').ok_or_else(invalid)?;
+    let parse_time = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|err| format!("invalid time {:?} in {:?}: {}", time, raw, err))
+    };
+
+    Ok(MaintenanceWindow {
+        days,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+        offset: match offset {
+            Some(offset) => parse_offset(offset)?,
+            None => FixedOffset::east_opt(0).expect("zero offset"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // Wednesday
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 15, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_window_covering_now() {
+        // Given
+        let window = parse_maintenance_window("mon-fri 09:00-17:00").unwrap();
+
+        // When
+        let closes_at = window.closes_at(at(10, 30));
+
+        // Then
+        assert_eq!(closes_at, Some(at(17, 0)));
+        assert_eq!(window.closes_at(at(17, 0)), None);
+        assert_eq!(window.closes_at(at(8, 59)), None);
+    }
+
+    #[test]
+    fn test_window_on_other_days() {
+        let window = parse_maintenance_window("sat,sun 00:00-23:59").unwrap();
+
+        assert_eq!(window.closes_at(at(12, 0)), None);
+    }
+
+    #[test]
+    fn test_window_spanning_midnight_with_offset() {
+        // Given 22:00-06:00 in UTC+02:00, i.e. 20:00-04:00 UTC, opening on Tuesdays only
+        let window = parse_maintenance_window("tue 22:00-06:00 +02:00").unwrap();
+
+        // When / Then: Wednesday 03:00 UTC is 05:00 local, the window opened on Tuesday
+        assert_eq!(window.closes_at(at(3, 0)), Some(at(4, 0)));
+        // Wednesday 21:00 UTC is 23:00 local, the window does not open on Wednesdays
+        assert_eq!(window.closes_at(at(21, 0)), None);
+    }
+
+    #[test]
+    fn test_whole_day_window() {
+        let window = parse_maintenance_window("00:00-00:00").unwrap();
+
+        assert_eq!(
+            window.closes_at(at(12, 0)),
+            Some(Utc.with_ymd_and_hms(2025, 1, 16, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_maintenance_window_rejects_invalid_values() {
+        assert!(parse_maintenance_window("").is_err());
+        assert!(parse_maintenance_window("09:00").is_err());
+        assert!(parse_maintenance_window("mon-fri").is_err());
+        assert!(parse_maintenance_window("someday 09:00-17:00").is_err());
+        assert!(parse_maintenance_window("09:00-25:00").is_err());
+        assert!(parse_maintenance_window("09:00-17:00 +99:00").is_err());
+        assert!(parse_maintenance_window("mon 09:00-17:00 UTC extra").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+// execution counters and duration histograms of the jobs by tool (the action's command), so
+// operators can see which tools dominate the runtime. rendered in the Prometheus text format
+
+// upper bounds of the duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+#[derive(Debug, Default, Clone)]
+struct ToolMetrics {
+    executions: u64,
+    failures: u64,
+    // cumulative counts of the executions that lasted at most each bucket's bound
+    buckets: [u64; DURATION_BUCKETS.len()],
+    duration_sum: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    tools: Arc<Mutex<BTreeMap<String, ToolMetrics>>>,
+    // jobs reported without being run (out of scope, tool unavailable, cancelled...)
+    skipped: Arc<AtomicU64>,
+    started_at: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            tools: Arc::default(),
+            skipped: Arc::default(),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+// what the agent did during its lifetime, emitted on shutdown
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub jobs_run: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub uploaded_bytes: u64,
+    pub uptime_secs: u64,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} job(s) run ({} succeeded, {} failed), {} skipped, {} bytes uploaded, up for {}s",
+            self.jobs_run,
+            self.succeeded,
+            self.failed,
+            self.skipped,
+            self.uploaded_bytes,
+            self.uptime_secs
+        )
+    }
+}
+
+// the metrics pushed to the API as JSON (`--metrics-push-secs`): the counters of every tool
+// along with the agent-wide totals of the summary
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub tools: BTreeMap<String, ToolSnapshot>,
+    #[serde(flatten)]
+    pub summary: Summary,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ToolSnapshot {
+    pub executions: u64,
+    pub failures: u64,
+    pub duration_seconds_sum: f64,
+    pub duration_seconds_buckets: Vec<Bucket>,
+}
+
+// cumulative count of the executions that lasted at most `le` seconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+impl Metrics {
+    pub fn record(&self, tool: &str, duration: Duration, success: bool) {
+        let mut tools = self.tools.lock().unwrap();
+        let metrics = tools.entry(tool.to_string()).or_default();
+        let seconds = duration.as_secs_f64();
+
+        metrics.executions += 1;
+        if !success {
+            metrics.failures += 1;
+        }
+        metrics.duration_sum += seconds;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(metrics.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn record_skipped(&self, count: usize) {
+        self.skipped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    // totals over every tool. uploads are tracked by the upload queue
+    pub fn summary(&self, uploaded_bytes: u64) -> Summary {
+        let (jobs_run, failed) =
+            self.tools
+                .lock()
+                .unwrap()
+                .values()
+                .fold((0, 0), |(executions, failures), metrics| {
+                    (executions + metrics.executions, failures + metrics.failures)
+                });
+
+        Summary {
+            jobs_run,
+            succeeded: jobs_run - failed,
+            failed,
+            skipped: self.skipped.load(Ordering::Relaxed),
+            uploaded_bytes,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+        }
+    }
+
+    pub fn snapshot(&self, uploaded_bytes: u64) -> MetricsSnapshot {
+        let tools = self
+            .tools
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tool, metrics)| {
+                let buckets = DURATION_BUCKETS
+                    .iter()
+                    .zip(metrics.buckets.iter())
+                    .map(|(le, count)| Bucket {
+                        le: *le,
+                        count: *count,
+                    })
+                    .collect();
+                let snapshot = ToolSnapshot {
+                    executions: metrics.executions,
+                    failures: metrics.failures,
+                    duration_seconds_sum: metrics.duration_sum,
+                    duration_seconds_buckets: buckets,
+                };
+                (tool.clone(), snapshot)
+            })
+            .collect();
+
+        MetricsSnapshot {
+            tools,
+            summary: self.summary(uploaded_bytes),
+        }
+    }
+
+    // number of executions and failures of the tool, used by unit tests
+    #[allow(dead_code)]
+    pub fn counts(&self, tool: &str) -> (u64, u64) {
+        self.tools
+            .lock()
+            .unwrap()
+            .get(tool)
+            .map(|metrics| (metrics.executions, metrics.failures))
+            .unwrap_or_default()
+    }
+
+    pub fn render(&self) -> String {
+        let tools = self.tools.lock().unwrap();
+        let mut out = String::new();
+
+        // writing to a String cannot fail
+        let _ = writeln!(out, "# TYPE tool_executions_total counter");
+        for (tool, metrics) in tools.iter() {
+            let _ = writeln!(
+                out,
+                "tool_executions_total{{tool=\"{}\"}} {}",
+                escape(tool),
+                metrics.executions
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE tool_failures_total counter");
+        for (tool, metrics) in tools.iter() {
+            let _ = writeln!(
+                out,
+                "tool_failures_total{{tool=\"{}\"}} {}",
+                escape(tool),
+                metrics.failures
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE tool_duration_seconds histogram");
+        for (tool, metrics) in tools.iter() {
+            let tool = escape(tool);
+            for (bound, count) in DURATION_BUCKETS.iter().zip(metrics.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "tool_duration_seconds_bucket{{tool=\"{}\",le=\"{}\"}} {}",
+                    tool, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "tool_duration_seconds_bucket{{tool=\"{}\",le=\"+Inf\"}} {}",
+                tool, metrics.executions
+            );
+            let _ = writeln!(
+                out,
+                "tool_duration_seconds_sum{{tool=\"{}\"}} {}",
+                tool, metrics.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "tool_duration_seconds_count{{tool=\"{}\"}} {}",
+                tool, metrics.executions
+            );
+        }
+
+        out
+    }
+}
+
+// label values escape backslashes, double quotes and line feeds
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        // Given
+        let metrics = Metrics::default();
+        metrics.record("nmap", Duration::from_millis(300), true);
+        metrics.record("nmap", Duration::from_secs(20), false);
+
+        // When
+        let rendered = metrics.render();
+
+        // Then
+        assert!(rendered.contains("tool_executions_total{tool=\"nmap\"} 2\n"));
+        assert!(rendered.contains("tool_failures_total{tool=\"nmap\"} 1\n"));
+        assert!(rendered.contains("tool_duration_seconds_bucket{tool=\"nmap\",le=\"0.1\"} 0\n"));
+        assert!(rendered.contains("tool_duration_seconds_bucket{tool=\"nmap\",le=\"0.5\"} 1\n"));
+        assert!(rendered.contains("tool_duration_seconds_bucket{tool=\"nmap\",le=\"30\"} 2\n"));
+        assert!(rendered.contains("tool_duration_seconds_bucket{tool=\"nmap\",le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("tool_duration_seconds_sum{tool=\"nmap\"} 20.3\n"));
+        assert!(rendered.contains("tool_duration_seconds_count{tool=\"nmap\"} 2\n"));
+    }
+
+    #[test]
+    fn test_summary() {
+        // Given
+        let metrics = Metrics::default();
+        metrics.record("nmap", Duration::from_secs(1), true);
+        metrics.record("nmap", Duration::from_secs(2), false);
+        metrics.record("curl", Duration::from_secs(1), true);
+        metrics.record_skipped(2);
+
+        // When
+        let summary = metrics.summary(1024);
+
+        // Then
+        assert_eq!(
+            summary,
+            Summary {
+                jobs_run: 3,
+                succeeded: 2,
+                failed: 1,
+                skipped: 2,
+                uploaded_bytes: 1024,
+                uptime_secs: 0,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "3 job(s) run (2 succeeded, 1 failed), 2 skipped, 1024 bytes uploaded, up for 0s"
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // Given
+        let metrics = Metrics::default();
+        metrics.record("nmap", Duration::from_millis(300), true);
+        metrics.record("nmap", Duration::from_secs(20), false);
+
+        // When
+        let snapshot = serde_json::to_value(metrics.snapshot(0)).unwrap();
+
+        // Then
+        let nmap = &snapshot["tools"]["nmap"];
+        assert_eq!(nmap["executions"], 2);
+        assert_eq!(nmap["failures"], 1);
+        assert_eq!(nmap["duration_seconds_sum"], 20.3);
+        assert_eq!(
+            nmap["duration_seconds_buckets"][1],
+            serde_json::json!({"le": 0.5, "count": 1})
+        );
+        assert_eq!(snapshot["jobs_run"], 2);
+        assert_eq!(snapshot["failed"], 1);
+    }
+
+    #[test]
+    fn test_escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
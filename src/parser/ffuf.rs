@@ -0,0 +1,144 @@
+use serde::Deserialize;
+use serde_json::Value;
+use spdlog::warn;
+
+use super::paths::{DiscoveredPath, DiscoveredPaths};
+use super::{OutputParser, ParseError};
+
+/// Parses the JSON output of ffuf: either the report written with `-of json` (an object whose
+/// `results` list the matches), or the one result per line printed with `-json`. Results that
+/// cannot be read are skipped.
+pub struct FfufParser;
+
+// result as written by ffuf, which reports the response size as its length
+#[derive(Deserialize)]
+struct FfufResult {
+    url: String,
+    status: u16,
+    length: Option<u64>,
+}
+
+// the report written with `-of json`
+#[derive(Deserialize)]
+struct FfufReport {
+    results: Vec<Value>,
+}
+
+impl OutputParser for FfufParser {
+    fn parse(&self, raw: &str) -> Result<Value, ParseError> {
+        let results = match serde_json::from_str::<FfufReport>(raw.trim()) {
+            Ok(report) => report.results,
+            Err(_) => raw
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| {
+                    serde_json::from_str(line)
+                        .inspect_err(|err| warn!("Skipping unparseable ffuf line: {}", err))
+                        .ok()
+                })
+                .collect(),
+        };
+
+        let mut paths = DiscoveredPaths::default();
+        for result in results {
+            match serde_json::from_value::<FfufResult>(result) {
+                Ok(result) => paths.entries.push(DiscoveredPath {
+                    path: path_of(&result.url),
+                    status: result.status,
+                    size: result.length,
+                }),
+                Err(err) => warn!("Skipping malformed ffuf result: {}", err),
+            }
+        }
+
+        Ok(serde_json::to_value(paths)?)
+    }
+}
+
+// path (and query) of the fuzzed url, the url itself when it cannot be parsed
+fn path_of(raw: &str) -> String {
+    match url::Url::parse(raw) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        Err(_) => raw.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(raw: &str) -> DiscoveredPaths {
+        serde_json::from_value(FfufParser.parse(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_ffuf_report() {
+        // Given a report written with `-of json`, one of its results being malformed
+        let raw = json!({
+            "commandline": "ffuf -u http://10.0.0.1/FUZZ -w words.txt -of json -o /dev/stdout",
+            "time": "2025-08-28T12:41:34Z",
+            "results": [
+                {
+                    "input": {"FUZZ": "admin"},
+                    "position": 3,
+                    "status": 301,
+                    "length": 178,
+                    "words": 6,
+                    "lines": 8,
+                    "content-type": "text/html",
+                    "redirectlocation": "http://10.0.0.1/admin/",
+                    "url": "http://10.0.0.1/admin",
+                    "host": "10.0.0.1"
+                },
+                {
+                    "input": {"FUZZ": "search?q=1"},
+                    "status": 200,
+                    "length": 1024,
+                    "url": "http://10.0.0.1/search?q=1"
+                },
+                {"input": {"FUZZ": "broken"}, "url": "http://10.0.0.1/broken"}
+            ],
+            "config": {"method": "GET"}
+        })
+        .to_string();
+
+        // When
+        let paths = parse(&raw);
+
+        // Then
+        assert_eq!(
+            paths.entries,
+            vec![
+                DiscoveredPath {
+                    path: "/admin".to_string(),
+                    status: 301,
+                    size: Some(178),
+                },
+                DiscoveredPath {
+                    path: "/search?q=1".to_string(),
+                    status: 200,
+                    size: Some(1024),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_ffuf_json_lines() {
+        let raw = format!(
+            "{}\nnot json\n{}\n",
+            json!({"url": "http://10.0.0.1/.git/HEAD", "status": 200, "length": 23}),
+            json!({"url": "http://10.0.0.1/backup", "status": 403, "length": 277}),
+        );
+
+        let paths = parse(&raw);
+
+        assert_eq!(paths.entries.len(), 2);
+        assert_eq!(paths.entries[0].path, "/.git/HEAD");
+        assert_eq!(paths.entries[1].status, 403);
+    }
+}
@@ -0,0 +1,114 @@
+use regex::Regex;
+use serde_json::Value;
+use spdlog::warn;
+
+use super::paths::{DiscoveredPath, DiscoveredPaths};
+use super::{OutputParser, ParseError};
+
+/// Parses the output of `gobuster dir`, one result per line such as
+/// `/admin (Status: 301) [Size: 178] [--> http://10.0.0.1/admin/]`. The banner and progress
+/// lines are ignored, results that cannot be read are skipped.
+pub struct GobusterParser;
+
+impl OutputParser for GobusterParser {
+    fn parse(&self, raw: &str) -> Result<Value, ParseError> {
+        let colors = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").expect("valid color regex");
+        let result = Regex::new(
+            r"^(?P<path>\S+)\s+\(Status:\s*(?P<status>\d+)\)(?:\s*\[Size:\s*(?P<size>\d+)\])?",
+        )
+        .expect("valid result regex");
+
+        let mut paths = DiscoveredPaths::default();
+        for line in raw.lines() {
+            // progress updates are rewritten in place with carriage returns
+            let line = line.rsplit('\r').next().unwrap_or_default();
+            let line = colors.replace_all(line, "");
+            let line = line.trim();
+            if !line.contains("(Status:") {
+                continue;
+            }
+
+            let entry = result.captures(line).and_then(|captures| {
+                Some(DiscoveredPath {
+                    path: captures["path"].to_string(),
+                    status: captures["status"].parse().ok()?,
+                    size: match captures.name("size") {
+                        Some(size) => Some(size.as_str().parse().ok()?),
+                        None => None,
+                    },
+                })
+            });
+            match entry {
+                Some(entry) => paths.entries.push(entry),
+                None => warn!("Skipping unparseable gobuster line: {:?}", line),
+            }
+        }
+
+        Ok(serde_json::to_value(paths)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "===============================================================
+Gobuster v3.6
+by OJ Reeves (@TheColonial) & Christian Mehlmauer (@firefart)
+===============================================================
+[+] Url:                     http://10.0.0.1
+[+] Method:                  GET
+[+] Status codes:            200,204,301,302,307,401,403
+===============================================================
+Starting gobuster in directory enumeration mode
+===============================================================
+/admin                (Status: 301) [Size: 178] [--> http://10.0.0.1/admin/]
+\x1b[2K/index.html           (Status: 200) [Size: 612]
+/server-status        (Status: 403) [Size: 277]
+Progress: 1200 / 4614 (26.01%)\r/login (Status: 200)
+/broken               (Status: 9999999) [Size: 1]
+===============================================================
+Finished
+===============================================================
+";
+
+    fn parse(raw: &str) -> DiscoveredPaths {
+        serde_json::from_value(GobusterParser.parse(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_gobuster_output() {
+        let paths = parse(SAMPLE);
+
+        assert_eq!(
+            paths.entries,
+            vec![
+                DiscoveredPath {
+                    path: "/admin".to_string(),
+                    status: 301,
+                    size: Some(178),
+                },
+                DiscoveredPath {
+                    path: "/index.html".to_string(),
+                    status: 200,
+                    size: Some(612),
+                },
+                DiscoveredPath {
+                    path: "/server-status".to_string(),
+                    status: 403,
+                    size: Some(277),
+                },
+                DiscoveredPath {
+                    path: "/login".to_string(),
+                    status: 200,
+                    size: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_output() {
+        assert!(parse("").entries.is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use spdlog::warn;
+
+use super::{OutputParser, ParseError};
+
+/// Parses the JSON output of `masscan -oJ`. masscan emits one entry per discovered port, so
+/// entries are grouped by host.
+pub struct MasscanParser;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MasscanResult {
+    pub hosts: Vec<MasscanHost>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MasscanHost {
+    pub ip: String,
+    pub ports: Vec<MasscanPort>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MasscanPort {
+    pub port: u16,
+    pub proto: String,
+    pub status: String,
+}
+
+// raw entry as written by masscan
+#[derive(Deserialize)]
+struct MasscanEntry {
+    ip: String,
+    ports: Vec<MasscanPort>,
+}
+
+impl OutputParser for MasscanParser {
+    fn parse(&self, raw: &str) -> Result<Value, ParseError> {
+        let mut hosts: BTreeMap<String, Vec<MasscanPort>> = BTreeMap::new();
+
+        for entry in parse_entries(raw)? {
+            match serde_json::from_value::<MasscanEntry>(entry) {
+                Ok(entry) => hosts.entry(entry.ip).or_default().extend(entry.ports),
+                Err(err) => warn!("Skipping malformed masscan entry: {}", err),
+            }
+        }
+
+        let result = MasscanResult {
+            hosts: hosts
+                .into_iter()
+                .map(|(ip, ports)| MasscanHost { ip, ports })
+                .collect(),
+        };
+
+        Ok(serde_json::to_value(result)?)
+    }
+}
+
+// some masscan versions leave a trailing comma before the closing bracket, which is not valid
+// JSON, so it is removed before trying again
+fn parse_entries(raw: &str) -> Result<Vec<Value>, ParseError> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(vec![]);
+    }
+
+    match serde_json::from_str(raw) {
+        Ok(entries) => Ok(entries),
+        Err(err) => {
+            let without_trailing_comma = raw
+                .strip_suffix(']')
+                .map(|body| format!("{}]", body.trim_end().trim_end_matches(',')));
+
+            match without_trailing_comma {
+                Some(fixed) => Ok(serde_json::from_str(&fixed)?),
+                None => Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SAMPLE: &str = r#"[
+{   "ip": "10.0.0.1",   "timestamp": "1693900000", "ports": [ {"port": 80, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] }
+,
+{   "ip": "10.0.0.2",   "timestamp": "1693900001", "ports": [ {"port": 53, "proto": "udp", "status": "open", "reason": "none", "ttl": 64} ] }
+,
+{   "ip": "10.0.0.1",   "timestamp": "1693900002", "ports": [ {"port": 443, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] }
+]"#;
+
+    fn parse(raw: &str) -> MasscanResult {
+        serde_json::from_value(MasscanParser.parse(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_groups_ports_by_host() {
+        let result = parse(SAMPLE);
+
+        assert_eq!(result.hosts.len(), 2);
+        assert_eq!(result.hosts[0].ip, "10.0.0.1");
+        assert_eq!(
+            result.hosts[0].ports,
+            vec![
+                MasscanPort {
+                    port: 80,
+                    proto: "tcp".to_string(),
+                    status: "open".to_string(),
+                },
+                MasscanPort {
+                    port: 443,
+                    proto: "tcp".to_string(),
+                    status: "open".to_string(),
+                },
+            ]
+        );
+        assert_eq!(result.hosts[1].ip, "10.0.0.2");
+        assert_eq!(result.hosts[1].ports[0].proto, "udp");
+    }
+
+    #[test]
+    fn test_parse_skips_malformed_entries() {
+        let raw = json!([
+            {"ip": "10.0.0.1", "ports": [{"port": 22, "proto": "tcp", "status": "open"}]},
+            {"ip": "10.0.0.2"},
+            {"finished": 1},
+        ])
+        .to_string();
+
+        let result = parse(&raw);
+
+        assert_eq!(result.hosts.len(), 1);
+        assert_eq!(result.hosts[0].ports[0].port, 22);
+    }
+
+    #[test]
+    fn test_parse_tolerates_trailing_comma() {
+        let raw = r#"[
+{"ip": "10.0.0.1", "ports": [{"port": 22, "proto": "tcp", "status": "open"}]},
+]"#;
+
+        assert_eq!(parse(raw).hosts.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_empty_output() {
+        assert!(parse("").hosts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_output() {
+        assert!(MasscanParser.parse("not json").is_err());
+    }
+}
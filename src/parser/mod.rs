@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde_json::Value;
+
+pub mod ffuf;
+pub mod gobuster;
+pub mod masscan;
+pub mod paths;
+
+use ffuf::FfufParser;
+use gobuster::GobusterParser;
+use masscan::MasscanParser;
+
+/// Turns the raw output of a tool into a structured JSON value.
+pub trait OutputParser: Send + Sync {
+    fn parse(&self, raw: &str) -> Result<Value, ParseError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Maps an action's variant (usually the tool name) to the parser handling its output.
+#[derive(Clone)]
+pub struct ParserRegistry {
+    parsers: HashMap<String, Arc<dyn OutputParser>>,
+}
+
+impl ParserRegistry {
+    /// Registry holding the parsers shipped with the agent.
+    pub fn new() -> Self {
+        let mut registry = ParserRegistry {
+            parsers: HashMap::new(),
+        };
+        registry.register("masscan", MasscanParser);
+        registry.register("gobuster", GobusterParser);
+        registry.register("ffuf", FfufParser);
+
+        registry
+    }
+
+    pub fn register<P: OutputParser + 'static>(&mut self, variant: &str, parser: P) {
+        self.parsers.insert(variant.to_string(), Arc::new(parser));
+    }
+
+    pub fn get(&self, variant: &str) -> Option<Arc<dyn OutputParser>> {
+        self.parsers.get(variant).cloned()
+    }
+
+    /// Parses the output with the parser registered for the variant. Returns `None` when no
+    /// parser is registered, in which case the raw output is used as is.
+    pub fn parse(&self, variant: &str, raw: &str) -> Option<Result<Value, ParseError>> {
+        self.get(variant).map(|parser| parser.parse(raw))
+    }
+}
+
+impl Default for ParserRegistry {
+    fn default() -> Self {
+        ParserRegistry::new()
+    }
+}
+
+impl fmt::Debug for ParserRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.parsers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct LineCountParser;
+
+    impl OutputParser for LineCountParser {
+        fn parse(&self, raw: &str) -> Result<Value, ParseError> {
+            Ok(json!({ "lines": raw.lines().count() }))
+        }
+    }
+
+    #[test]
+    fn test_parse_with_registered_parser() {
+        let mut registry = ParserRegistry::new();
+        registry.register("lines", LineCountParser);
+
+        let parsed = registry.parse("lines", "a\nb\nc").unwrap().unwrap();
+
+        assert_eq!(parsed, json!({ "lines": 3 }));
+    }
+
+    #[test]
+    fn test_parse_without_registered_parser() {
+        let registry = ParserRegistry::new();
+
+        assert!(registry.parse("unknown", "raw output").is_none());
+    }
+
+    #[test]
+    fn test_builtin_parsers_are_registered() {
+        let registry = ParserRegistry::new();
+
+        assert!(registry.get("masscan").is_some());
+        assert!(registry.get("gobuster").is_some());
+        assert!(registry.get("ffuf").is_some());
+    }
+}
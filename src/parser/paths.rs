@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// Paths found by a content discovery tool (gobuster, ffuf), in the order they were reported.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct DiscoveredPaths {
+    pub entries: Vec<DiscoveredPath>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct DiscoveredPath {
+    pub path: String,
+    pub status: u16,
+    /// Size of the response body in bytes, when the tool reports it.
+    pub size: Option<u64>,
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use spdlog::warn;
+
+/// Lowest and highest nice values, from the highest scheduling priority to the lowest.
+pub const NICE_RANGE: (i32, i32) = (-20, 19);
+
+/// I/O scheduling class of a process, as set by `ionice` (Linux only).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Rejected: it could starve the host's other processes, see [`IoClass::check`].
+    Realtime,
+    BestEffort,
+    /// Only gets disk time when no other process needs it.
+    Idle,
+}
+
+impl IoClass {
+    /// Fails for the classes a job may not ask for.
+    pub fn check(self) -> Result<(), String> {
+        match self {
+            IoClass::Realtime => Err("the realtime I/O class is not allowed".to_string()),
+            IoClass::BestEffort | IoClass::Idle => Ok(()),
+        }
+    }
+}
+
+/// Brings `nice` into [`NICE_RANGE`], and to at least `current` (the agent's nice value) as a
+/// job may not get a higher priority than the agent, warning when it was out of them.
+pub fn clamp_nice(nice: i32, current: i32) -> i32 {
+    let clamped = nice.clamp(current.max(NICE_RANGE.0), NICE_RANGE.1);
+    if clamped != nice {
+        warn!(
+            "Nice value {} out of range [{}, {}], using {}",
+            nice,
+            current.max(NICE_RANGE.0),
+            NICE_RANGE.1,
+            clamped
+        );
+    }
+    clamped
+}
+
+/// Nice value of the agent's process.
+#[cfg(unix)]
+pub fn current_nice() -> i32 {
+    // -1 is a valid nice value, the call cannot fail for the calling process anyway
+    unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) }
+}
+
+/// Makes the spawned process set its own nice value and I/O class before executing. The nice
+/// value is never lower than the agent's one.
+#[cfg(unix)]
+pub fn set_priority(
+    command: &mut std::process::Command,
+    nice: Option<i32>,
+    io_class: Option<IoClass>,
+) {
+    use std::{io, os::unix::process::CommandExt};
+
+    let nice = nice.map(|nice| clamp_nice(nice, current_nice()));
+    #[cfg(not(target_os = "linux"))]
+    if io_class.is_some() {
+        warn!("I/O scheduling classes are only supported on Linux, ignoring it");
+    }
+
+    // only async-signal-safe calls are allowed between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice
+                && libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(io_class) = io_class {
+                set_io_class(io_class)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_class(io_class: IoClass) -> std::io::Result<()> {
+    // see linux/ioprio.h: the class is stored above the level (0 to 7) within the class
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const DEFAULT_LEVEL: libc::c_int = 4;
+
+    let (class, level) = match io_class {
+        // rejected when the action is parsed
+        IoClass::Realtime => {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
+        IoClass::BestEffort => (2, DEFAULT_LEVEL),
+        IoClass::Idle => (3, 0),
+    };
+    let priority = (class << IOPRIO_CLASS_SHIFT) | level;
+
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_nice() {
+        assert_eq!(clamp_nice(10, 0), 10);
+        assert_eq!(clamp_nice(50, 0), 19);
+        assert_eq!(clamp_nice(-5, 0), 0);
+        assert_eq!(clamp_nice(-100, -30), -20);
+        assert_eq!(clamp_nice(3, 5), 5);
+    }
+
+    #[test]
+    fn test_realtime_io_class_is_rejected() {
+        assert!(IoClass::Realtime.check().is_err());
+        assert!(IoClass::Idle.check().is_ok());
+    }
+}
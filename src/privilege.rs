@@ -0,0 +1,90 @@
+use std::{ffi::CString, io, os::unix::process::CommandExt, process::Command};
+
+/// Unprivileged account spawned actions are run as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Resolves a user name through the system's user database.
+pub fn lookup_user(name: &str) -> Result<User, io::Error> {
+    let not_found = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {:?} does not exist", name),
+        )
+    };
+    let c_name = CString::new(name).map_err(|_| not_found())?;
+
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    if result.is_null() {
+        return Err(not_found());
+    }
+
+    Ok(User {
+        name: name.to_string(),
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+    })
+}
+
+/// Makes the spawned process drop its supplementary groups, then switch to the user's gid and
+/// uid before executing. The agent itself keeps its privileges.
+pub fn drop_privileges(command: &mut Command, user: &User) {
+    let (uid, gid) = (user.uid, user.gid);
+
+    // only async-signal-safe calls are allowed between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(1, &gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_user_root() {
+        let user = lookup_user("root").unwrap();
+
+        assert_eq!(user.uid, 0);
+        assert_eq!(user.gid, 0);
+    }
+
+    #[test]
+    fn test_lookup_nonexistent_user() {
+        let err = lookup_user("non_existing_user").unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("non_existing_user"));
+    }
+}
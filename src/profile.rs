@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use serde_json::{Value, json};
+
+use crate::interpolate::interpolate;
+
+// named bundles of flag values (`--profile`), so operators managing many agents apply a profile
+// instead of tuning every knob. a profile maps long flag names (without the dashes) to a string
+// or a number, a list for the repeatable flags or a boolean for the switches. it is either built
+// in or a JSON file, and flags given on the command line take precedence over its values. its
+// strings are expanded like the command line (`${VAR}`), and it may provide the required flags
+
+pub type Profile = BTreeMap<String, Value>;
+
+pub const BUILTIN_PROFILES: [&str; 3] = ["quick", "thorough", "internal"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("unknown profile {0:?}, expected one of {BUILTIN_PROFILES:?} or a profile file")]
+    Unknown(String),
+
+    #[error("could not read profile {0:?}: {1}")]
+    Unreadable(PathBuf, std::io::Error),
+
+    #[error("invalid profile {0:?}: {1}")]
+    Invalid(String, String),
+}
+
+fn builtin(name: &str) -> Option<Value> {
+    let profile = match name {
+        // short cycles, reports and uploads given up on quickly
+        "quick" => json!({
+            "cycle-budget-secs": 300,
+            "max-concurrent-uploads": 4,
+            "upload-retries": 1,
+            "report-retries": 1,
+        }),
+        // long running scans with large artifacts
+        "thorough" => json!({
+            "cycle-budget-secs": 7200,
+            "max-concurrent-uploads": 1,
+            "upload-retries": 5,
+            "upload-backoff-ms": 5000,
+        }),
+        // jobs restricted to the private networks
+        "internal" => json!({
+            "allowed-target": ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"],
+        }),
+        _ => return None,
+    };
+
+    Some(profile)
+}
+
+// the built-in profile called `name`, or else the profile file at that path
+pub fn load(name: &str) -> Result<Profile, ProfileError> {
+    let raw = match builtin(name) {
+        Some(profile) => profile,
+        None => {
+            let path = PathBuf::from(name);
+            if !path.is_file() {
+                return Err(ProfileError::Unknown(name.to_string()));
+            }
+            let content =
+                fs::read_to_string(&path).map_err(|err| ProfileError::Unreadable(path, err))?;
+            serde_json::from_str(&content)
+                .map_err(|err| ProfileError::Invalid(name.to_string(), err.to_string()))?
+        }
+    };
+
+    serde_json::from_value(raw)
+        .map_err(|err| ProfileError::Invalid(name.to_string(), err.to_string()))
+}
+
+// command line arguments setting `flag` to `value`
+pub fn flag_args(flag: &str, value: &Value) -> Result<Vec<String>, String> {
+    let option = format!("--{}", flag);
+    // attached to the flag, so a value starting with a dash is not taken for another flag
+    let scalar = |value: &Value| match value {
+        Value::String(value) => interpolate(value)
+            .map(|value| format!("{}={}", option, value))
+            .map_err(|err| format!("{}: {}", option, err)),
+        Value::Number(value) => Ok(format!("{}={}", option, value)),
+        _ => Err(format!("unexpected value {} for {}", value, option)),
+    };
+
+    match value {
+        Value::Bool(true) => Ok(vec![option]),
+        Value::Bool(false) => Ok(vec![]),
+        Value::Array(values) => values.iter().map(scalar).collect(),
+        value => Ok(vec![scalar(value)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_builtin_and_file_profiles() {
+        // Given
+        let path =
+            std::env::temp_dir().join(format!("agent-profile-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, r#"{"strict": true, "cycle-budget-secs": 60}"#).unwrap();
+
+        // When
+        let file = load(path.to_str().unwrap()).unwrap();
+        let quick = load("quick").unwrap();
+
+        // Then
+        assert_eq!(file["strict"], json!(true));
+        assert_eq!(file["cycle-budget-secs"], json!(60));
+        assert_eq!(quick["cycle-budget-secs"], json!(300));
+        assert!(matches!(load("missing"), Err(ProfileError::Unknown(_))));
+        for name in BUILTIN_PROFILES {
+            assert!(load(name).is_ok(), "{}", name);
+        }
+        fs::write(&path, "[1, 2]").unwrap();
+        assert!(matches!(
+            load(path.to_str().unwrap()),
+            Err(ProfileError::Invalid(..))
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flag_args() {
+        assert_eq!(flag_args("strict", &json!(true)).unwrap(), ["--strict"]);
+        assert!(flag_args("strict", &json!(false)).unwrap().is_empty());
+        assert_eq!(
+            flag_args("cycle-budget-secs", &json!(60)).unwrap(),
+            ["--cycle-budget-secs=60"]
+        );
+        assert_eq!(
+            flag_args("allowed-target", &json!(["10.0.0.0/8", "host"])).unwrap(),
+            ["--allowed-target=10.0.0.0/8", "--allowed-target=host"]
+        );
+        assert_eq!(
+            flag_args("results-field", &json!("-r ${AGENT_UNSET_VAR:-x}")).unwrap(),
+            ["--results-field=-r x"]
+        );
+        assert!(flag_args("token", &json!("${AGENT_UNSET_VAR}")).is_err());
+        assert!(flag_args("header", &json!({"a": "b"})).is_err());
+        assert!(flag_args("allowed-target", &json!([null])).is_err());
+    }
+}
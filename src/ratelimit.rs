@@ -0,0 +1,179 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+
+use spdlog::warn;
+
+// global packets-per-second budget of the scan tools, so jobs do not overwhelm their targets. it
+// is passed to each tool through its own rate option, appended to the job's arguments. a job
+// asking for a higher rate is capped to the budget
+
+// rate options of the well-known scanners, others can be given with `--rate-flag`
+pub const DEFAULT_RATE_FLAGS: [(&str, &str); 4] = [
+    ("nmap", "--max-rate"),
+    ("masscan", "--rate"),
+    ("naabu", "-rate"),
+    ("zmap", "--rate"),
+];
+
+// tools already reported as not rate limited, so every job of theirs does not warn again
+static UNLIMITED_TOOLS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    packets_per_second: u64,
+    // rate option by tool command
+    flags: HashMap<String, String>,
+}
+
+impl RateLimit {
+    pub fn new(packets_per_second: u64) -> Self {
+        RateLimit {
+            packets_per_second,
+            flags: DEFAULT_RATE_FLAGS
+                .iter()
+                .map(|(tool, flag)| (tool.to_string(), flag.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn set_flag(&mut self, tool: String, flag: String) {
+        self.flags.insert(tool, flag);
+    }
+
+    // rate option of the tool, looked up by the name of its command
+    pub fn flag(&self, cmd: &str) -> Option<&str> {
+        let name = Path::new(cmd)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(cmd);
+        self.flags.get(name).map(String::as_str)
+    }
+
+    // `args` with the tool's rate option set to the budget, or lowered to it when the job asks
+    // for more. None when the tool has no known rate option
+    pub fn apply(&self, cmd: &str, args: &[String]) -> Option<Vec<String>> {
+        let flag = self.flag(cmd)?;
+        let budget = self.packets_per_second;
+        let mut args = args.to_vec();
+
+        let inline = format!("{}=", flag);
+        match args
+            .iter()
+            .position(|arg| arg == flag || arg.starts_with(&inline))
+        {
+            Some(index) if args[index] == flag => {
+                if let Some(value) = args.get_mut(index + 1) {
+                    *value = capped(value, budget);
+                }
+            }
+            Some(index) => {
+                let value = capped(&args[index][inline.len()..], budget);
+                args[index] = format!("{}{}", inline, value);
+            }
+            None => args.extend([flag.to_string(), budget.to_string()]),
+        }
+
+        Some(args)
+    }
+}
+
+// warn that the tool has no known rate option, once per tool. returns whether it warned
+pub fn warn_unlimited(cmd: &str) -> bool {
+    let first = UNLIMITED_TOOLS.lock().unwrap().insert(cmd.to_string());
+    if first {
+        warn!("{} has no known rate option, it is not rate limited", cmd);
+    }
+    first
+}
+
+// the rate asked for, unless it is above the budget. rates that do not parse are replaced
+fn capped(rate: &str, budget: u64) -> String {
+    match rate.parse::<f64>() {
+        Ok(value) if value <= budget as f64 => rate.to_string(),
+        _ => budget.to_string(),
+    }
+}
+
+// "tool=flag" rate option of a tool given on the command line
+pub fn parse_rate_flag(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((tool, flag)) if !tool.is_empty() && flag.starts_with('-') => {
+            Ok((tool.to_string(), flag.to_string()))
+        }
+        _ => Err(format!(
+            "invalid rate flag {:?}, expected \"tool=flag\" (e.g. \"nmap=--max-rate\")",
+            raw
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_apply_injects_the_tool_rate_flag() {
+        // Given
+        let limit = RateLimit::new(500);
+
+        // When
+        let nmap = limit.apply("/usr/bin/nmap", &args(&["-sS", "10.0.0.1"]));
+        let masscan = limit.apply("masscan", &args(&["-p80", "10.0.0.0/24"]));
+
+        // Then
+        assert_eq!(nmap, Some(args(&["-sS", "10.0.0.1", "--max-rate", "500"])));
+        assert_eq!(
+            masscan,
+            Some(args(&["-p80", "10.0.0.0/24", "--rate", "500"]))
+        );
+        assert_eq!(limit.apply("curl", &args(&["http://example.com"])), None);
+    }
+
+    #[test]
+    fn test_apply_caps_the_job_rate() {
+        let mut limit = RateLimit::new(500);
+        limit.set_flag("scanner".to_string(), "--pps".to_string());
+
+        assert_eq!(
+            limit.apply("nmap", &args(&["--max-rate", "10000", "host"])),
+            Some(args(&["--max-rate", "500", "host"]))
+        );
+        assert_eq!(
+            limit.apply("nmap", &args(&["--max-rate", "100", "host"])),
+            Some(args(&["--max-rate", "100", "host"]))
+        );
+        assert_eq!(
+            limit.apply("masscan", &args(&["--rate=1e6", "host"])),
+            Some(args(&["--rate=500", "host"]))
+        );
+        assert_eq!(
+            limit.apply("scanner", &args(&["host"])),
+            Some(args(&["host", "--pps", "500"]))
+        );
+    }
+
+    #[test]
+    fn test_unlimited_tools_are_warned_about_once() {
+        assert!(warn_unlimited("agent-test-curl"));
+        assert!(!warn_unlimited("agent-test-curl"));
+        assert!(warn_unlimited("agent-test-wget"));
+    }
+
+    #[test]
+    fn test_parse_rate_flag() {
+        assert_eq!(
+            parse_rate_flag("nmap=--max-rate"),
+            Ok(("nmap".to_string(), "--max-rate".to_string()))
+        );
+        assert!(parse_rate_flag("nmap").is_err());
+        assert!(parse_rate_flag("=--rate").is_err());
+        assert!(parse_rate_flag("nmap=rate").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+// resources of the host sent along with the registration so the scheduler can place heavy jobs
+// on agents able to run them. metrics the platform does not provide are left out
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HostResources {
+    pub cpu_cores: Option<usize>,
+    // in bytes
+    pub memory_total: Option<u64>,
+    pub memory_available: Option<u64>,
+    // free space of the filesystem holding the working directory, in bytes
+    pub disk_free: Option<u64>,
+}
+
+impl HostResources {
+    pub fn collect() -> HostResources {
+        let (memory_total, memory_available) = memory();
+
+        HostResources {
+            cpu_cores: std::thread::available_parallelism()
+                .ok()
+                .map(|cores| cores.get()),
+            memory_total,
+            memory_available,
+            disk_free: std::env::current_dir().ok().and_then(|dir| disk_free(&dir)),
+        }
+    }
+}
+
+// total and available memory from /proc/meminfo, whose values are in kB
+#[cfg(target_os = "linux")]
+fn memory() -> (Option<u64>, Option<u64>) {
+    match std::fs::read_to_string("/proc/meminfo") {
+        Ok(meminfo) => parse_meminfo(&meminfo),
+        Err(_) => (None, None),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn memory() -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_meminfo(meminfo: &str) -> (Option<u64>, Option<u64>) {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kb| kb * 1024)
+    };
+
+    (field("MemTotal"), field("MemAvailable"))
+}
+
+#[cfg(unix)]
+pub fn disk_free(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    // blocks available to unprivileged users, in fragment size units
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn disk_free(_dir: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16318440 kB\nMemFree:         1024000 kB\nMemAvailable:    8159220 kB\n";
+
+        let (total, available) = parse_meminfo(meminfo);
+
+        assert_eq!(total, Some(16318440 * 1024));
+        assert_eq!(available, Some(8159220 * 1024));
+    }
+
+    #[test]
+    fn test_parse_meminfo_missing_fields() {
+        assert_eq!(parse_meminfo("MemFree: 12 kB\n"), (None, None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_disk_free_of_missing_dir() {
+        assert!(disk_free(Path::new("/non/existing/dir")).is_none());
+        assert!(disk_free(&std::env::temp_dir()).is_some());
+    }
+}
@@ -0,0 +1,250 @@
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc};
+
+use crate::shell;
+
+// recurring jobs defined locally (`--local-job`), run by the agent on its own schedule besides
+// the jobs pushed by the server (e.g. a heartbeat self-check scan). they are written
+// "<cron expression> <cmd> [args]", the expression being the usual five fields "minute hour
+// day-of-month month day-of-week" evaluated in UTC. each field is `*`, a value, a range "a-b"
+// or a list of them, optionally stepped ("*/15", "0-30/10"). days of the week go from 0 (or 7)
+// for Sunday to 6
+
+// the minutes elapsed since the last evaluation are checked up to this far back, so a long
+// pause does not run a job once for each of the occurrences it missed
+const MAX_CATCH_UP: TimeDelta = TimeDelta::days(7);
+
+// values matched by a field, as a bit set
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Field {
+    values: u64,
+    // `*`, matters for the days which match when either of their fields does
+    any: bool,
+}
+
+impl Field {
+    fn parse(raw: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut values = 0u64;
+        for part in raw.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step = step
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .ok_or_else(|| format!("invalid step {:?}", step))?;
+                    (range, step)
+                }
+                None => (part, 1),
+            };
+            let parse_value = |value: &str| {
+                value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|value| (min..=max).contains(value))
+                    .ok_or_else(|| format!("invalid value {:?}, expected {}-{}", value, min, max))
+            };
+            let (first, last) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((first, last)) => (parse_value(first)?, parse_value(last)?),
+                // a stepped value runs up to the end of the range ("5/15")
+                None if step > 1 => (parse_value(range)?, max),
+                None => (parse_value(range)?, parse_value(range)?),
+            };
+            if first > last {
+                return Err(format!("invalid range {:?}", range));
+            }
+            for value in (first..=last).step_by(step as usize) {
+                values |= 1 << value;
+            }
+        }
+
+        Ok(Field {
+            values,
+            any: raw == "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl CronSchedule {
+    pub fn matches(&self, time: DateTime<Utc>) -> bool {
+        // as in cron, a day matches when either its day of the month or of the week does, unless
+        // one of them is `*`
+        let day = self.days.matches(time.day());
+        let weekday = self.weekdays.matches(time.weekday().num_days_from_sunday());
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes.matches(time.minute())
+            && self.hours.matches(time.hour())
+            && self.months.matches(time.month())
+            && day_matches
+    }
+
+    // whether the schedule had an occurrence after the evaluation at `since` and up to `now`,
+    // only the current minute being checked on the first evaluation
+    pub fn is_due(&self, since: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        let Ok(now) = now.duration_trunc(TimeDelta::minutes(1)) else {
+            return false;
+        };
+        let mut minute =
+            match since.and_then(|since| since.duration_trunc(TimeDelta::minutes(1)).ok()) {
+                Some(since) => (since + TimeDelta::minutes(1)).max(now - MAX_CATCH_UP),
+                None => now,
+            };
+
+        while minute <= now {
+            if self.matches(minute) {
+                return true;
+            }
+            minute += TimeDelta::minutes(1);
+        }
+        false
+    }
+}
+
+pub fn parse_cron(raw: &str) -> Result<CronSchedule, String> {
+    let fields: Vec<&str> = raw.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+        return Err(format!(
+            "invalid cron expression {:?}, expected 5 fields",
+            raw
+        ));
+    };
+    let invalid = |field: &'static str| {
+        move |err: String| format!("invalid {} in cron expression {:?}: {}", field, raw, err)
+    };
+
+    let mut weekdays = Field::parse(weekdays, 0, 7).map_err(invalid("day of the week"))?;
+    // 7 is Sunday too
+    if weekdays.matches(7) {
+        weekdays.values |= 1;
+    }
+
+    Ok(CronSchedule {
+        minutes: Field::parse(minutes, 0, 59).map_err(invalid("minute"))?,
+        hours: Field::parse(hours, 0, 23).map_err(invalid("hour"))?,
+        days: Field::parse(days, 1, 31).map_err(invalid("day of the month"))?,
+        months: Field::parse(months, 1, 12).map_err(invalid("month"))?,
+        weekdays,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalJob {
+    pub schedule: CronSchedule,
+    pub cmd: String,
+    pub args: Vec<String>,
+}
+
+impl LocalJob {
+    // named after its command line
+    pub fn name(&self) -> String {
+        shell::command_line(
+            [self.cmd.as_str()]
+                .into_iter()
+                .chain(self.args.iter().map(String::as_str)),
+        )
+    }
+}
+
+// the command line is split like a shell does, so an argument may be quoted to hold spaces
+pub fn parse_local_job(raw: &str) -> Result<LocalJob, String> {
+    let parts = shell::split_words(raw)?;
+    if parts.len() < 6 {
+        return Err(format!(
+            "invalid local job {:?}, expected \"<cron expression> <cmd> [args]\" (e.g. \"*/5 * * * * nmap -sn 10.0.0.0/24\")",
+            raw
+        ));
+    }
+
+    Ok(LocalJob {
+        schedule: parse_cron(&parts[..5].join(" "))?,
+        cmd: parts[5].clone(),
+        args: parts[6..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(raw: &str) -> DateTime<Utc> {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        // Given every quarter of an hour during office hours on weekdays
+        let schedule = parse_cron("*/15 9-17 * * 1-5").unwrap();
+
+        // Then (2025-06-16 is a Monday)
+        assert!(schedule.matches(at("2025-06-16T09:00:00Z")));
+        assert!(schedule.matches(at("2025-06-16T17:45:00Z")));
+        assert!(!schedule.matches(at("2025-06-16T09:10:00Z")));
+        assert!(!schedule.matches(at("2025-06-16T18:00:00Z")));
+        assert!(!schedule.matches(at("2025-06-15T09:00:00Z")));
+
+        let sundays = parse_cron("0 0 * * 7").unwrap();
+        assert!(sundays.matches(at("2025-06-15T00:00:00Z")));
+        // the 1st of the month or any Monday
+        let either = parse_cron("30 6 1 * 1").unwrap();
+        assert!(either.matches(at("2025-06-16T06:30:00Z")));
+        assert!(either.matches(at("2025-07-01T06:30:00Z")));
+        assert!(!either.matches(at("2025-07-02T06:30:00Z")));
+        let listed = parse_cron("5,10/20 0 * * *").unwrap();
+        assert!(listed.matches(at("2025-06-16T00:05:00Z")));
+        assert!(listed.matches(at("2025-06-16T00:50:00Z")));
+        assert!(!listed.matches(at("2025-06-16T00:20:00Z")));
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(parse_cron(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_is_due() {
+        let hourly = parse_cron("0 * * * *").unwrap();
+
+        assert!(hourly.is_due(None, at("2025-06-16T10:00:42Z")));
+        assert!(!hourly.is_due(None, at("2025-06-16T10:01:00Z")));
+        // an occurrence between two evaluations is not missed, nor run twice
+        assert!(hourly.is_due(Some(at("2025-06-16T09:59:30Z")), at("2025-06-16T10:05:00Z")));
+        assert!(!hourly.is_due(Some(at("2025-06-16T10:00:10Z")), at("2025-06-16T10:00:50Z")));
+        assert!(!hourly.is_due(Some(at("2025-06-16T10:05:00Z")), at("2025-06-16T10:59:59Z")));
+    }
+
+    #[test]
+    fn test_parse_local_job() {
+        let job = parse_local_job("*/5 * * * * nmap -sn 10.0.0.0/24").unwrap();
+
+        assert_eq!(job.cmd, "nmap");
+        assert_eq!(job.args, ["-sn", "10.0.0.0/24"]);
+        assert_eq!(job.name(), "nmap -sn 10.0.0.0/24");
+        let job = parse_local_job(r#"0 * * * * '/opt/my tools/scan' "target with space""#).unwrap();
+        assert_eq!(job.cmd, "/opt/my tools/scan");
+        assert_eq!(job.args, ["target with space"]);
+        assert!(parse_local_job("*/5 * * * *").is_err());
+        assert!(parse_local_job("*/5 * * * nmap").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::{collections::HashMap, net::IpAddr};
+
+// allowlist of the targets jobs may scan. when it is set, jobs whose arguments reference a host,
+// an address or a network that is not covered by one of its entries are rejected instead of
+// being run, as are the ones reading their targets from a downloaded input
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScopeEntry {
+    Network { addr: IpAddr, prefix: u8 },
+    // exact hostname, or any of its subdomains when written `*.example.com`
+    Host(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    entries: Vec<ScopeEntry>,
+    // positions of the arguments holding targets, by tool command
+    target_args: HashMap<String, Vec<usize>>,
+}
+
+impl ScopeEntry {
+    fn contains(&self, target: &ScopeEntry) -> bool {
+        match (self, target) {
+            (
+                ScopeEntry::Network { addr, prefix },
+                ScopeEntry::Network {
+                    addr: target_addr,
+                    prefix: target_prefix,
+                },
+            ) => target_prefix >= prefix && network_contains(addr, *prefix, target_addr),
+            (ScopeEntry::Host(host), ScopeEntry::Host(target)) => match host.strip_prefix("*.") {
+                Some(domain) => target
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => host == target,
+            },
+            _ => false,
+        }
+    }
+}
+
+// whether `addr` belongs to the `network/prefix` network, both being of the same family
+fn network_contains(network: &IpAddr, prefix: u8, addr: &IpAddr) -> bool {
+    let (network, addr, bits) = match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            (u32::from(*network) as u128, u32::from(*addr) as u128, 32)
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(*network), u128::from(*addr), 128),
+        _ => return false,
+    };
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix as u32;
+    network >> shift == addr >> shift
+}
+
+// parses an address, a network (CIDR notation) or a hostname given on the command line
+pub fn parse_scope_entry(raw: &str) -> Result<ScopeEntry, String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Err("empty scope entry".to_string());
+    }
+
+    if let Some((addr, prefix)) = raw.split_once('/') {
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid network {:?}: {}", raw, err))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| format!("invalid prefix length in {:?}", raw))?;
+        return Ok(ScopeEntry::Network { addr, prefix });
+    }
+
+    match raw.parse::<IpAddr>() {
+        Ok(addr) => Ok(ScopeEntry::Network {
+            prefix: if addr.is_ipv4() { 32 } else { 128 },
+            addr,
+        }),
+        Err(_) => Ok(ScopeEntry::Host(raw.to_lowercase())),
+    }
+}
+
+// parses a "tool=position[,position...]" rule telling which (zero-based) arguments of the tool
+// are targets
+pub fn parse_target_args(raw: &str) -> Result<(String, Vec<usize>), String> {
+    let (tool, positions) = raw.split_once('=').ok_or_else(|| {
+        format!(
+            "invalid target arguments {:?}, expected \"tool=position[,position...]\"",
+            raw
+        )
+    })?;
+    if tool.is_empty() {
+        return Err(format!("missing tool in target arguments {:?}", raw));
+    }
+    let positions = positions
+        .split(',')
+        .map(|position| {
+            position
+                .trim()
+                .parse::<usize>()
+                .map_err(|err| format!("invalid position in {:?}: {}", raw, err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((tool.to_string(), positions))
+}
+
+// target found in an argument: an address or network when it parses as such, a hostname
+// otherwise. urls and "host:port" are reduced to their host
+fn parse_target(arg: &str) -> Option<ScopeEntry> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return None;
+    }
+    if let Ok(entry @ ScopeEntry::Network { .. }) = parse_scope_entry(arg) {
+        return Some(entry);
+    }
+    if arg.contains("://") {
+        let url = url::Url::parse(arg).ok()?;
+        return parse_target(
+            url.host_str()?
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        );
+    }
+    let host = match arg.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => arg,
+    };
+    parse_scope_entry(host).ok()
+}
+
+// whether a tool's argument may be a hostname (or "host:port"): dot-separated labels of letters,
+// digits and hyphens, ending with a letters-only one (e.g. "scanme.org"). file names such as
+// "out.xml" look the same, they are taken for targets too
+fn looks_like_hostname(arg: &str) -> bool {
+    let host = match arg.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => arg,
+    };
+    let labels: Vec<&str> = host.split('.').collect();
+    labels.len() > 1
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+impl Scope {
+    pub fn new(entries: Vec<ScopeEntry>, target_args: Vec<(String, Vec<usize>)>) -> Scope {
+        Scope {
+            entries,
+            target_args: target_args.into_iter().collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    // arguments of the command holding targets: the configured positions for known tools, or
+    // every argument looking like an address, a network, a url or a hostname
+    fn targets<'a>(&self, cmd: &str, args: &'a [String]) -> Vec<&'a str> {
+        match self.target_args.get(cmd) {
+            Some(positions) => positions
+                .iter()
+                .filter_map(|position| args.get(*position))
+                .map(String::as_str)
+                .collect(),
+            None => args
+                .iter()
+                .map(String::as_str)
+                .filter(|arg| {
+                    matches!(parse_target(arg), Some(ScopeEntry::Network { .. }))
+                        || arg.contains("://")
+                        || looks_like_hostname(arg)
+                })
+                .collect(),
+        }
+    }
+
+    // targets of the command that are not covered by the allowlist, always empty when no
+    // allowlist is set. a target that cannot be parsed (e.g. a url without a host) is not
+    // covered either, nor the `{input:<name>}` arguments whose contents are only known once
+    // downloaded
+    pub fn out_of_scope(&self, cmd: &str, args: &[String]) -> Vec<String> {
+        if !self.is_enabled() {
+            return vec![];
+        }
+
+        let inputs = args
+            .iter()
+            .map(String::as_str)
+            .filter(|arg| arg.contains("{input:"));
+        self.targets(cmd, args)
+            .into_iter()
+            .filter(|arg| !arg.contains("{input:"))
+            .filter(|arg| match parse_target(arg) {
+                Some(target) => !self.entries.iter().any(|entry| entry.contains(&target)),
+                None => true,
+            })
+            .chain(inputs)
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_scope(entries: Vec<&str>, target_args: Vec<&str>) -> Scope {
+        Scope::new(
+            entries
+                .into_iter()
+                .map(|entry| parse_scope_entry(entry).unwrap())
+                .collect(),
+            target_args
+                .into_iter()
+                .map(|rule| parse_target_args(rule).unwrap())
+                .collect(),
+        )
+    }
+
+    fn args(args: Vec<&str>) -> Vec<String> {
+        args.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_in_scope_targets() {
+        // Given
+        let scope = make_scope(
+            vec![
+                "10.0.0.0/24",
+                "2001:db8::/32",
+                "*.example.com",
+                "scanme.org",
+            ],
+            vec!["curl=1", "ping=2"],
+        );
+
+        // When
+        let nmap = scope.out_of_scope("nmap", &args(vec!["-sV", "10.0.0.0/28", "2001:db8::1"]));
+        let curl = scope.out_of_scope("curl", &args(vec!["-I", "https://www.example.com/login"]));
+        let ping = scope.out_of_scope("ping", &args(vec!["-c", "1", "scanme.org:80"]));
+
+        // Then
+        assert!(nmap.is_empty());
+        assert!(curl.is_empty());
+        assert!(ping.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_scope_targets() {
+        // Given
+        let scope = make_scope(vec!["10.0.0.0/24", "*.example.com"], vec!["curl=1"]);
+
+        // When
+        let nmap = scope.out_of_scope("nmap", &args(vec!["10.0.0.5", "10.0.1.5", "10.0.0.0/16"]));
+        let curl = scope.out_of_scope("curl", &args(vec!["-I", "https://example.org"]));
+
+        // Then
+        assert_eq!(nmap, vec!["10.0.1.5", "10.0.0.0/16"]);
+        assert_eq!(curl, vec!["https://example.org"]);
+    }
+
+    #[test]
+    fn test_unparsable_targets_are_out_of_scope() {
+        // Given
+        let scope = make_scope(
+            vec!["10.0.0.0/24", "*.example.com"],
+            vec!["curl=0", "ping=0"],
+        );
+
+        // When
+        let curl = scope.out_of_scope("curl", &args(vec!["http://[www.example.com"]));
+        let nohost = scope.out_of_scope("curl", &args(vec!["file:///etc/passwd"]));
+        let ping = scope.out_of_scope("ping", &args(vec!["10.0.0.0/40"]));
+
+        // Then
+        assert_eq!(curl, vec!["http://[www.example.com"]);
+        assert_eq!(nohost, vec!["file:///etc/passwd"]);
+        assert_eq!(ping, vec!["10.0.0.0/40"]);
+    }
+
+    #[test]
+    fn test_hostnames_of_tools_without_rule_are_checked() {
+        // Given
+        let scope = make_scope(vec!["10.0.0.0/24", "*.example.com"], vec![]);
+
+        // When
+        let in_scope = scope.out_of_scope(
+            "nuclei",
+            &args(vec!["-u", "https://www.example.com", "-severity", "high"]),
+        );
+        let out = scope.out_of_scope(
+            "nuclei",
+            &args(vec![
+                "-u",
+                "scanme.org",
+                "http://example.org",
+                "-o",
+                "/tmp/out.json",
+            ]),
+        );
+
+        // Then
+        assert!(in_scope.is_empty());
+        assert_eq!(out, vec!["scanme.org", "http://example.org"]);
+    }
+
+    #[test]
+    fn test_inputs_are_out_of_scope() {
+        let scope = make_scope(vec!["10.0.0.0/24"], vec!["nmap=1"]);
+
+        let out = scope.out_of_scope("nmap", &args(vec!["-iL", "{input:targets}", "10.0.0.1"]));
+
+        assert_eq!(out, vec!["{input:targets}"]);
+        assert!(
+            make_scope(vec![], vec![])
+                .out_of_scope("nmap", &args(vec!["-iL", "{input:targets}"]))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_disabled_scope_allows_everything() {
+        let scope = make_scope(vec![], vec![]);
+
+        assert!(
+            scope
+                .out_of_scope("nmap", &args(vec!["8.8.8.8"]))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_scope_entry_rejects_invalid_prefix() {
+        assert!(parse_scope_entry("10.0.0.0/33").is_err());
+        assert!(parse_scope_entry("10.0.0.0/abc").is_err());
+        assert!(parse_target_args("nmap").is_err());
+        assert!(parse_target_args("nmap=a").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::{self, Display};
+
+use serde_json::json;
+
+use crate::action::Action;
+use crate::job::Job;
+use crate::tool::Tool;
+
+// Result of the `--self-test` mode: a smoke test of the built binary on the target platform
+// for packagers and CI. it needs no network nor API, only the usual system commands
+
+type SelfTestCheck = fn() -> Result<(), String>;
+
+const CHECKS: [(&str, SelfTestCheck); 4] = [
+    ("output capture", check_output_capture),
+    ("exit code and stderr", check_exit_code),
+    ("tool discovery", check_tool_discovery),
+    ("serialization", check_serialization),
+];
+
+#[derive(Debug, Default)]
+pub struct SelfTestReport {
+    // name of each check along with its failure, if any
+    pub checks: Vec<(String, Option<String>)>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|(_, error)| error.is_none())
+    }
+}
+
+pub fn run_self_test() -> SelfTestReport {
+    SelfTestReport {
+        checks: CHECKS
+            .iter()
+            .map(|(name, check)| (name.to_string(), check().err()))
+            .collect(),
+    }
+}
+
+// a spawned process has its standard output captured
+fn check_output_capture() -> Result<(), String> {
+    let action = Action::new("echo".to_string(), vec!["self-test".to_string()]);
+    let output = action.run().map_err(|err| err.to_string())?;
+
+    expect("output", &output, "self-test\n")
+}
+
+fn check_exit_code() -> Result<(), String> {
+    let script = "echo oops >&2; exit 3".to_string();
+    let action = Action::new("sh".to_string(), vec!["-c".to_string(), script]);
+    let output = action
+        .execute(&Default::default())
+        .map_err(|err| err.to_string())?;
+
+    expect("exit code", &format!("{:?}", output.exit_code), "Some(3)")?;
+    expect("stderr", &output.stderr, "oops\n")
+}
+
+// tools are looked up on the PATH
+fn check_tool_discovery() -> Result<(), String> {
+    if !Tool::from_cmd("sh".to_string()).is_available() {
+        return Err("sh not found on the PATH".to_string());
+    }
+    if Tool::from_cmd("agent-self-test-missing-tool".to_string()).is_available() {
+        return Err("a missing tool was reported available".to_string());
+    }
+
+    Ok(())
+}
+
+// jobs as sent by the API survive a JSON and a MessagePack round trip
+fn check_serialization() -> Result<(), String> {
+    let raw = json!({
+        "id": "550e8400-e29b-41d4-a716-446655440001",
+        "name": "self-test",
+        "created_at": "2025-08-28T12:41:34.061276Z",
+        "agent_id": "550e8400-e29b-41d4-a716-446655440002",
+        "action": {"cmd": "echo", "args": ["hi"], "variant": "", "timeout": 1.5},
+        "redactions": ["secret"],
+    });
+    let job: Job = serde_json::from_value(raw).map_err(|err| err.to_string())?;
+    let serialized = serde_json::to_value(&job).map_err(|err| err.to_string())?;
+    let job: Job = serde_json::from_value(serialized.clone()).map_err(|err| err.to_string())?;
+    let reserialized = serde_json::to_value(&job).map_err(|err| err.to_string())?;
+    if serialized != reserialized {
+        return Err(format!(
+            "JSON round trip changed the job: {} != {}",
+            serialized, reserialized
+        ));
+    }
+
+    let packed = rmp_serde::to_vec_named(&serialized).map_err(|err| err.to_string())?;
+    let unpacked: serde_json::Value =
+        rmp_serde::from_slice(&packed).map_err(|err| err.to_string())?;
+    if unpacked != serialized {
+        return Err("MessagePack round trip changed the job".to_string());
+    }
+
+    Ok(())
+}
+
+fn expect(what: &str, actual: &str, expected: &str) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {} {:?}, got {:?}",
+            what, expected, actual
+        ))
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, error) in &self.checks {
+            match error {
+                None => writeln!(f, "ok {}", name)?,
+                Some(error) => writeln!(f, "FAILED {}: {}", name, error)?,
+            }
+        }
+        write!(
+            f,
+            "Self-test {}",
+            if self.passed() { "PASSED" } else { "FAILED" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_self_test() {
+        let report = run_self_test();
+
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.checks.len(), CHECKS.len());
+    }
+
+    #[test]
+    fn test_report_with_failure() {
+        let report = SelfTestReport {
+            checks: vec![
+                ("output capture".to_string(), None),
+                ("serialization".to_string(), Some("broken".to_string())),
+            ],
+        };
+
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "ok output capture\nFAILED serialization: broken\nSelf-test FAILED"
+        );
+    }
+}
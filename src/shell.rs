@@ -0,0 +1,243 @@
+use std::{borrow::Cow, path::Path, process::Command};
+
+use crate::tool::Tool;
+
+// interpreter of the shell-mode actions (`"shell": true`), whose command line is run as a
+// script instead of being spawned directly, so it may use pipes, redirections or loops. they are
+// only run when the operator enables them with `--shell`, which selects the interpreter: `sh` by
+// default (`cmd` on Windows), or another one when the scripts rely on its features (e.g. bash or
+// pwsh)
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shell {
+    interpreter: String,
+}
+
+pub const DEFAULT_INTERPRETER: &str = if cfg!(windows) { "cmd" } else { "sh" };
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell {
+            interpreter: DEFAULT_INTERPRETER.to_string(),
+        }
+    }
+}
+
+impl Shell {
+    // option of the interpreter taking the script to run
+    pub fn script_flag(&self) -> &'static str {
+        let name = Path::new(&self.interpreter)
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "cmd" => "/C",
+            "pwsh" | "powershell" => "-Command",
+            _ => "-c",
+        }
+    }
+
+    pub fn command(&self, script: &str) -> Command {
+        let mut command = Command::new(&self.interpreter);
+        command.arg(self.script_flag()).arg(script);
+        command
+    }
+}
+
+// the interpreter must exist, given by path or looked up on the PATH
+pub fn parse_shell(raw: &str) -> Result<Shell, String> {
+    if raw.is_empty() {
+        return Err("invalid shell, expected the path or name of an interpreter".to_string());
+    }
+    if !Path::new(raw).is_file() && !Tool::from_cmd(raw.to_string()).is_available() {
+        return Err(format!("shell {:?} not found", raw));
+    }
+
+    Ok(Shell {
+        interpreter: raw.to_string(),
+    })
+}
+
+// a word of a command line as the platform parses it (POSIX sh, or the Windows rules of
+// CommandLineToArgvW), quoted only when it is empty or holds spaces or special characters
+pub fn quote(word: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        quote_windows(word)
+    } else {
+        quote_posix(word)
+    }
+}
+
+// rendering of a command line (logs, job logs, reports) which runs the same argv when pasted
+// in a terminal
+pub fn command_line<'a>(words: impl IntoIterator<Item = &'a str>) -> String {
+    words.into_iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+// words of a command line following the POSIX sh quoting rules (single and double quotes,
+// backslash escapes), without any expansion
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let unterminated = || format!("unterminated quote in {:?}", line);
+    let mut words = Vec::new();
+    // None between words, so an empty quoted word is still a word
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '"' => break,
+                        // only these are escaped between double quotes
+                        '\\' => match chars.next().ok_or_else(unterminated)? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            c => word.extend(['\\', c]),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_default().push(c),
+                None => return Err(format!("trailing backslash in {:?}", line)),
+            },
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+fn quote_posix(word: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return Cow::Borrowed(word);
+    }
+
+    // nothing is special between single quotes, a single quote ends them to be escaped
+    Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+}
+
+// the metacharacters of cmd, which runs the shell-mode scripts, lose their meaning between quotes
+fn quote_windows(word: &str) -> Cow<'_, str> {
+    if !word.is_empty() && !word.contains([' ', '\t', '"', '&', '|', '^', '<', '>']) {
+        return Cow::Borrowed(word);
+    }
+
+    // backslashes are only special before a quote, including the closing one
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in word.chars() {
+        if c == '"' {
+            quoted.extend(std::iter::repeat_n('\\', backslashes + 1));
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+        quoted.push(c);
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes));
+    quoted.push('"');
+
+    Cow::Owned(quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_posix() {
+        assert_eq!(quote_posix("10.0.0.1/24"), "10.0.0.1/24");
+        assert_eq!(quote_posix("--script=http-title"), "--script=http-title");
+        assert_eq!(quote_posix("/opt/my tools/nmap"), "'/opt/my tools/nmap'");
+        assert_eq!(quote_posix("it's"), r"'it'\''s'");
+        assert_eq!(quote_posix("$HOME"), "'$HOME'");
+        assert_eq!(quote_posix(""), "''");
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("nmap  -sn 10.0.0.0/24").unwrap(),
+            ["nmap", "-sn", "10.0.0.0/24"]
+        );
+        assert_eq!(
+            split_words(r#"'/opt/my tools/nmap' "target with space" a\ b"#).unwrap(),
+            ["/opt/my tools/nmap", "target with space", "a b"]
+        );
+        assert_eq!(
+            split_words(r#"'' "say \"hi\"" '$HOME'"#).unwrap(),
+            ["", r#"say "hi""#, "$HOME"]
+        );
+        assert!(split_words("'unterminated").is_err());
+        assert!(split_words(r"trailing\").is_err());
+    }
+
+    #[test]
+    fn test_quote_windows() {
+        assert_eq!(quote_windows(r"C:\tools\nmap.exe"), r"C:\tools\nmap.exe");
+        assert_eq!(
+            quote_windows(r"C:\Program Files\Nmap\"),
+            r#""C:\Program Files\Nmap\\""#
+        );
+        assert_eq!(quote_windows(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote_windows(r#"a\"b"#), r#""a\\\"b""#);
+        assert_eq!(quote_windows(""), r#""""#);
+        assert_eq!(quote_windows("a&b|c"), r#""a&b|c""#);
+        assert_eq!(quote_windows("<x>"), r#""<x>""#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_line_is_parsed_back_by_the_shell() {
+        // Given words with spaces and quotes
+        let args = ["target with space", "it's", r#"a "b""#, ""];
+
+        // When the rendered command line is run by the shell
+        let script = format!("printf '[%s]' {}", command_line(args));
+        let output = Shell::default().command(&script).output().unwrap();
+
+        // Then it gets the same words
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            r#"[target with space][it's][a "b"][]"#
+        );
+        assert_eq!(
+            command_line(["/opt/my tools/nmap", "-p", "80"]),
+            "'/opt/my tools/nmap' -p 80"
+        );
+    }
+
+    #[test]
+    fn test_script_flag() {
+        let shell = |interpreter: &str| Shell {
+            interpreter: interpreter.to_string(),
+        };
+
+        assert_eq!(shell("/bin/bash").script_flag(), "-c");
+        assert_eq!(shell("sh").script_flag(), "-c");
+        assert_eq!(shell("pwsh").script_flag(), "-Command");
+        assert_eq!(shell("cmd.exe").script_flag(), "/C");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_shell() {
+        assert!(parse_shell("sh").is_ok());
+        assert!(parse_shell("/bin/sh").is_ok());
+        assert!(parse_shell("agent-missing-shell").is_err());
+        assert!(parse_shell("").is_err());
+    }
+}
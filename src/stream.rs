@@ -0,0 +1,106 @@
+use std::io::Read;
+
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use reqwest::Body;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+
+// size of the chunks read from the streamed field
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Builds a JSON body equivalent to `value` with the string `field` set to the content of
+// `reader`. the field is read and escaped chunk by chunk while the body is sent, so a huge
+// value never has to be serialized in memory at once.
+pub fn json_with_streamed_field<T, R>(
+    value: &T,
+    field: &str,
+    reader: R,
+) -> Result<Body, serde_json::Error>
+where
+    T: Serialize,
+    R: Read + Send + 'static,
+{
+    let mut object = match serde_json::to_value(value)? {
+        serde_json::Value::Object(object) => object,
+        _ => serde_json::Map::new(),
+    };
+    object.remove(field);
+
+    let head = format!("{{{}:\"", serde_json::to_string(field)?);
+    let mut tail = String::from("\"");
+    for (key, value) in &object {
+        tail.push_str(&format!(",{}:{}", serde_json::to_string(key)?, value));
+    }
+    tail.push('}');
+
+    let chunks = stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        match reader.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => Some((Ok(Bytes::from(escape(&buffer[..n]))), Some(reader))),
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+
+    let body = stream::iter([Ok::<_, std::io::Error>(Bytes::from(head))])
+        .chain(chunks)
+        .chain(stream::iter([Ok(Bytes::from(tail))]));
+
+    Ok(Body::wrap_stream(body))
+}
+
+// Body sending the rest of `file`, from its current position, read chunk by chunk while the
+// body is sent.
+pub fn file_body(file: tokio::fs::File) -> Body {
+    let chunks = stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buffer.truncate(n);
+                Some((Ok::<_, std::io::Error>(Bytes::from(buffer)), Some(file)))
+            }
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+
+    Body::wrap_stream(chunks)
+}
+
+// escape raw bytes for a JSON string. only ASCII bytes ever need escaping, so multi-byte UTF-8
+// characters split across two chunks are passed through untouched
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'"' => escaped.extend_from_slice(b"\\\""),
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            0..0x20 => escaped.extend_from_slice(format!("\\u{:04x}", byte).as_bytes()),
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_matches_serde() {
+        let raw = "quote \" backslash \\ newline \n tab \t bell \u{7} é ✓";
+
+        let escaped = String::from_utf8(escape(raw.as_bytes())).unwrap();
+
+        assert_eq!(
+            format!("\"{}\"", escaped),
+            serde_json::to_string(raw).unwrap()
+        );
+    }
+}
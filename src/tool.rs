@@ -2,16 +2,81 @@ use std::env;
 use std::fmt::Display;
 #[cfg(unix)]
 use std::fs;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::thread;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use spdlog::{debug, error};
+use spdlog::{debug, error, warn};
+
+pub const DEFAULT_VERSION_PROBE_ATTEMPTS: u32 = 3;
+pub const DEFAULT_VERSION_PROBE_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Tool {
     cmd: String,
     version: Option<String>,
     version_arg: Option<String>,
+    // how the dashboard groups and describes the tool (e.g. "recon", "exploitation")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    // set when the command found in the PATH is a wrapper of a sandboxed package, whose
+    // execution context (confinement, filesystem view) differs from a native install
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    packaging: Option<Packaging>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Packaging {
+    Snap,
+    Flatpak,
+}
+
+// directories the snap wrappers are installed in, depending on the distribution
+#[cfg(unix)]
+const SNAP_BIN_DIRS: [&str; 2] = ["/snap/bin", "/var/lib/snapd/snap/bin"];
+
+// the snap wrappers are links to the snap binary itself
+#[cfg(unix)]
+const SNAP_BINARY: &str = "/usr/bin/snap";
+
+// system wide (/var/lib/flatpak) or per user (~/.local/share/flatpak) exports
+#[cfg(unix)]
+const FLATPAK_EXPORTS_DIR: &str = "flatpak/exports/bin";
+
+impl Packaging {
+    /// Packaging of the wrapper installed at `path`, recognized by the known install paths of
+    /// the wrappers and, for links, of their target.
+    #[cfg(unix)]
+    pub fn of(path: &Path) -> Option<Packaging> {
+        if SNAP_BIN_DIRS
+            .iter()
+            .any(|dir| path.parent() == Some(Path::new(dir)))
+        {
+            return Some(Packaging::Snap);
+        }
+        if path
+            .parent()
+            .is_some_and(|dir| dir.ends_with(FLATPAK_EXPORTS_DIR))
+        {
+            return Some(Packaging::Flatpak);
+        }
+
+        match fs::canonicalize(path) {
+            Ok(target) if target == Path::new(SNAP_BINARY) => Some(Packaging::Snap),
+            Ok(target) if target != path => Packaging::of(&target),
+            _ => None,
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn of(_path: &Path) -> Option<Packaging> {
+        None
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -22,10 +87,30 @@ pub enum ToolError {
     #[error("failed to run {0}: {1}")]
     CommandFailed(String, #[source] std::io::Error),
 
+    #[error("{0} printed no version and exited with {1}")]
+    ProbeFailed(String, ExitStatus),
+
     #[error("utf8 decode failed")]
     Utf8Error,
 }
 
+// how many times the version probe of a tool is attempted before giving up, and how long to
+// wait between two attempts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VersionProbe {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for VersionProbe {
+    fn default() -> Self {
+        VersionProbe {
+            attempts: DEFAULT_VERSION_PROBE_ATTEMPTS,
+            delay: DEFAULT_VERSION_PROBE_DELAY,
+        }
+    }
+}
+
 impl Tool {
     /// Creates a new Tool instance with the given command and tries to fetch its version.
     #[allow(dead_code)]
@@ -34,6 +119,9 @@ impl Tool {
             cmd,
             version: None,
             version_arg: None,
+            category: None,
+            description: None,
+            packaging: None,
         };
 
         debug!("Getting tool version...");
@@ -45,30 +133,92 @@ impl Tool {
         tool
     }
 
+    /// Creates a Tool instance for the given command without fetching its version.
+    pub fn from_cmd(cmd: String) -> Tool {
+        Tool {
+            cmd,
+            version: None,
+            version_arg: None,
+            category: None,
+            description: None,
+            packaging: None,
+        }
+    }
+
     /// Attempts to execute the tool with its version argument and store the version string.
     pub fn get_version(&mut self) -> Result<(), ToolError> {
+        let output = self.run_version()?;
+        self.set_version(output.stdout)
+    }
+
+    /// Same as `get_version`, retrying up to `probe.attempts` times in total when the tool
+    /// could not be run or printed nothing. Many tools print their version and still exit with
+    /// an error, the exit status is not looked at. The output of the last attempt is kept
+    /// whatever it is, and a missing version argument is not retried. Blocks while waiting
+    /// between two attempts.
+    pub fn probe_version(&mut self, probe: &VersionProbe) -> Result<(), ToolError> {
+        let mut attempt = 1;
+        loop {
+            let result = self.run_version().and_then(|output| {
+                if output.stdout.trim_ascii().is_empty() && attempt < probe.attempts {
+                    return Err(ToolError::ProbeFailed(self.cmd.clone(), output.status));
+                }
+                self.set_version(output.stdout)
+            });
+            match result {
+                Err(err @ ToolError::MissingVersionArg(_)) => return Err(err),
+                Err(err) if attempt < probe.attempts => {
+                    warn!(
+                        "Version probe {}/{} of {} failed: {}",
+                        attempt, probe.attempts, self.cmd, err
+                    );
+                    attempt += 1;
+                    thread::sleep(probe.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn run_version(&self) -> Result<Output, ToolError> {
         let version_arg = self
             .version_arg
-            .clone()
+            .as_ref()
             .ok_or_else(|| ToolError::MissingVersionArg(self.cmd.clone()))?;
 
-        let output = Command::new(&self.cmd)
+        Command::new(&self.cmd)
             .arg(version_arg)
             .output()
-            .map_err(|e| ToolError::CommandFailed(self.cmd.clone(), e))?;
+            .map_err(|e| ToolError::CommandFailed(self.cmd.clone(), e))
+    }
 
-        let version = String::from_utf8(output.stdout).map_err(|_| ToolError::Utf8Error)?;
+    fn set_version(&mut self, stdout: Vec<u8>) -> Result<(), ToolError> {
+        let version = String::from_utf8(stdout).map_err(|_| ToolError::Utf8Error)?;
         self.version = Some(version);
         Ok(())
     }
 
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
+
     pub fn version(&self) -> &Option<String> {
         &self.version
     }
 
-    /// Checks if the tool is available in the system PATH. Its the only part of the project's
-    /// code where we had to use macros to cross-platform
+    /// Records whether the command found in the PATH is a snap or flatpak wrapper.
+    pub fn detect_packaging(&mut self) {
+        self.packaging = self.resolve().and_then(|path| Packaging::of(&path));
+    }
+
+    /// Checks if the tool is available in the system PATH.
     pub fn is_available(&self) -> bool {
+        self.resolve().is_some()
+    }
+
+    /// Finds the tool in the system PATH. Its the only part of the project's code where we had
+    /// to use macros to cross-platform
+    pub fn resolve(&self) -> Option<PathBuf> {
         if let Some(paths) = env::var_os("PATH") {
             #[cfg(unix)]
             {
@@ -78,7 +228,7 @@ impl Tool {
                         use std::os::unix::fs::PermissionsExt;
                         let mode = metadata.permissions().mode();
                         if mode & 0o111 != 0 {
-                            return true; // executable bit set
+                            return Some(full_path); // executable bit set
                         }
                     }
                 }
@@ -99,13 +249,13 @@ impl Tool {
                     for ext in &exts {
                         let candidate = path.join(format!("{}{}", self.cmd, ext));
                         if candidate.exists() {
-                            return true;
+                            return Some(candidate);
                         }
                     }
                 }
             }
         }
-        false
+        None
     }
 }
 
@@ -135,6 +285,9 @@ mod tests {
             cmd,
             version: None,
             version_arg: None,
+            category: None,
+            description: None,
+            packaging: None,
         };
 
         assert!(tool.is_available());
@@ -146,6 +299,9 @@ mod tests {
             cmd: "non_existing_cmd".to_string(),
             version: None,
             version_arg: None,
+            category: None,
+            description: None,
+            packaging: None,
         };
 
         assert!(!tool.is_available());
@@ -159,12 +315,18 @@ mod tests {
             cmd: "echo".to_string(),
             version: None,
             version_arg: Some("--version".to_string()),
+            category: None,
+            description: None,
+            packaging: None,
         };
         #[cfg(windows)]
         let mut tool = Tool {
             cmd: "cmd".to_string(),
             version: None,
             version_arg: Some("/C ver".to_string()), // "ver" prints Windows version
+            category: None,
+            description: None,
+            packaging: None,
         };
 
         let _ = tool.get_version();
@@ -173,6 +335,206 @@ mod tests {
         assert!(!tool.version().as_ref().unwrap().is_empty());
     }
 
+    #[test]
+    fn test_category_and_description_round_trip() {
+        // Given a tool definition with its metadata
+        let definition = serde_json::json!({
+            "cmd": "nmap",
+            "version_arg": "--version",
+            "category": "recon",
+            "description": "Network exploration and port scanning",
+        });
+
+        // When
+        let tool: Tool = serde_json::from_value(definition.clone()).unwrap();
+        let serialized = serde_json::to_value(&tool).unwrap();
+
+        // Then
+        assert_eq!(tool.category.as_deref(), Some("recon"));
+        assert_eq!(serialized["category"], definition["category"]);
+        assert_eq!(serialized["description"], definition["description"]);
+    }
+
+    #[test]
+    fn test_metadata_is_optional() {
+        let tool: Tool = serde_json::from_value(serde_json::json!({"cmd": "echo"})).unwrap();
+
+        let serialized = serde_json::to_value(&tool).unwrap();
+
+        assert!(serialized.get("category").is_none());
+        assert!(serialized.get("description").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_version_retries_transient_failures() {
+        // Given a version command failing on its first run only
+        let dir = env::temp_dir().join(format!("agent-probe-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("probed");
+        let script = dir.join("version.sh");
+        fs::write(
+            &script,
+            format!(
+                "if [ -e {0} ]; then echo 1.2.3; else touch {0}; exit 1; fi",
+                marker.display()
+            ),
+        )
+        .unwrap();
+        let mut tool = Tool {
+            cmd: "sh".to_string(),
+            version: None,
+            version_arg: Some(script.display().to_string()),
+            category: None,
+            description: None,
+            packaging: None,
+        };
+        let probe = VersionProbe {
+            attempts: 2,
+            delay: Duration::from_millis(10),
+        };
+
+        // When
+        let result = tool.probe_version(&probe);
+
+        // Then
+        assert!(result.is_ok());
+        assert_eq!(tool.version().as_deref(), Some("1.2.3\n"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_version_keeps_the_output_of_a_failing_tool() {
+        // Given a tool printing its version, then exiting with an error
+        let dir = env::temp_dir().join(format!("agent-probe-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let runs = dir.join("runs");
+        let script = dir.join("version.sh");
+        fs::write(
+            &script,
+            format!("echo run >> {}; echo 7.94; exit 1", runs.display()),
+        )
+        .unwrap();
+        let mut tool = Tool {
+            cmd: "sh".to_string(),
+            version: None,
+            version_arg: Some(script.display().to_string()),
+            category: None,
+            description: None,
+            packaging: None,
+        };
+        let probe = VersionProbe {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+
+        // When
+        let result = tool.probe_version(&probe);
+
+        // Then its version is taken at once
+        assert!(result.is_ok());
+        assert_eq!(tool.version().as_deref(), Some("7.94\n"));
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_version_falls_back_to_the_output_of_the_last_attempt() {
+        let mut tool = Tool {
+            cmd: "false".to_string(),
+            version: None,
+            version_arg: Some("-x".to_string()),
+            category: None,
+            description: None,
+            packaging: None,
+        };
+        let probe = VersionProbe {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+
+        assert!(tool.probe_version(&probe).is_ok());
+        assert_eq!(tool.version().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_probe_version_gives_up_when_the_tool_cannot_run() {
+        let mut tool = Tool {
+            cmd: "non_existing_cmd".to_string(),
+            version: None,
+            version_arg: Some("--version".to_string()),
+            category: None,
+            description: None,
+            packaging: None,
+        };
+        let probe = VersionProbe {
+            attempts: 2,
+            delay: Duration::from_millis(1),
+        };
+
+        let result = tool.probe_version(&probe);
+
+        assert!(matches!(result, Err(ToolError::CommandFailed(..))));
+        assert!(tool.version().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packaging_of_wrappers() {
+        // Given wrappers at the install paths of snap and flatpak, and a native binary
+        let snap = Path::new("/snap/bin/nmap");
+        let flatpak = Path::new("/home/user/.local/share/flatpak/exports/bin/org.zaproxy.ZAP");
+        let native = Path::new("/usr/bin/nmap");
+
+        // Then
+        assert_eq!(Packaging::of(snap), Some(Packaging::Snap));
+        assert_eq!(
+            Packaging::of(Path::new("/var/lib/snapd/snap/bin/nmap")),
+            Some(Packaging::Snap)
+        );
+        assert_eq!(Packaging::of(flatpak), Some(Packaging::Flatpak));
+        assert_eq!(Packaging::of(native), None);
+        assert_eq!(
+            Packaging::of(Path::new("/snap/nmap/current/bin/nmap")),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_packaging_of_link_to_wrapper() {
+        // Given a link to a flatpak export, as installed in ~/bin
+        let dir = env::temp_dir().join(format!("agent-packaging-{}", uuid::Uuid::new_v4()));
+        let exports = dir.join("flatpak/exports/bin");
+        fs::create_dir_all(&exports).unwrap();
+        fs::write(exports.join("zap"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink(exports.join("zap"), dir.join("zap")).unwrap();
+
+        // When
+        let packaging = Packaging::of(&dir.join("zap"));
+
+        // Then
+        assert_eq!(packaging, Some(Packaging::Flatpak));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_packaging_in_capabilities() {
+        let mut tool = Tool::from_cmd("nmap".to_string());
+        assert!(
+            serde_json::to_value(&tool)
+                .unwrap()
+                .get("packaging")
+                .is_none()
+        );
+
+        tool.packaging = Some(Packaging::Snap);
+
+        assert_eq!(serde_json::to_value(&tool).unwrap()["packaging"], "snap");
+    }
+
     #[test]
     fn test_new_does_not_panic_even_if_version_arg_none() {
         // Here we construct with just the binary name
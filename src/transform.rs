@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+use crate::job::Job;
+
+// hook rewriting each fetched job before it is queued, so a deployment can enforce its own
+// conventions consistently (e.g. a mandatory `--output` flag, normalized targets). a rejected
+// job is not run, it is reported as skipped with the given reason
+pub trait JobTransformer: Send + Sync + Debug {
+    fn transform(&self, job: &mut Job) -> Result<(), String>;
+}
+
+// leaves the jobs as the server sent them
+#[derive(Debug, Default)]
+pub struct NoopTransformer;
+
+impl JobTransformer for NoopTransformer {
+    fn transform(&self, _job: &mut Job) -> Result<(), String> {
+        Ok(())
+    }
+}
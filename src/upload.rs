@@ -0,0 +1,711 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use spdlog::{debug, warn};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::api::ApiClient;
+use crate::api::client::ClientError;
+
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 2;
+pub const DEFAULT_UPLOAD_RETRIES: u32 = 3;
+pub const DEFAULT_UPLOAD_BACKOFF: Duration = Duration::from_secs(1);
+// reports are small, so they are retried sooner but fewer times than artifacts
+pub const DEFAULT_REPORT_RETRIES: u32 = 2;
+pub const DEFAULT_REPORT_BACKOFF: Duration = Duration::from_millis(200);
+
+// how many times a failed transfer is retried, waiting `backoff` times the number of the retry
+// before each one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const ARTIFACTS: RetryPolicy = RetryPolicy {
+        retries: DEFAULT_UPLOAD_RETRIES,
+        backoff: DEFAULT_UPLOAD_BACKOFF,
+    };
+    pub const REPORTS: RetryPolicy = RetryPolicy {
+        retries: DEFAULT_REPORT_RETRIES,
+        backoff: DEFAULT_REPORT_BACKOFF,
+    };
+
+    // time waited before the `retry`th retry, starting at 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff * retry
+    }
+}
+
+// what becomes of the local copy of an artifact once uploaded. artifacts that could not be
+// uploaded are always kept, as it is their only copy
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ArtifactRetention {
+    // left where the job wrote them
+    #[default]
+    All,
+    None,
+    // the most recently uploaded ones
+    Last(usize),
+    // the ones of the failed jobs, for debugging
+    OnFailure,
+}
+
+// "all", "none", "last:N" or "on-failure"
+pub fn parse_artifact_retention(raw: &str) -> Result<ArtifactRetention, String> {
+    match raw {
+        "all" => Ok(ArtifactRetention::All),
+        "none" => Ok(ArtifactRetention::None),
+        "on-failure" => Ok(ArtifactRetention::OnFailure),
+        _ => raw
+            .strip_prefix("last:")
+            .and_then(|count| count.parse().ok())
+            .map(ArtifactRetention::Last)
+            .ok_or_else(|| {
+                format!(
+                    "invalid artifact retention {:?}, expected \"all\", \"none\", \"last:N\" or \"on-failure\"",
+                    raw
+                )
+            }),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadStatus {
+    Pending,
+    // reference of the artifact returned by the server
+    Uploaded(String),
+    Failed(String),
+}
+
+// uploads of each job, by artifact path
+type Statuses = Arc<Mutex<HashMap<Uuid, Vec<(PathBuf, UploadStatus)>>>>;
+
+// Bounded queue uploading job artifacts in the background: at most `max_concurrent` uploads
+// run at once and failed ones are retried, so a burst of large artifacts neither saturates the
+// link nor blocks the execution of new jobs.
+#[derive(Debug, Clone)]
+pub struct UploadQueue {
+    permits: Arc<Semaphore>,
+    retry: RetryPolicy,
+    statuses: Statuses,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    // number of uploads currently in flight and the highest number seen
+    active: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+    // size of the artifacts uploaded so far
+    uploaded_bytes: Arc<AtomicU64>,
+    retention: ArtifactRetention,
+    // total size of the retained artifacts, the oldest ones being removed beyond it
+    retention_max_bytes: Option<u64>,
+    // directory owned by the agent, the retention policy never removes a file outside of it as
+    // the paths of the artifacts come from the server
+    retention_dir: Option<PathBuf>,
+    // uploaded artifacts kept by the retention policy with their size, oldest first
+    retained: Arc<Mutex<VecDeque<(PathBuf, u64)>>>,
+    // air-gapped mode: the artifacts are copied into `<dir>/<job_id>/` instead of being uploaded
+    copy_dir: Option<PathBuf>,
+}
+
+impl Default for UploadQueue {
+    fn default() -> Self {
+        UploadQueue::new(DEFAULT_MAX_CONCURRENT_UPLOADS, RetryPolicy::ARTIFACTS)
+    }
+}
+
+impl UploadQueue {
+    pub fn new(max_concurrent: usize, retry: RetryPolicy) -> Self {
+        UploadQueue {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            retry,
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            handles: Arc::new(Mutex::new(Vec::new())),
+            active: Arc::new(AtomicUsize::new(0)),
+            peak: Arc::new(AtomicUsize::new(0)),
+            uploaded_bytes: Arc::new(AtomicU64::new(0)),
+            retention: ArtifactRetention::default(),
+            retention_max_bytes: None,
+            retention_dir: None,
+            retained: Arc::new(Mutex::new(VecDeque::new())),
+            copy_dir: None,
+        }
+    }
+
+    pub fn set_copy_dir(&mut self, dir: Option<PathBuf>) {
+        self.copy_dir = dir;
+    }
+
+    pub fn set_retention(
+        &mut self,
+        retention: ArtifactRetention,
+        max_bytes: Option<u64>,
+        dir: Option<PathBuf>,
+    ) {
+        self.retention = retention;
+        self.retention_max_bytes = max_bytes;
+        // resolved once, the artifacts' paths are resolved too before being compared to it
+        self.retention_dir = dir.map(|dir| fs::canonicalize(&dir).unwrap_or(dir));
+    }
+
+    pub fn get_artifacts_dir(&self) -> Option<&PathBuf> {
+        self.retention_dir.as_ref()
+    }
+
+    // resolve the artifact (relative to the directory owned by the agent) following links and
+    // "..", none when it does not lie in that directory
+    pub fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let dir = self.retention_dir.as_ref()?;
+        fs::canonicalize(dir.join(path))
+            .ok()
+            .filter(|path| path.starts_with(dir))
+    }
+
+    // whether the artifact lies in the directory owned by the agent
+    fn is_owned(&self, path: &Path) -> bool {
+        self.resolve(path).is_some()
+    }
+
+    // upload `path` as an artifact of the job in the background, then apply the retention
+    // policy to it
+    pub fn enqueue(&self, client: &ApiClient, job_id: Uuid, path: PathBuf, job_succeeded: bool) {
+        self.statuses
+            .lock()
+            .unwrap()
+            .entry(job_id)
+            .or_default()
+            .push((path.clone(), UploadStatus::Pending));
+
+        let queue = self.clone();
+        let client = client.clone();
+        let handle = tokio::spawn(async move {
+            let status = queue.upload(&client, job_id, &path).await;
+            if matches!(status, UploadStatus::Uploaded(_)) {
+                queue.apply_retention(&path, job_succeeded);
+            }
+            queue.set_status(job_id, &path, status);
+        });
+        let mut handles = self.handles.lock().unwrap();
+        // the handles of the finished uploads are dropped, so they do not pile up
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+
+    async fn upload(&self, client: &ApiClient, job_id: Uuid, path: &PathBuf) -> UploadStatus {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(active, Ordering::SeqCst);
+
+        let uri = client.endpoints().job_artifacts(&job_id);
+        let mut attempt = 0;
+        let status = loop {
+            let result = match &self.copy_dir {
+                Some(dir) => UploadQueue::copy(dir, job_id, path).await,
+                None => client.upload_file(&uri, path, attempt > 0).await,
+            };
+            match result {
+                Ok(reference) => {
+                    debug!("Uploaded artifact {:?} of job {}", path, job_id);
+                    if let Ok(metadata) = tokio::fs::metadata(path).await {
+                        self.uploaded_bytes
+                            .fetch_add(metadata.len(), Ordering::Relaxed);
+                    }
+                    break UploadStatus::Uploaded(reference);
+                }
+                Err(err) if attempt < self.retry.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Upload of {:?} failed ({}), retrying ({}/{})",
+                        path, err, attempt, self.retry.retries
+                    );
+                    tokio::time::sleep(self.retry.delay(attempt)).await;
+                }
+                Err(err) => {
+                    warn!("Giving up uploading {:?} of job {}: {}", path, job_id, err);
+                    break UploadStatus::Failed(err.to_string());
+                }
+            }
+        };
+
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        status
+    }
+
+    // copy the artifact next to the reports, its path is the reference of the artifact
+    async fn copy(dir: &Path, job_id: Uuid, path: &Path) -> Result<String, ClientError> {
+        let name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("artifact {:?} has no file name", path),
+            )
+        })?;
+        let job_dir = dir.join(job_id.to_string());
+        tokio::fs::create_dir_all(&job_dir).await?;
+        let copy = job_dir.join(name);
+        tokio::fs::copy(path, &copy).await?;
+        Ok(copy.display().to_string())
+    }
+
+    // keep or remove the local copy of an uploaded artifact, then remove the oldest retained
+    // ones when there are too many of them or they take too much space
+    fn apply_retention(&self, path: &Path, job_succeeded: bool) {
+        let keep = match self.retention {
+            ArtifactRetention::All => return,
+            ArtifactRetention::None => false,
+            ArtifactRetention::Last(count) => count > 0,
+            ArtifactRetention::OnFailure => !job_succeeded,
+        };
+        if !self.is_owned(path) {
+            warn!(
+                "Keeping uploaded artifact {:?}, it is not in the artifacts directory",
+                path
+            );
+            return;
+        }
+        let max_count = match self.retention {
+            ArtifactRetention::Last(count) => count,
+            _ => usize::MAX,
+        };
+
+        let mut expired = Vec::new();
+        {
+            let mut retained = self.retained.lock().unwrap();
+            if keep {
+                let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+                retained.push_back((path.to_path_buf(), size));
+            } else {
+                expired.push(path.to_path_buf());
+            }
+
+            let mut total: u64 = retained.iter().map(|(_, size)| size).sum();
+            while retained.len() > max_count
+                || self.retention_max_bytes.is_some_and(|max| total > max)
+            {
+                let Some((path, size)) = retained.pop_front() else {
+                    break;
+                };
+                total -= size;
+                expired.push(path);
+            }
+        }
+
+        for path in expired {
+            match fs::remove_file(&path) {
+                Ok(()) => debug!("Removed uploaded artifact {:?}", path),
+                Err(err) => warn!("Could not remove uploaded artifact {:?}: {}", path, err),
+            }
+        }
+    }
+
+    fn set_status(&self, job_id: Uuid, path: &PathBuf, status: UploadStatus) {
+        let mut statuses = self.statuses.lock().unwrap();
+        if let Some(entry) = statuses
+            .get_mut(&job_id)
+            .and_then(|uploads| uploads.iter_mut().find(|(p, _)| p == path))
+        {
+            entry.1 = status;
+        }
+    }
+
+    // whether all the uploads of the job are done, successfully or not
+    pub fn is_settled(&self, job_id: &Uuid) -> bool {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .is_none_or(|uploads| {
+                uploads
+                    .iter()
+                    .all(|(_, status)| *status != UploadStatus::Pending)
+            })
+    }
+
+    // references of the artifacts of the job the server confirmed receiving
+    pub fn confirmed(&self, job_id: &Uuid) -> Vec<String> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .map(|uploads| {
+                uploads
+                    .iter()
+                    .filter_map(|(_, status)| match status {
+                        UploadStatus::Uploaded(reference) => Some(reference.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // whether the artifact is still being uploaded, by any job
+    pub fn is_pending(&self, path: &Path) -> bool {
+        self.statuses.lock().unwrap().values().any(|uploads| {
+            uploads
+                .iter()
+                .any(|(p, status)| p == path && *status == UploadStatus::Pending)
+        })
+    }
+
+    // drop the statuses of the uploads of a reported job
+    pub fn forget(&self, job_id: &Uuid) {
+        self.statuses.lock().unwrap().remove(job_id);
+    }
+
+    // used by unit tests
+    #[allow(dead_code)]
+    pub fn statuses(&self, job_id: &Uuid) -> Vec<UploadStatus> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .map(|uploads| uploads.iter().map(|(_, status)| status.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    // wait for every enqueued upload to be done
+    #[allow(dead_code)]
+    pub async fn wait_idle(&self) {
+        let handles: Vec<JoinHandle<()>> = self.handles.lock().unwrap().drain(..).collect();
+        futures::future::join_all(handles).await;
+    }
+
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes.load(Ordering::Relaxed)
+    }
+
+    // highest number of concurrent uploads so far
+    #[allow(dead_code)]
+    pub fn peak_concurrency(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockServer;
+    use serde_json::json;
+
+    fn make_artifact(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("agent-artifact-{}", Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_uploads_stay_within_concurrency_limit() {
+        // Given a slow artifacts endpoint
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        let uri = format!("/jobs/{}/artifacts", job_id);
+        server.mock_with_delay(
+            "POST",
+            &uri,
+            201,
+            Duration::from_millis(200),
+            json!({"data": {"attributes": {"id": "artifact"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let queue = UploadQueue::new(
+            2,
+            RetryPolicy {
+                retries: 0,
+                backoff: Duration::ZERO,
+            },
+        );
+        let paths: Vec<PathBuf> = (0..6).map(|i| make_artifact(&i.to_string())).collect();
+
+        // When
+        for path in &paths {
+            queue.enqueue(&client, job_id, path.clone(), true);
+        }
+        assert!(!queue.is_settled(&job_id));
+        queue.wait_idle().await;
+
+        // Then
+        assert_eq!(queue.peak_concurrency(), 2);
+        assert!(queue.is_settled(&job_id));
+        assert_eq!(queue.confirmed(&job_id).len(), 6);
+        assert_eq!(server.requests_to("POST", &uri).len(), 6);
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_settled_uploads_are_pruned() {
+        // Given an upload which settled
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        let uri = format!("/jobs/{}/artifacts", job_id);
+        server.mock(
+            "POST",
+            &uri,
+            201,
+            json!({"data": {"attributes": {"id": "a1"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let queue = UploadQueue::default();
+        let path = make_artifact("scan output");
+        queue.enqueue(&client, job_id, path.clone(), true);
+        while !queue.is_settled(&job_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        while !queue.handles.lock().unwrap()[0].is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // When another upload is enqueued, and the job is reported
+        queue.enqueue(&client, job_id, path.clone(), true);
+        let handles = queue.handles.lock().unwrap().len();
+        queue.wait_idle().await;
+        queue.forget(&job_id);
+
+        // Then neither the handle of the settled upload nor the statuses are kept
+        assert_eq!(handles, 1);
+        assert!(queue.statuses(&job_id).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_artifacts_are_copied_in_air_gapped_mode() {
+        // Given a queue copying the artifacts, and no server
+        let client = ApiClient::new("file:///".to_string(), String::new()).unwrap();
+        let dir = std::env::temp_dir().join(format!("agent-copies-{}", Uuid::new_v4()));
+        let mut queue = UploadQueue::default();
+        queue.set_copy_dir(Some(dir.clone()));
+        let job_id = Uuid::new_v4();
+        let path = make_artifact("scan output");
+
+        // When
+        queue.enqueue(&client, job_id, path.clone(), true);
+        queue.wait_idle().await;
+
+        // Then the artifact is copied into the directory of the job
+        let copy = dir.join(job_id.to_string()).join(path.file_name().unwrap());
+        assert_eq!(queue.confirmed(&job_id), vec![copy.display().to_string()]);
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "scan output");
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_upload_is_resumed() {
+        // Given a server that received the first 5 bytes before the upload failed
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        let uri = format!("/jobs/{}/artifacts", job_id);
+        server.mock_with_headers("HEAD", &uri, 200, vec![("Upload-Offset", "5")], json!({}));
+        server.mock(
+            "POST",
+            &uri,
+            503,
+            json!({"errors": [{"detail": "connection lost"}]}),
+        );
+        server.mock(
+            "POST",
+            &uri,
+            201,
+            json!({"data": {"attributes": {"id": "a1"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let queue = UploadQueue::new(
+            1,
+            RetryPolicy {
+                retries: 1,
+                backoff: Duration::from_millis(10),
+            },
+        );
+        let path = make_artifact("scan results");
+
+        // When
+        queue.enqueue(&client, job_id, path.clone(), true);
+        queue.wait_idle().await;
+
+        // Then the retry only sent the missing bytes, the server being asked for them once
+        assert_eq!(server.requests_to("HEAD", &uri).len(), 1);
+        assert_eq!(
+            queue.statuses(&job_id),
+            vec![UploadStatus::Uploaded("a1".to_string())]
+        );
+        let requests = server.requests_to("POST", &uri);
+        assert_eq!(requests[0].body, "scan results");
+        assert_eq!(requests[0].header("content-range"), None);
+        assert_eq!(requests[1].body, "results");
+        assert_eq!(requests[1].header("content-range"), Some("bytes 5-11/12"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_artifacts_kept_on_failure() {
+        // Given
+        let server = MockServer::start().await;
+        let (failed, succeeded) = (Uuid::new_v4(), Uuid::new_v4());
+        for job_id in [failed, succeeded] {
+            server.mock(
+                "POST",
+                &format!("/jobs/{}/artifacts", job_id),
+                201,
+                json!({"data": {"attributes": {"id": "a1"}}}),
+            );
+        }
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let mut queue = UploadQueue::default();
+        queue.set_retention(
+            ArtifactRetention::OnFailure,
+            None,
+            Some(std::env::temp_dir()),
+        );
+        let (failed_path, succeeded_path) = (make_artifact("failed"), make_artifact("ok"));
+
+        // When
+        queue.enqueue(&client, failed, failed_path.clone(), false);
+        queue.enqueue(&client, succeeded, succeeded_path.clone(), true);
+        queue.wait_idle().await;
+
+        // Then only the artifact of the failed job is kept
+        assert!(failed_path.is_file());
+        assert!(!succeeded_path.exists());
+        assert_eq!(queue.confirmed(&succeeded), ["a1"]);
+        std::fs::remove_file(failed_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_last_artifacts_kept_within_budget() {
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        let uri = format!("/jobs/{}/artifacts", job_id);
+        server.mock(
+            "POST",
+            &uri,
+            201,
+            json!({"data": {"attributes": {"id": "a"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let mut queue = UploadQueue::default();
+        queue.set_retention(
+            ArtifactRetention::Last(2),
+            Some(10),
+            Some(std::env::temp_dir()),
+        );
+        let paths: Vec<PathBuf> = ["first", "second", "third"]
+            .iter()
+            .map(|content| make_artifact(content))
+            .collect();
+
+        // one at a time, so they are retained in order
+        for path in &paths {
+            queue.enqueue(&client, job_id, path.clone(), true);
+            queue.wait_idle().await;
+        }
+
+        // the last two fit in the count but not in the 10 bytes budget
+        assert!(!paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].is_file());
+        std::fs::remove_file(&paths[2]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_artifacts_outside_of_the_artifacts_dir_are_kept() {
+        // Given an artifact outside of the artifacts directory
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        server.mock(
+            "POST",
+            &format!("/jobs/{}/artifacts", job_id),
+            201,
+            json!({"data": {"attributes": {"id": "a1"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let mut queue = UploadQueue::default();
+        let dir = std::env::temp_dir().join(format!("agent-artifacts-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        queue.set_retention(ArtifactRetention::None, None, Some(dir.clone()));
+        let outside = make_artifact("outside");
+        let inside = dir.join("inside");
+        std::fs::write(&inside, "inside").unwrap();
+        // a path escaping the directory through ".."
+        let escaping = dir.join("..").join(outside.file_name().unwrap());
+
+        // When
+        queue.enqueue(&client, job_id, inside.clone(), true);
+        queue.enqueue(&client, job_id, escaping, true);
+        queue.wait_idle().await;
+
+        // Then only the artifact in the directory is removed
+        assert!(!inside.exists());
+        assert!(outside.is_file());
+        std::fs::remove_file(outside).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_artifact_retention() {
+        assert_eq!(parse_artifact_retention("all"), Ok(ArtifactRetention::All));
+        assert_eq!(
+            parse_artifact_retention("none"),
+            Ok(ArtifactRetention::None)
+        );
+        assert_eq!(
+            parse_artifact_retention("last:5"),
+            Ok(ArtifactRetention::Last(5))
+        );
+        assert_eq!(
+            parse_artifact_retention("on-failure"),
+            Ok(ArtifactRetention::OnFailure)
+        );
+        assert!(parse_artifact_retention("last:").is_err());
+        assert!(parse_artifact_retention("some").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_uploads_are_retried() {
+        let server = MockServer::start().await;
+        let job_id = Uuid::new_v4();
+        let uri = format!("/jobs/{}/artifacts", job_id);
+        server.mock("POST", &uri, 503, json!({"errors": [{"detail": "busy"}]}));
+        server.mock(
+            "POST",
+            &uri,
+            201,
+            json!({"data": {"attributes": {"id": "a1"}}}),
+        );
+        let client = ApiClient::new(server.url(), "token".to_string()).unwrap();
+        let queue = UploadQueue::new(
+            1,
+            RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(10),
+            },
+        );
+        let path = make_artifact("scan results");
+
+        queue.enqueue(&client, job_id, path.clone(), true);
+        queue.wait_idle().await;
+
+        assert_eq!(
+            queue.statuses(&job_id),
+            vec![UploadStatus::Uploaded("a1".to_string())]
+        );
+        let requests = server.requests_to("POST", &uri);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].body, "scan results");
+        assert_eq!(queue.uploaded_bytes(), "scan results".len() as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+}
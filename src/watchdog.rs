@@ -0,0 +1,161 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use spdlog::error;
+
+// detects a wedged poll loop (e.g. a blocking call made on the runtime, or a request which never
+// completes), which would otherwise stop making progress while the process looks alive. the
+// loop pets the watchdog while it waits for the next cycle and at the boundaries of each cycle,
+// and a thread of its own calls `on_stall` once no pet arrived in time: within the timeout
+// between cycles, within the cycle's own bound during one. the agent then aborts, so its
+// supervisor restarts it. the jobs run in the background, long ones do not hold the cycle
+
+// the loop pets the watchdog this many times per timeout, so a late pet is not mistaken for a
+// stall
+const PETS_PER_TIMEOUT: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    // when the watchdog was last petted, and how long the next pet may take
+    last_pet: Arc<Mutex<(Instant, Duration)>>,
+    stopped: Arc<AtomicBool>,
+    timeout: Duration,
+    // longest a poll cycle may take
+    max_cycle: Duration,
+}
+
+impl Watchdog {
+    pub fn spawn<F>(timeout: Duration, max_cycle: Duration, on_stall: F) -> Watchdog
+    where
+        F: FnOnce(Duration) + Send + 'static,
+    {
+        let watchdog = Watchdog {
+            last_pet: Arc::new(Mutex::new((Instant::now(), timeout))),
+            stopped: Arc::default(),
+            timeout,
+            max_cycle,
+        };
+
+        let state = watchdog.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(state.pet_interval());
+                if state.stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let (last_pet, limit) = *state.last_pet.lock().unwrap();
+                let elapsed = last_pet.elapsed();
+                if elapsed > limit {
+                    on_stall(elapsed);
+                    return;
+                }
+            }
+        });
+
+        watchdog
+    }
+
+    pub fn pet_interval(&self) -> Duration {
+        (self.timeout.min(self.max_cycle) / PETS_PER_TIMEOUT).max(Duration::from_millis(1))
+    }
+
+    pub fn pet(&self) {
+        self.pet_within(self.timeout);
+    }
+
+    fn pet_within(&self, limit: Duration) {
+        *self.last_pet.lock().unwrap() = (Instant::now(), limit);
+    }
+
+    // the loop exited on purpose, the shutdown may take as long as it needs
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    // await a poll cycle, petting the watchdog before and after it only: a cycle blocking the
+    // runtime's thread or awaiting forever makes it fire once the cycle's bound passed
+    pub async fn watch<F: Future>(&self, cycle: F) -> F::Output {
+        self.pet_within(self.max_cycle);
+        let output = cycle.await;
+        self.pet();
+        output
+    }
+}
+
+// what the agent does when the watchdog fires: abort, so it gets restarted
+pub fn abort_on_stall(elapsed: Duration) {
+    error!(
+        "The poll loop made no progress for {:?}, aborting so the agent gets restarted",
+        elapsed
+    );
+    std::process::abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn make_watchdog(
+        timeout: Duration,
+        max_cycle: Duration,
+    ) -> (Watchdog, mpsc::Receiver<Duration>) {
+        let (stalled_tx, stalled_rx) = mpsc::channel();
+        let watchdog = Watchdog::spawn(timeout, max_cycle, move |elapsed| {
+            let _ = stalled_tx.send(elapsed);
+        });
+        (watchdog, stalled_rx)
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_fires_on_stalled_loop() {
+        // Given a cycle wedged by a blocking call
+        let (watchdog, stalled) =
+            make_watchdog(Duration::from_millis(200), Duration::from_millis(200));
+        let cycle = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            std::thread::sleep(Duration::from_millis(600));
+        };
+
+        // When
+        watchdog.watch(cycle).await;
+
+        // Then
+        let elapsed = stalled.try_recv().unwrap();
+        assert!(elapsed > Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_fires_on_cycle_awaiting_forever() {
+        // Given a cycle awaiting a future which never completes, without blocking
+        let (watchdog, stalled) =
+            make_watchdog(Duration::from_millis(200), Duration::from_millis(200));
+        let cycle = std::future::pending::<()>();
+
+        // When
+        let result = tokio::time::timeout(Duration::from_millis(800), watchdog.watch(cycle)).await;
+
+        // Then
+        assert!(result.is_err());
+        let elapsed = stalled.try_recv().unwrap();
+        assert!(elapsed > Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_stays_quiet_while_loop_runs() {
+        // Given a cycle longer than the timeout between cycles, but within its own bound
+        let (watchdog, stalled) = make_watchdog(Duration::from_millis(200), Duration::from_secs(2));
+        let cycle = tokio::time::sleep(Duration::from_millis(600));
+
+        // When
+        watchdog.watch(cycle).await;
+        watchdog.stop();
+        std::thread::sleep(Duration::from_millis(400));
+
+        // Then it never fired, not even once stopped
+        assert!(stalled.try_recv().is_err());
+    }
+}
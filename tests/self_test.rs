@@ -0,0 +1,16 @@
+use std::process::Command;
+
+// the built binary passes its own smoke test on the host, without any API to talk to
+#[cfg(unix)]
+#[test]
+fn test_self_test_passes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_agent"))
+        .arg("--self-test")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("ok output capture"));
+    assert!(stdout.ends_with("Self-test PASSED\n"));
+}